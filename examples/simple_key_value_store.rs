@@ -64,7 +64,9 @@ use routing::sendable::Sendable;
 use routing::types;
 use routing::id::Id;
 use routing::authority::Authority;
+use routing::churn::ChurnEvent;
 use routing::NameType;
+use routing::BatchItemResult;
 use routing::error::{ResponseError, InterfaceError};
 use routing::data::{Data, DataRequest};
 use routing::plain_data::PlainData;
@@ -175,6 +177,17 @@ impl routing::client_interface::Interface for TestClient {
         }
     }
 
+    fn handle_get_error_response(&mut self, data_location: NameType, _data_request: DataRequest,
+                                 response_error: ResponseError) {
+        println!("Testing client received get_error_response from {:?} with error {:?}",
+                    data_location, response_error);
+    }
+
+    fn handle_get_batch_response(&mut self,
+                                 results: Vec<(NameType, DataRequest, BatchItemResult)>) {
+        println!("Testing client received get_batch_response with {} results", results.len());
+    }
+
     fn handle_put_response(&mut self, response_error: ResponseError, _request_data: Data) {
         match response_error {
             ResponseError::NoData =>
@@ -183,11 +196,21 @@ impl routing::client_interface::Interface for TestClient {
                 println!("Testing client received put_response with error InvalidRequest"),
             ResponseError::FailedToStoreData(data) =>
                 println!("Testing client received put_response with error FailedToStoreData for {}", data.name()),
+            ResponseError::RateLimitExceeded =>
+                println!("Testing client received put_response with error RateLimitExceeded"),
+            ResponseError::InvalidSuccessor(error) =>
+                println!("Testing client received put_response with error {}", error),
+            ResponseError::InvalidAppend(error) =>
+                println!("Testing client received put_response with error {}", error),
         }
     }
 
-    fn handle_post_response(&mut self, _response_error: ResponseError, _request_data: Data) {
-         unimplemented!();
+    fn handle_put_batch_response(&mut self, results: Vec<(Data, BatchItemResult)>) {
+        println!("Testing client received put_batch_response with {} results", results.len());
+    }
+
+    fn handle_post_response(&mut self, response_error: ResponseError, _request_data: Data) {
+        println!("Testing client received post_response with error {}", response_error);
     }
 
     fn handle_delete_response(&mut self, _response_error: ResponseError, _request_data: Data) {
@@ -290,8 +313,8 @@ impl Interface for TestNode {
         unimplemented!();
     }
 
-    fn handle_churn(&mut self, close_group: Vec<NameType>) -> Vec<MethodCall> {
-        for name in close_group {
+    fn handle_churn(&mut self, churn_event: ChurnEvent) -> Vec<MethodCall> {
+        for name in churn_event.close_group_names() {
           println!("RT: {:?}", name);
         }
         vec![]
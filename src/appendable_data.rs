@@ -0,0 +1,463 @@
+// Copyright 2015 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use rand::random;
+use rustc_serialize::{Decoder, Encodable, Encoder};
+use std::cmp;
+use sodiumoxide::crypto::sign;
+use NameType;
+use canonical::{CanonicalEncoder, APPENDABLE_DATA_DOMAIN, APPENDED_ENTRY_DOMAIN};
+use error::{AppendableDataError, RoutingError};
+use structured_data::StructuredData;
+
+/// An entry of an AppendableData, signed by its author.  The signature covers the name of the
+/// AppendableData, so an entry cannot be replayed into another one, and the version it is
+/// appended to, so an entry cannot be replayed after the owner pruned it.  A random nonce tells
+/// apart entries appending the same data.
+#[derive(Debug, Eq, PartialEq, PartialOrd, Ord, Clone, RustcDecodable, RustcEncodable)]
+pub struct AppendedEntry {
+    version: u64,
+    nonce: u64,
+    data: Vec<u8>,
+    author: sign::PublicKey,
+    signature: sign::Signature,
+}
+
+impl AppendedEntry {
+    /// Creates an entry for the given version of the AppendableData with the given name, signed
+    /// by its author
+    pub fn new(appendable_data_name: &NameType, version: u64, data: Vec<u8>,
+               author: sign::PublicKey, secret_key: &sign::SecretKey)
+            -> Result<AppendedEntry, RoutingError> {
+        let nonce = random::<u64>();
+        let signature = sign::sign_detached(
+            &try!(AppendedEntry::data_to_sign(appendable_data_name, version, nonce, &data)),
+            secret_key);
+        Ok(AppendedEntry {
+            version: version,
+            nonce: nonce,
+            data: data,
+            author: author,
+            signature: signature,
+        })
+    }
+
+    /// Get the version of the AppendableData the entry was appended to
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Get the data
+    pub fn data(&self) -> &Vec<u8> {
+        &self.data
+    }
+
+    /// Get the key of the author
+    pub fn author(&self) -> &sign::PublicKey {
+        &self.author
+    }
+
+    /// Returns true if the author signed this entry for the AppendableData with the given name
+    pub fn verify(&self, appendable_data_name: &NameType) -> bool {
+        match AppendedEntry::data_to_sign(appendable_data_name, self.version, self.nonce,
+                                          &self.data) {
+            Ok(data) => sign::verify_detached(&self.signature, &data, &self.author),
+            Err(_) => false,
+        }
+    }
+
+    fn data_to_sign(appendable_data_name: &NameType, version: u64, nonce: u64, data: &Vec<u8>)
+            -> Result<Vec<u8>, RoutingError> {
+        let mut encoder = CanonicalEncoder::new(APPENDED_ENTRY_DOMAIN);
+        encoder.name(appendable_data_name).u64(version).u64(nonce).bytes(data);
+        Ok(encoder.into_bytes())
+    }
+}
+
+/// AppendableData
+/// An append-only log, such as a mailbox or event feed.  Its owner signs which keys are
+/// authorised to append; each of them (and the owner) can append signed entries with a Post of
+/// this AppendableData carrying only the new entries, see to_append.  Only the owner can prune
+/// the log, by signing the next version together with the entries it keeps, so no one else can
+/// bring back entries the owner pruned.  The name is computed as for StructuredData, so the two
+/// share the type tag space.
+#[derive(Debug, Eq, PartialEq, PartialOrd, Ord, Clone, RustcDecodable, RustcEncodable)]
+pub struct AppendableData {
+    type_tag: u64,
+    identifier: NameType,
+    owner: sign::PublicKey,
+    authorised_keys: Vec<sign::PublicKey>,
+    version: u64,
+    owner_signature: sign::Signature,
+    entries: Vec<AppendedEntry>,
+}
+
+impl AppendableData {
+    /// Creates the first version, without entries, signed by the owner
+    pub fn new(type_tag: u64,
+               identifier: NameType,
+               owner: sign::PublicKey,
+               authorised_keys: Vec<sign::PublicKey>,
+               secret_key: &sign::SecretKey) -> Result<AppendableData, RoutingError> {
+        AppendableData::signed(type_tag, identifier, owner, authorised_keys, 0, vec![],
+                               secret_key)
+    }
+
+    /// Computes the name from the type tag and identifier, to be used by GETs
+    pub fn compute_name(type_tag: u64, identifier: &NameType) -> NameType {
+        StructuredData::compute_name(type_tag, identifier)
+    }
+
+    /// Returns name and validates invariants
+    pub fn name(&self) -> NameType {
+        AppendableData::compute_name(self.type_tag, &self.identifier)
+    }
+
+    /// Returns a copy carrying only the given entries, to be sent in a Post appending them
+    pub fn to_append(&self, entries: Vec<AppendedEntry>) -> AppendableData {
+        AppendableData {
+            type_tag: self.type_tag,
+            identifier: self.identifier.clone(),
+            owner: self.owner.clone(),
+            authorised_keys: self.authorised_keys.clone(),
+            version: self.version,
+            owner_signature: self.owner_signature.clone(),
+            entries: entries,
+        }
+    }
+
+    /// Returns the next version, signed by the owner, keeping only the latest entries
+    pub fn prune(&self, keep_latest: usize, secret_key: &sign::SecretKey)
+            -> Result<AppendableData, RoutingError> {
+        let version = match self.version.checked_add(1) {
+            Some(version) => version,
+            None => return Err(RoutingError::from(AppendableDataError::VersionNotIncremented)),
+        };
+        let first_kept = self.entries.len() - cmp::min(keep_latest, self.entries.len());
+        let pruned = try!(AppendableData::signed(self.type_tag, self.identifier.clone(),
+                                                 self.owner.clone(),
+                                                 self.authorised_keys.clone(),
+                                                 version,
+                                                 self.entries[first_kept..].to_vec(),
+                                                 secret_key));
+        if !pruned.verify_owner_signature() {
+            return Err(RoutingError::from(AppendableDataError::InvalidOwnerSignature));
+        }
+        Ok(pruned)
+    }
+
+    /// Applies a Post of other to the stored version.  With the same version, other's entries
+    /// are appended if all of them are valid, authorised and signed for this version; entries
+    /// signed for an earlier version, which the owner may have pruned, are refused.  With the
+    /// next version, other replaces this one if it is signed by the owner, including the entries
+    /// it keeps: this is how the owner prunes.
+    pub fn update_with_other(&mut self, other: AppendableData)
+            -> Result<(), AppendableDataError> {
+        if other.type_tag != self.type_tag {
+            return Err(AppendableDataError::WrongTypeTag);
+        }
+        if other.identifier != self.identifier {
+            return Err(AppendableDataError::WrongIdentifier);
+        }
+        if other.owner != self.owner {
+            return Err(AppendableDataError::OwnerMismatch);
+        }
+        if other.version == self.version {
+            if other.authorised_keys != self.authorised_keys {
+                return Err(AppendableDataError::AuthorisedKeysMismatch);
+            }
+            if &other.owner_signature.0[..] != &self.owner_signature.0[..] {
+                return Err(AppendableDataError::OwnerSignatureMismatch);
+            }
+            try!(self.verify_entries(&other.entries));
+            if other.entries.iter().any(|entry| entry.version != self.version) {
+                return Err(AppendableDataError::EntryVersionMismatch);
+            }
+            for entry in other.entries.iter() {
+                if self.entries.iter()
+                       .any(|existing| &existing.signature.0[..] == &entry.signature.0[..]) {
+                    return Err(AppendableDataError::DuplicateEntries);
+                }
+            }
+            self.entries.extend(other.entries.into_iter());
+            Ok(())
+        } else if self.version.checked_add(1) == Some(other.version) {
+            try!(other.verify());
+            *self = other;
+            Ok(())
+        } else {
+            Err(AppendableDataError::VersionNotIncremented)
+        }
+    }
+
+    /// Confirms the owner signature, over the entries kept from earlier versions as well, and
+    /// that every entry is validly signed by an authorised key
+    pub fn verify(&self) -> Result<(), AppendableDataError> {
+        if !self.verify_owner_signature() {
+            return Err(AppendableDataError::InvalidOwnerSignature);
+        }
+        self.verify_entries(&self.entries)
+    }
+
+    /// Returns true if the key is the owner's or one the owner authorised to append
+    pub fn is_authorised(&self, key: &sign::PublicKey) -> bool {
+        *key == self.owner || self.authorised_keys.contains(key)
+    }
+
+    /// Get the type_tag
+    pub fn get_type_tag(&self) -> u64 {
+        self.type_tag
+    }
+
+    /// Get the identifier
+    pub fn get_identifier(&self) -> &NameType {
+        &self.identifier
+    }
+
+    /// Get the owner key
+    pub fn get_owner(&self) -> &sign::PublicKey {
+        &self.owner
+    }
+
+    /// Get the keys authorised to append
+    pub fn get_authorised_keys(&self) -> &Vec<sign::PublicKey> {
+        &self.authorised_keys
+    }
+
+    /// Get the version, which is incremented each time the owner prunes
+    pub fn get_version(&self) -> u64 {
+        self.version
+    }
+
+    /// Get the entries, oldest first
+    pub fn get_entries(&self) -> &Vec<AppendedEntry> {
+        &self.entries
+    }
+
+    pub fn payload_size(&self) -> usize {
+        self.entries.iter().fold(0, |total, entry| total + entry.data.len())
+    }
+
+    fn signed(type_tag: u64, identifier: NameType, owner: sign::PublicKey,
+              authorised_keys: Vec<sign::PublicKey>, version: u64, entries: Vec<AppendedEntry>,
+              secret_key: &sign::SecretKey) -> Result<AppendableData, RoutingError> {
+        let data = try!(AppendableData::data_to_sign(type_tag, &identifier, &owner,
+                                                     &authorised_keys, version,
+                                                     &AppendableData::kept(&entries, version)));
+        Ok(AppendableData {
+            type_tag: type_tag,
+            identifier: identifier,
+            owner: owner,
+            authorised_keys: authorised_keys,
+            version: version,
+            owner_signature: sign::sign_detached(&data, secret_key),
+            entries: entries,
+        })
+    }
+
+    // The signatures of the entries kept from earlier versions, which the owner signed when
+    // pruning; entries of the current version were appended since
+    fn kept(entries: &[AppendedEntry], version: u64) -> Vec<sign::Signature> {
+        entries.iter()
+               .filter(|entry| entry.version < version)
+               .map(|entry| entry.signature.clone())
+               .collect()
+    }
+
+    fn verify_owner_signature(&self) -> bool {
+        match AppendableData::data_to_sign(self.type_tag, &self.identifier, &self.owner,
+                                           &self.authorised_keys, self.version,
+                                           &AppendableData::kept(&self.entries, self.version)) {
+            Ok(data) => sign::verify_detached(&self.owner_signature, &data, &self.owner),
+            Err(_) => false,
+        }
+    }
+
+    fn verify_entries(&self, entries: &[AppendedEntry]) -> Result<(), AppendableDataError> {
+        let name = self.name();
+        for (index, entry) in entries.iter().enumerate() {
+            if !self.is_authorised(&entry.author) {
+                return Err(AppendableDataError::NotAuthorised);
+            }
+            if !entry.verify(&name) {
+                return Err(AppendableDataError::InvalidEntrySignature);
+            }
+            if entry.version > self.version {
+                return Err(AppendableDataError::EntryVersionMismatch);
+            }
+            if entries[index + 1..].iter()
+                   .any(|other| &other.signature.0[..] == &entry.signature.0[..]) {
+                return Err(AppendableDataError::DuplicateEntries);
+            }
+        }
+        Ok(())
+    }
+
+    // The owner signs the header and the entries it kept when pruning, so authorised keys can
+    // append without the owner, but no one else can choose which entries a version keeps
+    fn data_to_sign(type_tag: u64, identifier: &NameType, owner: &sign::PublicKey,
+                    authorised_keys: &Vec<sign::PublicKey>, version: u64,
+                    kept: &[sign::Signature]) -> Result<Vec<u8>, RoutingError> {
+        let mut encoder = CanonicalEncoder::new(APPENDABLE_DATA_DOMAIN);
+        encoder.u64(type_tag)
+               .name(identifier)
+               .public_key(owner)
+               .public_keys(authorised_keys)
+               .u64(version)
+               .signatures(kept);
+        Ok(encoder.into_bytes())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sodiumoxide::crypto;
+    use error::AppendableDataError;
+    use test_utils::Random;
+    use NameType;
+
+    #[test]
+    fn append_and_prune() {
+        let owner = crypto::sign::gen_keypair();
+        let author = crypto::sign::gen_keypair();
+        let stranger = crypto::sign::gen_keypair();
+
+        let mut appendable_data = AppendableData::new(3, Random::generate_random(), owner.0,
+                                                      vec![author.0], &owner.1).unwrap();
+        assert!(appendable_data.verify().is_ok());
+        let name = appendable_data.name();
+
+        // the owner and authorised keys can append
+        let entries = vec![
+            AppendedEntry::new(&name, 0, vec![1u8], owner.0, &owner.1).unwrap(),
+            AppendedEntry::new(&name, 0, vec![2u8, 3u8], author.0, &author.1).unwrap()];
+        let first_entry = entries[0].clone();
+        let post = appendable_data.to_append(entries);
+        assert!(appendable_data.update_with_other(post.clone()).is_ok());
+        assert_eq!(appendable_data.get_entries().len(), 2);
+        assert_eq!(appendable_data.payload_size(), 3);
+
+        // the same entries cannot be appended twice, the same data can
+        assert_eq!(appendable_data.update_with_other(post.clone()),
+                   Err(AppendableDataError::DuplicateEntries));
+        let again = AppendedEntry::new(&name, 0, vec![1u8], owner.0, &owner.1).unwrap();
+        let post = appendable_data.to_append(vec![again]);
+        assert!(appendable_data.update_with_other(post).is_ok());
+        assert_eq!(appendable_data.get_entries().len(), 3);
+
+        // others cannot append, nor claim an authorised key
+        let entry = AppendedEntry::new(&name, 0, vec![4u8], stranger.0, &stranger.1).unwrap();
+        let post = appendable_data.to_append(vec![entry]);
+        assert_eq!(appendable_data.update_with_other(post),
+                   Err(AppendableDataError::NotAuthorised));
+        let entry = AppendedEntry::new(&name, 0, vec![4u8], author.0, &stranger.1).unwrap();
+        let post = appendable_data.to_append(vec![entry]);
+        assert_eq!(appendable_data.update_with_other(post),
+                   Err(AppendableDataError::InvalidEntrySignature));
+
+        // an entry for another AppendableData cannot be replayed
+        let entry = AppendedEntry::new(&Random::generate_random(), 0, vec![4u8], author.0,
+                                       &author.1).unwrap();
+        let post = appendable_data.to_append(vec![entry]);
+        assert_eq!(appendable_data.update_with_other(post),
+                   Err(AppendableDataError::InvalidEntrySignature));
+        assert_eq!(appendable_data.get_entries().len(), 3);
+
+        // nor one signed for a version the owner did not reach yet
+        let early = AppendedEntry::new(&name, 1, vec![5u8], author.0, &author.1).unwrap();
+        let post = appendable_data.to_append(vec![early.clone()]);
+        assert_eq!(appendable_data.update_with_other(post),
+                   Err(AppendableDataError::EntryVersionMismatch));
+
+        // only the owner can prune
+        assert!(appendable_data.prune(1, &author.1).is_err());
+        let pruned = appendable_data.prune(2, &owner.1).unwrap();
+        assert_eq!(pruned.get_version(), 1);
+        assert_eq!(pruned.get_entries()[0].data(), &vec![2u8, 3u8]);
+        let unpruned = appendable_data.clone();
+        assert!(appendable_data.update_with_other(pruned.clone()).is_ok());
+        assert_eq!(appendable_data, pruned);
+
+        // the owner's signature for the next version cannot be reused to keep other entries
+        let mut restored = pruned.to_append(unpruned.get_entries().clone());
+        assert_eq!(restored.verify(), Err(AppendableDataError::InvalidOwnerSignature));
+        assert_eq!(unpruned.clone().update_with_other(restored.clone()),
+                   Err(AppendableDataError::InvalidOwnerSignature));
+        restored.entries.clear();
+        assert_eq!(unpruned.clone().update_with_other(restored),
+                   Err(AppendableDataError::InvalidOwnerSignature));
+
+        // the entries pruned cannot be replayed; new ones are signed for the new version
+        let post = appendable_data.to_append(vec![first_entry]);
+        assert_eq!(appendable_data.update_with_other(post),
+                   Err(AppendableDataError::EntryVersionMismatch));
+        let post = appendable_data.to_append(vec![early]);
+        assert!(appendable_data.update_with_other(post).is_ok());
+        assert_eq!(appendable_data.get_entries().len(), 3);
+        assert!(appendable_data.verify().is_ok());
+
+        // a Post of an outdated version is refused
+        let mut stale = AppendableData::new(3, appendable_data.get_identifier().clone(), owner.0,
+                                            vec![author.0], &owner.1).unwrap();
+        assert_eq!(stale.update_with_other(appendable_data.prune(0, &owner.1).unwrap()),
+                   Err(AppendableDataError::VersionNotIncremented));
+    }
+
+    #[test]
+    fn last_version() {
+        let owner = crypto::sign::gen_keypair();
+        let last = AppendableData::signed(3, Random::generate_random(), owner.0, vec![],
+                                          ::std::u64::MAX, vec![], &owner.1).unwrap();
+        assert!(last.verify().is_ok());
+        assert!(last.prune(0, &owner.1).is_err());
+        let mut first = AppendableData::signed(3, last.get_identifier().clone(), owner.0, vec![],
+                                               0, vec![], &owner.1).unwrap();
+        assert_eq!(last.clone().update_with_other(first.clone()),
+                   Err(AppendableDataError::VersionNotIncremented));
+        assert_eq!(first.update_with_other(last),
+                   Err(AppendableDataError::VersionNotIncremented));
+    }
+
+    #[test]
+    fn canonical_encoding() {
+        let mut expected = vec![0, 0, 0, 0, 0, 0, 0, 24];
+        expected.extend(b"routing/AppendableData/2".iter().cloned());
+        expected.extend(vec![0, 0, 0, 0, 0, 0, 0, 5].into_iter());
+        expected.extend(vec![1u8; 64].into_iter());
+        expected.extend(vec![3u8; 32].into_iter());
+        expected.extend(vec![0, 0, 0, 0, 0, 0, 0, 1].into_iter());
+        expected.extend(vec![4u8; 32].into_iter());
+        expected.extend(vec![0, 0, 0, 0, 0, 0, 0, 2].into_iter());
+        expected.extend(vec![0, 0, 0, 0, 0, 0, 0, 1].into_iter());
+        expected.extend(vec![5u8; 64].into_iter());
+        assert_eq!(AppendableData::data_to_sign(5, &NameType([1u8; 64]),
+                                                &crypto::sign::PublicKey([3u8; 32]),
+                                                &vec![crypto::sign::PublicKey([4u8; 32])],
+                                                2, &[crypto::sign::Signature([5u8; 64])]).ok(),
+                   Some(expected));
+
+        let mut expected = vec![0, 0, 0, 0, 0, 0, 0, 23];
+        expected.extend(b"routing/AppendedEntry/2".iter().cloned());
+        expected.extend(vec![1u8; 64].into_iter());
+        expected.extend(vec![0, 0, 0, 0, 0, 0, 0, 2].into_iter());
+        expected.extend(vec![0, 0, 0, 0, 0, 0, 0, 9].into_iter());
+        expected.extend(vec![0, 0, 0, 0, 0, 0, 0, 2, 7, 8].into_iter());
+        assert_eq!(AppendedEntry::data_to_sign(&NameType([1u8; 64]), 2, 9, &vec![7, 8]).ok(),
+                   Some(expected));
+    }
+}
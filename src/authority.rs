@@ -19,7 +19,10 @@ use rustc_serialize::{Decoder, Encodable, Encoder};
 use routing_table::RoutingTable;
 use NameType;
 use sodiumoxide::crypto;
-use messages::{RoutingMessage, MessageType};
+use messages::{RoutingMessage, MessageType, SignedMessage};
+use types::{DestinationAddress, SourceAddress};
+use error::RoutingError;
+use utils::public_key_to_client_name;
 
 #[derive(RustcEncodable, RustcDecodable, PartialEq, PartialOrd, Eq, Ord, Debug, Clone)]
 pub enum Authority {
@@ -51,60 +54,161 @@ impl Authority {
 /// This returns our calculated authority with regards
 /// to the element passed in from the message and the message header.
 /// Note that the message has first to pass Sentinel as to be verified.
-/// a) if the message is from and signed by a client,
+/// a) if the message is addressed to a client we relay for,
+///       and the client's name is within our close group range
+///    -> Managed Client
+/// b) if the message is a response carrying the request it answers,
+///       the response is addressed to us, and the request was sent by us
+///    -> the group authority we sent the request with, if we still hold it;
+///       Managed Node if we sent it as a single node; otherwise Unknown
+/// c) if the message is from and signed by a client,
 ///       the originating node is within our close group range
 ///       and the element is not the destination
 ///    -> Client Manager
-/// b) if the element is within our close group range
+/// d) if the element is within our close group range
 ///       and the destination is the element
 ///       and the element is not our name (to exclude false positive for ManagedNode)
 ///    -> Network-Addressable-Element Manager
-/// c) if the message is from a group,
+/// e) if the message is from a group,
 ///       the destination is within our close group,
 ///       and our id is not the destination
 ///    -> Node Manager
-/// d) if the message is from a group,
-///       the group is within our close group range,
+/// f) if the message is from a group or a node within our close group range,
 ///       and the destination is our id
 ///    -> Managed Node
-/// e) otherwise return Unknown Authority
+/// g) otherwise return Unknown Authority
+///
+/// The element of a request is the name of the data or PublicId it carries, or its destination
+/// if it carries none.  Responses which do not carry their request, and messages between
+/// nodes, take their destination as the element.
 
 // extract the element from RoutingMessage,
 // then pass on to determine_authority
 pub fn our_authority(message       : &RoutingMessage,
                      routing_table : &RoutingTable) -> Authority {
 
+    match message.destination {
+        DestinationAddress::RelayToClient(ref relay, ref client_key) => {
+            if *relay == routing_table.our_name()
+                && routing_table.address_in_our_close_group_range(
+                       &public_key_to_client_name(client_key)) {
+                return Authority::ManagedClient(client_key.clone()); }
+            return Authority::Unknown;
+        },
+        DestinationAddress::RelayToNode(_, _) => { return Authority::Unknown; },
+        DestinationAddress::Direct(_) => {},
+    }
+
+    let destination = message.non_relayed_destination();
+
     // Purposely listing all the cases and not using wild cards so
     // that if a new message is added to the MessageType enum, compiler
     // will warn us that we need to add it here.
     let element = match message.message_type {
-        MessageType::ConnectRequest(_)      => None,
-        MessageType::ConnectResponse(_)     => None,
-        MessageType::FindGroup              => None,
-        MessageType::FindGroupResponse(_)   => None,
-        MessageType::GetData(_)             => Some(message.non_relayed_destination()),
-        MessageType::GetDataResponse(_)     => None,
-        MessageType::DeleteData(_)          => None,
-        MessageType::DeleteDataResponse(_)  => None,
-        MessageType::GetGroupKey            => None,
-        MessageType::GetGroupKeyResponse(_) => None,
-        MessageType::Post(ref data)         => Some(data.name()),
-        MessageType::PostResponse(_, _)     => None,
-        MessageType::PutData(ref data)      => Some(data.name()),
-        MessageType::PutDataResponse(_, _)  => None,
-        MessageType::PutKey                 => None,
-        MessageType::PutPublicId(ref public_id) => Some(public_id.name()),
-        MessageType::PutPublicIdResponse(_, _) => None,
-        MessageType::Refresh(_,_)           => None,
-        MessageType::Unknown                => None,
+        MessageType::ConnectRequest(_)      => destination,
+        MessageType::ConnectResponse(_)     => destination,
+        MessageType::FindGroup              => destination,
+        MessageType::FindGroupResponse(_, _) => destination,
+        MessageType::GetData(_)             => destination,
+        MessageType::GetDataResponse(ref response) =>
+            return response_authority(message, routing_table, &response.orig_request),
+        MessageType::GetDataErrorResponse(ref error_return) =>
+            return response_authority(message, routing_table, &error_return.orig_request),
+        MessageType::GetDataBatch(_)        => destination,
+        MessageType::DeleteData(_)          => destination,
+        MessageType::DeleteDataResponse(ref error_return) =>
+            return response_authority(message, routing_table, &error_return.orig_request),
+        MessageType::GetGroupKey            => destination,
+        MessageType::GetGroupKeyResponse(_) => destination,
+        MessageType::Post(ref data)         => data.name(),
+        MessageType::PostResponse(ref error_return, _) =>
+            return response_authority(message, routing_table, &error_return.orig_request),
+        MessageType::PutData(ref data)      => data.name(),
+        MessageType::PutDataResponse(ref error_return, _) =>
+            return response_authority(message, routing_table, &error_return.orig_request),
+        // all items of a batch must be bound for the same group, see RoutingClient::put_batch;
+        // it is decided by the first
+        MessageType::PutDataBatch(ref data) => match data.first() {
+            Some(data) => data.name(),
+            None       => return Authority::Unknown,
+        },
+        MessageType::BatchResponse(ref response) =>
+            return response_authority(message, routing_table, &response.orig_request),
+        MessageType::PutKey                 => destination,
+        MessageType::PutPublicId(ref public_id) => public_id.name(),
+        MessageType::PutPublicIdResponse(_, ref orig_request) =>
+            return response_authority(message, routing_table, orig_request),
+        MessageType::Refresh(_,_)           => destination,
+        MessageType::RouteReport(_)         => destination,
+        MessageType::Unknown                => { return Authority::Unknown; },
     };
 
-    let element = match element {
-        Some(e) => e,
-        None    => { return Authority::Unknown; }
+    return determine_authority(message, routing_table, element);
+}
+
+fn response_authority(message       : &RoutingMessage,
+                      routing_table : &RoutingTable,
+                      orig_request  : &SignedMessage) -> Authority {
+    let our_name = routing_table.our_name();
+    let orig_request = match orig_request.get_routing_message() {
+        Ok(orig_request) => orig_request,
+        Err(_) => return Authority::Unknown,
     };
+    if message.non_relayed_destination() != our_name
+        || orig_request.non_relayed_source() != our_name {
+        return Authority::Unknown; }
+    match orig_request.authority {
+        Authority::ClientManager(name) |
+        Authority::NaeManager(name) |
+        Authority::NodeManager(name) => {
+            if routing_table.address_in_our_close_group_range(&name) {
+                orig_request.authority.clone()
+            } else {
+                Authority::Unknown
+            }
+        },
+        Authority::ManagedNode |
+        Authority::Unknown => Authority::ManagedNode,
+        Authority::ManagedClient(_) |
+        Authority::Client(_) => Authority::Unknown,
+    }
+}
 
-    return determine_authority(message, routing_table, element);
+/// Checks that the authority the sender claims for the message is plausible, as it is taken
+/// as the message's from_authority otherwise unchecked.  A group authority has to be claimed by
+/// a node which, according to our routing table, is in the close group of the group's name, and
+/// which signed the message with the key we know for it, as the source is set by the sender; a
+/// client authority by the client the message is relayed for.  Fails with BadAuthority, or
+/// FailedSignature if the message is not signed with the sender's key.
+pub fn verify_from_authority(message_wrap  : &SignedMessage,
+                             message       : &RoutingMessage,
+                             routing_table : &RoutingTable,
+                             sender_key    : Option<&crypto::sign::PublicKey>)
+                             -> Result<(), RoutingError> {
+    let plausible = match message.authority {
+        Authority::ClientManager(ref group) |
+        Authority::NaeManager(ref group) |
+        Authority::NodeManager(ref group) => match message.source {
+            SourceAddress::Direct(ref sender) => {
+                if !routing_table.is_plausibly_in_close_group_of(sender, group) {
+                    return Err(RoutingError::BadAuthority);
+                }
+                // a sender whose key we do not know can not show it is the node it names
+                return match sender_key {
+                    Some(sender_key) if message_wrap.verify_signature(sender_key) => Ok(()),
+                    Some(_) => Err(RoutingError::FailedSignature),
+                    None => Err(RoutingError::BadAuthority),
+                };
+            },
+            SourceAddress::RelayedForClient(_, _) |
+            SourceAddress::RelayedForNode(_, _) => false,
+        },
+        Authority::ManagedClient(ref client_key) |
+        Authority::Client(ref client_key) => message.client_key().as_ref() == Some(client_key),
+        Authority::ManagedNode |
+        Authority::Unknown => true,
+    };
+    if plausible { Ok(()) } else { Err(RoutingError::BadAuthority) }
 }
 
 // determine_authority is split off to allow unit tests to test it
@@ -134,9 +238,8 @@ fn determine_authority(message       : &RoutingMessage,
         && routing_table.address_in_our_close_group_range(&message.non_relayed_destination())
         && message.non_relayed_destination() != routing_table.our_name() {
         return Authority::NodeManager(message.non_relayed_destination()); }
-    else if message.from_group()
-                   .map(|group| routing_table.address_in_our_close_group_range(&group))
-                   .unwrap_or(false)
+    else if routing_table.address_in_our_close_group_range(
+                &message.from_group().unwrap_or(message.non_relayed_source()))
         && message.non_relayed_destination() == routing_table.our_name() {
         return Authority::ManagedNode; }
     return Authority::Unknown;
@@ -150,7 +253,7 @@ mod test {
     use public_id::PublicId;
     use messages::{RoutingMessage, MessageType};
     use id::Id;
-    use test_utils::{Random, xor, test};
+    use test_utils::{Random, xor, test, CloseGroupFixture};
     use rand::random;
     use utils::{public_key_to_client_name};
     use name_type::{closer_to_target, NameType};
@@ -158,6 +261,12 @@ mod test {
     use sodiumoxide::crypto;
     use data::{Data};
     use immutable_data::{ImmutableData, ImmutableDataType};
+    use plain_data::PlainData;
+    use data::DataRequest;
+    use error::{ResponseError, RoutingError};
+    use messages::{SignedMessage, GetDataResponse, ErrorReturn, BatchResponse};
+    use types::{RouteReport, RoutePath};
+    use std::collections::BTreeMap;
 
 #[test]
 fn our_authority_full_routing_table() {
@@ -168,7 +277,7 @@ fn our_authority_full_routing_table() {
         routing_table.add_node(NodeInfo::new(
                                PublicId::new(&Id::new()),
                                test::random_endpoints(),
-                               Some(test::random_endpoint())));
+                               vec![test::random_endpoint()]));
         count += 1;
         if count > 100 { break; }
         // if routing_node.routing_table.size() >=
@@ -281,4 +390,237 @@ fn our_authority_full_routing_table() {
         Authority::ManagedNode);
 }
 
+// The positions we can hold relative to a message, see message_at
+#[derive(Clone, Copy, Debug)]
+enum Position {
+    ClientManager,
+    NaeManager,
+    NodeManager,
+    ManagedNode,
+    ManagedClient,
+    Outside,
+}
+
+static POSITIONS: [Position; 6] = [Position::ClientManager, Position::NaeManager,
+    Position::NodeManager, Position::ManagedNode, Position::ManagedClient, Position::Outside];
+
+// Returns a message of the given type whose header places us at the given position; the
+// element of messages carrying a name is expected to be the fixture's name_in_range.
+fn message_at(fixture: &CloseGroupFixture, message_type: MessageType, position: Position)
+        -> RoutingMessage {
+    let (destination, source, authority) = match position {
+        Position::ClientManager => (
+            DestinationAddress::Direct(fixture.name_outside_range),
+            SourceAddress::RelayedForClient(Random::generate_random(), fixture.client_in_range),
+            Authority::Client(fixture.client_in_range)),
+        Position::NaeManager => (
+            DestinationAddress::Direct(fixture.name_in_range),
+            SourceAddress::Direct(Random::generate_random()),
+            Authority::ClientManager(Random::generate_random())),
+        Position::NodeManager => (
+            DestinationAddress::Direct(fixture.close_node),
+            SourceAddress::Direct(Random::generate_random()),
+            Authority::NaeManager(Random::generate_random())),
+        Position::ManagedNode => (
+            DestinationAddress::Direct(fixture.our_name()),
+            SourceAddress::Direct(fixture.close_node),
+            Authority::NodeManager(fixture.our_name())),
+        Position::ManagedClient => (
+            DestinationAddress::RelayToClient(fixture.our_name(), fixture.client_in_range),
+            SourceAddress::Direct(Random::generate_random()),
+            Authority::NaeManager(Random::generate_random())),
+        Position::Outside => (
+            DestinationAddress::Direct(fixture.name_outside_range),
+            SourceAddress::Direct(Random::generate_random()),
+            Authority::NaeManager(Random::generate_random())),
+    };
+    RoutingMessage {
+        destination : destination,
+        source      : source,
+        orig_message: None,
+        message_type: message_type,
+        message_id  : random::<u32>(),
+        authority   : authority,
+    }
+}
+
+#[test]
+fn our_authority_for_every_message_type() {
+    let fixture = CloseGroupFixture::new();
+    let client_name = public_key_to_client_name(&fixture.client_in_range);
+    let data = Data::PlainData(PlainData::new(fixture.name_in_range, vec![]));
+    let mut public_id : PublicId = Random::generate_random();
+    public_id.set_name(fixture.name_in_range);
+
+    // the request the responses answer, sent by us as the NaeManager of name_in_range
+    let orig_request = SignedMessage::new(&RoutingMessage {
+        destination : DestinationAddress::Direct(Random::generate_random()),
+        source      : SourceAddress::Direct(fixture.our_name()),
+        orig_message: None,
+        message_type: MessageType::GetData(DataRequest::PlainData),
+        message_id  : random::<u32>(),
+        authority   : Authority::NaeManager(fixture.name_in_range),
+    }, fixture.id.signing_private_key()).unwrap();
+    let error_return = ErrorReturn::new(ResponseError::NoData, orig_request.clone());
+
+    // expected authorities, in the order of POSITIONS
+    let carrying_element = [Authority::ClientManager(client_name),
+                            Authority::NaeManager(fixture.name_in_range),
+                            Authority::NodeManager(fixture.close_node),
+                            Authority::ManagedNode,
+                            Authority::ManagedClient(fixture.client_in_range),
+                            Authority::Unknown];
+    let addressing_element = [Authority::Unknown,
+                              Authority::NaeManager(fixture.name_in_range),
+                              Authority::NaeManager(fixture.close_node),
+                              Authority::ManagedNode,
+                              Authority::ManagedClient(fixture.client_in_range),
+                              Authority::Unknown];
+    let answering_request = [Authority::Unknown,
+                             Authority::Unknown,
+                             Authority::Unknown,
+                             Authority::NaeManager(fixture.name_in_range),
+                             Authority::ManagedClient(fixture.client_in_range),
+                             Authority::Unknown];
+    let unknown = [Authority::Unknown,
+                   Authority::Unknown,
+                   Authority::Unknown,
+                   Authority::Unknown,
+                   Authority::ManagedClient(fixture.client_in_range),
+                   Authority::Unknown];
+
+    let table = vec![
+        (MessageType::ConnectRequest(Random::generate_random()), &addressing_element),
+        (MessageType::ConnectResponse(Random::generate_random()), &addressing_element),
+        (MessageType::FindGroup, &addressing_element),
+        (MessageType::FindGroupResponse(vec![], 0), &addressing_element),
+        (MessageType::GetData(DataRequest::PlainData), &addressing_element),
+        (MessageType::GetDataResponse(GetDataResponse {
+             data           : data.clone(),
+             orig_request   : orig_request.clone(),
+             group_pub_keys : BTreeMap::new(),
+         }), &answering_request),
+        (MessageType::GetDataErrorResponse(error_return.clone()), &answering_request),
+        (MessageType::GetDataBatch(vec![(data.name(), DataRequest::PlainData)]),
+            &addressing_element),
+        (MessageType::DeleteData(DataRequest::PlainData), &addressing_element),
+        (MessageType::DeleteDataResponse(error_return.clone()), &answering_request),
+        (MessageType::GetGroupKey, &addressing_element),
+        (MessageType::GetGroupKeyResponse(BTreeMap::new()), &addressing_element),
+        (MessageType::Post(data.clone()), &carrying_element),
+        (MessageType::PostResponse(error_return.clone(), BTreeMap::new()), &answering_request),
+        (MessageType::PutData(data.clone()), &carrying_element),
+        (MessageType::PutDataResponse(error_return.clone(), BTreeMap::new()),
+            &answering_request),
+        (MessageType::PutDataBatch(vec![data.clone()]), &carrying_element),
+        (MessageType::BatchResponse(BatchResponse::refused(1, ResponseError::NoData,
+                                                           orig_request.clone())),
+            &answering_request),
+        (MessageType::PutKey, &addressing_element),
+        (MessageType::PutPublicId(public_id.clone()), &carrying_element),
+        (MessageType::PutPublicIdResponse(public_id.clone(), orig_request.clone()),
+            &answering_request),
+        (MessageType::Refresh(0, vec![]), &addressing_element),
+        (MessageType::RouteReport(RouteReport {
+             message_id  : 0,
+             destination : Random::generate_random(),
+             route       : RoutePath::new(orig_request.signature(), 0, vec![],
+                                          fixture.id.signing_private_key()),
+             reporter    : public_id.clone(),
+         }), &addressing_element),
+        (MessageType::Unknown, &unknown),
+    ];
+
+    for (message_type, expected) in table {
+        for (position, expected) in POSITIONS.iter().zip(expected.iter()) {
+            let message = message_at(&fixture, message_type.clone(), *position);
+            assert!(super::our_authority(&message, &fixture.routing_table) == *expected,
+                    "{:?} at {:?}", message_type, position);
+        }
+    }
+
+    // a response to a request we sent as a single node is ours as ManagedNode
+    let mut orig_message = orig_request.get_routing_message().unwrap();
+    orig_message.authority = Authority::Unknown;
+    let orig_request = SignedMessage::new(&orig_message, fixture.id.signing_private_key())
+                           .unwrap();
+    let response = message_at(&fixture,
+        MessageType::PutDataResponse(ErrorReturn::new(ResponseError::NoData, orig_request),
+                                     BTreeMap::new()),
+        Position::ManagedNode);
+    assert_eq!(super::our_authority(&response, &fixture.routing_table), Authority::ManagedNode);
+}
+
+#[test]
+fn verify_claimed_from_authority() {
+    let fixture = CloseGroupFixture::new();
+    let other_client_key = crypto::sign::gen_keypair().0;
+    let claim_signed = |source: SourceAddress, authority: Authority, signer: &Id| {
+        let message = RoutingMessage {
+            destination : DestinationAddress::Direct(fixture.name_in_range),
+            source      : source.clone(),
+            orig_message: None,
+            message_type: MessageType::GetData(DataRequest::PlainData),
+            message_id  : random::<u32>(),
+            authority   : authority,
+        };
+        let signed_message = SignedMessage::new(&message, signer.signing_private_key()).unwrap();
+        // the key we know for a sender in our routing table, or ourselves
+        let sender_key = match source {
+            SourceAddress::Direct(ref sender) if *sender == fixture.our_name() =>
+                Some(fixture.id.signing_public_key()),
+            SourceAddress::Direct(ref sender) =>
+                fixture.routing_table.public_id(sender).map(|public_id|
+                    public_id.signing_public_key()),
+            _ => None,
+        };
+        super::verify_from_authority(&signed_message, &message, &fixture.routing_table,
+                                     sender_key.as_ref())
+    };
+    let claim = |source: SourceAddress, authority: Authority| {
+        let signer = match source {
+            SourceAddress::Direct(ref sender) if *sender == fixture.our_name() => &fixture.id,
+            _ => &fixture.close_node_id,
+        };
+        claim_signed(source, authority, signer).is_ok()
+    };
+
+    // a node in the group of the name, or we ourselves, can speak for it
+    for group_authority in vec![Authority::ClientManager(fixture.name_in_range),
+                                Authority::NaeManager(fixture.name_in_range),
+                                Authority::NodeManager(fixture.name_in_range)] {
+        assert!(claim(SourceAddress::Direct(fixture.close_node), group_authority.clone()));
+        assert!(claim(SourceAddress::Direct(fixture.our_name()), group_authority.clone()));
+        // we know a whole group of nodes closer to the name than a far away node
+        assert!(!claim(SourceAddress::Direct(fixture.name_outside_range), group_authority.clone()));
+        assert!(!claim(SourceAddress::RelayedForNode(fixture.close_node, Random::generate_random()),
+                       group_authority.clone()));
+
+        // a node forging the name of a close node in the source is refused, as is a node whose
+        // key we do not know
+        match claim_signed(SourceAddress::Direct(fixture.close_node), group_authority.clone(),
+                           &Id::new()) {
+            Err(RoutingError::FailedSignature) => {},
+            _ => panic!("Expected FailedSignature"),
+        }
+        match claim_signed(SourceAddress::Direct(fixture.name_in_range), group_authority,
+                           &Id::new()) {
+            Err(RoutingError::BadAuthority) => {},
+            _ => panic!("Expected BadAuthority"),
+        }
+    }
+    // a client can only speak for itself
+    let relayed_client = SourceAddress::RelayedForClient(fixture.close_node,
+                                                         fixture.client_in_range);
+    assert!(claim(relayed_client.clone(), Authority::Client(fixture.client_in_range)));
+    assert!(claim(relayed_client.clone(), Authority::ManagedClient(fixture.client_in_range)));
+    assert!(!claim(relayed_client.clone(), Authority::Client(other_client_key)));
+    assert!(!claim(SourceAddress::Direct(fixture.close_node),
+                   Authority::Client(fixture.client_in_range)));
+
+    // a node's claim on itself, or no claim at all, needs no check
+    assert!(claim(SourceAddress::Direct(fixture.name_outside_range), Authority::ManagedNode));
+    assert!(claim(relayed_client, Authority::Unknown));
+}
+
 }
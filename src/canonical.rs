@@ -0,0 +1,321 @@
+// Copyright 2015 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! The canonical byte encoding of everything routing signs.  It is written by hand rather than
+//! derived from the serialisation, so signatures do not depend on field order or encoder
+//! behaviour, and other implementations can produce valid signatures from this description.
+//!
+//! The signed bytes of a structure start with its domain, naming the structure and the
+//! version of its canonical encoding, followed by its fields in the documented order:
+//!
+//! * an unsigned integer is 8 bytes, big-endian
+//! * a byte string (including the domain, as UTF-8) is its length as an integer, then the bytes
+//! * a name is its 64 bytes; a public key its 32 bytes; a signature its 64 bytes
+//! * a list is its number of elements as an integer, then the elements
+//! * an enum is the index of its variant as an integer, then the variant's fields
+//!
+//! | structure          | domain                        | fields                               |
+//! |--------------------|-------------------------------|--------------------------------------|
+//! | SignedMessage      | `routing/SignedMessage/1`     | encoded body (byte string)           |
+//! | StructuredData     | `routing/StructuredData/1`    | type tag, identifier, data (byte     |
+//! |                    |                               | string), previous owner keys (list), |
+//! |                    |                               | current owner keys (list), version,  |
+//! |                    |                               | previous owner policy, current owner |
+//! |                    |                               | policy                               |
+//! | OwnershipPolicy    |                               | Majority = 0; Weighted = 1, then     |
+//! |                    |                               | threshold, weights (list of integers)|
+//! | SignedPlainData    | `routing/SignedPlainData/3`   | identifier, version, value (byte     |
+//! |                    |                               | string)                              |
+//! | AppendableData     | `routing/AppendableData/2`    | type tag, identifier, owner key,     |
+//! |                    |                               | authorised keys (list), version,     |
+//! |                    |                               | signatures of the entries kept from  |
+//! |                    |                               | earlier versions (list)              |
+//! | AppendedEntry      | `routing/AppendedEntry/2`     | name of the AppendableData, its      |
+//! |                    |                               | version, nonce, data (byte string)   |
+//! | RoutePath          | `routing/RoutePath/1`         | signature of the message, path index,|
+//! |                    |                               | first hops (list of names)           |
+//! | KeyChallenge       | `routing/KeyChallenge/1`      | name of the relay, nonce (byte       |
+//! |                    |                               | string)                              |
+//!
+//! A SignedMessage is signed over the body exactly as it is sent, which is never re-encoded on
+//! the way: nodes forward the SignedMessage they received.  The body is the RoutingMessage in
+//! the wire format envelope, which an implementation must produce byte for byte:
+//!
+//! * the envelope is CBOR tag 5483_301 around a two element array of the wire format version,
+//!   currently 1, and the encoded RoutingMessage as a byte string
+//! * the RoutingMessage is a CBOR map of its six fields keyed by their names as text strings, in
+//!   the order destination, source, orig_message, message_type, message_id, authority
+//! * an enum variant without fields is its name as a text string; a variant with fields a map of
+//!   "variant" to its name and "fields" to the array of its fields
+//! * a name is CBOR tag 5483_000 around the array of its 64 bytes, each an unsigned integer
+//! * an absent orig_message is null; a present one is the SignedMessage as a map of
+//!   encoded_body, signature and route
+//! * integers use the shortest CBOR encoding
+//!
+//! The signed_message_body_vector test pins the bytes of an example.
+
+use sodiumoxide::crypto::sign;
+use NameType;
+
+pub static SIGNED_MESSAGE_DOMAIN: &'static str = "routing/SignedMessage/1";
+pub static STRUCTURED_DATA_DOMAIN: &'static str = "routing/StructuredData/1";
+pub static SIGNED_PLAIN_DATA_DOMAIN: &'static str = "routing/SignedPlainData/3";
+pub static APPENDABLE_DATA_DOMAIN: &'static str = "routing/AppendableData/2";
+pub static APPENDED_ENTRY_DOMAIN: &'static str = "routing/AppendedEntry/2";
+pub static ROUTE_PATH_DOMAIN: &'static str = "routing/RoutePath/1";
+pub static KEY_CHALLENGE_DOMAIN: &'static str = "routing/KeyChallenge/1";
+
+/// Writes the canonical encoding of a signed structure.
+pub struct CanonicalEncoder {
+    bytes: Vec<u8>,
+}
+
+impl CanonicalEncoder {
+    /// Starts the encoding of a structure in the given domain.
+    pub fn new(domain: &str) -> CanonicalEncoder {
+        let mut encoder = CanonicalEncoder { bytes: Vec::new() };
+        encoder.bytes(domain.as_bytes());
+        encoder
+    }
+
+    pub fn u64(&mut self, value: u64) -> &mut CanonicalEncoder {
+        for shift in (0..8).rev() {
+            self.bytes.push((value >> (shift * 8)) as u8);
+        }
+        self
+    }
+
+    pub fn bytes(&mut self, value: &[u8]) -> &mut CanonicalEncoder {
+        self.u64(value.len() as u64);
+        self.bytes.extend(value.iter().cloned());
+        self
+    }
+
+    pub fn name(&mut self, name: &NameType) -> &mut CanonicalEncoder {
+        self.bytes.extend(name.0.iter().cloned());
+        self
+    }
+
+    pub fn names(&mut self, names: &[NameType]) -> &mut CanonicalEncoder {
+        self.u64(names.len() as u64);
+        for name in names {
+            self.name(name);
+        }
+        self
+    }
+
+    pub fn signature(&mut self, signature: &sign::Signature) -> &mut CanonicalEncoder {
+        self.bytes.extend(signature.0.iter().cloned());
+        self
+    }
+
+    pub fn signatures(&mut self, signatures: &[sign::Signature]) -> &mut CanonicalEncoder {
+        self.u64(signatures.len() as u64);
+        for signature in signatures {
+            self.signature(signature);
+        }
+        self
+    }
+
+    pub fn public_key(&mut self, public_key: &sign::PublicKey) -> &mut CanonicalEncoder {
+        self.bytes.extend(public_key.0.iter().cloned());
+        self
+    }
+
+    pub fn public_keys(&mut self, public_keys: &[sign::PublicKey]) -> &mut CanonicalEncoder {
+        self.u64(public_keys.len() as u64);
+        for public_key in public_keys {
+            self.public_key(public_key);
+        }
+        self
+    }
+
+    pub fn u64s(&mut self, values: &[u64]) -> &mut CanonicalEncoder {
+        self.u64(values.len() as u64);
+        for value in values {
+            self.u64(*value);
+        }
+        self
+    }
+
+    /// Returns the bytes to sign.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+/// Returns the bytes signed for a SignedMessage with the given encoded body.
+pub fn signed_message_bytes(encoded_body: &[u8]) -> Vec<u8> {
+    let mut encoder = CanonicalEncoder::new(SIGNED_MESSAGE_DOMAIN);
+    encoder.bytes(encoded_body);
+    encoder.into_bytes()
+}
+
+/// Returns the bytes signed for a RoutePath of the message with the given signature.
+pub fn route_path_bytes(message_signature: &sign::Signature, path_index: u8,
+                        first_hops: &[NameType]) -> Vec<u8> {
+    let mut encoder = CanonicalEncoder::new(ROUTE_PATH_DOMAIN);
+    encoder.signature(message_signature)
+           .u64(path_index as u64)
+           .names(first_hops);
+    encoder.into_bytes()
+}
+
+/// Returns the bytes a client signs to answer the KeyChallenge of the relay with the given name.
+pub fn key_challenge_bytes(relay_name: &NameType, nonce: &[u8]) -> Vec<u8> {
+    let mut encoder = CanonicalEncoder::new(KEY_CHALLENGE_DOMAIN);
+    encoder.name(relay_name)
+           .bytes(nonce);
+    encoder.into_bytes()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sodiumoxide::crypto::sign;
+    use authority::Authority;
+    use id::Id;
+    use messages::{MessageType, RoutingMessage, SignedMessage};
+    use types::{DestinationAddress, SourceAddress};
+    use NameType;
+
+    #[test]
+    fn encoder() {
+        let mut encoder = CanonicalEncoder::new("d");
+        encoder.u64(0x0102030405060708)
+               .bytes(&[9, 10])
+               .name(&NameType([11u8; 64]))
+               .public_keys(&[sign::PublicKey([12u8; 32])])
+               .u64s(&[13]);
+        let mut expected = vec![0, 0, 0, 0, 0, 0, 0, 1, b'd',
+                                1, 2, 3, 4, 5, 6, 7, 8,
+                                0, 0, 0, 0, 0, 0, 0, 2, 9, 10];
+        expected.extend(vec![11u8; 64].into_iter());
+        expected.extend(vec![0, 0, 0, 0, 0, 0, 0, 1].into_iter());
+        expected.extend(vec![12u8; 32].into_iter());
+        expected.extend(vec![0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 13].into_iter());
+        assert_eq!(encoder.into_bytes(), expected);
+    }
+
+    #[test]
+    fn signed_message_vector() {
+        let mut expected = vec![0, 0, 0, 0, 0, 0, 0, 23];
+        expected.extend(b"routing/SignedMessage/1".iter().cloned());
+        expected.extend(vec![0, 0, 0, 0, 0, 0, 0, 3, 1, 2, 3].into_iter());
+        assert_eq!(signed_message_bytes(&[1, 2, 3]), expected);
+    }
+
+    #[test]
+    fn signed_message_body_vector() {
+        let message = RoutingMessage {
+            destination : DestinationAddress::Direct(NameType([2u8; 64])),
+            source      : SourceAddress::Direct(NameType([1u8; 64])),
+            orig_message: None,
+            message_type: MessageType::FindGroup,
+            message_id  : 7,
+            authority   : Authority::ManagedNode,
+        };
+        let direct = |byte: u8| {
+            let mut bytes = vec![0xa2, 0x67];
+            bytes.extend(b"variant".iter().cloned());
+            bytes.push(0x66);
+            bytes.extend(b"Direct".iter().cloned());
+            bytes.push(0x66);
+            bytes.extend(b"fields".iter().cloned());
+            bytes.extend(vec![0x81, 0xda, 0x00, 0x53, 0xa9, 0xf8, 0x98, 0x40].into_iter());
+            bytes.extend(vec![byte; 64].into_iter());
+            bytes
+        };
+        // the envelope: tag 5483_301, version 1, and a byte string of 281 bytes
+        let mut body = vec![0xda, 0x00, 0x53, 0xab, 0x25, 0x82, 0x01, 0x59, 0x01, 0x19];
+        body.push(0xa6);
+        body.push(0x6b);
+        body.extend(b"destination".iter().cloned());
+        body.extend(direct(2).into_iter());
+        body.push(0x66);
+        body.extend(b"source".iter().cloned());
+        body.extend(direct(1).into_iter());
+        body.push(0x6c);
+        body.extend(b"orig_message".iter().cloned());
+        body.push(0xf6);
+        body.push(0x6c);
+        body.extend(b"message_type".iter().cloned());
+        body.push(0x69);
+        body.extend(b"FindGroup".iter().cloned());
+        body.push(0x6a);
+        body.extend(b"message_id".iter().cloned());
+        body.push(0x07);
+        body.push(0x69);
+        body.extend(b"authority".iter().cloned());
+        body.push(0x6b);
+        body.extend(b"ManagedNode".iter().cloned());
+        assert_eq!(body.len(), 291);
+
+        let id = Id::new();
+        let signed_message = SignedMessage::new(&message, id.signing_private_key()).unwrap();
+        assert_eq!(signed_message.encoded_body(), &body);
+
+        let mut expected = vec![0, 0, 0, 0, 0, 0, 0, 23];
+        expected.extend(b"routing/SignedMessage/1".iter().cloned());
+        expected.extend(vec![0, 0, 0, 0, 0, 0, 0x01, 0x23].into_iter());
+        expected.extend(body.into_iter());
+        assert_eq!(signed_message_bytes(signed_message.encoded_body()), expected);
+        assert!(sign::verify_detached(signed_message.signature(), &expected,
+                                      &id.signing_public_key()));
+    }
+
+    #[test]
+    fn route_path_vector() {
+        let mut expected = vec![0, 0, 0, 0, 0, 0, 0, 19];
+        expected.extend(b"routing/RoutePath/1".iter().cloned());
+        expected.extend(vec![1u8; 64].into_iter());
+        expected.extend(vec![0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 1].into_iter());
+        expected.extend(vec![3u8; 64].into_iter());
+        assert_eq!(route_path_bytes(&sign::Signature([1u8; 64]), 2, &[NameType([3u8; 64])]),
+                   expected);
+    }
+
+    #[test]
+    fn key_challenge_vector() {
+        let mut expected = vec![0, 0, 0, 0, 0, 0, 0, 22];
+        expected.extend(b"routing/KeyChallenge/1".iter().cloned());
+        expected.extend(vec![4u8; 64].into_iter());
+        expected.extend(vec![0, 0, 0, 0, 0, 0, 0, 2, 5, 6].into_iter());
+        assert_eq!(key_challenge_bytes(&NameType([4u8; 64]), &[5, 6]), expected);
+    }
+
+    #[test]
+    fn signed_message_signature() {
+        let id = Id::new();
+        let message = RoutingMessage {
+            destination : DestinationAddress::Direct(NameType([2u8; 64])),
+            source      : SourceAddress::Direct(id.name()),
+            orig_message: None,
+            message_type: MessageType::FindGroup,
+            message_id  : 7,
+            authority   : Authority::ManagedNode,
+        };
+        let signed_message = SignedMessage::new(&message, id.signing_private_key()).unwrap();
+        assert!(signed_message.verify_signature(&id.signing_public_key()));
+        assert!(sign::verify_detached(signed_message.signature(),
+                                      &signed_message_bytes(signed_message.encoded_body()),
+                                      &id.signing_public_key()));
+        assert!(!sign::verify_detached(signed_message.signature(),
+                                       signed_message.encoded_body(),
+                                       &id.signing_public_key()));
+    }
+}
@@ -0,0 +1,458 @@
+// Copyright 2015 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use std::cmp::Ordering;
+
+use NameType;
+use name_type::closer_to_target;
+use node_interface::MethodCall;
+use public_id::PublicId;
+use routing_table::is_plausibly_in_close_group_of;
+use types;
+
+/// How our responsibility range, the distance to the furthest member of our close group,
+/// changed during churn.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum RangeChange {
+    /// the furthest close group member is now further away; we are responsible for more names
+    Grew,
+    /// the furthest close group member is now closer; we are responsible for fewer names
+    Shrank,
+    /// the furthest close group member is unchanged
+    Unchanged,
+}
+
+/// AccountTransfer lists, for a set of names held by a persona, what has to be done after churn.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct AccountTransfer {
+    /// names we remain responsible for, each with the close group members which became
+    /// responsible for it and should receive a copy
+    pub push: Vec<(NameType, Vec<NameType>)>,
+    /// names we are still responsible for, but weren't before the churn
+    pub newly_responsible: Vec<NameType>,
+    /// names we are no longer responsible for and may drop
+    pub drop: Vec<NameType>,
+}
+
+impl AccountTransfer {
+    /// Returns a MethodCall::Refresh to the group of every name that has to be pushed to new
+    /// group members.  The payload for a name is provided by `payload`; names for which it
+    /// returns None are skipped.
+    pub fn refresh_calls<P>(&self, type_tag: u64, mut payload: P) -> Vec<MethodCall>
+            where P: FnMut(&NameType) -> Option<Vec<u8>> {
+        self.push.iter()
+                 .filter_map(|&(ref name, _)| payload(name).map(|payload| {
+                     MethodCall::Refresh {
+                         type_tag: type_tag,
+                         from_group: name.clone(),
+                         payload: payload,
+                     }
+                 }))
+                 .collect::<Vec<MethodCall>>()
+    }
+}
+
+/// ChurnEvent describes a change in our close group.  It is passed to the Interface for every
+/// routing table change that alters our close group.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct ChurnEvent {
+    /// our own name
+    pub our_name: NameType,
+    /// our close group before the change, sorted by distance to our name
+    pub previous_close_group: Vec<PublicId>,
+    /// our close group after the change, sorted by distance to our name
+    pub close_group: Vec<PublicId>,
+    /// nodes that joined our close group
+    pub added: Vec<PublicId>,
+    /// nodes that left our close group
+    pub removed: Vec<PublicId>,
+    /// whether our responsibility range grew or shrank
+    pub range_change: RangeChange,
+    /// the names of all contacts in our routing table before the change
+    pub previous_contacts: Vec<NameType>,
+    /// the names of all contacts in our routing table after the change
+    pub contacts: Vec<NameType>,
+}
+
+impl ChurnEvent {
+    /// Diffs the previous and the current close group, both sorted by distance to our name.  The
+    /// names of all contacts in our routing table before and after the change decide which names
+    /// we are responsible for.  Returns None if the close group did not change.
+    pub fn new(our_name: NameType,
+               previous_close_group: Vec<PublicId>,
+               close_group: Vec<PublicId>,
+               previous_contacts: Vec<NameType>,
+               contacts: Vec<NameType>) -> Option<ChurnEvent> {
+        let added = close_group.iter()
+                               .filter(|public_id| !previous_close_group.contains(public_id))
+                               .cloned()
+                               .collect::<Vec<PublicId>>();
+        let removed = previous_close_group.iter()
+                                          .filter(|public_id| !close_group.contains(public_id))
+                                          .cloned()
+                                          .collect::<Vec<PublicId>>();
+        if added.is_empty() && removed.is_empty() {
+            return None;
+        }
+        let range_change = ChurnEvent::range_change(&our_name, &previous_close_group,
+                                                    &close_group);
+        Some(ChurnEvent {
+            our_name: our_name,
+            previous_close_group: previous_close_group,
+            close_group: close_group,
+            added: added,
+            removed: removed,
+            range_change: range_change,
+            previous_contacts: previous_contacts,
+            contacts: contacts,
+        })
+    }
+
+    /// Returns the names of our close group after the change, with our own name at index 0.
+    pub fn close_group_names(&self) -> Vec<NameType> {
+        Some(self.our_name.clone()).into_iter()
+            .chain(self.close_group.iter().map(|public_id| public_id.name()))
+            .collect::<Vec<NameType>>()
+    }
+
+    /// Returns the names of our close group before the change, with our own name at index 0.
+    pub fn previous_close_group_names(&self) -> Vec<NameType> {
+        Some(self.our_name.clone()).into_iter()
+            .chain(self.previous_close_group.iter().map(|public_id| public_id.name()))
+            .collect::<Vec<NameType>>()
+    }
+
+    /// Determines for each of the given names whether we are, and were, among the GROUP_SIZE
+    /// nodes closest to it, as far as our whole routing table knows, and which nodes became
+    /// responsible for the names we keep.
+    pub fn account_transfer(&self, names: &[NameType]) -> AccountTransfer {
+        let previous_known_names = self.previous_known_names();
+        let known_names = self.known_names();
+        let mut account_transfer = AccountTransfer {
+            push: Vec::new(),
+            newly_responsible: Vec::new(),
+            drop: Vec::new(),
+        };
+        for name in names {
+            if !is_plausibly_in_close_group_of(known_names.iter().cloned(), &self.our_name, name) {
+                account_transfer.drop.push(name.clone());
+                continue;
+            }
+            if !is_plausibly_in_close_group_of(previous_known_names.iter().cloned(),
+                                               &self.our_name, name) {
+                account_transfer.newly_responsible.push(name.clone());
+            }
+            let previous_group = ChurnEvent::responsible_group(name, &previous_known_names);
+            let group = ChurnEvent::responsible_group(name, &known_names);
+            let new_holders = group.into_iter()
+                                   .filter(|node| !previous_group.contains(node))
+                                   .filter(|node| *node != self.our_name)
+                                   .collect::<Vec<NameType>>();
+            if !new_holders.is_empty() {
+                account_transfer.push.push((name.clone(), new_holders));
+            }
+        }
+        account_transfer
+    }
+
+    // Our own name and those of our contacts after the change.
+    fn known_names(&self) -> Vec<NameType> {
+        Some(self.our_name.clone()).into_iter().chain(self.contacts.iter().cloned()).collect()
+    }
+
+    // Our own name and those of our contacts before the change.
+    fn previous_known_names(&self) -> Vec<NameType> {
+        Some(self.our_name.clone()).into_iter()
+            .chain(self.previous_contacts.iter().cloned())
+            .collect()
+    }
+
+    // The GROUP_SIZE nodes among the candidates closest to the target.
+    fn responsible_group(target: &NameType, candidates: &Vec<NameType>) -> Vec<NameType> {
+        let mut group = candidates.clone();
+        group.sort_by(|a, b| if a == b {
+                                 Ordering::Equal
+                             } else if closer_to_target(a, b, target) {
+                                 Ordering::Less
+                             } else {
+                                 Ordering::Greater
+                             });
+        group.truncate(types::GROUP_SIZE);
+        group
+    }
+
+    // While our close group is not full, we are responsible for the whole address space,
+    // represented as None.
+    fn furthest_close_node(close_group: &Vec<PublicId>) -> Option<NameType> {
+        if close_group.len() < types::GROUP_SIZE {
+            return None;
+        }
+        close_group.last().map(|public_id| public_id.name())
+    }
+
+    fn range_change(our_name: &NameType,
+                    previous_close_group: &Vec<PublicId>,
+                    close_group: &Vec<PublicId>) -> RangeChange {
+        match (ChurnEvent::furthest_close_node(previous_close_group),
+               ChurnEvent::furthest_close_node(close_group)) {
+            (None, None) => RangeChange::Unchanged,
+            (None, Some(_)) => RangeChange::Shrank,
+            (Some(_), None) => RangeChange::Grew,
+            (Some(previous), Some(current)) => {
+                if previous == current {
+                    RangeChange::Unchanged
+                } else if closer_to_target(&current, &previous, our_name) {
+                    RangeChange::Shrank
+                } else {
+                    RangeChange::Grew
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AccountTransfer, ChurnEvent, RangeChange};
+    use node_interface::MethodCall;
+    use test_utils::Random;
+    use id::Id;
+    use public_id::PublicId;
+    use name_type::{closer_to_target, NameType};
+    use types;
+
+    fn sorted_public_ids(our_name: &NameType, count: usize) -> Vec<PublicId> {
+        let mut public_ids = (0..count).map(|_| PublicId::new(&Id::new()))
+                                       .collect::<Vec<PublicId>>();
+        public_ids.sort_by(|a, b| if closer_to_target(&a.name(), &b.name(), our_name) {
+                                      ::std::cmp::Ordering::Less
+                                  } else {
+                                      ::std::cmp::Ordering::Greater
+                                  });
+        public_ids
+    }
+
+    fn names_of(public_ids: &[PublicId]) -> Vec<NameType> {
+        public_ids.iter().map(|public_id| public_id.name()).collect()
+    }
+
+    // A churn event in a routing table holding nothing but our close group.
+    fn close_group_churn(our_name: NameType, previous_close_group: Vec<PublicId>,
+                         close_group: Vec<PublicId>) -> Option<ChurnEvent> {
+        let previous_contacts = names_of(&previous_close_group);
+        let contacts = names_of(&close_group);
+        ChurnEvent::new(our_name, previous_close_group, close_group, previous_contacts, contacts)
+    }
+
+    // Whether fewer than GROUP_SIZE of the other known names are closer to the name than the node.
+    fn in_group(node: &NameType, known_names: &[NameType], name: &NameType) -> bool {
+        known_names.iter()
+                   .filter(|known| *known != node && closer_to_target(known, node, name))
+                   .count() < types::GROUP_SIZE
+    }
+
+    // The name furthest from ours.
+    fn opposite(our_name: &NameType) -> NameType {
+        let mut opposite = our_name.clone();
+        for byte in opposite.0.iter_mut() {
+            *byte = !*byte;
+        }
+        opposite
+    }
+
+    // A name closer to ours than any other.
+    fn neighbour(our_name: &NameType) -> NameType {
+        let mut neighbour = our_name.clone();
+        neighbour.0[neighbour.0.len() - 1] ^= 1;
+        neighbour
+    }
+
+    #[test]
+    fn unchanged_close_group() {
+        let our_name = Id::new().name();
+        let close_group = sorted_public_ids(&our_name, types::GROUP_SIZE);
+        assert!(close_group_churn(our_name, close_group.clone(), close_group).is_none());
+    }
+
+    #[test]
+    fn node_joined_and_left() {
+        let our_name = Id::new().name();
+        let nodes = sorted_public_ids(&our_name, types::GROUP_SIZE + 1);
+
+        // the closest node joins, pushing out the furthest one
+        let previous_close_group = nodes[1..].to_vec();
+        let close_group = nodes[..types::GROUP_SIZE].to_vec();
+        let churn_event = close_group_churn(our_name.clone(), previous_close_group.clone(),
+                                            close_group.clone()).unwrap();
+        assert_eq!(churn_event.added, vec![nodes[0].clone()]);
+        assert_eq!(churn_event.removed, vec![nodes[types::GROUP_SIZE].clone()]);
+        assert_eq!(churn_event.range_change, RangeChange::Shrank);
+        assert_eq!(churn_event.close_group_names()[0], our_name);
+        assert_eq!(churn_event.close_group_names().len(), types::GROUP_SIZE + 1);
+
+        // and leaves again
+        let churn_event = close_group_churn(our_name.clone(), close_group,
+                                            previous_close_group).unwrap();
+        assert_eq!(churn_event.added, vec![nodes[types::GROUP_SIZE].clone()]);
+        assert_eq!(churn_event.removed, vec![nodes[0].clone()]);
+        assert_eq!(churn_event.range_change, RangeChange::Grew);
+    }
+
+    #[test]
+    fn partial_close_group() {
+        let our_name = Id::new().name();
+        let nodes = sorted_public_ids(&our_name, types::GROUP_SIZE);
+
+        // while the group is not full, we remain responsible for the whole address space
+        let churn_event = close_group_churn(our_name.clone(), nodes[..2].to_vec(),
+                                            nodes[..3].to_vec()).unwrap();
+        assert_eq!(churn_event.added, vec![nodes[2].clone()]);
+        assert!(churn_event.removed.is_empty());
+        assert_eq!(churn_event.range_change, RangeChange::Unchanged);
+
+        // filling the group narrows our range
+        let churn_event = close_group_churn(our_name.clone(),
+                                            nodes[..types::GROUP_SIZE - 1].to_vec(),
+                                            nodes.clone()).unwrap();
+        assert_eq!(churn_event.range_change, RangeChange::Shrank);
+
+        // losing a member of a full group widens it again
+        let churn_event = close_group_churn(our_name, nodes.clone(),
+                                            nodes[1..].to_vec()).unwrap();
+        assert_eq!(churn_event.removed, vec![nodes[0].clone()]);
+        assert_eq!(churn_event.range_change, RangeChange::Grew);
+    }
+
+    #[test]
+    fn account_transfer() {
+        let our_name = Id::new().name();
+        let nodes = sorted_public_ids(&our_name, 8 * types::GROUP_SIZE);
+        let previous_close_group = nodes[..types::GROUP_SIZE].to_vec();
+        // the furthest node of our close group leaves, the next one joins
+        let mut close_group = nodes[..types::GROUP_SIZE - 1].to_vec();
+        close_group.push(nodes[types::GROUP_SIZE].clone());
+        let leaving = nodes[types::GROUP_SIZE - 1].name();
+        let previous_contacts = names_of(&nodes);
+        let contacts = previous_contacts.iter()
+                                        .filter(|name| **name != leaving)
+                                        .cloned()
+                                        .collect::<Vec<NameType>>();
+        let churn_event = ChurnEvent::new(our_name.clone(), previous_close_group, close_group,
+                                          previous_contacts.clone(), contacts.clone()).unwrap();
+
+        // names near us, far from us and anywhere; most random names are outside our range
+        let mut names = vec![our_name.clone(), neighbour(&our_name), opposite(&our_name)];
+        for _ in 0..50 {
+            names.push(Random::generate_random());
+        }
+        let account_transfer = churn_event.account_transfer(&names);
+
+        // we are responsible for names near ours, and drop those other contacts are closer to
+        assert!(!account_transfer.drop.contains(&our_name));
+        assert!(!account_transfer.drop.contains(&neighbour(&our_name)));
+        assert!(account_transfer.drop.contains(&opposite(&our_name)));
+        for name in &names {
+            assert_eq!(account_transfer.drop.contains(name),
+                       !in_group(&our_name, &contacts, name));
+        }
+        for name in &names {
+            let dropped = account_transfer.drop.contains(name);
+            let pushed = account_transfer.push.iter().any(|&(ref pushed, _)| pushed == name);
+            assert!(!(dropped && pushed));
+            assert!(!(dropped && account_transfer.newly_responsible.contains(name)));
+        }
+        // copies of a name are only pushed to the node replacing the one which left its group
+        let previous_known_names = Some(our_name.clone()).into_iter()
+            .chain(previous_contacts.iter().cloned())
+            .collect::<Vec<NameType>>();
+        let known_names = Some(our_name.clone()).into_iter()
+            .chain(contacts.iter().cloned())
+            .collect::<Vec<NameType>>();
+        for &(ref name, ref new_holders) in &account_transfer.push {
+            assert_eq!(new_holders.len(), 1);
+            assert!(in_group(&leaving, &previous_known_names, name));
+            assert!(!in_group(&new_holders[0], &previous_known_names, name));
+            assert!(in_group(&new_holders[0], &known_names, name));
+        }
+
+        let refresh_calls = account_transfer.refresh_calls(5, |name| {
+            if *name == our_name { None } else { Some(name.0.to_vec()) }
+        });
+        let expected_calls = account_transfer.push.iter()
+                                             .filter(|&&(ref name, _)| *name != our_name)
+                                             .count();
+        assert_eq!(refresh_calls.len(), expected_calls);
+        for refresh_call in refresh_calls {
+            match refresh_call {
+                MethodCall::Refresh { type_tag, from_group, payload } => {
+                    assert_eq!(type_tag, 5);
+                    assert_eq!(payload, from_group.0.to_vec());
+                },
+                _ => panic!("Expected a refresh"),
+            }
+        }
+    }
+
+    #[test]
+    fn account_transfer_when_range_shrinks() {
+        let our_name = Id::new().name();
+        let nodes = sorted_public_ids(&our_name, 8 * types::GROUP_SIZE);
+        // a node closer than all of our close group joins, pushing out the furthest one
+        let previous_contacts = names_of(&nodes[1..]);
+        let contacts = names_of(&nodes);
+        let churn_event = ChurnEvent::new(our_name.clone(),
+                                          nodes[1..types::GROUP_SIZE + 1].to_vec(),
+                                          nodes[..types::GROUP_SIZE].to_vec(),
+                                          previous_contacts.clone(),
+                                          contacts.clone()).unwrap();
+        assert_eq!(churn_event.range_change, RangeChange::Shrank);
+
+        let mut names = vec![our_name.clone(), neighbour(&our_name), opposite(&our_name)];
+        for _ in 0..50 {
+            names.push(Random::generate_random());
+        }
+        let account_transfer = churn_event.account_transfer(&names);
+
+        assert!(!account_transfer.drop.contains(&our_name));
+        assert!(account_transfer.drop.contains(&opposite(&our_name)));
+        for name in &names {
+            let dropped = account_transfer.drop.contains(name);
+            assert_eq!(dropped, !in_group(&our_name, &contacts, name));
+            // a name we held and drop went to the node which joined, as it displaced us
+            if dropped && in_group(&our_name, &previous_contacts, name) {
+                assert!(closer_to_target(&nodes[0].name(), &our_name, name));
+            }
+        }
+        // no node left, so we did not become responsible for any name
+        assert!(account_transfer.newly_responsible.is_empty());
+    }
+
+    #[test]
+    fn account_transfer_in_partial_group() {
+        let our_name = Id::new().name();
+        let nodes = sorted_public_ids(&our_name, 3);
+        let churn_event = close_group_churn(our_name.clone(), nodes[..2].to_vec(),
+                                            nodes.clone()).unwrap();
+        let names = (0..10).map(|_| Random::generate_random()).collect::<Vec<NameType>>();
+        // while the group is not full, everyone is responsible for everything
+        assert_eq!(churn_event.account_transfer(&names), AccountTransfer {
+            push: names.iter().map(|name| (name.clone(), vec![nodes[2].name()])).collect(),
+            newly_responsible: vec![],
+            drop: vec![],
+        });
+    }
+}
@@ -16,7 +16,8 @@
 // relating to use of the SAFE Network Software.
 
 use error::{ResponseError, RoutingError};
-use data::Data;
+use data::{Data, DataRequest};
+use messages::BatchItemResult;
 use NameType;
 
 #[deny(missing_docs)]
@@ -26,10 +27,24 @@ pub trait Interface : Sync + Send {
     /// consumes data in response or handles the error
     fn handle_get_response(&mut self, data_location : NameType, data : Data);
 
+    /// handles a get request refused by the network, e.g. when our rate limit was exceeded
+    fn handle_get_error_response(&mut self, _data_location : NameType,
+                                            _data_request  : DataRequest,
+                                            _response_error: ResponseError) {}
+
+    /// consumes the results of a batch of get requests, each request with the name it asked
+    /// for and its result; the data of a Forwarded request arrives in handle_get_response
+    fn handle_get_batch_response(&mut self, _results : Vec<(NameType, DataRequest,
+                                                            BatchItemResult)>) {}
+
     /// handles the result of a put request
     fn handle_put_response(&mut self, response_error : ResponseError,
                                       request_data   : Data);
 
+    /// handles the results of a batch of put requests, each data with its result; the result of
+    /// a Forwarded put arrives in handle_put_response
+    fn handle_put_batch_response(&mut self, _results : Vec<(Data, BatchItemResult)>) {}
+
     /// handles the result of a post request
     fn handle_post_response(&mut self, response_error : ResponseError,
                                        request_data   : Data);
@@ -37,4 +52,8 @@ pub trait Interface : Sync + Send {
     /// handles the result of a delete request
     fn handle_delete_response(&mut self, response_error : ResponseError,
                                          request_data   : Data);
+
+    /// notifies that data which does not match the name it was requested under arrived through
+    /// the given relay. The data was not handed over.
+    fn handle_misbehaving_source(&mut self, _source : NameType) {}
 }
@@ -0,0 +1,139 @@
+// Copyright 2015 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Messages above a size threshold are compressed on connections where both sides announced
+//! Capability::Compression.  Only the encoded SignedMessage is compressed, so the signature
+//! inside stays over the uncompressed body, and every hop decompresses what it forwards.
+
+use std::cell::Cell;
+use std::io::{Read, Write};
+use cbor::CborBytes;
+use flate2;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use error::RoutingError;
+use types::Bytes;
+use wire_format;
+
+/// Messages smaller than this are sent as they are.
+pub const COMPRESSION_THRESHOLD : usize = 1024;
+/// We refuse to decompress a message beyond this size.
+pub const MAX_DECOMPRESSED_SIZE : u64 = 16 * 1024 * 1024;
+
+/// The compressed wire bytes of a message.
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct CompressedMessage {
+    compressed: CborBytes,
+}
+
+/// Compresses messages over the threshold, counting what it saved.
+pub struct Compressor {
+    threshold: usize,
+    // counters are updated from the membrane's non-mutating send functions
+    bytes_saved: Cell<u64>,
+    messages_compressed: Cell<u64>,
+}
+
+impl Compressor {
+    pub fn new() -> Compressor {
+        Compressor {
+            threshold: COMPRESSION_THRESHOLD,
+            bytes_saved: Cell::new(0),
+            messages_compressed: Cell::new(0),
+        }
+    }
+
+    /// Sets the size from which messages are compressed.
+    pub fn set_threshold(&mut self, threshold: usize) {
+        self.threshold = threshold;
+    }
+
+    /// Returns the bytes to send: a CompressedMessage if the message is over the threshold and
+    /// compressing it saves space, otherwise the message as it is.
+    pub fn compress(&self, bytes: &Bytes) -> Bytes {
+        if bytes.len() < self.threshold {
+            return bytes.clone();
+        }
+        let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::Default);
+        let compressed = match encoder.write_all(&bytes[..]).and_then(|()| encoder.finish()) {
+            Ok(compressed) => compressed,
+            Err(_) => return bytes.clone(),
+        };
+        match wire_format::encode(&CompressedMessage { compressed: CborBytes(compressed) }) {
+            Ok(message) => {
+                if message.len() >= bytes.len() {
+                    return bytes.clone();
+                }
+                self.bytes_saved.set(self.bytes_saved.get() + (bytes.len() - message.len()) as u64);
+                self.messages_compressed.set(self.messages_compressed.get() + 1);
+                message
+            },
+            Err(_) => bytes.clone(),
+        }
+    }
+
+    /// Returns the number of bytes compression saved so far.
+    pub fn bytes_saved(&self) -> u64 {
+        self.bytes_saved.get()
+    }
+
+    /// Returns the number of messages sent compressed so far.
+    pub fn messages_compressed(&self) -> u64 {
+        self.messages_compressed.get()
+    }
+}
+
+/// Returns the message inside if the bytes are a CompressedMessage, otherwise the bytes as they
+/// are.  Fails if the compressed message is corrupt or decompresses beyond MAX_DECOMPRESSED_SIZE.
+pub fn decompress(bytes: Bytes) -> Result<Bytes, RoutingError> {
+    let message = match wire_format::decode::<CompressedMessage>(&bytes) {
+        Ok(message) => message,
+        Err(_) => return Ok(bytes),
+    };
+    let mut decompressed = Vec::new();
+    let _ = try!(ZlibDecoder::new(&message.compressed.0[..])
+                     .take(MAX_DECOMPRESSED_SIZE + 1)
+                     .read_to_end(&mut decompressed));
+    if decompressed.len() as u64 > MAX_DECOMPRESSED_SIZE {
+        return Err(RoutingError::MessageTooLarge);
+    }
+    Ok(decompressed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn compress_over_threshold() {
+        let compressor = Compressor::new();
+
+        let small = vec![7u8; COMPRESSION_THRESHOLD - 1];
+        assert_eq!(compressor.compress(&small), small);
+        assert_eq!(compressor.messages_compressed(), 0);
+
+        let large = vec![7u8; COMPRESSION_THRESHOLD * 4];
+        let compressed = compressor.compress(&large);
+        assert!(compressed.len() < large.len());
+        assert_eq!(compressor.messages_compressed(), 1);
+        assert_eq!(compressor.bytes_saved(), (large.len() - compressed.len()) as u64);
+
+        assert_eq!(decompress(compressed).ok(), Some(large));
+        // anything else passes through untouched
+        assert_eq!(decompress(small.clone()).ok(), Some(small));
+    }
+}
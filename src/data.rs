@@ -16,42 +16,62 @@
 // relating to use of the SAFE Network Software.
 
 use rustc_serialize::{Decoder, Encodable, Encoder};
-pub use structured_data::StructuredData;
+pub use structured_data::{StructuredData, StructuredDataHistory};
 pub use immutable_data::{ImmutableData, ImmutableDataType};
-pub use plain_data::PlainData;
+pub use plain_data::{PlainData, SignedPlainData};
+pub use appendable_data::{AppendableData, AppendedEntry};
 use NameType;
 
 /// This is the data types routing handles in the public interface
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, RustcEncodable, RustcDecodable)]
 pub enum Data {
     StructuredData(StructuredData),
+    /// consecutive versions of a StructuredData, oldest first; only returned for a GetData, a
+    /// PutData or Post of it is refused with InvalidRequest
+    StructuredDataHistory(StructuredDataHistory),
     ImmutableData(ImmutableData),
-    PlainData(PlainData)
+    PlainData(PlainData),
+    SignedPlainData(SignedPlainData),
+    AppendableData(AppendableData),
 }
 
 impl Data {
     pub fn name(&self) -> NameType {
         match *self {
             Data::StructuredData(ref d) => d.name(),
+            Data::StructuredDataHistory(ref d) => d.name(),
             Data::ImmutableData(ref d)  => d.name(),
             Data::PlainData(ref d)      => d.name(),
+            Data::SignedPlainData(ref d) => d.name(),
+            Data::AppendableData(ref d) => d.name(),
         }
     }
 
     pub fn payload_size(&self) -> usize {
         match *self {
             Data::StructuredData(ref d) => d.payload_size(),
+            Data::StructuredDataHistory(ref d) => d.payload_size(),
             Data::ImmutableData(ref d)  => d.payload_size(),
             Data::PlainData(ref d)      => d.payload_size(),
+            Data::SignedPlainData(ref d) => d.payload_size(),
+            Data::AppendableData(ref d) => d.payload_size(),
         }
     }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, RustcEncodable, RustcDecodable)]
 pub enum DataRequest {
+    /// the current version of the StructuredData with this type tag
     StructuredData(u64),
+    /// the given version of the StructuredData with this type tag, if still held
+    StructuredDataVersion(u64, u64),
+    /// up to the given number of the most recent versions of the StructuredData with this type tag
+    StructuredDataHistory(u64, u64),
     ImmutableData(ImmutableDataType),
     PlainData,
+    SignedPlainData,
+    /// the AppendableData with this type tag
+    AppendableData(u64),
 }
 
 #[cfg(test)]
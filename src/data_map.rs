@@ -0,0 +1,406 @@
+// Copyright 2015 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Content too large for a single message is split into ImmutableData chunks, described by a
+//! DataMap.  The DataMap is itself stored as ImmutableData or StructuredData, and is all a client
+//! needs to retrieve and join the content again.
+//!
+//! Through a RoutingClient, DataMap::store puts the content and returns the name of its DataMap.
+//! To read it back, DataMap::request asks for the DataMap under that name; once it arrived at the
+//! client interface, request_chunks asks for its chunks and returns a ChunkCollector, which is
+//! handed the chunks as they arrive and joins the content once all of them did.
+
+use std::cmp;
+use std::collections::BTreeMap;
+use rustc_serialize::{Decoder, Encodable, Encoder};
+use sodiumoxide::crypto;
+use sodiumoxide::crypto::secretbox;
+use sodiumoxide::crypto::sign;
+
+use NameType;
+use client_interface::Interface;
+use data::{Data, DataRequest, ImmutableData, ImmutableDataType, StructuredData};
+use error::DataMapError;
+use routing_client::RoutingClient;
+use utils::{encode, decode};
+
+/// The largest chunk the content is split into.
+pub static MAX_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Describes a single chunk of the content.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, RustcEncodable, RustcDecodable)]
+pub struct ChunkDetails {
+    /// the name the chunk is stored under
+    pub name: NameType,
+    /// the SHA512 of the chunk's content, before encryption
+    pub content_hash: NameType,
+    /// the size of the chunk's content, before encryption
+    pub size: u64,
+}
+
+/// The chunks of the content, in order.  If the chunks are encrypted, the key of each chunk is
+/// derived from the content hash of the chunk before it, and the nonce from the chunk after it;
+/// so only the holder of the DataMap can decrypt them.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, RustcEncodable, RustcDecodable)]
+pub struct DataMap {
+    chunks: Vec<ChunkDetails>,
+    encrypted: bool,
+}
+
+impl DataMap {
+    /// Splits the content into chunks of at most MAX_CHUNK_SIZE, optionally encrypted.
+    pub fn split(content: &[u8], encrypt: bool) -> (DataMap, Vec<ImmutableData>) {
+        DataMap::split_into(content, MAX_CHUNK_SIZE, encrypt)
+    }
+
+    /// Splits the content into chunks of at most chunk_size, optionally encrypted.
+    pub fn split_into(content: &[u8], chunk_size: usize, encrypt: bool)
+            -> (DataMap, Vec<ImmutableData>) {
+        let chunk_size = cmp::max(chunk_size, 1);
+        let contents = content.chunks(chunk_size).collect::<Vec<&[u8]>>();
+        let content_hashes = contents.iter()
+            .map(|chunk| NameType(crypto::hash::sha512::hash(chunk).0))
+            .collect::<Vec<NameType>>();
+
+        let mut chunks = Vec::with_capacity(contents.len());
+        let mut chunk_details = Vec::with_capacity(contents.len());
+        for (index, chunk) in contents.iter().enumerate() {
+            let value = match encrypt {
+                true => {
+                    let (key, nonce) = DataMap::chunk_key(&content_hashes, index);
+                    secretbox::seal(chunk, &nonce, &key)
+                },
+                false => chunk.to_vec(),
+            };
+            let immutable_data = ImmutableData::new(ImmutableDataType::Normal, value);
+            chunk_details.push(ChunkDetails {
+                name: immutable_data.name(),
+                content_hash: content_hashes[index].clone(),
+                size: chunk.len() as u64,
+            });
+            chunks.push(immutable_data);
+        }
+
+        (DataMap { chunks: chunk_details, encrypted: encrypt }, chunks)
+    }
+
+    /// Returns the details of the chunks, in order.
+    pub fn chunks(&self) -> &Vec<ChunkDetails> {
+        &self.chunks
+    }
+
+    /// Returns true if the chunks are encrypted.
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypted
+    }
+
+    /// Returns the size of the content.  A DataMap decoded from the network may claim chunk sizes
+    /// which overflow, that is refused as a ChunkMismatch of the chunk where it happens.
+    pub fn content_size(&self) -> Result<u64, DataMapError> {
+        let mut total = 0u64;
+        for details in self.chunks.iter() {
+            total = match total.checked_add(details.size) {
+                Some(total) => total,
+                None => return Err(DataMapError::ChunkMismatch(details.name.clone())),
+            };
+        }
+        Ok(total)
+    }
+
+    /// Joins the content from the given chunks, checking each chunk against its name and
+    /// content hash.
+    pub fn join(&self, chunks: &BTreeMap<NameType, ImmutableData>)
+            -> Result<Vec<u8>, DataMapError> {
+        let content_hashes = self.chunks.iter()
+            .map(|chunk| chunk.content_hash.clone())
+            .collect::<Vec<NameType>>();
+        let mut content = Vec::new();
+        for (index, details) in self.chunks.iter().enumerate() {
+            let chunk = match chunks.get(&details.name) {
+                Some(chunk) => chunk,
+                None => return Err(DataMapError::MissingChunk(details.name.clone())),
+            };
+            if chunk.name() != details.name {
+                return Err(DataMapError::ChunkMismatch(details.name.clone()));
+            }
+            let value = match self.encrypted {
+                true => {
+                    let (key, nonce) = DataMap::chunk_key(&content_hashes, index);
+                    match secretbox::open(chunk.value(), &nonce, &key) {
+                        Ok(value) => value,
+                        Err(_) => return Err(DataMapError::ChunkMismatch(details.name.clone())),
+                    }
+                },
+                false => chunk.value().clone(),
+            };
+            if value.len() as u64 != details.size
+                || NameType(crypto::hash::sha512::hash(&value).0) != details.content_hash {
+                return Err(DataMapError::ChunkMismatch(details.name.clone()));
+            }
+            content.extend(value.into_iter());
+        }
+        Ok(content)
+    }
+
+    /// Stores the DataMap as ImmutableData, under the hash of its serialisation.
+    pub fn to_immutable_data(&self) -> Result<ImmutableData, DataMapError> {
+        Ok(ImmutableData::new(ImmutableDataType::Normal, try!(encode(self))))
+    }
+
+    /// Reads a DataMap stored as ImmutableData.
+    pub fn from_immutable_data(immutable_data: &ImmutableData) -> Result<DataMap, DataMapError> {
+        Ok(try!(decode(immutable_data.value())))
+    }
+
+    /// Stores the DataMap as the first version of a StructuredData, signed by its owner, so the
+    /// content can be found under a chosen identifier and later replaced.
+    pub fn to_structured_data(&self, type_tag: u64, identifier: NameType,
+                              owner_key: sign::PublicKey, secret_key: &sign::SecretKey)
+            -> Result<StructuredData, DataMapError> {
+        let mut structured_data = StructuredData::new(type_tag, identifier, try!(encode(self)),
+                                                      vec![owner_key], 0, vec![owner_key],
+                                                      vec![]);
+        let signature = try!(structured_data.sign(secret_key));
+        structured_data.replace_signatures(vec![signature]);
+        Ok(structured_data)
+    }
+
+    /// Reads a DataMap stored as StructuredData.
+    pub fn from_structured_data(structured_data: &StructuredData)
+            -> Result<DataMap, DataMapError> {
+        Ok(try!(decode(structured_data.get_data())))
+    }
+
+    /// Puts the chunks of the content and its DataMap as ImmutableData through the client, and
+    /// returns the name of the DataMap.
+    pub fn store<F: Interface>(client: &mut RoutingClient<F>, content: &[u8], encrypt: bool)
+            -> Result<NameType, DataMapError> {
+        let (data_map, chunks) = DataMap::split(content, encrypt);
+        for chunk in chunks {
+            try!(client.put(chunk.name(), Data::ImmutableData(chunk)));
+        }
+        let data_map = try!(data_map.to_immutable_data());
+        let data_map_name = data_map.name();
+        try!(client.put(data_map_name.clone(), Data::ImmutableData(data_map)));
+        Ok(data_map_name)
+    }
+
+    /// Requests the DataMap stored by store under the given name through the client.  The
+    /// response arrives at the client interface, and is read with from_immutable_data.
+    pub fn request<F: Interface>(client: &mut RoutingClient<F>, data_map_name: NameType)
+            -> Result<(), DataMapError> {
+        Ok(try!(client.get(data_map_name, DataRequest::ImmutableData(ImmutableDataType::Normal))))
+    }
+
+    /// Requests all chunks through the client.  The responses arrive at the client interface;
+    /// hand them to the returned collector, which joins the content once all chunks arrived.
+    pub fn request_chunks<F: Interface>(&self, client: &mut RoutingClient<F>)
+            -> Result<ChunkCollector, DataMapError> {
+        for chunk in self.chunks.iter() {
+            try!(client.get(chunk.name.clone(),
+                            DataRequest::ImmutableData(ImmutableDataType::Normal)));
+        }
+        Ok(ChunkCollector::new(self.clone()))
+    }
+
+    // The key is taken from the content hash of the previous chunk, the nonce from the next.
+    fn chunk_key(content_hashes: &[NameType], index: usize) -> (secretbox::Key, secretbox::Nonce) {
+        let count = content_hashes.len();
+        let previous = &content_hashes[(index + count - 1) % count];
+        let next = &content_hashes[(index + 1) % count];
+        let mut key = [0u8; secretbox::KEYBYTES];
+        let mut nonce = [0u8; secretbox::NONCEBYTES];
+        for (byte, hash_byte) in key.iter_mut().zip(previous.0.iter()) {
+            *byte = *hash_byte;
+        }
+        for (byte, hash_byte) in nonce.iter_mut().zip(next.0.iter()) {
+            *byte = *hash_byte;
+        }
+        (secretbox::Key(key), secretbox::Nonce(nonce))
+    }
+}
+
+/// Collects the chunks of a DataMap as they arrive at the client interface, see
+/// DataMap::request_chunks.
+#[derive(Debug, Clone)]
+pub struct ChunkCollector {
+    data_map: DataMap,
+    chunks: BTreeMap<NameType, ImmutableData>,
+}
+
+impl ChunkCollector {
+    /// Collects the chunks of the DataMap.
+    pub fn new(data_map: DataMap) -> ChunkCollector {
+        ChunkCollector { data_map: data_map, chunks: BTreeMap::new() }
+    }
+
+    /// Takes the data received for a get under the given name.  Returns false, and keeps
+    /// nothing, if it is not one of our chunks.
+    pub fn add(&mut self, location: NameType, data: Data) -> bool {
+        let chunk = match data {
+            Data::ImmutableData(chunk) => chunk,
+            _ => return false,
+        };
+        if chunk.name() != location
+            || !self.data_map.chunks.iter().any(|details| details.name == location) {
+            return false;
+        }
+        let _ = self.chunks.insert(location, chunk);
+        true
+    }
+
+    /// Returns the names of the chunks which did not arrive yet, in order.
+    pub fn missing_chunks(&self) -> Vec<NameType> {
+        self.data_map.chunks.iter()
+            .filter(|details| !self.chunks.contains_key(&details.name))
+            .map(|details| details.name.clone())
+            .collect()
+    }
+
+    /// Returns true if all chunks arrived.
+    pub fn is_complete(&self) -> bool {
+        self.missing_chunks().is_empty()
+    }
+
+    /// Joins the content from the chunks which arrived.
+    pub fn join(&self) -> Result<Vec<u8>, DataMapError> {
+        self.data_map.join(&self.chunks)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::BTreeMap;
+    use rand::{thread_rng, Rng};
+    use sodiumoxide::crypto;
+    use data::{Data, ImmutableData, ImmutableDataType};
+    use error::DataMapError;
+    use test_utils::Random;
+    use NameType;
+
+    fn random_content(size: usize) -> Vec<u8> {
+        let mut content = vec![0u8; size];
+        thread_rng().fill_bytes(&mut content);
+        content
+    }
+
+    fn by_name(chunks: Vec<ImmutableData>) -> BTreeMap<NameType, ImmutableData> {
+        chunks.into_iter().map(|chunk| (chunk.name(), chunk)).collect()
+    }
+
+    #[test]
+    fn split_and_join() {
+        let content = random_content(1000);
+        for encrypt in vec![false, true] {
+            let (data_map, chunks) = DataMap::split_into(&content, 300, encrypt);
+            assert_eq!(data_map.chunks().len(), 4);
+            assert_eq!(data_map.content_size().ok(), Some(1000));
+            assert_eq!(data_map.chunks().iter().map(|chunk| chunk.size).collect::<Vec<_>>(),
+                       vec![300, 300, 300, 100]);
+            assert_eq!(chunks[0].value() == &content[..300].to_vec(), !encrypt);
+            assert_eq!(data_map.join(&by_name(chunks)).ok(), Some(content.clone()));
+        }
+    }
+
+    #[test]
+    fn join_checks_chunks() {
+        let content = random_content(1000);
+        let (data_map, chunks) = DataMap::split_into(&content, 300, true);
+        let missing_name = chunks[1].name();
+        let mut chunks = by_name(chunks);
+
+        // a chunk stored under the wrong name is refused
+        let _ = chunks.insert(missing_name.clone(), ImmutableData::new(
+            ImmutableDataType::Normal, random_content(316)));
+        match data_map.join(&chunks) {
+            Err(DataMapError::ChunkMismatch(name)) => assert_eq!(name, missing_name),
+            _ => panic!("Expected a ChunkMismatch"),
+        }
+
+        let _ = chunks.remove(&missing_name);
+        match data_map.join(&chunks) {
+            Err(DataMapError::MissingChunk(name)) => assert_eq!(name, missing_name),
+            _ => panic!("Expected a MissingChunk"),
+        }
+    }
+
+    #[test]
+    fn unchecked_sizes() {
+        let content = random_content(1000);
+        let (mut data_map, chunks) = DataMap::split_into(&content, 300, false);
+        let chunks = by_name(chunks);
+
+        // a size far beyond the content is refused when the chunk arrives, not allocated
+        data_map.chunks[0].size = ::std::u64::MAX;
+        match data_map.join(&chunks) {
+            Err(DataMapError::ChunkMismatch(name)) => assert_eq!(name, data_map.chunks[0].name),
+            _ => panic!("Expected a ChunkMismatch"),
+        }
+
+        // sizes adding up beyond u64 are refused as well
+        data_map.chunks[1].size = ::std::u64::MAX;
+        match data_map.content_size() {
+            Err(DataMapError::ChunkMismatch(name)) => assert_eq!(name, data_map.chunks[1].name),
+            _ => panic!("Expected a ChunkMismatch"),
+        }
+    }
+
+    #[test]
+    fn collect_chunks() {
+        let content = random_content(1000);
+        let (data_map, mut chunks) = DataMap::split_into(&content, 300, true);
+        let mut collector = ChunkCollector::new(data_map.clone());
+        assert_eq!(collector.missing_chunks(), data_map.chunks().iter()
+            .map(|chunk| chunk.name.clone()).collect::<Vec<_>>());
+
+        // the responses arrive in any order, along with data which is not ours
+        chunks.reverse();
+        let last_chunk = chunks.pop().unwrap();
+        for chunk in chunks {
+            assert!(collector.add(chunk.name(), Data::ImmutableData(chunk)));
+        }
+        let stranger = ImmutableData::new(ImmutableDataType::Normal, random_content(300));
+        assert!(!collector.add(stranger.name(), Data::ImmutableData(stranger.clone())));
+        assert!(!collector.add(last_chunk.name(), Data::ImmutableData(stranger)));
+        assert!(!collector.is_complete());
+        assert_eq!(collector.missing_chunks(), vec![last_chunk.name()]);
+        match collector.join() {
+            Err(DataMapError::MissingChunk(name)) => assert_eq!(name, last_chunk.name()),
+            _ => panic!("Expected a MissingChunk"),
+        }
+
+        assert!(collector.add(last_chunk.name(), Data::ImmutableData(last_chunk)));
+        assert!(collector.is_complete());
+        assert_eq!(collector.join().ok(), Some(content));
+    }
+
+    #[test]
+    fn store_data_map() {
+        let content = random_content(1000);
+        let (data_map, _) = DataMap::split_into(&content, 300, true);
+
+        let immutable_data = data_map.to_immutable_data().unwrap();
+        assert_eq!(DataMap::from_immutable_data(&immutable_data).ok(), Some(data_map.clone()));
+
+        let keys = crypto::sign::gen_keypair();
+        let structured_data = data_map.to_structured_data(1, Random::generate_random(),
+                                                          keys.0, &keys.1).unwrap();
+        assert_eq!(structured_data.remaining_signature_weight().ok(), Some(0));
+        assert_eq!(DataMap::from_structured_data(&structured_data).ok(), Some(data_map));
+    }
+}
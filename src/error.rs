@@ -24,6 +24,157 @@ use std::error;
 use std::fmt;
 use std::str;
 use data::Data;
+use NameType;
+
+//------------------------------------------------------------------------------
+#[deny(missing_docs)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Debug, RustcEncodable, RustcDecodable)]
+/// represents the reasons a StructuredData is not a valid successor of the stored version
+pub enum StructuredDataError {
+    /// the type tags differ
+    WrongTypeTag,
+    /// the identifiers differ
+    WrongIdentifier,
+    /// the version is not the stored version plus one
+    VersionNotIncremented,
+    /// the previous owner keys are not the current owner keys of the stored version
+    OwnerKeysMismatch,
+    /// the previous owner policy is not the current owner policy of the stored version
+    OwnershipPolicyMismatch,
+    /// too few previous owners signed
+    NotEnoughSignatures,
+    /// a signature is present more than once
+    DuplicateSignatures,
+    /// a signature is not valid for any of the previous owner keys
+    InvalidSignature,
+    /// a weighted ownership policy can never, or always, be met by the owners
+    InvalidOwnershipPolicy,
+}
+
+impl error::Error for StructuredDataError {
+    fn description(&self) -> &str {
+        match *self {
+            StructuredDataError::WrongTypeTag => "Wrong type tag",
+            StructuredDataError::WrongIdentifier => "Wrong identifier",
+            StructuredDataError::VersionNotIncremented => "Version not incremented",
+            StructuredDataError::OwnerKeysMismatch => "Previous owner keys mismatch",
+            StructuredDataError::OwnershipPolicyMismatch => "Previous owner policy mismatch",
+            StructuredDataError::NotEnoughSignatures => "Not enough signatures",
+            StructuredDataError::DuplicateSignatures => "Duplicate signatures",
+            StructuredDataError::InvalidSignature => "Invalid signature",
+            StructuredDataError::InvalidOwnershipPolicy => "Invalid ownership policy",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        None
+    }
+}
+
+impl fmt::Display for StructuredDataError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StructuredDataError::WrongTypeTag =>
+                fmt::Display::fmt("StructuredDataError::WrongTypeTag", f),
+            StructuredDataError::WrongIdentifier =>
+                fmt::Display::fmt("StructuredDataError::WrongIdentifier", f),
+            StructuredDataError::VersionNotIncremented =>
+                fmt::Display::fmt("StructuredDataError::VersionNotIncremented", f),
+            StructuredDataError::OwnerKeysMismatch =>
+                fmt::Display::fmt("StructuredDataError::OwnerKeysMismatch", f),
+            StructuredDataError::OwnershipPolicyMismatch =>
+                fmt::Display::fmt("StructuredDataError::OwnershipPolicyMismatch", f),
+            StructuredDataError::NotEnoughSignatures =>
+                fmt::Display::fmt("StructuredDataError::NotEnoughSignatures", f),
+            StructuredDataError::DuplicateSignatures =>
+                fmt::Display::fmt("StructuredDataError::DuplicateSignatures", f),
+            StructuredDataError::InvalidSignature =>
+                fmt::Display::fmt("StructuredDataError::InvalidSignature", f),
+            StructuredDataError::InvalidOwnershipPolicy =>
+                fmt::Display::fmt("StructuredDataError::InvalidOwnershipPolicy", f),
+        }
+    }
+}
+
+//------------------------------------------------------------------------------
+#[deny(missing_docs)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Debug, RustcEncodable, RustcDecodable)]
+/// represents the reasons a Post to an AppendableData is refused
+pub enum AppendableDataError {
+    /// the type tags differ
+    WrongTypeTag,
+    /// the identifiers differ
+    WrongIdentifier,
+    /// the owner is not the owner of the stored version
+    OwnerMismatch,
+    /// the authorised keys are not those of the stored version
+    AuthorisedKeysMismatch,
+    /// the owner signature is not that of the stored version
+    OwnerSignatureMismatch,
+    /// the version is neither the stored version nor the next one
+    VersionNotIncremented,
+    /// the owner signature is not valid
+    InvalidOwnerSignature,
+    /// an entry is appended by a key the owner did not authorise
+    NotAuthorised,
+    /// an entry is not validly signed by its author
+    InvalidEntrySignature,
+    /// an entry was not signed for the current version
+    EntryVersionMismatch,
+    /// an entry is present more than once
+    DuplicateEntries,
+}
+
+impl error::Error for AppendableDataError {
+    fn description(&self) -> &str {
+        match *self {
+            AppendableDataError::WrongTypeTag => "Wrong type tag",
+            AppendableDataError::WrongIdentifier => "Wrong identifier",
+            AppendableDataError::OwnerMismatch => "Owner mismatch",
+            AppendableDataError::AuthorisedKeysMismatch => "Authorised keys mismatch",
+            AppendableDataError::OwnerSignatureMismatch => "Owner signature mismatch",
+            AppendableDataError::VersionNotIncremented => "Version not incremented",
+            AppendableDataError::InvalidOwnerSignature => "Invalid owner signature",
+            AppendableDataError::NotAuthorised => "Key not authorised",
+            AppendableDataError::InvalidEntrySignature => "Invalid entry signature",
+            AppendableDataError::EntryVersionMismatch => "Entry not signed for this version",
+            AppendableDataError::DuplicateEntries => "Duplicate entries",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        None
+    }
+}
+
+impl fmt::Display for AppendableDataError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AppendableDataError::WrongTypeTag =>
+                fmt::Display::fmt("AppendableDataError::WrongTypeTag", f),
+            AppendableDataError::WrongIdentifier =>
+                fmt::Display::fmt("AppendableDataError::WrongIdentifier", f),
+            AppendableDataError::OwnerMismatch =>
+                fmt::Display::fmt("AppendableDataError::OwnerMismatch", f),
+            AppendableDataError::AuthorisedKeysMismatch =>
+                fmt::Display::fmt("AppendableDataError::AuthorisedKeysMismatch", f),
+            AppendableDataError::OwnerSignatureMismatch =>
+                fmt::Display::fmt("AppendableDataError::OwnerSignatureMismatch", f),
+            AppendableDataError::VersionNotIncremented =>
+                fmt::Display::fmt("AppendableDataError::VersionNotIncremented", f),
+            AppendableDataError::InvalidOwnerSignature =>
+                fmt::Display::fmt("AppendableDataError::InvalidOwnerSignature", f),
+            AppendableDataError::NotAuthorised =>
+                fmt::Display::fmt("AppendableDataError::NotAuthorised", f),
+            AppendableDataError::InvalidEntrySignature =>
+                fmt::Display::fmt("AppendableDataError::InvalidEntrySignature", f),
+            AppendableDataError::EntryVersionMismatch =>
+                fmt::Display::fmt("AppendableDataError::EntryVersionMismatch", f),
+            AppendableDataError::DuplicateEntries =>
+                fmt::Display::fmt("AppendableDataError::DuplicateEntries", f),
+        }
+    }
+}
 
 //------------------------------------------------------------------------------
 #[deny(missing_docs)]
@@ -35,7 +186,21 @@ pub enum ResponseError {
     /// invalid request
     InvalidRequest,
     /// failure to store data
-    FailedToStoreData(Data)
+    FailedToStoreData(Data),
+    /// the relay refused the request, as the client exceeded its rate limit
+    RateLimitExceeded,
+    /// the StructuredData is not a valid successor of the stored version
+    InvalidSuccessor(StructuredDataError),
+    /// the Post to an AppendableData was refused
+    InvalidAppend(AppendableDataError),
+}
+
+impl From<StructuredDataError> for ResponseError {
+    fn from(e: StructuredDataError) -> ResponseError { ResponseError::InvalidSuccessor(e) }
+}
+
+impl From<AppendableDataError> for ResponseError {
+    fn from(e: AppendableDataError) -> ResponseError { ResponseError::InvalidAppend(e) }
 }
 
 impl error::Error for ResponseError {
@@ -44,11 +209,18 @@ impl error::Error for ResponseError {
             ResponseError::NoData => "No Data",
             ResponseError::InvalidRequest => "Invalid request",
             ResponseError::FailedToStoreData(_) => "Failed to store data",
+            ResponseError::RateLimitExceeded => "Rate limit exceeded",
+            ResponseError::InvalidSuccessor(_) => "Invalid successor",
+            ResponseError::InvalidAppend(_) => "Invalid append",
         }
     }
 
     fn cause(&self) -> Option<&error::Error> {
-        None
+        match *self {
+            ResponseError::InvalidSuccessor(ref err) => Some(err as &error::Error),
+            ResponseError::InvalidAppend(ref err) => Some(err as &error::Error),
+            _ => None,
+        }
     }
 }
 
@@ -59,10 +231,22 @@ impl fmt::Display for ResponseError {
             ResponseError::InvalidRequest => fmt::Display::fmt("ResponsError::InvalidRequest", f),
             ResponseError::FailedToStoreData(_) =>
                 fmt::Display::fmt("ResponseError::FailedToStoreData", f),
+            ResponseError::RateLimitExceeded =>
+                fmt::Display::fmt("ResponseError::RateLimitExceeded", f),
+            ResponseError::InvalidSuccessor(ref err) => fmt::Display::fmt(err, f),
+            ResponseError::InvalidAppend(ref err) => fmt::Display::fmt(err, f),
         }
     }
 }
 
+// the tag of the ResponseErrors of earlier releases, encoded as (type_tag, data)
+static RESPONSE_ERROR_TAG: u64 = 5483_100;
+// the tag of an InvalidSuccessor, encoded as (type_tag, reason), as earlier releases would
+// misread the reason under the tag above
+static RESPONSE_ERROR_REASON_TAG: u64 = 5483_101;
+// the tag of an InvalidAppend, encoded as (type_tag, reason)
+static RESPONSE_ERROR_APPEND_REASON_TAG: u64 = 5483_102;
+
 impl Encodable for ResponseError {
     fn encode<E: Encoder>(&self, e: &mut E)->Result<(), E::Error> {
         let type_tag;
@@ -73,22 +257,46 @@ impl Encodable for ResponseError {
             ResponseError::FailedToStoreData(ref err_data) => {
                 type_tag = "FailedToStoreData";
                 data = Some(err_data.clone());
-            }
+            },
+            ResponseError::RateLimitExceeded => type_tag = "RateLimitExceeded",
+            ResponseError::InvalidSuccessor(ref err) => {
+                return CborTagEncode::new(RESPONSE_ERROR_REASON_TAG,
+                                          &(&"InvalidSuccessor", err)).encode(e);
+            },
+            ResponseError::InvalidAppend(ref err) => {
+                return CborTagEncode::new(RESPONSE_ERROR_APPEND_REASON_TAG,
+                                          &(&"InvalidAppend", err)).encode(e);
+            },
         };
-        CborTagEncode::new(5483_100, &(&type_tag, &data)).encode(e)
+        CborTagEncode::new(RESPONSE_ERROR_TAG, &(&type_tag, &data)).encode(e)
     }
 }
 
 impl Decodable for ResponseError {
     fn decode<D: Decoder>(d: &mut D)->Result<ResponseError, D::Error> {
-        try!(d.read_u64());
-        // let mut type_tag : String;
-        // // let mut data : Option<Vec<u8>>;
-        let (type_tag, data) : (String, Option<Data>)
-            = try!(Decodable::decode(d));
+        let tag = try!(d.read_u64());
+        if tag == RESPONSE_ERROR_REASON_TAG {
+            let (type_tag, reason) : (String, StructuredDataError) = try!(Decodable::decode(d));
+            return match &type_tag[..] {
+                "InvalidSuccessor" => Ok(ResponseError::InvalidSuccessor(reason)),
+                _ => Err(d.error("Unrecognised ResponseError")),
+            };
+        }
+        if tag == RESPONSE_ERROR_APPEND_REASON_TAG {
+            let (type_tag, reason) : (String, AppendableDataError) = try!(Decodable::decode(d));
+            return match &type_tag[..] {
+                "InvalidAppend" => Ok(ResponseError::InvalidAppend(reason)),
+                _ => Err(d.error("Unrecognised ResponseError")),
+            };
+        }
+        if tag != RESPONSE_ERROR_TAG {
+            return Err(d.error("Unrecognised ResponseError tag"));
+        }
+        let (type_tag, data) : (String, Option<Data>) = try!(Decodable::decode(d));
         match &type_tag[..] {
             "NoData" => Ok(ResponseError::NoData),
             "InvalidRequest" => Ok(ResponseError::InvalidRequest),
+            "RateLimitExceeded" => Ok(ResponseError::RateLimitExceeded),
             "FailedToStoreData" => {
                 match data {
                     Some(err_data) => Ok(ResponseError::FailedToStoreData(err_data)),
@@ -138,6 +346,55 @@ impl fmt::Display for InterfaceError {
     }
 }
 
+//------------------------------------------------------------------------------
+#[deny(missing_docs)]
+#[derive(Debug)]
+/// represents the failures to split content into chunks, store it, or join it again
+pub enum DataMapError {
+    /// the chunk with this name was not provided
+    MissingChunk(NameType),
+    /// the chunk with this name does not match its name, size or content hash
+    ChunkMismatch(NameType),
+    /// failure to send, sign or serialise
+    Routing(RoutingError),
+}
+
+impl From<RoutingError> for DataMapError {
+    fn from(e: RoutingError) -> DataMapError { DataMapError::Routing(e) }
+}
+
+impl From<CborError> for DataMapError {
+    fn from(e: CborError) -> DataMapError { DataMapError::Routing(RoutingError::Cbor(e)) }
+}
+
+impl error::Error for DataMapError {
+    fn description(&self) -> &str {
+        match *self {
+            DataMapError::MissingChunk(_) => "Missing chunk",
+            DataMapError::ChunkMismatch(_) => "Chunk does not match the data map",
+            DataMapError::Routing(_) => "Routing error",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            DataMapError::Routing(ref err) => Some(err as &error::Error),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for DataMapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DataMapError::MissingChunk(ref name) => write!(f, "Missing chunk {:?}", name),
+            DataMapError::ChunkMismatch(ref name) =>
+                write!(f, "Chunk {:?} does not match the data map", name),
+            DataMapError::Routing(ref err) => fmt::Display::fmt(err, f),
+        }
+    }
+}
+
 //------------------------------------------------------------------------------
 pub enum ClientError {
     Io(io::Error),
@@ -182,8 +439,21 @@ pub enum RoutingError {
     /// routing table did not add the node information,
     /// either because it was already added, or because it did not improve the routing table
     RefusedFromRoutingTable,
+    /// routing table or relay map did not add the contact,
+    /// because the limit of contacts from its IP subnet was reached
+    SubnetLimitReached,
     /// We received a refresh message but it did not contain group source address
     RefreshNotFromGroup,
+    /// returned data does not match the name it was requested under
+    DataNameMismatch,
+    /// received a message encoded with a wire format version we do not support
+    UnsupportedWireFormat(u64),
+    /// received a message under the schema tag of a type other than the one expected
+    UnexpectedSchemaTag(u64),
+    /// the peer speaks none of our protocol versions, only those in the given range
+    IncompatibleProtocol(u64, u64),
+    /// a compressed message decompresses beyond the size we accept
+    MessageTooLarge,
     /// String errors
     Utf8(str::Utf8Error),
     /// interface error
@@ -205,6 +475,18 @@ impl From<ResponseError> for RoutingError {
     fn from(e: ResponseError) -> RoutingError { RoutingError::Response(e) }
 }
 
+impl From<StructuredDataError> for RoutingError {
+    fn from(e: StructuredDataError) -> RoutingError {
+        RoutingError::Response(ResponseError::InvalidSuccessor(e))
+    }
+}
+
+impl From<AppendableDataError> for RoutingError {
+    fn from(e: AppendableDataError) -> RoutingError {
+        RoutingError::Response(ResponseError::InvalidAppend(e))
+    }
+}
+
 impl From<CborError> for RoutingError {
     fn from(e: CborError) -> RoutingError { RoutingError::Cbor(e) }
 }
@@ -232,7 +514,13 @@ impl error::Error for RoutingError {
             RoutingError::RoutingTableEmpty => "Routing table empty",
             RoutingError::RejectedPublicId => "Rejected Public Id",
             RoutingError::RefusedFromRoutingTable => "Refused from routing table",
+            RoutingError::SubnetLimitReached => "Refused, limit of contacts from subnet reached",
             RoutingError::RefreshNotFromGroup => "Refresh message not from group",
+            RoutingError::DataNameMismatch => "Data does not match the requested name",
+            RoutingError::UnsupportedWireFormat(_) => "Unsupported wire format version",
+            RoutingError::UnexpectedSchemaTag(_) => "Unexpected schema tag",
+            RoutingError::IncompatibleProtocol(_, _) => "Incompatible protocol version",
+            RoutingError::MessageTooLarge => "Message too large",
             RoutingError::Utf8(_) => "String/Utf8 error",
             RoutingError::Interface(_) => "Interface error",
             RoutingError::Io(_) => "I/O error",
@@ -267,7 +555,17 @@ impl fmt::Display for RoutingError {
             RoutingError::RoutingTableEmpty => fmt::Display::fmt("routing table empty", f),
             RoutingError::RejectedPublicId => fmt::Display::fmt("Rejected Public Id", f),
             RoutingError::RefusedFromRoutingTable => fmt::Display::fmt("Refused from routing table", f),
+            RoutingError::SubnetLimitReached =>
+                fmt::Display::fmt("Refused, limit of contacts from subnet reached", f),
             RoutingError::RefreshNotFromGroup => fmt::Display::fmt("Refresh message not from group", f),
+            RoutingError::DataNameMismatch =>
+                fmt::Display::fmt("Data does not match the requested name", f),
+            RoutingError::UnsupportedWireFormat(version) =>
+                write!(f, "Unsupported wire format version {}", version),
+            RoutingError::UnexpectedSchemaTag(tag) => write!(f, "Unexpected schema tag {}", tag),
+            RoutingError::IncompatibleProtocol(min_version, max_version) =>
+                write!(f, "Incompatible protocol versions {} to {}", min_version, max_version),
+            RoutingError::MessageTooLarge => fmt::Display::fmt("Message too large", f),
             RoutingError::Utf8(ref err) => fmt::Display::fmt(err, f),
             RoutingError::Interface(ref err) => fmt::Display::fmt(err, f),
             RoutingError::Io(ref err) => fmt::Display::fmt(err, f),
@@ -293,6 +591,24 @@ mod test {
 
     #[test]
     fn test_response_error() {
-        test_object(ResponseError::NoData)
+        test_object(ResponseError::NoData);
+        test_object(ResponseError::RateLimitExceeded);
+        test_object(ResponseError::InvalidSuccessor(StructuredDataError::OwnerKeysMismatch));
+        test_object(ResponseError::InvalidAppend(AppendableDataError::AuthorisedKeysMismatch));
+    }
+
+    #[test]
+    fn earlier_response_error() {
+        // a NoData as encoded by earlier releases: tag 5483_100 around ("NoData", null)
+        let bytes = vec![0xda, 0x00, 0x53, 0xaa, 0x5c, 0x82,
+                         0x66, 0x4e, 0x6f, 0x44, 0x61, 0x74, 0x61, 0xf6];
+        let mut d = cbor::Decoder::from_bytes(&bytes[..]);
+        let decoded: ResponseError = d.decode().next().unwrap().unwrap();
+        assert_eq!(decoded, ResponseError::NoData);
+
+        // and is still encoded that way
+        let mut e = cbor::Encoder::from_memory();
+        e.encode(&[&ResponseError::NoData]).unwrap();
+        assert_eq!(e.as_bytes(), &bytes[..]);
     }
 }
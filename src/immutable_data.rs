@@ -56,8 +56,14 @@ impl ImmutableData {
 
     /// Returns name ensuring invariant
     pub fn name(&self) -> NameType {
-        let digest = crypto::hash::sha512::hash(&self.value);
-        match self.type_tag {
+        ImmutableData::compute_name(&self.type_tag, &self.value)
+    }
+
+    /// Computes the name the given value is stored under as the given type.  The name of the
+    /// Backup and Sacrificial copies is derived from the name of the Normal copy.
+    pub fn compute_name(type_tag: &ImmutableDataType, value: &[u8]) -> NameType {
+        let digest = crypto::hash::sha512::hash(value);
+        match *type_tag {
         ImmutableDataType::Normal => return NameType(digest.0),
         ImmutableDataType::Backup => return NameType(crypto::hash::sha512::hash(&digest.0).0),
         ImmutableDataType::Sacrificial => return NameType(crypto::hash::sha512::hash(
@@ -115,6 +121,18 @@ mod test {
         assert_eq!(&expected_immutable_data_sacrificial_name, &immutable_data_sacrificial_name);
     }
 
+    #[test]
+    fn compute_name() {
+        let value = generate_random();
+        for type_tag in vec![ImmutableDataType::Normal, ImmutableDataType::Backup,
+                             ImmutableDataType::Sacrificial] {
+            let immutable_data = ImmutableData::new(type_tag.clone(), value.clone());
+            assert_eq!(immutable_data.name(), ImmutableData::compute_name(&type_tag, &value));
+        }
+        assert!(ImmutableData::compute_name(&ImmutableDataType::Normal, &value) !=
+                ImmutableData::compute_name(&ImmutableDataType::Backup, &value));
+    }
+
     #[test]
     fn name_is_hash_of_lesser_type_name() {
         let value = generate_random();
@@ -44,6 +44,7 @@ extern crate rustc_serialize;
 extern crate maidsafe_sodiumoxide as sodiumoxide;
 extern crate time;
 extern crate itertools;
+extern crate flate2;
 
 extern crate crust;
 extern crate accumulator;
@@ -51,13 +52,16 @@ extern crate lru_time_cache;
 extern crate message_filter;
 extern crate sentinel;
 
+mod canonical;
 mod common_bits;
+mod compression;
 mod macros;
 mod messages;
 mod name_type;
 mod routing_table;
 mod relay;
 mod who_are_you;
+mod wire_format;
 
 pub mod client_interface;
 pub mod node_interface;
@@ -73,10 +77,17 @@ pub mod utils;
 pub mod public_id;
 pub mod error;
 pub mod authority;
+pub mod churn;
 pub mod structured_data;
 pub mod immutable_data;
 pub mod plain_data;
+pub mod appendable_data;
 pub mod data;
+pub mod data_map;
 pub mod user_message;
 /// NameType is a 512bit name to address elements on the DHT network.
 pub use name_type::{NameType, closer_to_target};
+/// Optional protocol features announced when connecting, and those agreed on for a connection.
+pub use who_are_you::{Capability, NegotiatedProtocol, supported_capabilities};
+/// The result of each item of a batch request.
+pub use messages::BatchItemResult;
@@ -19,13 +19,15 @@ use sodiumoxide::crypto::sign::Signature;
 use sodiumoxide::crypto::sign;
 use crust::Endpoint;
 use authority::Authority;
-use data::{Data, DataRequest};
+use canonical::signed_message_bytes;
+use data::{Data, DataRequest, ImmutableData};
 use types;
 use public_id::PublicId;
-use types::{DestinationAddress, SourceAddress};
-use error::{ResponseError};
+use types::{DestinationAddress, SourceAddress, RoutePath, RouteReport};
+use error::{ResponseError, RoutingError};
 use NameType;
 use utils;
+use wire_format;
 use cbor::{CborError};
 use std::collections::BTreeMap;
 
@@ -65,8 +67,78 @@ pub struct GetDataResponse {
 }
 
 impl GetDataResponse {
+    /// Returns true if the requester signed the request the data answers, directly or as the
+    /// request a group forwarded on.
     pub fn verify_request_came_from(&self, requester_pub_key: &sign::PublicKey) -> bool {
-        self.orig_request.verify_signature(requester_pub_key)
+        self.orig_request.verify_requester(requester_pub_key)
+    }
+
+    /// Returns false if the response carries ImmutableData whose content does not hash to the
+    /// name it was requested under, as the requested ImmutableDataType.  Other data can not be
+    /// checked against its name, and is accepted.
+    pub fn verify_data_matches_request(&self) -> bool {
+        match self.data {
+            Data::ImmutableData(_) => {},
+            _ => return true,
+        };
+        match self.requested_item() {
+            Some((name, data_request)) => data_matches_request(&self.data, &name, &data_request),
+            None => false,
+        }
+    }
+
+    /// Returns the name and the request of the data as the requester asked for it: the
+    /// destination of a GetData, or the item of a GetDataBatch by the name of the data.
+    pub fn requested_item(&self) -> Option<(NameType, DataRequest)> {
+        let (handled, requested) = match self.orig_request.answered_requests() {
+            Ok(requests) => requests,
+            Err(_) => return None,
+        };
+        match handled.message_type {
+            MessageType::GetData(ref data_request) =>
+                requested_get_item(&requested, data_request, Some(self.data.name())),
+            _ => None,
+        }
+    }
+}
+
+/// Returns false if the data is ImmutableData whose content does not hash to the name it was
+/// requested under, as the requested ImmutableDataType.  Other data can not be checked against
+/// its name, and is accepted.
+pub fn data_matches_request(data: &Data, name: &NameType, data_request: &DataRequest) -> bool {
+    let immutable_data = match *data {
+        Data::ImmutableData(ref immutable_data) => immutable_data,
+        _ => return true,
+    };
+    match *data_request {
+        DataRequest::ImmutableData(ref type_tag) =>
+            ImmutableData::compute_name(type_tag, immutable_data.value()) == *name,
+        _ => false,
+    }
+}
+
+// The item of the requester's request a GetData for the data request was handled as: the
+// GetData itself, or the item of a GetDataBatch asking for it under the name, if given.  None
+// if the items asking for it under different names can not be told apart.
+fn requested_get_item(requested: &RoutingMessage, data_request: &DataRequest,
+                      name: Option<NameType>) -> Option<(NameType, DataRequest)> {
+    match requested.message_type {
+        MessageType::GetData(ref requested_data_request)
+                if requested_data_request == data_request =>
+            Some((requested.non_relayed_destination(), data_request.clone())),
+        MessageType::GetDataBatch(ref items) => {
+            let names = items.iter()
+                .filter(|&&(ref item_name, ref item_request)| item_request == data_request
+                        && name.as_ref().map_or(true, |name| name == item_name))
+                .map(|&(ref item_name, _)| item_name.clone())
+                .collect::<Vec<NameType>>();
+            match names.first() {
+                Some(first) if names.iter().all(|item_name| item_name == first) =>
+                    Some((first.clone(), data_request.clone())),
+                _ => None,
+            }
+        },
+        _ => None,
     }
 }
 
@@ -86,6 +158,82 @@ impl ErrorReturn {
         }
     }
 
+    /// Returns true if the requester signed the request the error answers, directly or as the
+    /// request a group forwarded on.
+    pub fn verify_request_came_from(&self, requester_pub_key: &sign::PublicKey) -> bool {
+        self.orig_request.verify_requester(requester_pub_key)
+    }
+
+    /// Returns the name and the request of the data a GetDataErrorResponse answers, as the
+    /// requester asked for it.  None if the error answers an item of a GetDataBatch which can not
+    /// be told apart from another item asking for the same kind of data.
+    pub fn requested_get_item(&self) -> Option<(NameType, DataRequest)> {
+        let (handled, requested) = match self.orig_request.answered_requests() {
+            Ok(requests) => requests,
+            Err(_) => return None,
+        };
+        match handled.message_type {
+            MessageType::GetData(ref data_request) =>
+                requested_get_item(&requested, data_request, None),
+            _ => None,
+        }
+    }
+
+    /// Returns the data a PutDataResponse answers, if the requester asked to put it: the data
+    /// of a PutData, or an item of a PutDataBatch.
+    pub fn requested_put_item(&self) -> Option<Data> {
+        let (handled, requested) = match self.orig_request.answered_requests() {
+            Ok(requests) => requests,
+            Err(_) => return None,
+        };
+        let data = match handled.message_type {
+            MessageType::PutData(data) => data,
+            _ => return None,
+        };
+        match requested.message_type {
+            MessageType::PutData(ref requested_data) if *requested_data == data => Some(data),
+            MessageType::PutDataBatch(ref items) if items.contains(&data) => Some(data),
+            _ => None,
+        }
+    }
+}
+
+/// The result of one item of a GetDataBatch or PutDataBatch.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Debug, RustcEncodable, RustcDecodable)]
+pub enum BatchItemResult {
+    /// the group replied with this data
+    Data(Data),
+    /// the group accepted the item
+    Accepted,
+    /// the group sent the item on for further handling; its result arrives as the response
+    /// to a single request
+    Forwarded,
+    /// the group dropped the item without a response
+    Aborted,
+    /// the group refused the item
+    Failed(ResponseError),
+}
+
+/// The response of a group to a GetDataBatch or PutDataBatch, with a result for each item of
+/// the original request, in the order of the items.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Debug, RustcEncodable, RustcDecodable)]
+pub struct BatchResponse {
+    pub results        : Vec<BatchItemResult>,
+    pub orig_request   : SignedMessage,
+    pub group_pub_keys : BTreeMap<NameType, sign::PublicKey>,
+}
+
+impl BatchResponse {
+    /// Returns the response refusing every item of a batch with the same error.
+    pub fn refused(item_count: usize, error: ResponseError, orig_request: SignedMessage)
+            -> BatchResponse {
+        BatchResponse {
+            results        : vec![BatchItemResult::Failed(error); item_count],
+            orig_request   : orig_request,
+            group_pub_keys : BTreeMap::new(),
+        }
+    }
+
     pub fn verify_request_came_from(&self, requester_pub_key: &sign::PublicKey) -> bool {
         self.orig_request.verify_signature(requester_pub_key)
     }
@@ -99,9 +247,11 @@ pub enum MessageType {
     ConnectRequest(ConnectRequest),
     ConnectResponse(ConnectResponse),
     FindGroup,
-    FindGroupResponse(Vec<PublicId>),
+    FindGroupResponse(Vec<PublicId>, u64 /* network size estimate */),
     GetData(DataRequest),
     GetDataResponse(GetDataResponse),
+    GetDataErrorResponse(ErrorReturn),
+    GetDataBatch(Vec<(NameType, DataRequest)>),
     DeleteData(DataRequest),
     DeleteDataResponse(ErrorReturn),
     GetGroupKey,
@@ -110,10 +260,13 @@ pub enum MessageType {
     PostResponse(ErrorReturn, BTreeMap<NameType, sign::PublicKey>),
     PutData(Data),
     PutDataResponse(ErrorReturn, BTreeMap<NameType, sign::PublicKey>),
+    PutDataBatch(Vec<Data>),
+    BatchResponse(BatchResponse),
     PutKey,
     PutPublicId(PublicId),
     PutPublicIdResponse(PublicId, SignedMessage),
     Refresh(u64, Vec<u8>),
+    RouteReport(RouteReport),
     Unknown,
 }
 
@@ -229,7 +382,7 @@ impl RoutingMessage {
     /// the interface has processed the message.
     /// Note: this is not for XOR-forwarding; then the header is preserved!
     pub fn create_reply(&self, our_name : &NameType, our_authority : &Authority)
-        -> Result<RoutingMessage, CborError> {
+        -> Result<RoutingMessage, RoutingError> {
         // Commented the below code as it doesn't compile.
         let mut reply_message = self.clone();
 
@@ -261,45 +414,175 @@ impl RoutingMessage {
 
 }
 
+/// A RoutingMessage as encoded by earlier releases, read into the current one.  Messages it
+/// nests are of the earlier releases as well.
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct LegacyRoutingMessage {
+    pub destination  : DestinationAddress,
+    pub source       : SourceAddress,
+    pub orig_message : Option<LegacySignedMessage>,
+    pub message_type : LegacyMessageType,
+    pub message_id   : types::MessageId,
+    pub authority    : Authority
+}
+
+impl LegacyRoutingMessage {
+    pub fn into_routing_message(self) -> RoutingMessage {
+        RoutingMessage {
+            destination  : self.destination,
+            source       : self.source,
+            orig_message : self.orig_message.map(LegacySignedMessage::into_signed_message),
+            message_type : self.message_type.into_message_type(),
+            message_id   : self.message_id,
+            authority    : self.authority,
+        }
+    }
+}
+
+/// The MessageType of earlier releases, whose FindGroupResponse carried no network size
+/// estimate; it is read as an estimate of 0, which is ignored.
+#[derive(RustcEncodable, RustcDecodable)]
+pub enum LegacyMessageType {
+    ConnectRequest(ConnectRequest),
+    ConnectResponse(ConnectResponse),
+    FindGroup,
+    FindGroupResponse(Vec<PublicId>),
+    GetData(DataRequest),
+    GetDataResponse(LegacyGetDataResponse),
+    DeleteData(DataRequest),
+    DeleteDataResponse(LegacyErrorReturn),
+    GetGroupKey,
+    GetGroupKeyResponse(BTreeMap<NameType, sign::PublicKey>),
+    Post(Data),
+    PostResponse(LegacyErrorReturn, BTreeMap<NameType, sign::PublicKey>),
+    PutData(Data),
+    PutDataResponse(LegacyErrorReturn, BTreeMap<NameType, sign::PublicKey>),
+    PutKey,
+    PutPublicId(PublicId),
+    PutPublicIdResponse(PublicId, LegacySignedMessage),
+    Refresh(u64, Vec<u8>),
+    Unknown,
+}
+
+impl LegacyMessageType {
+    pub fn into_message_type(self) -> MessageType {
+        match self {
+            LegacyMessageType::ConnectRequest(request) => MessageType::ConnectRequest(request),
+            LegacyMessageType::ConnectResponse(response) =>
+                MessageType::ConnectResponse(response),
+            LegacyMessageType::FindGroup => MessageType::FindGroup,
+            LegacyMessageType::FindGroupResponse(group) => MessageType::FindGroupResponse(group, 0),
+            LegacyMessageType::GetData(request) => MessageType::GetData(request),
+            LegacyMessageType::GetDataResponse(response) =>
+                MessageType::GetDataResponse(response.into_get_data_response()),
+            LegacyMessageType::DeleteData(request) => MessageType::DeleteData(request),
+            LegacyMessageType::DeleteDataResponse(error) =>
+                MessageType::DeleteDataResponse(error.into_error_return()),
+            LegacyMessageType::GetGroupKey => MessageType::GetGroupKey,
+            LegacyMessageType::GetGroupKeyResponse(keys) => MessageType::GetGroupKeyResponse(keys),
+            LegacyMessageType::Post(data) => MessageType::Post(data),
+            LegacyMessageType::PostResponse(error, keys) =>
+                MessageType::PostResponse(error.into_error_return(), keys),
+            LegacyMessageType::PutData(data) => MessageType::PutData(data),
+            LegacyMessageType::PutDataResponse(error, keys) =>
+                MessageType::PutDataResponse(error.into_error_return(), keys),
+            LegacyMessageType::PutKey => MessageType::PutKey,
+            LegacyMessageType::PutPublicId(public_id) => MessageType::PutPublicId(public_id),
+            LegacyMessageType::PutPublicIdResponse(public_id, request) =>
+                MessageType::PutPublicIdResponse(public_id, request.into_signed_message()),
+            LegacyMessageType::Refresh(type_tag, payload) =>
+                MessageType::Refresh(type_tag, payload),
+            LegacyMessageType::Unknown => MessageType::Unknown,
+        }
+    }
+}
+
+/// The GetDataResponse of earlier releases.
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct LegacyGetDataResponse {
+    pub data           : Data,
+    pub orig_request   : LegacySignedMessage,
+    pub group_pub_keys : BTreeMap<NameType, sign::PublicKey>,
+}
+
+impl LegacyGetDataResponse {
+    pub fn into_get_data_response(self) -> GetDataResponse {
+        GetDataResponse {
+            data           : self.data,
+            orig_request   : self.orig_request.into_signed_message(),
+            group_pub_keys : self.group_pub_keys,
+        }
+    }
+}
+
+/// The ErrorReturn of earlier releases.
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct LegacyErrorReturn {
+    pub error        : ResponseError,
+    pub orig_request : LegacySignedMessage,
+}
+
+impl LegacyErrorReturn {
+    pub fn into_error_return(self) -> ErrorReturn {
+        ErrorReturn::new(self.error, self.orig_request.into_signed_message())
+    }
+}
+
+/// A SignedMessage as sent by earlier releases, before disjoint routes.  Its body is the untagged
+/// RoutingMessage of an earlier release, signed as it is, without a domain.
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct LegacySignedMessage {
+    pub encoded_body : Vec<u8>,
+    pub signature    : Signature,
+}
+
+impl LegacySignedMessage {
+    pub fn into_signed_message(self) -> SignedMessage {
+        SignedMessage {
+            encoded_body: self.encoded_body,
+            signature:    self.signature,
+            route:        None,
+        }
+    }
+}
+
 /// All messages sent / received are constructed from this type
 #[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Debug, RustcEncodable, RustcDecodable)]
 pub struct SignedMessage {
     encoded_body : Vec<u8>,
     signature    : Signature,
+    // not covered by the signature; only set on copies sent along disjoint paths
+    route        : Option<RoutePath>,
 }
 
 impl SignedMessage {
     pub fn new(message: &RoutingMessage, private_sign_key: &sign::SecretKey)
         -> Result<SignedMessage, CborError> {
 
-        let encoded_body = try!(utils::encode(&message));
-        let signature    = sign::sign_detached(&encoded_body, private_sign_key);
+        let encoded_body = try!(wire_format::encode(message));
+        let signature    = sign::sign_detached(&signed_message_bytes(&encoded_body),
+                                               private_sign_key);
 
         Ok(SignedMessage {
             encoded_body: encoded_body,
-            signature:    signature
+            signature:    signature,
+            route:        None,
         })
     }
 
-    pub fn with_signature(message: &RoutingMessage, signature: Signature)
-        -> Result<SignedMessage, CborError> {
-
-          let encoded_body = try!(utils::encode(&message));
-
-          Ok(SignedMessage {
-              encoded_body: encoded_body,
-              signature:    signature
-          })
-    }
-
+    /// Returns true if the signature is valid for the key.  A message of an earlier release,
+    /// whose body is not enveloped, was signed over the body as it is, without a domain.
     pub fn verify_signature(&self, public_sign_key: &sign::PublicKey) -> bool {
-        sign::verify_detached(&self.signature,
-                              &self.encoded_body,
-                              &public_sign_key)
+        let signed_bytes = if wire_format::is_enveloped(&self.encoded_body) {
+            signed_message_bytes(&self.encoded_body)
+        } else {
+            self.encoded_body.clone()
+        };
+        sign::verify_detached(&self.signature, &signed_bytes, &public_sign_key)
     }
 
-    pub fn get_routing_message(&self) -> Result<RoutingMessage, CborError> {
-        utils::decode::<RoutingMessage>(&self.encoded_body)
+    pub fn get_routing_message(&self) -> Result<RoutingMessage, RoutingError> {
+        wire_format::decode::<RoutingMessage>(&self.encoded_body)
     }
 
     pub fn encoded_body(&self) -> &Vec<u8> {
@@ -307,4 +590,50 @@ impl SignedMessage {
     }
 
     pub fn signature(&self) -> &Signature { &self.signature }
+
+    pub fn route(&self) -> Option<&RoutePath> { self.route.as_ref() }
+
+    /// Returns a copy of this message travelling along the given disjoint path.
+    pub fn with_route(&self, route: RoutePath) -> SignedMessage {
+        SignedMessage {
+            encoded_body: self.encoded_body.clone(),
+            signature:    self.signature.clone(),
+            route:        Some(route),
+        }
+    }
+
+    /// Returns true if the requester signed this message, or the request this message forwards
+    /// on as its orig_message.
+    pub fn verify_requester(&self, requester_pub_key: &sign::PublicKey) -> bool {
+        if self.verify_signature(requester_pub_key) {
+            return true;
+        }
+        match self.get_routing_message() {
+            Ok(RoutingMessage { orig_message: Some(ref orig_message), .. }) =>
+                orig_message.verify_signature(requester_pub_key),
+            _ => false,
+        }
+    }
+
+    /// Returns the request a group handled, carried as orig_request of its answer, and the
+    /// request the requester sent.  They differ if another group forwarded the request on, as
+    /// the forwarded message carries the requester's request as its orig_message; for an item
+    /// of a batch, that is the whole batch.
+    pub fn answered_requests(&self) -> Result<(RoutingMessage, RoutingMessage), RoutingError> {
+        let handled = try!(self.get_routing_message());
+        let requested = match handled.orig_message {
+            Some(ref orig_message) => try!(orig_message.get_routing_message()),
+            None => handled.clone(),
+        };
+        Ok((handled, requested))
+    }
+
+    /// Returns this message without the path it travelled along, e.g. to embed it in a response.
+    pub fn without_route(&self) -> SignedMessage {
+        SignedMessage {
+            encoded_body: self.encoded_body.clone(),
+            signature:    self.signature.clone(),
+            route:        None,
+        }
+    }
 }
@@ -17,9 +17,10 @@
 
 use data::{Data, DataRequest};
 use name_type::NameType;
-use types::{SourceAddress, DestinationAddress};
+use types::{SourceAddress, DestinationAddress, RouteReport};
 use authority::Authority;
 use error::{InterfaceError, ResponseError};
+use churn::ChurnEvent;
 
 /// MethodCall denotes a specific request to be carried out by routing.
 pub enum MethodCall {
@@ -33,7 +34,10 @@ pub enum MethodCall {
     Delete { name: NameType, data : Data },
     /// request to refresh
     Refresh { type_tag: u64, from_group: NameType, payload: Vec<u8> },
-    /// request to forward on the request to destination for further handling
+    /// request to forward on the request to destination for further handling.  When returned
+    /// for an item of a GetDataBatch or PutDataBatch, the forwarded request carries the whole
+    /// batch as its orig_message, the request the client signed, so the item is answered
+    /// against the batch
     Forward { destination: NameType },
     /// reply
     Reply { data: Data }
@@ -98,9 +102,24 @@ pub trait Interface : Sync + Send {
                             from_address   : SourceAddress,
                             response       : ResponseError) -> Vec<MethodCall>;
 
-    /// handles the actions to be carried out in the event of a churn. The function provides a list
-    /// of actions (of type MethodCall) to be carried out in order to update relevant nodes.
-    fn handle_churn(&mut self, close_group: Vec<NameType>) -> Vec<MethodCall>;
+    /// handles the actions to be carried out in the event of a churn. The churn event lists the
+    /// previous and new close group, the nodes that joined or left it and how our range changed.
+    /// The function provides a list of actions (of type MethodCall) to be carried out in order to
+    /// update relevant nodes.
+    fn handle_churn(&mut self, churn_event: ChurnEvent) -> Vec<MethodCall>;
+
+    /// notifies of a change in the estimated number of nodes in the network. The estimate is
+    /// averaged over our own estimate and those reported by the nodes in our close group.
+    fn handle_network_size_estimate(&mut self, _estimate: u64) {}
+
+    /// notifies that a copy of a request we sent along disjoint paths arrived at its destination
+    /// group, with the hops it passed. `first_hops` lists the first hop of every path the request
+    /// was sent along; paths which are never reported point to a node dropping requests.
+    fn handle_route_report(&mut self, _report: RouteReport, _first_hops: Vec<NameType>) {}
+
+    /// notifies that the given node sent us data which does not match the name it was
+    /// requested under. The data was neither cached nor passed on.
+    fn handle_misbehaving_source(&mut self, _source: NameType) {}
 
     /// attempts to potentially retrieve data from cache.
     fn handle_cache_get(&mut self,
@@ -16,7 +16,10 @@
 // relating to use of the SAFE Network Software.
 
 use rustc_serialize::{Decoder, Encodable, Encoder};
+use sodiumoxide::crypto;
+use sodiumoxide::crypto::sign;
 use NameType;
+use canonical::{CanonicalEncoder, SIGNED_PLAIN_DATA_DOMAIN};
 
 /// PlainData
 #[derive(Clone, RustcEncodable, RustcDecodable, PartialEq, Eq, PartialOrd, Ord, Debug)]
@@ -51,6 +54,105 @@ impl PlainData {
     }
 }
 
+/// SignedPlainData
+/// PlainData published by the owner of a signing key, whose signature binds the identifier,
+/// version and value to the owner.  The name is derived from the owner's key and the identifier,
+/// see compute_name, so no one can publish under a name belonging to another owner.  Personas
+/// should only accept a PUT over existing SignedPlainData with a higher version, see can_replace.
+#[derive(Clone, RustcEncodable, RustcDecodable, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct SignedPlainData {
+    name: NameType,
+    identifier: NameType,
+    version: u64,
+    value: Vec<u8>,
+    owner: sign::PublicKey,
+    signature: sign::Signature,
+}
+
+impl SignedPlainData {
+
+    /// Creates a new instance of SignedPlainData, signed with the owner's secret key
+    pub fn new(identifier: NameType, version: u64, value: Vec<u8>, owner: sign::PublicKey,
+               secret_key: &sign::SecretKey) -> SignedPlainData {
+        let signature = sign::sign_detached(
+            &SignedPlainData::data_to_sign(&identifier, version, &value), secret_key);
+        SignedPlainData {
+            name: SignedPlainData::compute_name(&owner, &identifier),
+            identifier: identifier,
+            version: version,
+            value: value,
+            owner: owner,
+            signature: signature,
+        }
+    }
+
+    /// Returns the name of the SignedPlainData of the given owner and identifier
+    pub fn compute_name(owner: &sign::PublicKey, identifier: &NameType) -> NameType {
+        let chain = identifier.0.iter()
+                    .chain(owner.0.iter())
+                    .map(|a|*a);
+
+        NameType(crypto::hash::sha512::hash(&chain.collect::<Vec<_>>()[..]).0)
+    }
+
+    /// Returns the identifier the name is derived from
+    pub fn identifier(&self) -> &NameType {
+        &self.identifier
+    }
+
+    /// Returns the version
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Returns the value
+    pub fn value(&self) -> &Vec<u8> {
+        &self.value
+    }
+
+    /// Returns the key of the owner who published this
+    pub fn owner(&self) -> &sign::PublicKey {
+        &self.owner
+    }
+
+    /// Returns the owner's signature over the identifier, version and value
+    pub fn signature(&self) -> &sign::Signature {
+        &self.signature
+    }
+
+    /// Returns name ensuring invariant
+    pub fn name(&self) -> NameType {
+        self.name.clone()
+    }
+
+    pub fn payload_size(&self) -> usize {
+        self.value.len()
+    }
+
+    /// Returns true if the name is derived from the owner's key and the identifier, and the
+    /// signature over the identifier, version and value is the owner's
+    pub fn verify(&self) -> bool {
+        self.name == SignedPlainData::compute_name(&self.owner, &self.identifier)
+            && sign::verify_detached(&self.signature,
+                   &SignedPlainData::data_to_sign(&self.identifier, self.version, &self.value),
+                   &self.owner)
+    }
+
+    /// Returns true if this is valid and may replace the existing data stored under the same
+    /// name, as it was published by the same owner with a higher version; so an old version the
+    /// owner signed can not be put back
+    pub fn can_replace(&self, existing: &SignedPlainData) -> bool {
+        self.name == existing.name && self.owner == existing.owner
+            && self.version > existing.version && self.verify()
+    }
+
+    fn data_to_sign(identifier: &NameType, version: u64, value: &Vec<u8>) -> Vec<u8> {
+        let mut encoder = CanonicalEncoder::new(SIGNED_PLAIN_DATA_DOMAIN);
+        encoder.name(identifier).u64(version).bytes(value);
+        encoder.into_bytes()
+    }
+}
+
 
 
 #[cfg(test)]
@@ -59,7 +161,7 @@ mod test {
     extern crate rand;
 
     use NameType;
-    use super::PlainData;
+    use super::{PlainData, SignedPlainData};
     use self::rand::Rng;
     use sodiumoxide::crypto;
     use rustc_serialize::hex::ToHex;
@@ -89,4 +191,65 @@ mod test {
         assert_eq!(plain_data2.value().to_hex(), value2.to_hex());
     }
 
+    #[test]
+    fn signed_plain_data() {
+        let owner = crypto::sign::gen_keypair();
+        let other = crypto::sign::gen_keypair();
+        let identifier = NameType(crypto::hash::sha512::hash(&generate_random()).0);
+
+        let signed_plain_data = SignedPlainData::new(identifier.clone(), 1, generate_random(),
+                                                     owner.0, &owner.1);
+        assert!(signed_plain_data.verify());
+        assert_eq!(signed_plain_data.name(),
+                   SignedPlainData::compute_name(&owner.0, &identifier));
+        assert_eq!(signed_plain_data.version(), 1);
+
+        // an update by the owner can replace it, one by someone else can not
+        let update = SignedPlainData::new(identifier.clone(), 2, generate_random(),
+                                          owner.0, &owner.1);
+        assert!(update.can_replace(&signed_plain_data));
+        let other_owner = SignedPlainData::new(identifier.clone(), 2, generate_random(),
+                                               other.0, &other.1);
+        assert!(other_owner.verify());
+        assert!(other_owner.name() != signed_plain_data.name());
+        assert!(!other_owner.can_replace(&signed_plain_data));
+
+        // claiming someone else's key does not verify
+        let forged = SignedPlainData::new(identifier.clone(), 2, generate_random(),
+                                          owner.0, &other.1);
+        assert!(!forged.verify());
+        assert!(!forged.can_replace(&signed_plain_data));
+
+        // nor does squatting a name not derived from the owner's key
+        let mut squatter = other_owner.clone();
+        squatter.name = signed_plain_data.name();
+        assert!(!squatter.verify());
+        let mut squatter = SignedPlainData::new(identifier.clone(), 0, generate_random(),
+                                                other.0, &other.1);
+        squatter.name = NameType(crypto::hash::sha512::hash(&generate_random()).0);
+        assert!(!squatter.verify());
+
+        // an older or the same version of the owner can not be put back
+        assert!(!signed_plain_data.can_replace(&update));
+        let same_version = SignedPlainData::new(identifier.clone(), 2, generate_random(),
+                                                owner.0, &owner.1);
+        assert!(!same_version.can_replace(&update));
+
+        // nor can the version be raised without the owner signing it
+        let mut raised = signed_plain_data.clone();
+        raised.version = 3;
+        assert!(!raised.verify());
+        assert!(!raised.can_replace(&update));
+    }
+
+    #[test]
+    fn canonical_encoding() {
+        let mut expected = vec![0, 0, 0, 0, 0, 0, 0, 25];
+        expected.extend(b"routing/SignedPlainData/3".iter().cloned());
+        expected.extend(vec![1u8; 64].into_iter());
+        expected.extend(vec![0, 0, 0, 0, 0, 0, 0, 2].into_iter());
+        expected.extend(vec![0, 0, 0, 0, 0, 0, 0, 2, 7, 8].into_iter());
+        assert_eq!(SignedPlainData::data_to_sign(&NameType([1u8; 64]), 2, &vec![7, 8]),
+                   expected);
+    }
 }
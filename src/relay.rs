@@ -22,68 +22,213 @@
 //! These messages include bootstrap actions by starting nodes or relay messages for clients.
 
 use std::collections::{BTreeMap, BTreeSet, HashMap};
-use time::{SteadyTime};
+use time::{Duration, SteadyTime};
 use crust::Endpoint;
 use id::Id;
+use error::RoutingError;
 use public_id::PublicId;
-use types::Address;
+use types;
+use types::{Address, Bytes, Subnet, SubnetLimits, RateLimits, RequestKind};
 use NameType;
 use sodiumoxide::crypto::sign;
 
 const MAX_RELAY : usize = 100;
+const MAX_UNKNOWN_CONNECTIONS : usize = 50;
+const UNKNOWN_CONNECTION_EXPIRY_SECS : i64 = 30;
+const MAX_PENDING_MESSAGES_PER_CLIENT : usize = 20;
+const MAX_PENDING_CLIENTS : usize = 100;
+const PENDING_MESSAGE_EXPIRY_SECS : i64 = 60;
+
+/// A token bucket holding at most one second's worth of its rate.  A full bucket always admits
+/// one request, so a single request larger than the rate is delayed rather than refused forever.
+/// A bucket with a rate of 0 admits nothing.
+struct TokenBucket {
+    rate: u64,
+    tokens: f64,
+    last_update: SteadyTime,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, time_now: SteadyTime) -> TokenBucket {
+        TokenBucket { rate: rate, tokens: rate as f64, last_update: time_now }
+    }
+
+    fn refill(&mut self, time_now: SteadyTime) {
+        let elapsed = (time_now - self.last_update).num_milliseconds() as f64 / 1000.0;
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.last_update = time_now;
+    }
+
+    fn admits(&self, amount: u64) -> bool {
+        if self.rate == 0 {
+            return false;
+        }
+        self.tokens >= amount as f64 || self.tokens >= self.rate as f64
+    }
+
+    fn take(&mut self, amount: u64) {
+        self.tokens -= amount as f64;
+    }
+}
+
+/// The message and byte buckets for one kind of request of a single relayed contact.
+struct RateLimiter {
+    messages: TokenBucket,
+    bytes: TokenBucket,
+}
+
+impl RateLimiter {
+    fn new(limit: (u64, u64), time_now: SteadyTime) -> RateLimiter {
+        RateLimiter {
+            messages: TokenBucket::new(limit.0, time_now),
+            bytes: TokenBucket::new(limit.1, time_now),
+        }
+    }
+
+    fn try_consume(&mut self, size: u64, time_now: SteadyTime) -> bool {
+        self.messages.refill(time_now);
+        self.bytes.refill(time_now);
+        if !self.messages.admits(1) || !self.bytes.admits(size) {
+            return false;
+        }
+        self.messages.take(1);
+        self.bytes.take(size);
+        true
+    }
+}
 
 
 /// The relay map is used to maintain a list of contacts for whom
 /// we are relaying messages, when we are ourselves connected to the network.
-/// These have to identify as Client(sign::PublicKey)
+/// These have to identify as Client(sign::PublicKey), or as Node(NameType) under
+/// their provisional name while they are joining the network
 pub struct RelayMap {
     relay_map: BTreeMap<Address, (PublicId, BTreeSet<Endpoint>)>,
     lookup_map: HashMap<Endpoint, Address>,
-    // FIXME : we don't want to store a value; but LRUcache can clear itself out
-    // however, we want the explicit timestamp stored and clear it at routing,
-    // to drop the connection on clearing; for now CM will just keep all these connections
+    // we store the explicit timestamp rather than using an LruCache, so that routing can drop
+    // the connections which expired; see expire_unknown_connections
     unknown_connections: HashMap<Endpoint, SteadyTime>,
+    unknown_connection_expiry: Duration,
+    max_unknown_connections: usize,
+    expired_unknown_connections: usize,
+    refused_unknown_connections: usize,
     our_name: NameType,
+    subnet_limits: SubnetLimits,
+    rate_limits: RateLimits,
+    rate_limiters: BTreeMap<(Address, RequestKind), RateLimiter>,
+    rate_limited_requests: usize,
+    // messages we could not deliver to a client, held for when it identifies itself again
+    pending_messages: BTreeMap<sign::PublicKey, Vec<(SteadyTime, Bytes)>>,
+    // clients whose last endpoint was dropped, and when; only for these messages are held
+    departed_clients: BTreeMap<sign::PublicKey, SteadyTime>,
+    pending_message_expiry: Duration,
+    max_pending_messages_per_client: usize,
+    max_pending_clients: usize,
 }
 
 impl RelayMap {
     /// This creates a new RelayMap.
     pub fn new(our_id: &Id) -> RelayMap {
+        RelayMap::with_subnet_limits(our_id,
+            SubnetLimits::new(types::MAX_RELAYED_CLIENTS_PER_SUBNET,
+                              types::MAX_RELAYED_CLIENTS_PER_SUBNET))
+    }
+
+    /// This creates a new RelayMap relaying for at most the given number of clients per IP subnet.
+    pub fn with_subnet_limits(our_id: &Id, subnet_limits: SubnetLimits) -> RelayMap {
         RelayMap {
             relay_map: BTreeMap::new(),
             lookup_map: HashMap::new(),
             unknown_connections: HashMap::new(),
+            unknown_connection_expiry: Duration::seconds(UNKNOWN_CONNECTION_EXPIRY_SECS),
+            max_unknown_connections: MAX_UNKNOWN_CONNECTIONS,
+            expired_unknown_connections: 0,
+            refused_unknown_connections: 0,
             our_name: our_id.name(),
+            subnet_limits: subnet_limits,
+            rate_limits: RateLimits::default(),
+            rate_limiters: BTreeMap::new(),
+            rate_limited_requests: 0,
+            pending_messages: BTreeMap::new(),
+            departed_clients: BTreeMap::new(),
+            pending_message_expiry: Duration::seconds(PENDING_MESSAGE_EXPIRY_SECS),
+            max_pending_messages_per_client: MAX_PENDING_MESSAGES_PER_CLIENT,
+            max_pending_clients: MAX_PENDING_CLIENTS,
         }
     }
 
+    /// Sets the number of contacts relayed for per IP subnet from now on.  Contacts already
+    /// relayed for are kept.
+    pub fn set_subnet_limits(&mut self, subnet_limits: SubnetLimits) {
+        self.subnet_limits = subnet_limits;
+    }
+
     /// Adds an IP Node info to the relay map if the relay map has open
     /// slots.  This returns true if Info was addded.
     /// Returns true is the endpoint is newly added, or was already present.
     /// Returns false if the threshold was reached or name is our name.
     /// Returns false if the endpoint is already assigned to a different name.
+    /// Returns false if the limit of clients from the endpoint's IP subnet was reached.
     pub fn add_client(&mut self, relay_info: PublicId, relay_endpoint: Endpoint) -> bool {
+        let relay_address = Address::Client(relay_info.signing_public_key());
+        self.add_relay(relay_address, relay_info, relay_endpoint)
+    }
+
+    /// Adds a node which is joining the network, under its provisional (unrelocated) name, so
+    /// that messages for RelayToNode can reach it until it becomes a member of the network.
+    /// Returns false for relocated ids, otherwise as add_client.
+    pub fn add_node(&mut self, relay_info: PublicId, relay_endpoint: Endpoint) -> bool {
+        if relay_info.is_relocated() {
+            return false;
+        }
+        let relay_address = Address::Node(relay_info.name());
+        self.add_relay(relay_address, relay_info, relay_endpoint)
+    }
+
+    fn add_relay(&mut self, relay_address: Address, relay_info: PublicId,
+                 relay_endpoint: Endpoint) -> bool {
         // always reject our own id
         if self.our_name == relay_info.name() {
             return false;
         }
         // impose limit on number of relay nodes active
-        if !self.relay_map.contains_key(&Address::Client(relay_info.signing_public_key()))
+        if !self.relay_map.contains_key(&relay_address)
             && self.relay_map.len() >= MAX_RELAY {
             return false;
         }
         if self.lookup_map.contains_key(&relay_endpoint) {
           return false; }
-        // only add Client
+        if self.check_subnet_limits(&relay_address, &relay_endpoint).is_err() {
+            return false;
+        }
         self.lookup_map.entry(relay_endpoint.clone())
-                       .or_insert(Address::Client(relay_info.signing_public_key()));
+                       .or_insert(relay_address.clone());
         let new_set = || { (relay_info.clone(), BTreeSet::<Endpoint>::new()) };
-        self.relay_map.entry(Address::Client(relay_info.signing_public_key()))
+        self.relay_map.entry(relay_address)
                       .or_insert_with(new_set).1
                       .insert(relay_endpoint);
         true
     }
 
+    /// Checks whether relaying for the given address on this endpoint would exceed the limit of
+    /// relays from the endpoint's IP subnet.  Returns SubnetLimitReached if so.
+    pub fn check_subnet_limits(&self, relay_address: &Address, relay_endpoint: &Endpoint)
+            -> Result<(), RoutingError> {
+        let subnet = match Subnet::of_endpoint(relay_endpoint) {
+            Some(subnet) => subnet,
+            None => return Ok(()),
+        };
+        let relays_in_subnet = self.relay_map.iter()
+            .filter(|&(address, _)| address != relay_address)
+            .filter(|&(_, &(_, ref endpoints))| endpoints.iter()
+                .any(|endpoint| Subnet::of_endpoint(endpoint).as_ref() == Some(&subnet)))
+            .count();
+        if relays_in_subnet >= self.subnet_limits.limit(&subnet) {
+            return Err(RoutingError::SubnetLimitReached);
+        }
+        Ok(())
+    }
+
     /// This removes the provided endpoint and returns a NameType if this endpoint
     /// was the last endpoint assocoiated with this Name; otherwise returns None.
     pub fn drop_endpoint(&mut self, endpoint_to_drop: &Endpoint) -> Option<Address> {
@@ -107,6 +252,14 @@ impl RelayMap {
             Some((name, (public_id, endpoints))) => {
                 if endpoints.is_empty() {
                     println!("Connection {:?} lost for relayed node {:?}", endpoint_to_drop, name);
+                    self.rate_limiters.remove(&(name.clone(), RequestKind::Get));
+                    self.rate_limiters.remove(&(name.clone(), RequestKind::Mutation));
+                    match *name {
+                        Address::Client(ref public_key) => {
+                            self.departed_clients.insert(public_key.clone(), SteadyTime::now());
+                        },
+                        Address::Node(_) => {},
+                    }
                     Some(name.clone())
                 } else {
                     self.relay_map.insert(name.clone(), (public_id.clone(), endpoints.clone()));
@@ -142,11 +295,164 @@ impl RelayMap {
         self.relay_map.get(relay_name)
     }
 
+    /// Sets the rate limits for requests relayed contacts send into the network.  The buckets
+    /// of contacts already being limited are reset.
+    pub fn set_rate_limits(&mut self, rate_limits: RateLimits) {
+        self.rate_limits = rate_limits;
+        self.rate_limiters.clear();
+    }
+
+    /// Accounts a request of the given kind and size sent by a relayed contact.  Returns false
+    /// if the contact exceeded its rate limit, in which case the request should not be sent
+    /// on into the network.  Addresses we do not relay for are not limited here.
+    pub fn check_rate_limit(&mut self, relay_address: &Address, request_kind: RequestKind,
+                            size: usize) -> bool {
+        if !self.relay_map.contains_key(relay_address) {
+            return true;
+        }
+        let time_now = SteadyTime::now();
+        let limit = self.rate_limits.limit(request_kind);
+        let admitted = self.rate_limiters.entry((relay_address.clone(), request_kind))
+            .or_insert_with(|| RateLimiter::new(limit, time_now))
+            .try_consume(size as u64, time_now);
+        if !admitted {
+            self.rate_limited_requests += 1;
+        }
+        admitted
+    }
+
+    /// Returns the number of requests refused because a relayed contact exceeded its rate limit.
+    pub fn rate_limited_requests(&self) -> usize {
+        self.rate_limited_requests
+    }
+
+    /// Sets how long undeliverable messages are held for a client, and how many are held per
+    /// client and for how many clients at most.
+    pub fn set_pending_message_limits(&mut self, expiry: Duration,
+                                      max_pending_messages_per_client: usize,
+                                      max_pending_clients: usize) {
+        self.pending_message_expiry = expiry;
+        self.max_pending_messages_per_client = max_pending_messages_per_client;
+        self.max_pending_clients = max_pending_clients;
+    }
+
+    /// Holds a message we failed to deliver to a client, until the client identifies itself
+    /// again.  When the client's queue is full, its oldest message is dropped.  Returns false if
+    /// the message was not queued, as we did not relay for the client within the pending message
+    /// expiry, or already hold messages for too many clients.
+    pub fn queue_pending_message(&mut self, public_key: sign::PublicKey, message: Bytes)
+            -> bool {
+        let time_now = SteadyTime::now();
+        let recently_relayed = match self.departed_clients.get(&public_key) {
+            Some(departed) => time_now - *departed <= self.pending_message_expiry,
+            None => false,
+        };
+        if !recently_relayed {
+            return false;
+        }
+        if !self.pending_messages.contains_key(&public_key)
+            && self.pending_messages.len() >= self.max_pending_clients {
+            return false;
+        }
+        if self.max_pending_messages_per_client == 0 {
+            return false;
+        }
+        let max_pending_messages = self.max_pending_messages_per_client;
+        let queue = self.pending_messages.entry(public_key).or_insert_with(Vec::new);
+        while queue.len() >= max_pending_messages {
+            queue.remove(0);
+        }
+        queue.push((time_now, message));
+        true
+    }
+
+    /// Removes and returns the messages held for a client which have not expired, oldest first.
+    pub fn take_pending_messages(&mut self, public_key: &sign::PublicKey) -> Vec<Bytes> {
+        let time_now = SteadyTime::now();
+        let expiry = self.pending_message_expiry;
+        match self.pending_messages.remove(public_key) {
+            Some(queue) => queue.into_iter()
+                .filter(|&(time, _)| time_now - time <= expiry)
+                .map(|(_, message)| message)
+                .collect::<Vec<Bytes>>(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Drops the held messages which expired, and returns how many were dropped.  Clients which
+    /// departed longer ago than the expiry are forgotten, so no more messages are held for them.
+    pub fn expire_pending_messages(&mut self) -> usize {
+        let time_now = SteadyTime::now();
+        let expiry = self.pending_message_expiry;
+        let long_departed = self.departed_clients.iter()
+            .filter(|&(_, departed)| time_now - *departed > expiry)
+            .map(|(public_key, _)| public_key.clone())
+            .collect::<Vec<sign::PublicKey>>();
+        for public_key in long_departed {
+            self.departed_clients.remove(&public_key);
+        }
+        let mut expired = 0;
+        for (_, queue) in self.pending_messages.iter_mut() {
+            let before = queue.len();
+            queue.retain(|&(time, _)| time_now - time <= expiry);
+            expired += before - queue.len();
+        }
+        let empty_queues = self.pending_messages.iter()
+            .filter(|&(_, queue)| queue.is_empty())
+            .map(|(public_key, _)| public_key.clone())
+            .collect::<Vec<sign::PublicKey>>();
+        for public_key in empty_queues {
+            self.pending_messages.remove(&public_key);
+        }
+        expired
+    }
+
+    /// Sets how long a connection may stay unidentified before it expires, and how many
+    /// unidentified connections are accepted at the same time.
+    pub fn set_unknown_connection_limits(&mut self, expiry: Duration,
+                                         max_unknown_connections: usize) {
+        self.unknown_connection_expiry = expiry;
+        self.max_unknown_connections = max_unknown_connections;
+    }
 
     /// On unknown NewConnection, register the endpoint we are connected to.
-    pub fn register_unknown_connection(&mut self, endpoint: Endpoint) {
-        // TODO: later prune and drop old unknown connections
+    /// Returns false if the limit of unidentified connections is reached; the connection
+    /// should then be dropped.
+    pub fn register_unknown_connection(&mut self, endpoint: Endpoint) -> bool {
+        if !self.unknown_connections.contains_key(&endpoint)
+            && self.unknown_connections.len() >= self.max_unknown_connections {
+            self.refused_unknown_connections += 1;
+            return false;
+        }
         self.unknown_connections.insert(endpoint, SteadyTime::now());
+        true
+    }
+
+    /// Removes and returns the unknown connections which did not identify themselves within
+    /// the expiry window.  These connections should be dropped.
+    pub fn expire_unknown_connections(&mut self) -> Vec<Endpoint> {
+        let time_now = SteadyTime::now();
+        let expiry = self.unknown_connection_expiry;
+        let expired = self.unknown_connections.iter()
+            .filter(|&(_, time)| time_now - *time > expiry)
+            .map(|(endpoint, _)| endpoint.clone())
+            .collect::<Vec<Endpoint>>();
+        for endpoint in &expired {
+            self.unknown_connections.remove(endpoint);
+        }
+        self.expired_unknown_connections += expired.len();
+        expired
+    }
+
+    /// Returns the number of unknown connections dropped because they did not identify
+    /// themselves in time.
+    pub fn expired_unknown_connections(&self) -> usize {
+        self.expired_unknown_connections
+    }
+
+    /// Returns the number of unknown connections refused because too many were open.
+    pub fn refused_unknown_connections(&self) -> usize {
+        self.refused_unknown_connections
     }
 
     /// When we receive an "I am" message on this connection, drop it
@@ -169,10 +475,14 @@ mod test {
     use crust::Endpoint;
     use id::Id;
     use public_id::PublicId;
-    use types::Address;
+    use error::RoutingError;
+    use types::{Address, SubnetLimits, RateLimits, RequestKind};
+    use NameType;
     use std::net::SocketAddr;
     use std::str::FromStr;
     use rand::random;
+    use sodiumoxide::crypto::sign;
+    use time::Duration;
 
     fn generate_random_endpoint() -> Endpoint {
         Endpoint::Tcp(SocketAddr::from_str(&format!("127.0.0.1:{}", random::<u16>())).unwrap())
@@ -225,6 +535,50 @@ mod test {
         assert_eq!(None, relay_map.get_endpoints(&test_id));
     }
 
+    #[test]
+    fn subnet_limits() {
+        let our_id : Id = Id::new();
+        let mut relay_map = RelayMap::with_subnet_limits(&our_id, SubnetLimits::new(2, 2));
+        let endpoint = |host: u8| {
+            Endpoint::Tcp(SocketAddr::from_str(&format!("1.2.3.{}:5483", host)).unwrap())
+        };
+        let test_public_id = PublicId::new(&Id::new());
+        assert_eq!(true, relay_map.add_client(test_public_id.clone(), endpoint(1)));
+        assert_eq!(true, relay_map.add_client(PublicId::new(&Id::new()), endpoint(2)));
+        // a known client can add further endpoints in the subnet
+        assert_eq!(true, relay_map.add_client(test_public_id.clone(), endpoint(3)));
+        // a new client from the same subnet is refused
+        let refused_public_id = PublicId::new(&Id::new());
+        match relay_map.check_subnet_limits(
+                &Address::Client(refused_public_id.signing_public_key()), &endpoint(4)) {
+            Err(RoutingError::SubnetLimitReached) => {},
+            _ => panic!("Expected SubnetLimitReached"),
+        }
+        assert_eq!(false, relay_map.add_client(refused_public_id.clone(), endpoint(4)));
+        assert_eq!(true, relay_map.add_client(refused_public_id, generate_random_endpoint()));
+    }
+
+    #[test]
+    fn add_node() {
+        let our_id : Id = Id::new();
+        let mut relay_map = RelayMap::new(&our_id);
+        let joining_id = Id::new();
+        let joining_public_id = PublicId::new(&joining_id);
+        let joining_address = Address::Node(joining_public_id.name());
+        let test_endpoint = generate_random_endpoint();
+        assert_eq!(true, relay_map.add_node(joining_public_id.clone(), test_endpoint.clone()));
+        assert_eq!(true, relay_map.contains_relay_for(&joining_address));
+        assert_eq!(false, relay_map.contains_relay_for(
+            &Address::Client(joining_public_id.signing_public_key())));
+        assert_eq!(Some(joining_address.clone()), relay_map.lookup_endpoint(&test_endpoint));
+
+        // a relocated id is not accepted as a joining node
+        let mut relocated_id = Id::new();
+        relocated_id.assign_relocated_name(NameType::new([1u8; 64]));
+        assert_eq!(false, relay_map.add_node(PublicId::new(&relocated_id),
+                                             generate_random_endpoint()));
+    }
+
     #[test]
     fn add_conflicting_endpoints() {
         let our_id : Id = Id::new();
@@ -283,5 +637,129 @@ mod test {
 
     // TODO: add test for drop_endpoint
 
-    // TODO: add tests for unknown_connections
+    #[test]
+    fn rate_limits() {
+        let our_id : Id = Id::new();
+        let mut relay_map = RelayMap::new(&our_id);
+        relay_map.set_rate_limits(RateLimits::new(2, 1000, 1, 100));
+        let client_public_id = PublicId::new(&Id::new());
+        let client_address = Address::Client(client_public_id.signing_public_key());
+        assert_eq!(true, relay_map.add_client(client_public_id.clone(),
+                                              generate_random_endpoint()));
+
+        // a burst of one second's worth of messages is admitted, then refused
+        assert_eq!(true, relay_map.check_rate_limit(&client_address, RequestKind::Get, 10));
+        assert_eq!(true, relay_map.check_rate_limit(&client_address, RequestKind::Get, 10));
+        assert_eq!(false, relay_map.check_rate_limit(&client_address, RequestKind::Get, 10));
+        assert_eq!(1, relay_map.rate_limited_requests());
+
+        // mutations are limited separately, and a full bucket admits an oversized request once
+        assert_eq!(true, relay_map.check_rate_limit(&client_address, RequestKind::Mutation, 500));
+        assert_eq!(false, relay_map.check_rate_limit(&client_address, RequestKind::Mutation, 1));
+        assert_eq!(2, relay_map.rate_limited_requests());
+
+        // addresses we do not relay for are not limited
+        let unknown_address = Address::Client(PublicId::new(&Id::new()).signing_public_key());
+        for _ in 0..5 {
+            assert_eq!(true, relay_map.check_rate_limit(&unknown_address, RequestKind::Get, 10));
+        }
+    }
+
+    #[test]
+    fn zero_rate_limits_refuse() {
+        let our_id : Id = Id::new();
+        let mut relay_map = RelayMap::new(&our_id);
+        relay_map.set_rate_limits(RateLimits::new(0, 1000, 1, 0));
+        let client_public_id = PublicId::new(&Id::new());
+        let client_address = Address::Client(client_public_id.signing_public_key());
+        assert_eq!(true, relay_map.add_client(client_public_id.clone(),
+                                              generate_random_endpoint()));
+
+        // not even the first request of a new contact is admitted, and none later
+        assert_eq!(false, relay_map.check_rate_limit(&client_address, RequestKind::Get, 10));
+        assert_eq!(false, relay_map.check_rate_limit(&client_address, RequestKind::Get, 10));
+        // a zero byte rate refuses as well, even an empty request
+        assert_eq!(false, relay_map.check_rate_limit(&client_address, RequestKind::Mutation, 0));
+        assert_eq!(3, relay_map.rate_limited_requests());
+    }
+
+    // Adds a client and drops its only endpoint again.
+    fn departed_client(relay_map: &mut RelayMap) -> sign::PublicKey {
+        let public_id = PublicId::new(&Id::new());
+        let endpoint = generate_random_endpoint();
+        assert_eq!(true, relay_map.add_client(public_id.clone(), endpoint.clone()));
+        assert_eq!(Some(Address::Client(public_id.signing_public_key())),
+                   relay_map.drop_endpoint(&endpoint));
+        public_id.signing_public_key()
+    }
+
+    #[test]
+    fn pending_messages() {
+        let our_id : Id = Id::new();
+        let mut relay_map = RelayMap::new(&our_id);
+        relay_map.set_pending_message_limits(Duration::seconds(60), 2, 1);
+        let client_key = departed_client(&mut relay_map);
+        let other_client_key = departed_client(&mut relay_map);
+
+        // nothing is held for a client we never relayed for
+        let stranger_key = PublicId::new(&Id::new()).signing_public_key();
+        assert_eq!(false, relay_map.queue_pending_message(stranger_key.clone(), vec![0u8]));
+        assert!(relay_map.take_pending_messages(&stranger_key).is_empty());
+
+        // the oldest message is dropped from a full queue
+        assert_eq!(true, relay_map.queue_pending_message(client_key.clone(), vec![1u8]));
+        assert_eq!(true, relay_map.queue_pending_message(client_key.clone(), vec![2u8]));
+        assert_eq!(true, relay_map.queue_pending_message(client_key.clone(), vec![3u8]));
+        // and messages are held for a bounded number of clients only
+        assert_eq!(false, relay_map.queue_pending_message(other_client_key.clone(), vec![4u8]));
+
+        assert_eq!(vec![vec![2u8], vec![3u8]], relay_map.take_pending_messages(&client_key));
+        assert!(relay_map.take_pending_messages(&client_key).is_empty());
+        assert!(relay_map.take_pending_messages(&other_client_key).is_empty());
+
+        // with a negative window, held messages expire, and the departed clients are forgotten
+        assert_eq!(true, relay_map.queue_pending_message(other_client_key.clone(), vec![4u8]));
+        relay_map.set_pending_message_limits(Duration::seconds(-1), 2, 1);
+        assert_eq!(1, relay_map.expire_pending_messages());
+        assert!(relay_map.take_pending_messages(&other_client_key).is_empty());
+        assert_eq!(false, relay_map.queue_pending_message(client_key.clone(), vec![5u8]));
+        assert!(relay_map.departed_clients.is_empty());
+    }
+
+    #[test]
+    fn unknown_connections() {
+        let our_id : Id = Id::new();
+        let mut relay_map = RelayMap::new(&our_id);
+        relay_map.set_unknown_connection_limits(Duration::seconds(60), 2);
+        let test_endpoint_1 = generate_random_endpoint();
+        let test_endpoint_2 = generate_random_endpoint();
+        assert_eq!(true, relay_map.register_unknown_connection(test_endpoint_1.clone()));
+        assert_eq!(true, relay_map.register_unknown_connection(test_endpoint_2.clone()));
+        assert_eq!(true, relay_map.lookup_unknown_connection(&test_endpoint_1));
+
+        // the cap refuses further connections, until one is identified
+        let mut test_endpoint_3 = generate_random_endpoint();
+        while test_endpoint_3 == test_endpoint_1 || test_endpoint_3 == test_endpoint_2 {
+            test_endpoint_3 = generate_random_endpoint();
+        }
+        assert_eq!(false, relay_map.register_unknown_connection(test_endpoint_3.clone()));
+        assert_eq!(1, relay_map.refused_unknown_connections());
+        assert_eq!(Some(test_endpoint_1.clone()),
+                   relay_map.remove_unknown_connection(&test_endpoint_1));
+        assert_eq!(false, relay_map.lookup_unknown_connection(&test_endpoint_1));
+        assert_eq!(true, relay_map.register_unknown_connection(test_endpoint_3.clone()));
+
+        // nothing expires within the window
+        assert!(relay_map.expire_unknown_connections().is_empty());
+        assert_eq!(0, relay_map.expired_unknown_connections());
+
+        // with a negative window, all remaining unidentified connections expire
+        relay_map.set_unknown_connection_limits(Duration::seconds(-1), 2);
+        let expired = relay_map.expire_unknown_connections();
+        assert_eq!(2, expired.len());
+        assert!(expired.contains(&test_endpoint_2));
+        assert!(expired.contains(&test_endpoint_3));
+        assert_eq!(2, relay_map.expired_unknown_connections());
+        assert_eq!(false, relay_map.lookup_unknown_connection(&test_endpoint_2));
+    }
 }
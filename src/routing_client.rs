@@ -21,21 +21,27 @@ use sodiumoxide;
 use sodiumoxide::crypto::sign;
 use std::sync::{Mutex, Arc, mpsc};
 use std::sync::mpsc::Receiver;
+use std::collections::{BTreeMap, BTreeSet};
+use time::{Duration, SteadyTime};
 
 use client_interface::Interface;
 use crust;
 use messages;
 use name_type::NameType;
 use error::RoutingError;
-use messages::{RoutingMessage, SignedMessage, MessageType, ErrorReturn};
+use messages::{RoutingMessage, SignedMessage, MessageType, ErrorReturn, BatchResponse,
+               BatchItemResult, data_matches_request};
 use types::{MessageId, DestinationAddress, SourceAddress, Address};
 use id::Id;
 use public_id::PublicId;
 use authority::Authority;
 use utils::*;
-use data::{Data, DataRequest};
-use cbor::{CborError};
-use who_are_you::IAm;
+use data::{Data, DataRequest, AppendableData, AppendedEntry};
+use who_are_you::{IAm, Capability, NegotiatedProtocol, KeyChallenge, ProtocolRefusal,
+                  KEY_CHALLENGE_VERSION, supported_capabilities, only_supported};
+use compression;
+use compression::Compressor;
+use wire_format;
 
 pub use crust::Endpoint;
 
@@ -45,6 +51,15 @@ type Event = crust::Event;
 type PortAndProtocol = crust::Port;
 
 static MAX_BOOTSTRAP_CONNECTIONS : usize = 3;
+static RELAY_RESPONSE_TIMEOUT_SECS : i64 = 10;
+
+// a request awaiting its response, see send_to_relays
+struct OutstandingRequest {
+    message  : RoutingMessage,
+    // the relays the request was sent through, the current one last
+    relays   : Vec<Endpoint>,
+    deadline : SteadyTime,
+}
 
 pub struct RoutingClient<F: Interface> {
     interface          : Arc<Mutex<F>>,
@@ -52,7 +67,21 @@ pub struct RoutingClient<F: Interface> {
     connection_manager : ConnectionManager,
     id                 : Id,
     public_id          : PublicId,
-    bootstrap          : Option<(Endpoint, Option<NameType>)>,
+    // the nodes relaying for us, with their name once they identified themselves
+    relays             : Vec<(Endpoint, Option<NameType>)>,
+    // relays which identified themselves, until they challenged us to prove our key
+    challenging_relays : BTreeMap<Endpoint, NameType>,
+    // requests are spread over the relays in turn
+    next_relay         : usize,
+    // responses dropped as their data did not match the requested name
+    mismatched_data_responses : usize,
+    capabilities       : BTreeSet<Capability>,
+    // what we agreed on with each relay that identified itself
+    relay_protocols    : BTreeMap<Endpoint, NegotiatedProtocol>,
+    compressor         : Compressor,
+    // gets which are retried through another relay if their response does not arrive
+    outstanding_requests : BTreeMap<MessageId, OutstandingRequest>,
+    relay_response_timeout : Duration,
     next_message_id    : MessageId
 }
 
@@ -73,21 +102,54 @@ impl<F> RoutingClient<F> where F: Interface {
             connection_manager : crust::ConnectionManager::new(tx),
             public_id          : PublicId::new(&id),
             id                 : id,
-            bootstrap          : None,
+            relays             : Vec::new(),
+            challenging_relays : BTreeMap::new(),
+            next_relay         : 0,
+            mismatched_data_responses : 0,
+            capabilities       : supported_capabilities(),
+            relay_protocols    : BTreeMap::new(),
+            compressor         : Compressor::new(),
+            outstanding_requests : BTreeMap::new(),
+            relay_response_timeout : Duration::seconds(RELAY_RESPONSE_TIMEOUT_SECS),
             next_message_id    : rand::random::<MessageId>()
         }
     }
 
-    fn bootstrap_name(&self) -> Result<NameType, RoutingError> {
-        match self.bootstrap {
-            Some((_, Some(name))) => Ok(name),
-            _                     => Err(RoutingError::NotBootstrapped),
-        }
+    /// Returns the number of responses dropped as their data did not match the requested name.
+    pub fn mismatched_data_responses(&self) -> usize {
+        self.mismatched_data_responses
+    }
+
+    /// Sets the optional protocol features we announce to relays we connect to from now on,
+    /// leaving out those we do not support.
+    pub fn set_capabilities(&mut self, capabilities: BTreeSet<Capability>) {
+        self.capabilities = only_supported(&capabilities);
+    }
+
+    /// Returns the protocol version and capabilities agreed on with the relay on the endpoint.
+    pub fn relay_protocol(&self, endpoint: &Endpoint) -> Option<&NegotiatedProtocol> {
+        self.relay_protocols.get(endpoint)
     }
 
-    fn source_address(&self) -> Result<SourceAddress, RoutingError> {
-        Ok(SourceAddress::RelayedForClient(try!(self.bootstrap_name()),
-                                           self.public_id.signing_public_key()))
+    /// Sets the size from which requests are compressed, to relays that agreed to it.
+    pub fn set_compression_threshold(&mut self, threshold: usize) {
+        self.compressor.set_threshold(threshold);
+    }
+
+    /// Returns the number of bytes compression saved on the requests we sent.
+    pub fn compression_bytes_saved(&self) -> u64 {
+        self.compressor.bytes_saved()
+    }
+
+    /// Sets how long we wait for the response to a request before sending it again through
+    /// another relay.
+    pub fn set_relay_response_timeout(&mut self, timeout: Duration) {
+        self.relay_response_timeout = timeout;
+    }
+
+    /// Returns true if at least one of our relays identified itself, so requests can be sent.
+    pub fn is_bootstrapped(&self) -> bool {
+        self.relays.iter().any(|&(_, ref name)| name.is_some())
     }
 
     fn public_sign_key(&self) -> sign::PublicKey { self.id.signing_public_key() }
@@ -96,87 +158,141 @@ impl<F> RoutingClient<F> where F: Interface {
     pub fn get(&mut self, location: NameType, data : DataRequest) -> Result<(), RoutingError> {
         let message = RoutingMessage {
             destination : DestinationAddress::Direct(location),
-            source      : try!(self.source_address()),
+            // set per relay, see send_to_relays
+            source      : SourceAddress::Direct(self.id.name()),
             orig_message: None,
             message_type: MessageType::GetData(data),
             message_id  : self.get_next_message_id(),
             authority   : Authority::Client(self.id.signing_public_key()),
             };
 
-        match self.send_to_bootstrap_node(&message){
-            Ok(_) => Ok(()),
-            //FIXME(ben) should not expose these errors to user 16/07/2015
-            Err(e) => Err(RoutingError::Cbor(e))
-        }
+        self.send_to_relays(message)
+    }
+
+    /// Retrieve many items held by the same group in one message, each by its name, answered
+    /// with the result of each item in handle_get_batch_response
+    pub fn get_batch(&mut self, location: NameType, data : Vec<(NameType, DataRequest)>)
+            -> Result<(), RoutingError> {
+        let message = RoutingMessage {
+            destination : DestinationAddress::Direct(location),
+            // set per relay, see send_to_relays
+            source      : SourceAddress::Direct(self.id.name()),
+            orig_message: None,
+            message_type: MessageType::GetDataBatch(data),
+            message_id  : self.get_next_message_id(),
+            authority   : Authority::Client(self.id.signing_public_key()),
+        };
+
+        self.send_to_relays(message)
     }
 
     /// Add something to the network, will always go via ClientManager group
     pub fn put(&mut self, location: NameType, data : Data) -> Result<(), RoutingError> {
         let message = RoutingMessage {
             destination : DestinationAddress::Direct(location),
-            source      : try!(self.source_address()),
+            // set per relay, see send_to_relays
+            source      : SourceAddress::Direct(self.id.name()),
             orig_message: None,
             message_type: MessageType::PutData(data),
             message_id  : self.get_next_message_id(),
             authority   : Authority::Client(self.id.signing_public_key()),
         };
 
-        match self.send_to_bootstrap_node(&message){
-            Ok(_) => Ok(()),
-            //FIXME(ben) should not expose these errors to user 16/07/2015
-            Err(e) => Err(RoutingError::Cbor(e))
-        }
+        self.send_to_relays(message)
+    }
+
+    /// Add many items to the network in one message, via the ClientManager group, answered
+    /// with the result of each item in handle_put_batch_response.  All items must be bound for
+    /// the same group: the group is decided by the name of the first item, and every item is
+    /// handled by it
+    pub fn put_batch(&mut self, location: NameType, data : Vec<Data>)
+            -> Result<(), RoutingError> {
+        let message = RoutingMessage {
+            destination : DestinationAddress::Direct(location),
+            // set per relay, see send_to_relays
+            source      : SourceAddress::Direct(self.id.name()),
+            orig_message: None,
+            message_type: MessageType::PutDataBatch(data),
+            message_id  : self.get_next_message_id(),
+            authority   : Authority::Client(self.id.signing_public_key()),
+        };
+
+        self.send_to_relays(message)
     }
 
     /// Mutate something one the network (you must own it and provide a proper update)
     pub fn post(&mut self, location: NameType, data : Data) -> Result<(), RoutingError> {
         let message = RoutingMessage {
             destination : DestinationAddress::Direct(location),
-            source      : try!(self.source_address()),
+            // set per relay, see send_to_relays
+            source      : SourceAddress::Direct(self.id.name()),
             orig_message: None,
             message_type: MessageType::Post(data),
             message_id  : self.get_next_message_id(),
             authority   : Authority::Client(self.id.signing_public_key()),
         };
 
-        match self.send_to_bootstrap_node(&message){
-            Ok(_) => Ok(()),
-            //FIXME(ben) should not expose these errors to user 16/07/2015
-            Err(e) => Err(RoutingError::Cbor(e))
-        }
+        self.send_to_relays(message)
+    }
+
+    /// Append an entry, signed by us, to an AppendableData we are authorised to append to
+    pub fn append(&mut self, appendable_data: &AppendableData, data: Vec<u8>)
+            -> Result<(), RoutingError> {
+        let name = appendable_data.name();
+        let entry = try!(AppendedEntry::new(&name, appendable_data.get_version(), data,
+                                            self.id.signing_public_key(),
+                                            self.id.signing_private_key()));
+        self.post(name, Data::AppendableData(appendable_data.to_append(vec![entry])))
     }
 
     /// Mutate something one the network (you must own it and provide a proper update)
     pub fn delete(&mut self, location: NameType, data : DataRequest) -> Result<(), RoutingError> {
         let message = RoutingMessage {
             destination : DestinationAddress::Direct(location),
-            source      : try!(self.source_address()),
+            // set per relay, see send_to_relays
+            source      : SourceAddress::Direct(self.id.name()),
             orig_message: None,
             message_type: MessageType::DeleteData(data),
             message_id  : self.get_next_message_id(),
             authority   : Authority::Client(self.id.signing_public_key()),
         };
 
-        match self.send_to_bootstrap_node(&message){
-            Ok(_) => Ok(()),
-            //FIXME(ben) should not expose these errors to user 16/07/2015
-            Err(e) => Err(RoutingError::Cbor(e))
-        }
+        self.send_to_relays(message)
     }
 
     pub fn poll_one(&mut self) {
+        self.retry_outstanding_requests();
         match self.event_input.try_recv() {
             Err(_) => (),
             Ok(crust::connection_manager::Event::NewMessage(endpoint, bytes)) => {
-                match decode::<IAm>(&bytes) {
+                let bytes = match compression::decompress(bytes) {
+                    Ok(bytes) => bytes,
+                    Err(_) => return,
+                };
+                match wire_format::decode::<IAm>(&bytes) {
                     Ok(msg) => {
-                        // Ignore, should have been handled while bootstrapping.
+                        // a relay connected after bootstrapping identifies itself
+                        self.handle_i_am(endpoint, msg);
+                        return;
+                    },
+                    Err(_)  => {;}
+                }
+                match wire_format::decode::<KeyChallenge>(&bytes) {
+                    Ok(challenge) => {
+                        self.handle_key_challenge(endpoint, challenge);
+                        return;
+                    },
+                    Err(_)  => {;}
+                }
+                match wire_format::decode::<ProtocolRefusal>(&bytes) {
+                    Ok(refusal) => {
+                        let _ = self.handle_protocol_refusal(endpoint, refusal);
                         return;
                     },
                     Err(_)  => {;}
                 }
 
-                let signed_msg = match decode::<SignedMessage>(&bytes) {
+                let signed_msg = match wire_format::decode::<SignedMessage>(&bytes) {
                     Ok(msg) => msg,
                     Err(_) => { debug_assert!(false); return }
                 };
@@ -188,26 +304,47 @@ impl<F> RoutingClient<F> where F: Interface {
 
                 info!("received a {:?} from {:?}", routing_msg.message_type, endpoint);
 
-                match self.bootstrap {
-                    Some((ref bootstrap_endpoint, _)) => {
-                        // only accept messages from our bootstrap endpoint
-                        if bootstrap_endpoint == &endpoint {
-                            match routing_msg.message_type {
-                                MessageType::GetDataResponse(result) => {
-                                    self.handle_get_data_response(result);
-                                },
-                                MessageType::PutDataResponse(put_response, _) => {
-                                    self.handle_put_data_response(put_response);
-                                },
-                                _ => {}
-                            }
-                        }
+                // only accept messages from our relays; responses can come back through any
+                // of them
+                let relay = match self.relays.iter()
+                        .find(|&&(ref relay_endpoint, _)| relay_endpoint == &endpoint)
+                        .and_then(|&(_, ref name)| name.clone()) {
+                    Some(relay) => relay,
+                    None => {
+                        info!("Received message but not from one of our relays");
+                        return;
+                    },
+                };
+                // the source is not checked against a signature here, so a misbehaving source is
+                // reported as the relay the message arrived through, whose key we challenged
+                match routing_msg.message_type {
+                    MessageType::GetDataResponse(result) => {
+                        self.handle_get_data_response(result, relay);
+                    },
+                    MessageType::GetDataErrorResponse(get_error) => {
+                        self.handle_get_data_error_response(get_error);
+                    },
+                    MessageType::PutDataResponse(put_response, _) => {
+                        self.handle_put_data_response(put_response);
+                    },
+                    MessageType::PostResponse(post_response, _) => {
+                        self.handle_post_response(post_response);
                     },
-                    _ => { info!("Received message but not fully bootstrapped"); }
+                    MessageType::BatchResponse(batch_response) => {
+                        self.handle_batch_response(batch_response, relay);
+                    },
+                    _ => {}
                 }
             },
-            _ => { // as a client, shall not handle any connection related change
-                   // TODO : try to re-bootstrap when lost the connection to the bootstrap node ?
+            Ok(crust::Event::NewBootstrapConnection(endpoint)) => {
+                // keep adding relays while crust finds them
+                ignore(self.add_relay(endpoint));
+            },
+            Ok(crust::Event::LostConnection(endpoint)) => {
+                self.drop_relay(&endpoint);
+            },
+            _ => { // as a client, shall not handle any other connection related change
+                   // TODO : try to re-bootstrap when lost the connection to all relays ?
             }
         };
     }
@@ -218,91 +355,270 @@ impl<F> RoutingClient<F> where F: Interface {
         try!(self.connection_manager.start_accepting(vec![]));
         self.connection_manager.bootstrap(MAX_BOOTSTRAP_CONNECTIONS);
 
+        // return as soon as one relay identified itself; further relays are added in poll_one
         loop {
             match self.event_input.recv() {
                 Err(_) => return Err(RoutingError::FailedToBootstrap),
                 Ok(crust::Event::NewBootstrapConnection(endpoint)) => {
-                    self.bootstrap = Some((endpoint.clone(), None));
-
-                    let i_am_msg = IAm {
-                        address   : Address::Client(self.public_id.signing_public_key()),
-                        public_id : self.public_id.clone(),
-                    };
-
-                    try!(self.connection_manager.send(endpoint, try!(encode(&i_am_msg))));
+                    try!(self.add_relay(endpoint));
                 },
                 Ok(crust::Event::NewMessage(endpoint, bytes)) => {
-                    if let Ok(msg) = decode::<IAm>(&bytes) {
+                    if let Ok(msg) = wire_format::decode::<IAm>(&bytes) {
                         self.handle_i_am(endpoint, msg);
+                    } else if let Ok(challenge) = wire_format::decode::<KeyChallenge>(&bytes) {
+                        self.handle_key_challenge(endpoint, challenge);
+                    } else if let Ok(refusal) = wire_format::decode::<ProtocolRefusal>(&bytes) {
+                        // give up once every relay refused us
+                        let error = self.handle_protocol_refusal(endpoint, refusal);
+                        if self.relays.is_empty() {
+                            return Err(error);
+                        }
+                    }
+                    if self.is_bootstrapped() {
                         return Ok(());
                     }
                 },
+                Ok(crust::Event::LostConnection(endpoint)) => {
+                    self.drop_relay(&endpoint);
+                },
                 _ => {}
             }
         }
+    }
 
-        Ok(())
+    fn add_relay(&mut self, endpoint: Endpoint) -> Result<(), RoutingError> {
+        if self.relays.len() >= MAX_BOOTSTRAP_CONNECTIONS
+            || self.relays.iter().any(|&(ref relay_endpoint, _)| relay_endpoint == &endpoint) {
+            self.connection_manager.drop_node(endpoint);
+            return Ok(());
+        }
+        self.relays.push((endpoint.clone(), None));
+
+        let i_am_msg = IAm::new(Address::Client(self.public_id.signing_public_key()),
+                                self.public_id.clone(), self.capabilities.clone());
+
+        match self.connection_manager.send(endpoint.clone(), try!(wire_format::encode(&i_am_msg))) {
+            Ok(()) => Ok(()),
+            Err(error) => {
+                self.drop_relay(&endpoint);
+                Err(RoutingError::Io(error))
+            },
+        }
     }
 
-    fn handle_i_am(&mut self, _endpoint: Endpoint, message: IAm) {
+    /// Drops the relay; the requests awaiting their response through it are sent through
+    /// another relay on the next poll.
+    fn drop_relay(&mut self, endpoint: &Endpoint) {
+        self.relays.retain(|&(ref relay_endpoint, _)| relay_endpoint != endpoint);
+        let _ = self.relay_protocols.remove(endpoint);
+        let _ = self.challenging_relays.remove(endpoint);
+        let time_now = SteadyTime::now();
+        for (_, request) in self.outstanding_requests.iter_mut() {
+            if request.relays.last() == Some(endpoint) {
+                request.deadline = time_now;
+            }
+        }
+    }
+
+    fn handle_i_am(&mut self, endpoint: Endpoint, message: IAm) {
         let node_name = match message.address {
             Address::Node(n) => n,
             // We don't care about clients.
             Address::Client(_) => {
-                // TODO: Remove from self.bootstrap if endpoint (arg)
-                // is the bootstrap endpoint (self.bootstrap.0).
+                self.drop_relay(&endpoint);
+                self.connection_manager.drop_node(endpoint);
                 return;
             }
         };
-
-        self.bootstrap = match self.bootstrap {
-            // TODO: Endpoints are currently non comparable(?)
-            Some((ref ep, None)) /* if ep == endpoint */ => {
-                Some((ep.clone(), Some(node_name)))
+        match message.negotiate(&self.capabilities) {
+            // the relay only relays for us once we answered its challenge
+            Ok(protocol) if protocol.version >= KEY_CHALLENGE_VERSION => {
+                let _ = self.relay_protocols.insert(endpoint.clone(), protocol);
+                let _ = self.challenging_relays.insert(endpoint, node_name);
+            },
+            // a relay speaking a version without challenges is refused, as it would relay for
+            // anyone claiming our key
+            Ok(_) => {
+                info!("Dropping relay {:?}, it speaks protocol versions {:?} to {:?} only.",
+                    endpoint, message.min_version, message.max_version);
+                match wire_format::encode(&ProtocolRefusal::for_relaying()) {
+                    Ok(refusal) => ignore(self.connection_manager.send(endpoint.clone(), refusal)),
+                    Err(_) => {},
+                }
+                self.drop_relay(&endpoint);
+                self.connection_manager.drop_node(endpoint);
+            },
+            Err(error) => {
+                info!("Dropping relay {:?}: {}", endpoint, error);
+                // tell the relay why, before dropping it
+                match wire_format::encode(&ProtocolRefusal::new()) {
+                    Ok(refusal) => ignore(self.connection_manager.send(endpoint.clone(), refusal)),
+                    Err(_) => {},
+                }
+                self.drop_relay(&endpoint);
+                self.connection_manager.drop_node(endpoint);
             },
-            _ => self.bootstrap.clone()
         }
     }
 
-    fn send_to_bootstrap_node(&mut self, message: &RoutingMessage)
-            -> Result<(), CborError> {
+    /// Drops the relay which speaks none of our protocol versions, returning the error naming
+    /// the versions it speaks.
+    fn handle_protocol_refusal(&mut self, endpoint: Endpoint, refusal: ProtocolRefusal)
+            -> RoutingError {
+        let error = refusal.to_error();
+        info!("Relay {:?} refused us: {}", endpoint, error);
+        self.drop_relay(&endpoint);
+        self.connection_manager.drop_node(endpoint);
+        error
+    }
+
+    /// Proves to the relay that we hold the key we identified with.
+    fn handle_key_challenge(&mut self, endpoint: Endpoint, challenge: KeyChallenge) {
+        let relay_name = match self.challenging_relays.remove(&endpoint) {
+            Some(relay_name) => relay_name,
+            None => return,
+        };
+        let response = challenge.answer(&relay_name, self.id.signing_private_key());
+        match wire_format::encode(&response) {
+            Ok(bytes) => ignore(self.connection_manager.send(endpoint.clone(), bytes)),
+            Err(_) => return,
+        }
+        self.mark_relay_identified(endpoint, relay_name);
+    }
 
-        match self.bootstrap {
-            Some((ref bootstrap_endpoint, _)) => {
-                let priv_key        = self.id.signing_private_key();
-                let signed_message  = try!(SignedMessage::new(message, priv_key));
-                let encoded_message = try!(encode(&signed_message));
+    fn mark_relay_identified(&mut self, endpoint: Endpoint, relay_name: NameType) {
+        for relay in self.relays.iter_mut() {
+            if relay.0 == endpoint && relay.1.is_none() {
+                relay.1 = Some(relay_name.clone());
+            }
+        }
+    }
 
-                let _ = self.connection_manager.send(bootstrap_endpoint.clone(),
-                                                     encoded_message);
-            },
-            None => {}
+    /// Sends the request through one of our relays, taking turns to spread the load.  If sending
+    /// fails, the relay is dropped and the next one is tried.  Gets are kept until their response
+    /// arrives; after the relay response timeout they are sent again through a relay not tried
+    /// yet, until all were tried.  Puts are not retried: a copy signed for another relay has
+    /// another source, so the network would take it for a new request and store it twice.
+    fn send_to_relays(&mut self, message: RoutingMessage) -> Result<(), RoutingError> {
+        let relay_endpoint = try!(self.send_through_relay(message.clone(), &[]));
+        let idempotent = match message.message_type {
+            MessageType::GetData(_) |
+            MessageType::GetDataBatch(_) => true,
+            _ => false,
         };
+        if idempotent {
+            let _ = self.outstanding_requests.insert(message.message_id, OutstandingRequest {
+                message  : message,
+                relays   : vec![relay_endpoint],
+                deadline : SteadyTime::now() + self.relay_response_timeout,
+            });
+        }
         Ok(())
     }
 
+    /// Sends the requests whose response did not arrive in time again, through a relay they
+    /// were not sent through yet.  Requests tried through all our relays are given up.
+    fn retry_outstanding_requests(&mut self) {
+        let time_now = SteadyTime::now();
+        let timed_out = self.outstanding_requests.iter()
+            .filter(|&(_, request)| request.deadline <= time_now)
+            .map(|(message_id, _)| message_id.clone())
+            .collect::<Vec<MessageId>>();
+        for message_id in timed_out {
+            let mut request = match self.outstanding_requests.remove(&message_id) {
+                Some(request) => request,
+                None => continue,
+            };
+            match self.send_through_relay(request.message.clone(), &request.relays) {
+                Ok(relay_endpoint) => {
+                    request.relays.push(relay_endpoint);
+                    request.deadline = SteadyTime::now() + self.relay_response_timeout;
+                    let _ = self.outstanding_requests.insert(message_id, request);
+                },
+                Err(_) => info!("Giving up on request {:?}, no response through {:?} relays.",
+                                message_id, request.relays.len()),
+            }
+        }
+    }
+
+    /// Signs the request for one of our relays not excluded and sends it, returning the relay's
+    /// endpoint.  The request is signed for each relay, as the source address names the relay
+    /// to route the response back through.
+    fn send_through_relay(&mut self, mut message: RoutingMessage, excluded: &[Endpoint])
+            -> Result<Endpoint, RoutingError> {
+        loop {
+            let identified_relays = self.relays.iter()
+                .filter(|&&(ref endpoint, _)| !excluded.contains(endpoint))
+                .filter_map(|&(ref endpoint, ref name)| match *name {
+                    Some(name) => Some((endpoint.clone(), name)),
+                    None => None,
+                })
+                .collect::<Vec<(Endpoint, NameType)>>();
+            if identified_relays.is_empty() {
+                return Err(RoutingError::NotBootstrapped);
+            }
+            let (relay_endpoint, relay_name) =
+                identified_relays[self.next_relay % identified_relays.len()].clone();
+            self.next_relay = self.next_relay.wrapping_add(1);
+
+            message.source = SourceAddress::RelayedForClient(relay_name,
+                                                             self.public_id.signing_public_key());
+            let priv_key        = self.id.signing_private_key();
+            let signed_message  = try!(SignedMessage::new(&message, priv_key));
+            let mut encoded_message = try!(wire_format::encode(&signed_message));
+            match self.relay_protocols.get(&relay_endpoint) {
+                Some(protocol) if protocol.supports(Capability::Compression) =>
+                    encoded_message = self.compressor.compress(&encoded_message),
+                _ => {},
+            }
+
+            match self.connection_manager.send(relay_endpoint.clone(), encoded_message) {
+                Ok(()) => return Ok(relay_endpoint),
+                Err(_) => {
+                    info!("Failed to send to relay {:?}, trying the next one", relay_endpoint);
+                    self.drop_relay(&relay_endpoint);
+                    self.connection_manager.drop_node(relay_endpoint);
+                },
+            }
+        }
+    }
+
     fn get_next_message_id(&mut self) -> MessageId {
         self.next_message_id = self.next_message_id.wrapping_add(1);
         self.next_message_id
     }
 
-    fn handle_get_data_response(&self, response: messages::GetDataResponse) {
+    fn handle_get_data_response(&mut self, response: messages::GetDataResponse,
+                                relay: NameType) {
         if !response.verify_request_came_from(&self.public_sign_key()) {
             return;
         }
 
+        if !response.verify_data_matches_request() {
+            self.mismatched_data_responses += 1;
+            info!("Dropping GetDataResponse through {:?}, data does not match the requested name \
+                ({:?} dropped so far).", relay, self.mismatched_data_responses);
+            self.interface.lock().unwrap().handle_misbehaving_source(relay);
+            return;
+        }
+
         let orig_request = match response.orig_request.get_routing_message() {
             Ok(l) => l,
             Err(_) => return
         };
 
-        let location = orig_request.non_relayed_destination();
+        // the data answers a GetData of ours, or an item of a GetDataBatch forwarded on
+        let location = match response.requested_item() {
+            Some((location, _)) => location,
+            None => return,
+        };
+        let _ = self.outstanding_requests.remove(&orig_request.message_id);
 
         let mut interface = self.interface.lock().unwrap();
         interface.handle_get_response(location, response.data);
     }
 
-    fn handle_put_data_response(&self, signed_error: ErrorReturn) {
+    fn handle_get_data_error_response(&mut self, signed_error: ErrorReturn) {
         if !signed_error.verify_request_came_from(&self.public_sign_key()) {
             return;
         }
@@ -312,15 +628,470 @@ impl<F> RoutingClient<F> where F: Interface {
             Err(_) => return
         };
 
-        // The request must have been a PUT message.
-        let orig_put_data = match orig_request.message_type {
-            MessageType::PutData(data) => data,
-            _                          => return
+        // The request must have been a GET message, or an item of a GET batch forwarded on.
+        let (location, data_request) = match signed_error.requested_get_item() {
+            Some(item) => item,
+            None       => return
+        };
+        let _ = self.outstanding_requests.remove(&orig_request.message_id);
+
+        let mut interface = self.interface.lock().unwrap();
+        interface.handle_get_error_response(location, data_request, signed_error.error);
+    }
+
+    fn handle_put_data_response(&self, signed_error: ErrorReturn) {
+        if !signed_error.verify_request_came_from(&self.public_sign_key()) {
+            return;
+        }
+
+        // The request must have been a PUT message, or an item of a PUT batch forwarded on.
+        let orig_put_data = match signed_error.requested_put_item() {
+            Some(data) => data,
+            None       => return
         };
 
         let mut interface = self.interface.lock().unwrap();
         interface.handle_put_response(signed_error.error, orig_put_data);
     }
+
+    fn handle_post_response(&self, signed_error: ErrorReturn) {
+        if !signed_error.verify_request_came_from(&self.public_sign_key()) {
+            return;
+        }
+
+        let orig_request = match signed_error.orig_request.get_routing_message() {
+            Ok(l)  => l,
+            Err(_) => return
+        };
+
+        // The request must have been a POST message.
+        let orig_post_data = match orig_request.message_type {
+            MessageType::Post(data) => data,
+            _                       => return
+        };
+
+        let mut interface = self.interface.lock().unwrap();
+        interface.handle_post_response(signed_error.error, orig_post_data);
+    }
+
+    /// Hands the result of each item of a batch to the interface.  A batch with Data which does
+    /// not match the name it was requested under is dropped, as a GetDataResponse would be.
+    fn handle_batch_response(&mut self, response: BatchResponse, relay: NameType) {
+        if !response.verify_request_came_from(&self.public_sign_key()) {
+            return;
+        }
+
+        let orig_request = match response.orig_request.get_routing_message() {
+            Ok(l)  => l,
+            Err(_) => return
+        };
+
+        // each item needs exactly one result
+        match orig_request.message_type {
+            MessageType::GetDataBatch(ref items) if items.len() == response.results.len() => {
+                let mismatched = items.iter().zip(response.results.iter())
+                    .any(|(&(ref name, ref data_request), result)| match *result {
+                        BatchItemResult::Data(ref data) =>
+                            !data_matches_request(data, name, data_request),
+                        _ => false,
+                    });
+                if mismatched {
+                    self.mismatched_data_responses += 1;
+                    info!("Dropping BatchResponse through {:?}, data does not match the requested \
+                        name ({:?} dropped so far).", relay, self.mismatched_data_responses);
+                    self.interface.lock().unwrap().handle_misbehaving_source(relay);
+                    return;
+                }
+                let _ = self.outstanding_requests.remove(&orig_request.message_id);
+                let mut interface = self.interface.lock().unwrap();
+                interface.handle_get_batch_response(items.iter().cloned()
+                    .zip(response.results.into_iter())
+                    .map(|((name, data_request), result)| (name, data_request, result))
+                    .collect());
+            },
+            MessageType::PutDataBatch(ref data) if data.len() == response.results.len() => {
+                let _ = self.outstanding_requests.remove(&orig_request.message_id);
+                let mut interface = self.interface.lock().unwrap();
+                interface.handle_put_batch_response(
+                    data.iter().cloned().zip(response.results.into_iter()).collect());
+            },
+            _ => {}
+        }
+    }
+}
+
+fn ignore<R,E>(_: Result<R,E>) {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::{OutstandingRequest, MAX_BOOTSTRAP_CONNECTIONS};
+    use std::sync::{Arc, Mutex};
+    use time::{Duration, SteadyTime};
+    use client_interface::Interface;
+    use data::{Data, DataRequest};
+    use error::{ResponseError, RoutingError};
+    use id::Id;
+    use immutable_data::{ImmutableData, ImmutableDataType};
+    use messages::{BatchItemResult, BatchResponse, ErrorReturn, GetDataResponse, RoutingMessage,
+                   MessageType, SignedMessage};
+    use plain_data::PlainData;
+    use std::collections::BTreeMap;
+    use public_id::PublicId;
+    use authority::Authority;
+    use test_utils::{Random, test};
+    use types::{Address, MessageId, DestinationAddress, SourceAddress};
+    use who_are_you::{IAm, KeyChallenge, ProtocolRefusal, KEY_CHALLENGE_VERSION,
+                      PROTOCOL_VERSION, supported_capabilities};
+    use NameType;
+
+    // Records the responses handed to the interface.
+    struct TestInterface {
+        get_responses       : Vec<(NameType, Data)>,
+        get_batch_responses : Vec<(NameType, DataRequest, BatchItemResult)>,
+        put_responses       : Vec<(ResponseError, Data)>,
+        misbehaving_sources : Vec<NameType>,
+    }
+
+    impl TestInterface {
+        fn new() -> TestInterface {
+            TestInterface {
+                get_responses       : Vec::new(),
+                get_batch_responses : Vec::new(),
+                put_responses       : Vec::new(),
+                misbehaving_sources : Vec::new(),
+            }
+        }
+    }
+
+    impl Interface for TestInterface {
+        fn handle_get_response(&mut self, data_location: NameType, data: Data) {
+            self.get_responses.push((data_location, data));
+        }
+        fn handle_get_error_response(&mut self, _data_location: NameType,
+                                     _data_request: DataRequest,
+                                     _response_error: ResponseError) {}
+        fn handle_get_batch_response(&mut self,
+                                     results: Vec<(NameType, DataRequest, BatchItemResult)>) {
+            self.get_batch_responses.extend(results.into_iter());
+        }
+        fn handle_put_response(&mut self, response_error: ResponseError, request_data: Data) {
+            self.put_responses.push((response_error, request_data));
+        }
+        fn handle_put_batch_response(&mut self, _results: Vec<(Data, BatchItemResult)>) {}
+        fn handle_post_response(&mut self, _response_error: ResponseError, _request_data: Data) {}
+        fn handle_delete_response(&mut self, _response_error: ResponseError,
+                                  _request_data: Data) {}
+        fn handle_misbehaving_source(&mut self, source: NameType) {
+            self.misbehaving_sources.push(source);
+        }
+    }
+
+    // A client with the given number of relays which identified themselves.  Nothing listens on
+    // their endpoints, so every send to them fails.
+    fn client_with_relays(count: usize) -> RoutingClient<TestInterface> {
+        let mut client = RoutingClient::new(Arc::new(Mutex::new(TestInterface::new())),
+                                            Id::new());
+        for _ in 0..count {
+            let endpoint = test::random_endpoint();
+            client.relays.push((endpoint.clone(), None));
+            let relay_id = Id::new();
+            client.handle_i_am(endpoint.clone(), IAm::new(Address::Node(relay_id.name()),
+                                                          PublicId::new(&relay_id),
+                                                          supported_capabilities()));
+            client.handle_key_challenge(endpoint, KeyChallenge::new());
+        }
+        client
+    }
+
+    fn outstanding_get(client: &mut RoutingClient<TestInterface>, relays: Vec<Endpoint>)
+            -> MessageId {
+        let message_id = client.get_next_message_id();
+        let message = RoutingMessage {
+            destination : DestinationAddress::Direct(Random::generate_random()),
+            source      : SourceAddress::Direct(client.id.name()),
+            orig_message: None,
+            message_type: MessageType::GetData(DataRequest::PlainData),
+            message_id  : message_id,
+            authority   : Authority::Client(client.id.signing_public_key()),
+        };
+        let _ = client.outstanding_requests.insert(message_id, OutstandingRequest {
+            message  : message,
+            relays   : relays,
+            deadline : SteadyTime::now() + Duration::minutes(1),
+        });
+        message_id
+    }
+
+    #[test]
+    fn bootstrap_on_multiple_relays() {
+        let mut client = client_with_relays(MAX_BOOTSTRAP_CONNECTIONS);
+        assert!(client.is_bootstrapped());
+        assert!(client.relays.iter().all(|&(_, ref name)| name.is_some()));
+        assert_eq!(client.relay_protocols.len(), MAX_BOOTSTRAP_CONNECTIONS);
+
+        // no relays are added beyond the limit
+        assert!(client.add_relay(test::random_endpoint()).is_ok());
+        assert_eq!(client.relays.len(), MAX_BOOTSTRAP_CONNECTIONS);
+
+        // a client claiming to be a relay is dropped
+        let mut client = client_with_relays(1);
+        let endpoint = test::random_endpoint();
+        client.relays.push((endpoint.clone(), None));
+        let other_id = Id::new();
+        client.handle_i_am(endpoint, IAm::new(Address::Client(other_id.signing_public_key()),
+                                              PublicId::new(&other_id),
+                                              supported_capabilities()));
+        assert_eq!(client.relays.len(), 1);
+    }
+
+    #[test]
+    fn failover_to_next_relay() {
+        let mut client = client_with_relays(3);
+        // every relay is tried and dropped when sending to it fails
+        match client.get(Random::generate_random(), DataRequest::PlainData) {
+            Err(RoutingError::NotBootstrapped) => {},
+            _ => panic!("Expected NotBootstrapped"),
+        }
+        assert!(client.relays.is_empty());
+        assert!(!client.is_bootstrapped());
+        assert!(client.outstanding_requests.is_empty());
+    }
+
+    #[test]
+    fn retry_through_relays_not_tried() {
+        let mut client = client_with_relays(2);
+        let first_relay = client.relays[0].0.clone();
+        let message_id = outstanding_get(&mut client, vec![first_relay.clone()]);
+
+        // not retried before its response timed out
+        client.retry_outstanding_requests();
+        assert!(client.outstanding_requests.contains_key(&message_id));
+        assert_eq!(client.relays.len(), 2);
+
+        // the retry goes through the other relay only, and is given up when it fails
+        client.set_relay_response_timeout(Duration::seconds(-1));
+        client.outstanding_requests.get_mut(&message_id).unwrap().deadline = SteadyTime::now();
+        client.retry_outstanding_requests();
+        assert!(!client.outstanding_requests.contains_key(&message_id));
+        assert_eq!(client.relays.len(), 1);
+        assert_eq!(client.relays[0].0, first_relay);
+    }
+
+    #[test]
+    fn drop_relay() {
+        let mut client = client_with_relays(2);
+        let first_relay = client.relays[0].0.clone();
+        let second_relay = client.relays[1].0.clone();
+        let on_first = outstanding_get(&mut client, vec![first_relay.clone()]);
+        let on_second = outstanding_get(&mut client, vec![first_relay.clone(),
+                                                          second_relay.clone()]);
+
+        client.drop_relay(&first_relay);
+        assert_eq!(client.relays.len(), 1);
+        assert!(client.relay_protocol(&first_relay).is_none());
+        assert!(client.relay_protocol(&second_relay).is_some());
+        assert!(client.is_bootstrapped());
+
+        // only the requests awaiting their response through the dropped relay are due
+        let time_now = SteadyTime::now();
+        assert!(client.outstanding_requests[&on_first].deadline <= time_now);
+        assert!(client.outstanding_requests[&on_second].deadline > time_now);
+    }
+
+    #[test]
+    fn incompatible_relays() {
+        let mut client = client_with_relays(2);
+        let refusing_relay = client.relays[0].0.clone();
+
+        // a relay refusing our protocol versions is dropped
+        let refusal = ProtocolRefusal { min_version: PROTOCOL_VERSION + 1,
+                                        max_version: PROTOCOL_VERSION + 1 };
+        match client.handle_protocol_refusal(refusing_relay.clone(), refusal) {
+            RoutingError::IncompatibleProtocol(min_version, _) =>
+                assert_eq!(min_version, PROTOCOL_VERSION + 1),
+            _ => panic!("Expected IncompatibleProtocol"),
+        }
+        assert_eq!(client.relays.len(), 1);
+        assert!(client.relay_protocol(&refusing_relay).is_none());
+
+        // as is a relay speaking none of our versions, once we told it so
+        let endpoint = test::random_endpoint();
+        client.relays.push((endpoint.clone(), None));
+        let relay_id = Id::new();
+        let mut i_am = IAm::new(Address::Node(relay_id.name()), PublicId::new(&relay_id),
+                                supported_capabilities());
+        i_am.min_version = PROTOCOL_VERSION + 1;
+        i_am.max_version = PROTOCOL_VERSION + 1;
+        client.handle_i_am(endpoint.clone(), i_am);
+        assert_eq!(client.relays.len(), 1);
+        assert!(client.relay_protocol(&endpoint).is_none());
+        assert!(client.is_bootstrapped());
+    }
+
+    #[test]
+    fn answer_key_challenge() {
+        let mut client = RoutingClient::new(Arc::new(Mutex::new(TestInterface::new())),
+                                            Id::new());
+        let endpoint = test::random_endpoint();
+        client.relays.push((endpoint.clone(), None));
+        let relay_id = Id::new();
+        client.handle_i_am(endpoint.clone(), IAm::new(Address::Node(relay_id.name()),
+                                                      PublicId::new(&relay_id),
+                                                      supported_capabilities()));
+        // requests are only sent once we answered the relay's challenge
+        assert!(!client.is_bootstrapped());
+        assert_eq!(client.challenging_relays.get(&endpoint), Some(&relay_id.name()));
+
+        // a challenge from a relay not awaiting our answer is ignored
+        let other_endpoint = test::random_endpoint();
+        client.relays.push((other_endpoint.clone(), None));
+        client.handle_key_challenge(other_endpoint.clone(), KeyChallenge::new());
+        assert!(!client.is_bootstrapped());
+
+        client.handle_key_challenge(endpoint, KeyChallenge::new());
+        assert!(client.is_bootstrapped());
+        assert!(client.challenging_relays.is_empty());
+
+        // a relay speaking the protocol from before key challenges is refused
+        let old_endpoint = test::random_endpoint();
+        client.relays.push((old_endpoint.clone(), None));
+        let mut old_i_am = IAm::new(Address::Node(relay_id.name()), PublicId::new(&relay_id),
+                                    supported_capabilities());
+        old_i_am.max_version = KEY_CHALLENGE_VERSION - 1;
+        client.handle_i_am(old_endpoint.clone(), old_i_am);
+        assert!(client.challenging_relays.is_empty());
+        assert!(client.relay_protocol(&old_endpoint).is_none());
+        assert!(!client.relays.iter().any(|&(ref endpoint, _)| endpoint == &old_endpoint));
+    }
+
+    fn signed_request(client: &RoutingClient<TestInterface>, message_type: MessageType)
+            -> SignedMessage {
+        let message = RoutingMessage {
+            destination : DestinationAddress::Direct(Random::generate_random()),
+            source      : SourceAddress::Direct(client.id.name()),
+            orig_message: None,
+            message_type: message_type,
+            message_id  : 1,
+            authority   : Authority::Client(client.id.signing_public_key()),
+        };
+        SignedMessage::new(&message, client.id.signing_private_key()).unwrap()
+    }
+
+    // The message a group sends when forwarding on an item of the request.
+    fn forwarded_item(request: &SignedMessage, item: MessageType) -> SignedMessage {
+        let forwarder = Id::new();
+        let message = RoutingMessage {
+            destination : DestinationAddress::Direct(Random::generate_random()),
+            source      : SourceAddress::Direct(forwarder.name()),
+            orig_message: Some(request.clone()),
+            message_type: item,
+            message_id  : 1,
+            authority   : Authority::ClientManager(Random::generate_random()),
+        };
+        SignedMessage::new(&message, forwarder.signing_private_key()).unwrap()
+    }
+
+    #[test]
+    fn batch_results_mapped_to_items() {
+        let mut client = client_with_relays(1);
+        let immutable_data = Data::ImmutableData(
+            ImmutableData::new(ImmutableDataType::Normal, vec![1u8, 2, 3]));
+        let other_name: NameType = Random::generate_random();
+        let batch = signed_request(&client, MessageType::GetDataBatch(vec![
+            (immutable_data.name(), DataRequest::ImmutableData(ImmutableDataType::Normal)),
+            (other_name.clone(), DataRequest::PlainData)]));
+        client.handle_batch_response(BatchResponse {
+            results        : vec![BatchItemResult::Data(immutable_data.clone()),
+                                  BatchItemResult::Failed(ResponseError::NoData)],
+            orig_request   : batch,
+            group_pub_keys : BTreeMap::new(),
+        }, Random::generate_random());
+        assert_eq!(client.interface.lock().unwrap().get_batch_responses, vec![
+            (immutable_data.name(), DataRequest::ImmutableData(ImmutableDataType::Normal),
+             BatchItemResult::Data(immutable_data.clone())),
+            (other_name.clone(), DataRequest::PlainData,
+             BatchItemResult::Failed(ResponseError::NoData))]);
+
+        // a batch with data not matching the name of its item is dropped, and its source reported
+        let batch = signed_request(&client, MessageType::GetDataBatch(vec![
+            (other_name.clone(), DataRequest::ImmutableData(ImmutableDataType::Normal))]));
+        let source: NameType = Random::generate_random();
+        client.handle_batch_response(BatchResponse {
+            results        : vec![BatchItemResult::Data(immutable_data.clone())],
+            orig_request   : batch,
+            group_pub_keys : BTreeMap::new(),
+        }, source.clone());
+        assert_eq!(client.mismatched_data_responses(), 1);
+        assert_eq!(client.interface.lock().unwrap().get_batch_responses.len(), 2);
+        assert_eq!(client.interface.lock().unwrap().misbehaving_sources, vec![source]);
+
+        // so is a single response with data not matching the requested name
+        let get = signed_request(&client, MessageType::GetData(
+            DataRequest::ImmutableData(ImmutableDataType::Normal)));
+        let source: NameType = Random::generate_random();
+        client.handle_get_data_response(GetDataResponse {
+            data           : immutable_data,
+            orig_request   : get,
+            group_pub_keys : BTreeMap::new(),
+        }, source.clone());
+        assert_eq!(client.mismatched_data_responses(), 2);
+        assert!(client.interface.lock().unwrap().get_responses.is_empty());
+        assert_eq!(client.interface.lock().unwrap().misbehaving_sources.len(), 2);
+        assert_eq!(client.interface.lock().unwrap().misbehaving_sources[1], source);
+    }
+
+    #[test]
+    fn forwarded_batch_items_answered() {
+        let mut client = client_with_relays(1);
+        let immutable_data = Data::ImmutableData(
+            ImmutableData::new(ImmutableDataType::Normal, vec![1u8, 2, 3]));
+        let get_batch = signed_request(&client, MessageType::GetDataBatch(vec![
+            (Random::generate_random(), DataRequest::PlainData),
+            (immutable_data.name(), DataRequest::ImmutableData(ImmutableDataType::Normal))]));
+
+        // the data of a forwarded item is handed over under the name of its item
+        let forwarded = forwarded_item(&get_batch, MessageType::GetData(
+            DataRequest::ImmutableData(ImmutableDataType::Normal)));
+        client.handle_get_data_response(GetDataResponse {
+            data           : immutable_data.clone(),
+            orig_request   : forwarded,
+            group_pub_keys : BTreeMap::new(),
+        }, Random::generate_random());
+        assert_eq!(client.interface.lock().unwrap().get_responses,
+                   vec![(immutable_data.name(), immutable_data.clone())]);
+
+        // not for an item the batch does not have
+        let forwarded = forwarded_item(&get_batch, MessageType::GetData(
+            DataRequest::StructuredData(5)));
+        client.handle_get_data_response(GetDataResponse {
+            data           : immutable_data.clone(),
+            orig_request   : forwarded,
+            group_pub_keys : BTreeMap::new(),
+        }, Random::generate_random());
+        assert_eq!(client.interface.lock().unwrap().get_responses.len(), 1);
+
+        // the result of a forwarded put is handed over with its data
+        let plain_data = Data::PlainData(PlainData::new(Random::generate_random(), vec![4u8]));
+        let other_data = Data::PlainData(PlainData::new(Random::generate_random(), vec![5u8]));
+        let put_batch = signed_request(&client, MessageType::PutDataBatch(vec![
+            other_data, plain_data.clone()]));
+        let forwarded = forwarded_item(&put_batch, MessageType::PutData(plain_data.clone()));
+        client.handle_put_data_response(ErrorReturn::new(ResponseError::NoData, forwarded));
+        assert_eq!(client.interface.lock().unwrap().put_responses,
+                   vec![(ResponseError::NoData, plain_data)]);
+
+        // but not for data the batch does not have, or a batch we did not sign
+        let unknown_data = Data::PlainData(PlainData::new(Random::generate_random(), vec![6u8]));
+        let forwarded = forwarded_item(&put_batch, MessageType::PutData(unknown_data.clone()));
+        client.handle_put_data_response(ErrorReturn::new(ResponseError::NoData, forwarded));
+        let other_client = client_with_relays(1);
+        let other_batch = signed_request(&other_client, MessageType::PutDataBatch(vec![
+            unknown_data.clone()]));
+        let forwarded = forwarded_item(&other_batch, MessageType::PutData(unknown_data));
+        client.handle_put_data_response(ErrorReturn::new(ResponseError::NoData, forwarded));
+        assert_eq!(client.interface.lock().unwrap().put_responses.len(), 1);
+    }
 }
 
 // #[cfg(test)]
@@ -26,9 +26,10 @@
 //! Other network management messages are handled by Routing after Sentinel resolution.
 
 use rand;
-use sodiumoxide::crypto::sign::{verify_detached, Signature};
+use sodiumoxide::crypto::sign::verify_detached;
 use sodiumoxide::crypto::sign;
-use std::collections::{BTreeMap};
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
 use std::boxed::Box;
 use std::ops::DerefMut;
 use std::net::SocketAddr;
@@ -44,28 +45,37 @@ use message_filter::MessageFilter;
 use NameType;
 use name_type::{closer_to_target_or_equal};
 use node_interface::Interface;
-use routing_table::{RoutingTable, NodeInfo};
+use routing_table::{RoutingTable, NodeInfo, PARALLELISM};
 use relay::{RelayMap};
 use sendable::Sendable;
 use data::{Data, DataRequest};
 use types;
-use types::{MessageId, Bytes, DestinationAddress, SourceAddress, Address};
-use authority::{Authority, our_authority};
-use who_are_you::IAm;
+use types::{MessageId, Bytes, DestinationAddress, SourceAddress, Address, RoutePath,
+            RouteReport, RateLimits, RequestKind, SubnetLimits};
+use authority::{Authority, our_authority, verify_from_authority};
+use canonical::signed_message_bytes;
+use who_are_you::{IAm, Capability, NegotiatedProtocol, KeyChallenge, KeyChallengeResponse,
+                  ProtocolRefusal, KEY_CHALLENGE_VERSION, supported_capabilities, only_supported};
+use compression;
+use compression::Compressor;
 use messages::{RoutingMessage, SignedMessage, MessageType,
-               ConnectRequest, ConnectResponse, ErrorReturn, GetDataResponse};
+               ConnectRequest, ConnectResponse, ErrorReturn, GetDataResponse,
+               BatchItemResult, BatchResponse};
 use error::{RoutingError, ResponseError, InterfaceError};
 use node_interface::MethodCall;
 use refresh_accumulator::RefreshAccumulator;
+use churn::ChurnEvent;
 use id::Id;
 use public_id::PublicId;
 use utils;
-use utils::{encode, decode};
+use wire_format;
 use sentinel::pure_sentinel::{PureSentinel, AddResult};
 use user_message::{SentinelPutRequest, SentinelPutResponse, SentinelGetDataResponse};
 
 type RoutingResult = Result<(), RoutingError>;
 
+static UNKNOWN_CONNECTION_SWEEP_SECS: i64 = 5;
+
 enum ConnectionName {
     Relay(Address),
     Routing(NameType),
@@ -75,6 +85,13 @@ enum ConnectionName {
     // ClaimedConnection(PublicId),
 }
 
+// a GetGroupKey we sent, with the keys each member of the group answered with
+struct GroupKeyRequest {
+    message_id: MessageId,
+    sent: SteadyTime,
+    responses: BTreeMap<NameType, BTreeMap<NameType, sign::PublicKey>>,
+}
+
 fn get_reflective_endpoint() -> Endpoint {
     match SocketAddr::from_str(&format!("127.0.0.1:{}", 0u16)) {
         Ok(socket_address) => Endpoint::Tcp(socket_address),
@@ -94,12 +111,31 @@ pub struct RoutingMembrane<F : Interface> {
     // for Routing
     id: Id,
     routing_table: RoutingTable,
+    close_group: Vec<PublicId>,
+    // the names of our contacts when the interface was last told of churn
+    churn_contacts: Vec<NameType>,
     relay_map: RelayMap,
     next_message_id: MessageId,
     filter: MessageFilter<types::FilterType>,
     public_id_cache: LruCache<NameType, PublicId>,
+    // keys of nodes outside our routing table, as a quorum of their group sent them on request
+    group_keys: LruCache<NameType, sign::PublicKey>,
+    group_key_requests: BTreeMap<NameType, GroupKeyRequest>,
     connection_cache: BTreeMap<NameType, SteadyTime>,
     refresh_accumulator: RefreshAccumulator,
+    network_size_estimates: BTreeMap<NameType, (u64, SteadyTime)>,
+    network_size_estimate: u64,
+    disjoint_paths: usize,
+    disjoint_routes: LruCache<MessageId, (NameType, Vec<NameType>, Signature)>,
+    route_arrivals: LruCache<(types::FilterType, u8), SteadyTime>,
+    route_filter: MessageFilter<(types::FilterType, u8)>,
+    last_unknown_connection_sweep: SteadyTime,
+    mismatched_data_responses: usize,
+    capabilities: BTreeSet<Capability>,
+    connection_protocols: BTreeMap<Endpoint, NegotiatedProtocol>,
+    // clients which identified themselves, until they answer our challenge
+    key_challenges: BTreeMap<Endpoint, (PublicId, KeyChallenge, NegotiatedProtocol)>,
+    compressor: Compressor,
     // for Persona logic
     interface: Box<F>,
     put_response_sentinel: PureSentinel<SentinelPutResponse, NameType>,
@@ -125,13 +161,29 @@ impl<F> RoutingMembrane<F> where F: Interface {
             accepting_on: accepting_on,
             bootstrap: bootstrap,
             routing_table : RoutingTable::new(&relocated_id.name()),
+            close_group: Vec::new(),
+            churn_contacts: Vec::new(),
             relay_map: RelayMap::new(&relocated_id),
             id : relocated_id,
             next_message_id: rand::random::<MessageId>(),
             filter: MessageFilter::with_expiry_duration(Duration::minutes(20)),
             public_id_cache: LruCache::with_expiry_duration(Duration::minutes(10)),
+            group_keys: LruCache::with_expiry_duration(Duration::minutes(10)),
+            group_key_requests: BTreeMap::new(),
             connection_cache: BTreeMap::new(),
             refresh_accumulator: RefreshAccumulator::new(),
+            network_size_estimates: BTreeMap::new(),
+            network_size_estimate: 1,
+            disjoint_paths: 1,
+            disjoint_routes: LruCache::with_expiry_duration(Duration::minutes(10)),
+            route_arrivals: LruCache::with_expiry_duration(Duration::minutes(10)),
+            route_filter: MessageFilter::with_expiry_duration(Duration::minutes(20)),
+            last_unknown_connection_sweep: SteadyTime::now(),
+            mismatched_data_responses: 0,
+            capabilities: supported_capabilities(),
+            connection_protocols: BTreeMap::new(),
+            key_challenges: BTreeMap::new(),
+            compressor: Compressor::new(),
             interface : Box::new(personas),
             put_response_sentinel: PureSentinel::new(),
             get_data_response_sentinel: PureSentinel::new(),
@@ -139,6 +191,44 @@ impl<F> RoutingMembrane<F> where F: Interface {
         }
     }
 
+    /// Send requests originating at this node along up to `path_count` paths with distinct first
+    /// hops, instead of swarming them.  Later hops avoid the first hops and the nodes their own
+    /// copy passed, but each picks from its own view of the network, so copies can still meet
+    /// further along.  The destination group reports which copies arrived, which is
+    /// passed on to Interface::handle_route_report.  A path count of 1 disables this.
+    pub fn set_disjoint_paths(&mut self, path_count: usize) {
+        self.disjoint_paths = cmp::max(1, cmp::min(path_count, PARALLELISM));
+    }
+
+    /// Sets the optional protocol features we announce in our IAm messages, leaving out those we
+    /// do not support.  A feature is used
+    /// on a connection only if the peer announced it as well.
+    pub fn set_capabilities(&mut self, capabilities: BTreeSet<Capability>) {
+        self.capabilities = only_supported(&capabilities);
+    }
+
+    /// Returns the protocol version and capabilities agreed on with the peer on the endpoint,
+    /// once it identified itself.
+    pub fn connection_protocol(&self, endpoint: &Endpoint) -> Option<&NegotiatedProtocol> {
+        self.connection_protocols.get(endpoint)
+    }
+
+    /// Records the protocol agreed on for a connection identified before the membrane took
+    /// over, i.e. our bootstrap connection.
+    pub fn add_connection_protocol(&mut self, endpoint: Endpoint, protocol: NegotiatedProtocol) {
+        let _ = self.connection_protocols.insert(endpoint, protocol);
+    }
+
+    /// Sets the size from which messages are compressed, on connections that agreed to it.
+    pub fn set_compression_threshold(&mut self, threshold: usize) {
+        self.compressor.set_threshold(threshold);
+    }
+
+    /// Returns the number of bytes compression saved on the messages we sent.
+    pub fn compression_bytes_saved(&self) -> u64 {
+        self.compressor.bytes_saved()
+    }
+
     /// Retrieve something from the network (non mutating) - Direct call
     pub fn get(&mut self, location: NameType, data : DataRequest) {
         let message_id = self.get_next_message_id();
@@ -151,7 +241,7 @@ impl<F> RoutingMembrane<F> where F: Interface {
             authority   : Authority::Unknown
         };
 
-        ignore(self.send_swarm_or_parallel(&message));
+        ignore(self.send_request(&message));
     }
 
     /// Add something to the network, will always go via ClientManager group
@@ -166,7 +256,7 @@ impl<F> RoutingMembrane<F> where F: Interface {
             authority   : Authority::Unknown,
         };
 
-        ignore(self.send_swarm_or_parallel(&message));
+        ignore(self.send_request(&message));
     }
 
     /// Add something to the network, will always go via ClientManager group
@@ -181,7 +271,7 @@ impl<F> RoutingMembrane<F> where F: Interface {
             authority   : Authority::Unknown,
         };
 
-        ignore(self.send_swarm_or_parallel(&message));
+        ignore(self.send_request(&message));
     }
 
     pub fn delete(&mut self, _destination: NameType, _data : Data) {
@@ -203,7 +293,7 @@ impl<F> RoutingMembrane<F> where F: Interface {
             authority   : Authority::Unknown,
         };
 
-        ignore(self.send_swarm_or_parallel(&message));
+        ignore(self.send_request(&message));
     }
 
     /// RoutingMembrane::Run starts the membrane
@@ -225,7 +315,14 @@ impl<F> RoutingMembrane<F> where F: Interface {
             match self.event_input.recv() {
                 Err(_) => (),
                 Ok(crust::Event::NewMessage(endpoint, bytes)) => {
-                    match decode::<SignedMessage>(&bytes) {
+                    let bytes = match compression::decompress(bytes) {
+                        Ok(bytes) => bytes,
+                        Err(error) => {
+                            info!("Dropped message from {:?}: {}", endpoint, error);
+                            continue;
+                        },
+                    };
+                    match wire_format::decode::<SignedMessage>(&bytes) {
                         Ok(message) => {
                             match self.lookup_endpoint(&endpoint) {
                                 // We sent this message to ourselves
@@ -240,11 +337,13 @@ impl<F> RoutingMembrane<F> where F: Interface {
                                 },
                                 // we hold an active connection to this endpoint,
                                 // mapped to a name in our relay map
-                                Some(ConnectionName::Relay(_)) => {
+                                Some(ConnectionName::Relay(relay_address)) => {
                                     // messages are owned by the signature of the sender
-                                    // we can handle it as a normal signed routing message.
+                                    // we can handle it as a normal signed routing message,
+                                    // once accounted against the sender's rate limits.
                                     // TODO(ben 29/07/2015) message can be validated
-                                    ignore(self.message_received(message));
+                                    ignore(self.relayed_message_received(relay_address, message,
+                                                                         bytes.len()));
                                 },
                                 Some(ConnectionName::OurBootstrap(bootstrap_node_name)) => {
                                     ignore(self.message_received(message));
@@ -260,9 +359,14 @@ impl<F> RoutingMembrane<F> where F: Interface {
                             };
                         },
                         // The message received is not a Signed Routing Message,
-                        // expect it to be an IAm message to identify a connection
+                        // expect it to be an IAm message to identify a connection,
+                        // or a client's answer to our challenge
                         Err(_) => {
-                            let _ = self.handle_i_am(&endpoint, bytes);
+                            if self.key_challenges.contains_key(&endpoint) {
+                                let _ = self.handle_key_challenge_response(&endpoint, bytes);
+                            } else {
+                                let _ = self.handle_i_am(&endpoint, bytes);
+                            }
                         },
                     };
                 },
@@ -276,12 +380,45 @@ impl<F> RoutingMembrane<F> where F: Interface {
                     // TODO(ben 23/07/2015): drop and stop crust bootstrapping
                 }
             };
+            self.expire_unknown_connections();
         }
     }
 
+    /// Drops the connections which did not identify themselves with an IAm in time.  As the
+    /// event loop has no timer, this is called after every event, sweeping at most every
+    /// UNKNOWN_CONNECTION_SWEEP_SECS seconds.
+    fn expire_unknown_connections(&mut self) {
+        let time_now = SteadyTime::now();
+        if time_now - self.last_unknown_connection_sweep
+            < Duration::seconds(UNKNOWN_CONNECTION_SWEEP_SECS) {
+            return;
+        }
+        self.last_unknown_connection_sweep = time_now;
+        for endpoint in self.relay_map.expire_unknown_connections() {
+            info!("Dropping unidentified connection {:?} ({:?} expired so far).", endpoint,
+                self.relay_map.expired_unknown_connections());
+            let _ = self.key_challenges.remove(&endpoint);
+            self.connection_manager.drop_node(endpoint);
+        }
+        let expired_messages = self.relay_map.expire_pending_messages();
+        if expired_messages > 0 {
+            info!("Dropped {:?} expired messages held for disconnected clients.",
+                expired_messages);
+        }
+    }
+
+    /// Sets how long and how many undeliverable messages are held for disconnected clients.
+    pub fn set_pending_message_limits(&mut self, expiry: Duration,
+                                      max_pending_messages_per_client: usize,
+                                      max_pending_clients: usize) {
+        self.relay_map.set_pending_message_limits(expiry, max_pending_messages_per_client,
+                                                  max_pending_clients);
+    }
+
     fn my_source_address(&self) -> SourceAddress {
         self.bootstrap.clone().map(|(_, name)| {
-            SourceAddress::RelayedForClient(name, self.id.signing_public_key())
+            SourceAddress::RelayedForNode(name,
+                utils::public_key_to_client_name(&self.id.signing_public_key()))
         })
         .unwrap_or(SourceAddress::Direct(self.id.name()))
     }
@@ -325,10 +462,12 @@ impl<F> RoutingMembrane<F> where F: Interface {
                 });
 
         let signed_message = try!(SignedMessage::new(&routing_msg, self.id.signing_private_key()));
-        let serialised_msg = try!(encode(&signed_message));
+        let serialised_msg = try!(wire_format::encode(&signed_message));
 
-        self.relay_map.add_client(connect_request.requester_fob, endpoint.clone());
         self.relay_map.remove_unknown_connection(endpoint);
+        try!(self.relay_map.check_subnet_limits(
+            &Address::Client(connect_request.requester_fob.signing_public_key()), endpoint));
+        self.relay_map.add_client(connect_request.requester_fob, endpoint.clone());
 
         debug_assert!(self.relay_map.contains_endpoint(&endpoint));
 
@@ -338,6 +477,92 @@ impl<F> RoutingMembrane<F> where F: Interface {
         }
     }
 
+    /// Requests from relayed contacts are accounted against their rate limits before they enter
+    /// the network.  Requests over the limit are answered with RateLimitExceeded, a GetData
+    /// with a GetDataErrorResponse.  A batch is accounted by its size like any other request.
+    /// Requests to store a StructuredDataHistory are answered with InvalidRequest.
+    fn relayed_message_received(&mut self, relay_address: Address, message_wrap: SignedMessage,
+                                size: usize) -> RoutingResult {
+        let message = try!(message_wrap.get_routing_message());
+        if stores_history(&message.message_type) {
+            info!("Refused {:?} from {:?}, a StructuredDataHistory can not be stored.",
+                message.message_id, relay_address);
+            return self.refuse_request(message_wrap, message, ResponseError::InvalidRequest);
+        }
+        let request_kind = match message.message_type {
+            MessageType::GetData(_) |
+            MessageType::GetDataBatch(_) => Some(RequestKind::Get),
+            MessageType::PutData(_) |
+            MessageType::PutDataBatch(_) |
+            MessageType::Post(_) |
+            MessageType::DeleteData(_) => Some(RequestKind::Mutation),
+            _ => None,
+        };
+        match request_kind {
+            Some(request_kind) => {
+                if !self.relay_map.check_rate_limit(&relay_address, request_kind, size) {
+                    info!("Refused {:?} from {:?}, rate limit exceeded ({:?} refused so far).",
+                        request_kind, relay_address, self.relay_map.rate_limited_requests());
+                    return self.refuse_rate_limited(message_wrap, message);
+                }
+            },
+            None => {}
+        };
+        self.message_received(message_wrap)
+    }
+
+    fn refuse_rate_limited(&mut self, message_wrap: SignedMessage, message: RoutingMessage)
+            -> RoutingResult {
+        self.refuse_request(message_wrap, message, ResponseError::RateLimitExceeded)
+    }
+
+    /// Answers the request with the error in the response matching its type, and returns the
+    /// error.
+    fn refuse_request(&mut self, message_wrap: SignedMessage, message: RoutingMessage,
+                      error: ResponseError) -> RoutingResult {
+        let error_return = ErrorReturn::new(error.clone(), message_wrap.without_route());
+        let response = match message.message_type {
+            MessageType::GetData(_) =>
+                MessageType::GetDataErrorResponse(error_return),
+            MessageType::PutData(_) =>
+                MessageType::PutDataResponse(error_return, BTreeMap::new()),
+            MessageType::Post(_) =>
+                MessageType::PostResponse(error_return, BTreeMap::new()),
+            MessageType::DeleteData(_) =>
+                MessageType::DeleteDataResponse(error_return),
+            MessageType::GetDataBatch(ref data_requests) =>
+                MessageType::BatchResponse(BatchResponse::refused(data_requests.len(),
+                    error.clone(), message_wrap.without_route())),
+            MessageType::PutDataBatch(ref data) =>
+                MessageType::BatchResponse(BatchResponse::refused(data.len(),
+                    error.clone(), message_wrap.without_route())),
+            _ => return Err(RoutingError::Response(error)),
+        };
+        let our_authority = our_authority(&message, &self.routing_table);
+        try!(self.send_reply(&message, our_authority, response));
+        Err(RoutingError::Response(error))
+    }
+
+    /// Sets the rate limits for requests our relayed contacts send into the network.
+    pub fn set_relay_rate_limits(&mut self, rate_limits: RateLimits) {
+        self.relay_map.set_rate_limits(rate_limits);
+    }
+
+    /// Sets the number of contacts accepted per IP subnet into our routing table, and the number
+    /// of contacts we relay for per IP subnet.
+    pub fn set_subnet_limits(&mut self, routing_table_limits: SubnetLimits,
+                             relay_limits: SubnetLimits) {
+        self.routing_table.set_subnet_limits(routing_table_limits);
+        self.relay_map.set_subnet_limits(relay_limits);
+    }
+
+    /// Sets how long a new connection may stay unidentified before it is dropped, and how many
+    /// unidentified connections are accepted at the same time.
+    pub fn set_unknown_connection_limits(&mut self, expiry: Duration,
+                                         max_unknown_connections: usize) {
+        self.relay_map.set_unknown_connection_limits(expiry, max_unknown_connections);
+    }
+
     /// When CRUST receives a connect to our listening port and establishes a new connection,
     /// the endpoint is given here as new connection
     fn handle_new_connection(&mut self, endpoint : Endpoint) {
@@ -353,7 +578,12 @@ impl<F> RoutingMembrane<F> where F: Interface {
                     endpoint);
             },
             None => {
-                self.relay_map.register_unknown_connection(endpoint.clone());
+                if !self.relay_map.register_unknown_connection(endpoint.clone()) {
+                    info!("Dropping NewConnection {:?}, too many unidentified connections ({:?} \
+                        refused so far).", endpoint, self.relay_map.refused_unknown_connections());
+                    self.connection_manager.drop_node(endpoint);
+                    return;
+                }
                 // Send a polite "I Am" message introducing ourselves.
                 ignore(self.send_i_am_msg(endpoint));
             }
@@ -367,14 +597,19 @@ impl<F> RoutingMembrane<F> where F: Interface {
         // The relay map will automatically drop the Name if the last endpoint to it is dropped
         self.relay_map.remove_unknown_connection(&endpoint);
         self.relay_map.drop_endpoint(&endpoint);
-        let mut trigger_handle_churn = false;
+        let _ = self.connection_protocols.remove(&endpoint);
+        let _ = self.key_challenges.remove(&endpoint);
         match self.routing_table.lookup_endpoint(&endpoint) {
             Some(name) => {
-                trigger_handle_churn = self.routing_table
-                    .address_in_our_close_group_range(&name);
-                self.routing_table.drop_node(&name);
-                info!("RT (size : {:?}) connection {:?} disconnected for {:?}.",
-                    self.routing_table.size(), endpoint, name);
+                // the node is only dropped once its last live connection is lost
+                match self.routing_table.drop_connection(&endpoint) {
+                    Some(_) => {
+                        info!("RT (size : {:?}) connection {:?} disconnected for {:?}.",
+                            self.routing_table.size(), endpoint, name);
+                    },
+                    None => info!("RT (size : {:?}) connection {:?} lost for {:?}, \
+                        other connections remain.", self.routing_table.size(), endpoint, name),
+                };
             },
             None => {}
         };
@@ -391,28 +626,42 @@ impl<F> RoutingMembrane<F> where F: Interface {
         };
         if drop_bootstrap { self.bootstrap = None; }
 
-        if trigger_handle_churn {
-            info!("Handle CHURN lost node");
-            let mut close_group : Vec<NameType> = self.routing_table
-                .our_close_group().iter()
-                .map(|node_info| node_info.fob.name())
-                .collect::<Vec<NameType>>();
-            close_group.insert(0, self.id.name().clone());
-            let churn_actions = self.mut_interface().handle_churn(close_group);
-            for action in churn_actions {
-                match action {
-                    MethodCall::Put { destination: x, content: y, } => self.put(x, y),
-                    MethodCall::Get { name: x, data_request: y } => self.get(x, y),
-                    MethodCall::Refresh { type_tag, from_group, payload } => self.refresh(type_tag, from_group, payload),
-                    MethodCall::Post { destination: x, content: y, } => self.post(x, y),
-                    MethodCall::Delete { name: x, data : y } => self.delete(x, y),
-                    MethodCall::Forward { destination } =>
-                        info!("IGNORED: on handle_churn MethodCall:Forward {} is not a Valid action", destination),
-                    MethodCall::Reply { data: _data } =>
-                        info!("IGNORED: on handle_churn MethodCall:Reply is not a Valid action")
-                };
-            }
+        self.check_churn();
+    }
+
+    /// Compares our close group against the last one handed to the interface and, if it changed,
+    /// passes the churn event on and carries out the returned actions.  This is called after
+    /// every change to the routing table.
+    fn check_churn(&mut self) {
+        let close_group = self.routing_table.our_close_group().into_iter()
+                              .map(|node_info| node_info.fob)
+                              .collect::<Vec<PublicId>>();
+        let contacts = self.routing_table.contact_names();
+        let churn_event = match ChurnEvent::new(self.id.name(), self.close_group.clone(),
+                                                close_group.clone(), self.churn_contacts.clone(),
+                                                contacts.clone()) {
+            Some(churn_event) => churn_event,
+            None => return,
         };
+        self.close_group = close_group;
+        self.churn_contacts = contacts;
+        info!("Handle CHURN added {:?} removed {:?}",
+            churn_event.added.iter().map(|public_id| public_id.name()).collect::<Vec<_>>(),
+            churn_event.removed.iter().map(|public_id| public_id.name()).collect::<Vec<_>>());
+        let churn_actions = self.mut_interface().handle_churn(churn_event);
+        for action in churn_actions {
+            match action {
+                MethodCall::Put { destination: x, content: y, } => self.put(x, y),
+                MethodCall::Get { name: x, data_request: y } => self.get(x, y),
+                MethodCall::Refresh { type_tag, from_group, payload } => self.refresh(type_tag, from_group, payload),
+                MethodCall::Post { destination: x, content: y, } => self.post(x, y),
+                MethodCall::Delete { name: x, data : y } => self.delete(x, y),
+                MethodCall::Forward { destination } =>
+                    info!("IGNORED: on handle_churn MethodCall:Forward {} is not a Valid action", destination),
+                MethodCall::Reply { data: _data } =>
+                    info!("IGNORED: on handle_churn MethodCall:Reply is not a Valid action")
+            };
+        }
     }
 
     fn construct_find_group_msg(&mut self) -> RoutingMessage {
@@ -441,14 +690,77 @@ impl<F> RoutingMembrane<F> where F: Interface {
 
         let message = try!(message_wrap.get_routing_message());
 
+        // record copies sent along disjoint paths before the filter drops all but the first
+        match message_wrap.route() {
+            Some(route) => self.record_route_arrival(&message, route),
+            None => {}
+        };
+
         // filter check
         if self.filter.check(&message.get_filter()) {
+            // a copy travelling along another disjoint path is still passed on along its path
+            match message_wrap.route() {
+                Some(route) => { ignore(self.forward_routed_copy(&message, &message_wrap, route)); },
+                None => {}
+            };
             // should just return quietly
             return Err(RoutingError::FilterCheckFailed);
         }
+
+        // check the group or client authority the sender claims only where the message is
+        // delivered to us; a node passing it on need not know the sender, and the members
+        // answering our GetGroupKey are checked by agreeing on their keys
+        let sender_key = self.sender_key(&message);
+        let delivered = our_authority(&message, &self.routing_table) != Authority::Unknown;
+        let authority_check = match message.message_type {
+            MessageType::GetGroupKeyResponse(_) => Ok(()),
+            _ if delivered => verify_from_authority(&message_wrap, &message, &self.routing_table,
+                                                    sender_key.as_ref()),
+            _ => Ok(()),
+        };
+
+        // refuse data which does not match the name it was requested under, before it is
+        // cached, delivered or forwarded; it is not added to the filter, so a valid copy from
+        // another source still gets through.  The source is only reported if it signed the
+        // message, as anyone can name another node as the source.
+        match message.message_type {
+            MessageType::GetDataResponse(ref response) => {
+                if !response.verify_data_matches_request() {
+                    self.mismatched_data_responses += 1;
+                    info!("Dropping GetDataResponse from {:?}, data does not match the requested \
+                        name ({:?} dropped so far).", message.source,
+                        self.mismatched_data_responses);
+                    let signed_by_source = sender_key.as_ref()
+                        .map(|key| message_wrap.verify_signature(key)).unwrap_or(false);
+                    if signed_by_source {
+                        self.mut_interface().handle_misbehaving_source(
+                            message.non_relayed_source());
+                    }
+                    return Err(RoutingError::DataNameMismatch);
+                }
+            },
+            _ => {}
+        }
+
         // add to filter
         self.filter.add(message.get_filter());
 
+        // a message claiming an authority the sender cannot hold is still passed on, but not
+        // handled; for a group member we know no key for, we ask its group for the keys
+        match authority_check {
+            Ok(()) => {},
+            Err(error) => {
+                info!("Not handling {:?} from {:?}, implausible authority {:?}.",
+                    message.message_type, message.source, message.authority);
+                match (sender_key.is_none(), message.from_group()) {
+                    (true, Some(group)) => ignore(self.request_group_keys(group)),
+                    _ => {},
+                }
+                self.forward_received(&message_wrap, &message);
+                return Err(error);
+            },
+        }
+
         // Caching on GetData and GetDataRequest
         match message.message_type {
             // Add to cache, only for ImmutableData; For StructuredData caching
@@ -481,7 +793,7 @@ impl<F> RoutingMembrane<F> where F: Interface {
                     Ok(MethodCall::Reply { data }) => {
                         let response = GetDataResponse {
                             data           : data,
-                            orig_request   : message_wrap.clone(),
+                            orig_request   : message_wrap.without_route(),
                             group_pub_keys : BTreeMap::new()
                         };
                         let our_authority = our_authority(&message, &self.routing_table);
@@ -496,18 +808,20 @@ impl<F> RoutingMembrane<F> where F: Interface {
         }
 
         // Forward
-        ignore(self.send_swarm_or_parallel_or_relay_with_signature(
-            &message, message_wrap.signature().clone()));
+        self.forward_received(&message_wrap, &message);
 
         let address_in_close_group_range =
             self.address_in_close_group_range(&message.non_relayed_destination());
 
         // Handle FindGroupResponse
         match  message.message_type {
-            MessageType::FindGroupResponse(ref vec_of_public_ids) =>
+            MessageType::FindGroupResponse(ref vec_of_public_ids, network_size_estimate) => {
+                self.update_network_size_estimate(message.non_relayed_source(),
+                                                  network_size_estimate);
                 ignore(self.handle_find_group_response(
                             vec_of_public_ids.clone(),
-                            address_in_close_group_range.clone())),
+                            address_in_close_group_range.clone()))
+            },
              _ => (),
         };
 
@@ -529,7 +843,6 @@ impl<F> RoutingMembrane<F> where F: Interface {
         // pre-sentinel message handling
         match message.message_type {
             // MessageType::GetKey => self.handle_get_key(header, body),
-            // MessageType::GetGroupKey => self.handle_get_group_key(header, body),
             MessageType::ConnectRequest(request) => self.handle_connect_request(request, message_wrap),
             _ => {
                 // Sentinel check
@@ -538,6 +851,10 @@ impl<F> RoutingMembrane<F> where F: Interface {
                 match message.message_type {
                     MessageType::ConnectResponse(response) => self.handle_connect_response(response),
                     MessageType::FindGroup => self.handle_find_group(message),
+                    MessageType::GetGroupKey => self.handle_get_group_key(message),
+                    MessageType::GetGroupKeyResponse(ref group_keys) =>
+                        self.handle_get_group_key_response(message_wrap, message.clone(),
+                                                           group_keys.clone()),
                     // Handled above for some reason.
                     //MessageType::FindGroupResponse(find_group_response) => self.handle_find_group_response(find_group_response),
                     MessageType::GetData(ref request) => self.handle_get_data(message_wrap,
@@ -563,6 +880,11 @@ impl<F> RoutingMembrane<F> where F: Interface {
                                                                      response.clone()),
                         }
                     },
+                    // a history is only ever returned for a GetData, never stored
+                    MessageType::PutData(Data::StructuredDataHistory(_)) |
+                    MessageType::Post(Data::StructuredDataHistory(_)) =>
+                        self.refuse_request(message_wrap, message.clone(),
+                                            ResponseError::InvalidRequest),
                     MessageType::PutData(ref data) => {
                         match message.source.actual_source() {
                             Address::Node(name) =>
@@ -573,6 +895,9 @@ impl<F> RoutingMembrane<F> where F: Interface {
                                                             data.clone(), name),
                         }
                     },
+                    MessageType::GetDataBatch(_) |
+                    MessageType::PutDataBatch(_) =>
+                        self.handle_batch(message_wrap, message.clone()),
                     MessageType::PutPublicId(ref id) =>
                         self.handle_put_public_id(message_wrap, message.clone(), id.clone()),
                     MessageType::Refresh(ref tag, ref data) =>
@@ -583,6 +908,8 @@ impl<F> RoutingMembrane<F> where F: Interface {
                         => self.handle_post_response(message_wrap,
                                                      message.clone(),
                                                      response.clone()),
+                    MessageType::RouteReport(ref report) =>
+                        self.handle_route_report(message_wrap, message.clone(), report.clone()),
                     _ => {
                         Err(RoutingError::UnknownMessageType)
                     }
@@ -591,6 +918,16 @@ impl<F> RoutingMembrane<F> where F: Interface {
         }
     }
 
+    // Passes a received message on towards its destination, along its own path if it has one.
+    fn forward_received(&mut self, message_wrap: &SignedMessage, message: &RoutingMessage) {
+        match message_wrap.route() {
+            Some(route) => ignore(self.forward_routed_copy(message, message_wrap, route)),
+            // forwarded as received, as the signature covers the body exactly as it was sent
+            None => ignore(self.send_swarm_or_parallel_or_relay_signed_message(
+                message_wrap, &message.destination_address())),
+        };
+    }
+
     /// Scan all passing messages for the existance of nodes in the address space.
     /// If a node is detected with a name that would improve our routing table,
     /// then try to connect.  During a delay of 5 seconds, we collapse
@@ -622,13 +959,16 @@ impl<F> RoutingMembrane<F> where F: Interface {
 
     // -----Name-based Send Functions----------------------------------------
 
+    /// Messages which cannot be delivered to a client are held, in case the client reconnects.
     fn send_out_as_relay(&mut self, name: &Address, msg: Bytes) {
         let mut failed_endpoints : Vec<Endpoint> = Vec::new();
+        let mut delivered = false;
         match self.relay_map.get_endpoints(name) {
             Some(&(_, ref endpoints)) => {
                 for endpoint in endpoints {
-                    match self.connection_manager.send(endpoint.clone(), msg.clone()) {
-                        Ok(_) => break,
+                    match self.connection_manager.send(endpoint.clone(),
+                                                       self.prepare_for(endpoint, &msg)) {
+                        Ok(_) => { delivered = true; break },
                         Err(_) => {
                             info!("Dropped relay connection {:?} on failed attempt
                                 to relay for node {:?}", endpoint, name);
@@ -643,19 +983,189 @@ impl<F> RoutingMembrane<F> where F: Interface {
             self.relay_map.drop_endpoint(&failed_endpoint);
             self.connection_manager.drop_node(failed_endpoint);
         }
+        match (name, delivered) {
+            (&Address::Client(ref public_key), false) => {
+                if !self.relay_map.queue_pending_message(public_key.clone(), msg) {
+                    info!("Dropped message for disconnected client {:?}, we did not relay for \
+                        it recently or hold messages for too many clients.", name);
+                }
+            },
+            _ => {}
+        }
     }
 
-    fn send_swarm_or_parallel(&self, msg : &RoutingMessage) -> Result<(), RoutingError> {
+    // Sends to the first of the peer's live connections that accepts the message; a failing
+    // connection will be reported to us as a lost connection.  Returns false if none did.
+    fn send_to_node(&self, peer: &NodeInfo, bytes: &Bytes) -> bool {
+        for peer_endpoint in &peer.connected_endpoints {
+            match self.connection_manager.send(peer_endpoint.clone(),
+                                               self.prepare_for(peer_endpoint, bytes)) {
+                Ok(_)  => return true,
+                Err(_) => continue,
+            };
+        }
+        false
+    }
+
+    // Compresses the message if the peer on the endpoint agreed to compression.
+    fn prepare_for(&self, endpoint: &Endpoint, bytes: &Bytes) -> Bytes {
+        match self.connection_protocols.get(endpoint) {
+            Some(protocol) if protocol.supports(Capability::Compression) =>
+                self.compressor.compress(bytes),
+            _ => bytes.clone(),
+        }
+    }
+
+    // Requests originating at this node are sent along disjoint paths if so configured.
+    fn send_request(&mut self, msg: &RoutingMessage) -> RoutingResult {
+        if self.disjoint_paths > 1 && self.routing_table.size() > 0 {
+            let signed_message = try!(SignedMessage::new(msg, self.id.signing_private_key()));
+            self.send_along_disjoint_paths(&signed_message, msg)
+        } else {
+            self.send_swarm_or_parallel(msg)
+        }
+    }
+
+    /// Sends a copy of the message to each of the closest connected nodes to the destination,
+    /// each tagged with its own path.  The first hops are kept to validate the route reports.
+    fn send_along_disjoint_paths(&mut self, signed_message: &SignedMessage,
+                                 msg: &RoutingMessage) -> RoutingResult {
         let destination = msg.non_relayed_destination();
-        let signed_message = try!(SignedMessage::new(&msg, self.id.signing_private_key()));
-        self.send_swarm_or_parallel_signed_message(&signed_message, &destination)
+        let first_hops = self.routing_table.target_nodes(&destination).into_iter()
+                             .filter(|node_info| node_info.is_connected())
+                             .take(self.disjoint_paths)
+                             .collect::<Vec<NodeInfo>>();
+        if first_hops.is_empty() {
+            return self.send_swarm_or_parallel_signed_message(signed_message, &destination);
+        }
+        let first_hop_names = first_hops.iter().map(|node_info| node_info.id())
+                                        .collect::<Vec<NameType>>();
+        for (path_index, first_hop) in first_hops.iter().enumerate() {
+            let copy = signed_message.with_route(RoutePath::new(signed_message.signature(),
+                path_index as u8, first_hop_names.clone(), self.id.signing_private_key()));
+            let bytes = try!(wire_format::encode(&copy));
+            let _ = self.send_to_node(first_hop, &bytes);
+        }
+        self.disjoint_routes.add(msg.message_id, (destination, first_hop_names,
+                                                  signed_message.signature().clone()));
+        Ok(())
+    }
+
+    /// Copies along different disjoint paths share the message's filter, so they are filtered
+    /// by their path as well: each path is forwarded once, even where two paths cross.
+    fn forward_routed_copy(&mut self, message: &RoutingMessage, signed_message: &SignedMessage,
+                           route: &RoutePath) -> RoutingResult {
+        let routed_copy = (message.get_filter(), route.path_index);
+        if self.route_filter.check(&routed_copy) {
+            return Err(RoutingError::FilterCheckFailed);
+        }
+        self.route_filter.add(routed_copy);
+        self.forward_along_route(message, signed_message, route)
+    }
+
+    /// Passes a copy travelling along a disjoint path on to a single next hop.  Each path takes
+    /// a different rank among our nodes closest to the destination, and never a node it already
+    /// passed or the first hop of another path.  This tends to keep the copies apart, but as we
+    /// do not know the hops of other copies it does not make the paths node-disjoint beyond
+    /// their first hops.  Within the destination group copies are swarmed.
+    fn forward_along_route(&mut self, message: &RoutingMessage, signed_message: &SignedMessage,
+                           route: &RoutePath) -> RoutingResult {
+        let destination = message.non_relayed_destination();
+        if self.address_in_close_group_range(&destination) {
+            return self.send_swarm_or_parallel_or_relay_signed_message(
+                signed_message, &message.destination_address());
+        }
+        let our_name = self.id.name();
+        let candidates = self.routing_table.target_nodes(&destination).into_iter()
+                             .filter(|node_info| node_info.is_connected())
+                             .filter(|node_info| node_info.id() != our_name)
+                             .filter(|node_info| !route.hops.contains(&node_info.id()))
+                             .filter(|node_info| !route.first_hops.contains(&node_info.id()))
+                             .collect::<Vec<NodeInfo>>();
+        if candidates.is_empty() {
+            return self.send_swarm_or_parallel_signed_message(
+                &signed_message.without_route(), &destination);
+        }
+        let next_hop = &candidates[route.path_index as usize % candidates.len()];
+        let mut next_route = route.clone();
+        next_route.hops.push(next_hop.id());
+        let bytes = try!(wire_format::encode(&signed_message.with_route(next_route)));
+        let _ = self.send_to_node(next_hop, &bytes);
+        Ok(())
+    }
+
+    /// Within the destination group, reports each path a copy of a message arrived on back to
+    /// its sender, once per path.  The report is sent with the authority of the destination
+    /// group, so the sender can check we are in it.
+    fn record_route_arrival(&mut self, message: &RoutingMessage, route: &RoutePath) {
+        match message.message_type {
+            MessageType::RouteReport(_) => return,
+            _ => {}
+        };
+        if !self.address_in_close_group_range(&message.non_relayed_destination()) {
+            return;
+        }
+        let arrival = (message.get_filter(), route.path_index);
+        if self.route_arrivals.contains_key(&arrival) {
+            return;
+        }
+        self.route_arrivals.add(arrival, SteadyTime::now());
+        let report = RoutingMessage {
+            destination  : message.reply_destination(),
+            source       : self.my_source_address(),
+            orig_message : None,
+            message_type : MessageType::RouteReport(RouteReport {
+                message_id  : message.message_id,
+                destination : message.non_relayed_destination(),
+                route       : route.clone(),
+                reporter    : PublicId::new(&self.id),
+            }),
+            message_id   : self.get_next_message_id(),
+            authority    : Authority::NaeManager(message.non_relayed_destination()),
+        };
+        ignore(self.send_swarm_or_parallel_or_relay(&report));
+    }
+
+    /// Passes reports on copies of our own messages to the interface, together with the first
+    /// hops of all paths the message was sent along.  Only reports signed by a member of the
+    /// destination group, on a path we signed for the message, are accepted.
+    fn handle_route_report(&mut self, message_wrap: SignedMessage, message: RoutingMessage,
+                           report: RouteReport) -> RoutingResult {
+        if message.non_relayed_destination() != self.id.name() {
+            return Ok(());
+        }
+        let (destination, first_hops, signature) =
+            match self.disjoint_routes.get(&report.message_id) {
+                Some(&(ref destination, ref first_hops, ref signature)) =>
+                    (destination.clone(), first_hops.clone(), signature.clone()),
+                None => return Ok(()),
+            };
+        // the reporter has to be the sender, and claim the destination group's authority;
+        // verify_from_authority already checked it plausibly is in that group, and signed
+        // the report with its key
+        let from_reporter = match message.source {
+            SourceAddress::Direct(ref sender) => *sender == report.reporter.name(),
+            _ => false,
+        };
+        if !from_reporter || report.destination != destination
+            || message.authority != Authority::NaeManager(destination.clone()) {
+            return Err(RoutingError::BadAuthority);
+        }
+        if !message_wrap.verify_signature(&report.reporter.signing_public_key()) {
+            return Err(RoutingError::FailedSignature);
+        }
+        // only accept reports on a path we sent the message along
+        if report.route.first_hops != first_hops
+            || !report.route.verify(&signature, &self.id.signing_public_key()) {
+            return Err(RoutingError::BadAuthority);
+        }
+        self.mut_interface().handle_route_report(report, first_hops);
+        Ok(())
     }
 
-    fn send_swarm_or_parallel_with_signature(&self, msg: &RoutingMessage,
-        signature : Signature) -> Result<(), RoutingError> {
+    fn send_swarm_or_parallel(&self, msg : &RoutingMessage) -> Result<(), RoutingError> {
         let destination = msg.non_relayed_destination();
-        let signed_message = try!(SignedMessage::with_signature(&msg,
-            signature));
+        let signed_message = try!(SignedMessage::new(&msg, self.id.signing_private_key()));
         self.send_swarm_or_parallel_signed_message(&signed_message, &destination)
     }
 
@@ -663,15 +1173,10 @@ impl<F> RoutingMembrane<F> where F: Interface {
         destination: &NameType) -> Result<(), RoutingError> {
 
         if self.routing_table.size() > 0 {
-            let bytes = try!(encode(&signed_message));
+            let bytes = try!(wire_format::encode(&signed_message));
 
             for peer in self.routing_table.target_nodes(&destination) {
-                match peer.connected_endpoint {
-                    Some(peer_endpoint) => {
-                        ignore(self.connection_manager.send(peer_endpoint, bytes.clone()));
-                    },
-                    None => {}
-                };
+                let _ = self.send_to_node(&peer, &bytes);
             }
 
             // FIXME(ben 24/07/2015)
@@ -685,9 +1190,10 @@ impl<F> RoutingMembrane<F> where F: Interface {
         } else {
             match self.bootstrap {
                 Some((ref bootstrap_endpoint, _)) => {
-                    let msg = try!(encode(&signed_message));
+                    let msg = try!(wire_format::encode(&signed_message));
 
-                    match self.connection_manager.send(bootstrap_endpoint.clone(), msg) {
+                    match self.connection_manager.send(bootstrap_endpoint.clone(),
+                                                       self.prepare_for(bootstrap_endpoint, &msg)) {
                         Ok(_)  => Ok(()),
                         Err(e) => Err(RoutingError::Io(e))
                     }
@@ -713,7 +1219,7 @@ impl<F> RoutingMembrane<F> where F: Interface {
     // it back to ourselves
     // this is the logically correct behaviour.
     fn send_reflective_to_us(&self, signed_message: &SignedMessage) -> Result<(), RoutingError> {
-        let bytes = try!(encode(&signed_message));
+        let bytes = try!(wire_format::encode(&signed_message));
         let new_event = crust::Event::NewMessage(self.reflective_endpoint.clone(), bytes);
         match self.sender_clone.send(new_event) {
             Ok(_) => {},
@@ -733,22 +1239,12 @@ impl<F> RoutingMembrane<F> where F: Interface {
             &signed_message, &destination)
     }
 
-    fn send_swarm_or_parallel_or_relay_with_signature(&mut self, msg: &RoutingMessage,
-        signature: Signature) -> Result<(), RoutingError> {
-
-        let destination = msg.destination_address();
-        let signed_message = try!(SignedMessage::with_signature(
-            msg, signature));
-        self.send_swarm_or_parallel_or_relay_signed_message(
-            &signed_message, &destination)
-    }
-
     fn send_swarm_or_parallel_or_relay_signed_message(&mut self,
         signed_message: &SignedMessage, destination_address: &DestinationAddress)
         -> Result<(), RoutingError> {
 
         if destination_address.non_relayed_destination() == self.id.name() {
-            let bytes = try!(encode(signed_message));
+            let bytes = try!(wire_format::encode(signed_message));
 
             match *destination_address {
                 DestinationAddress::RelayToClient(_, public_key) => {
@@ -794,12 +1290,37 @@ impl<F> RoutingMembrane<F> where F: Interface {
 
     fn handle_i_am(&mut self, endpoint: &Endpoint, serialised_message: Bytes)
         -> RoutingResult {
-        match decode::<IAm>(&serialised_message) {
+        match wire_format::decode::<IAm>(&serialised_message) {
             Ok(i_am) => {
-                let mut trigger_handle_churn = false;
+                let protocol = match i_am.negotiate(&self.capabilities) {
+                    Ok(protocol) => protocol,
+                    Err(error) => {
+                        info!("Dropping connection on {:?} as {:?} speaks protocol versions \
+                            {:?} to {:?} only.", endpoint, i_am.public_id.name(),
+                            i_am.min_version, i_am.max_version);
+                        // tell the peer why, before dropping it
+                        match wire_format::encode(&ProtocolRefusal::new()) {
+                            Ok(refusal) =>
+                                ignore(self.connection_manager.send(endpoint.clone(), refusal)),
+                            Err(_) => {},
+                        }
+                        self.relay_map.remove_unknown_connection(endpoint);
+                        self.connection_manager.drop_node(endpoint.clone());
+                        return Err(error);
+                    },
+                };
                 match i_am.public_id.is_relocated() {
                     // if it is relocated, we consider the connection for our routing table
                     true => {
+                        // an additional connection from a node already in our routing table
+                        // is kept as an alternative route to that node
+                        if self.routing_table.add_connection(&i_am.public_id, endpoint) {
+                            info!("RT (size : {:?}) added connection on {:?} to known node {:?}",
+                                self.routing_table.size(), endpoint, i_am.public_id.name());
+                            self.relay_map.remove_unknown_connection(endpoint);
+                            let _ = self.connection_protocols.insert(endpoint.clone(), protocol);
+                            return Ok(());
+                        }
                         // check we have a cache for his public id from the relocation procedure
                         match self.public_id_cache.get(&i_am.public_id.name()) {
                             Some(cached_public_id) => {
@@ -807,7 +1328,7 @@ impl<F> RoutingMembrane<F> where F: Interface {
                                 if cached_public_id == &i_am.public_id {
                                     let peer_endpoints = vec![endpoint.clone()];
                                     let peer_node_info = NodeInfo::new(i_am.public_id.clone(), peer_endpoints,
-                                        Some(endpoint.clone()));
+                                        vec![endpoint.clone()]);
                                     // FIXME: node info cloned for debug printout below
                                     let (added, _) = self.routing_table.add_node(peer_node_info.clone());
                                     // TODO: drop dropped node in connection_manager
@@ -817,11 +1338,9 @@ impl<F> RoutingMembrane<F> where F: Interface {
                                             endpoint, i_am.public_id.name());
                                         self.relay_map.remove_unknown_connection(endpoint);
                                         self.connection_manager.drop_node(endpoint.clone());
-                                        return Err(RoutingError::RefusedFromRoutingTable); }
+                                        return Err(self.refusal_reason(&peer_node_info)); }
                                     info!("RT (size : {:?}) added connected node {:?} on {:?}",
                                         self.routing_table.size(), peer_node_info.fob.name(), endpoint);
-                                    trigger_handle_churn = self.routing_table
-                                        .address_in_our_close_group_range(&peer_node_info.fob.name());
                                 } else {
                                     info!("I Am, relocated name {:?} conflicted with cached fob.",
                                         i_am.public_id.name());
@@ -841,7 +1360,7 @@ impl<F> RoutingMembrane<F> where F: Interface {
                                     true => {
                                         let peer_endpoints = vec![endpoint.clone()];
                                         let peer_node_info = NodeInfo::new(i_am.public_id.clone(),
-                                            peer_endpoints, Some(endpoint.clone()));
+                                            peer_endpoints, vec![endpoint.clone()]);
                                         // FIXME: node info cloned for debug printout below
                                         let (added, _) = self.routing_table.add_node(
                                             peer_node_info.clone());
@@ -852,11 +1371,9 @@ impl<F> RoutingMembrane<F> where F: Interface {
                                                 endpoint, i_am.public_id.name());
                                             self.relay_map.remove_unknown_connection(endpoint);
                                             self.connection_manager.drop_node(endpoint.clone());
-                                            return Err(RoutingError::RefusedFromRoutingTable); }
+                                            return Err(self.refusal_reason(&peer_node_info)); }
                                         info!("RT (size : {:?}) added connected node {:?} on {:?}",
                                             self.routing_table.size(), peer_node_info.fob.name(), endpoint);
-                                        trigger_handle_churn = self.routing_table
-                                            .address_in_our_close_group_range(&peer_node_info.fob.name());
                                     },
                                     false => {
                                         info!("Dropping connection on {:?} as {:?} is relocated,
@@ -872,48 +1389,149 @@ impl<F> RoutingMembrane<F> where F: Interface {
                     // if it is not relocated, we consider the connection for our relay_map
                     false => {
                         // move endpoint based on identification
-                        match i_am.address {
+                        let relay_address = match i_am.address {
+                            // a client can only identify with the key of its public id
                             Address::Client(public_key) => {
-                                self.relay_map.add_client(i_am.public_id.clone(), endpoint.clone());
+                                if public_key != i_am.public_id.signing_public_key() {
+                                    self.relay_map.remove_unknown_connection(endpoint);
+                                    self.connection_manager.drop_node(endpoint.clone());
+                                    return Err(RoutingError::BadAuthority);
+                                }
+                                Address::Client(public_key)
+                            },
+                            // a joining node can only identify under its provisional name
+                            Address::Node(node_name) => {
+                                if node_name != i_am.public_id.name() {
+                                    self.relay_map.remove_unknown_connection(endpoint);
+                                    self.connection_manager.drop_node(endpoint.clone());
+                                    return Err(RoutingError::BadAuthority);
+                                }
+                                Address::Node(node_name)
+                            },
+                        };
+                        let subnet_check = self.relay_map
+                            .check_subnet_limits(&relay_address, endpoint);
+                        match subnet_check {
+                            Ok(()) => {
+                                match relay_address {
+                                    // the client stays unidentified until it proved it holds
+                                    // its key
+                                    Address::Client(_) if
+                                            protocol.version >= KEY_CHALLENGE_VERSION => {
+                                        return self.send_key_challenge(endpoint,
+                                            i_am.public_id.clone(), protocol);
+                                    },
+                                    // an older client can not prove its key, so it is refused
+                                    Address::Client(_) => {
+                                        info!("Refused to relay for {:?} on {:?}, it speaks \
+                                            protocol versions {:?} to {:?} only.",
+                                            relay_address, endpoint, i_am.min_version,
+                                            i_am.max_version);
+                                        match wire_format::encode(
+                                                &ProtocolRefusal::for_relaying()) {
+                                            Ok(refusal) => ignore(self.connection_manager
+                                                .send(endpoint.clone(), refusal)),
+                                            Err(_) => {},
+                                        }
+                                        self.relay_map.remove_unknown_connection(endpoint);
+                                        self.connection_manager.drop_node(endpoint.clone());
+                                        return Err(RoutingError::IncompatibleProtocol(
+                                            i_am.min_version, i_am.max_version));
+                                    },
+                                    Address::Node(_) => {
+                                        self.relay_map.remove_unknown_connection(endpoint);
+                                        self.relay_map.add_node(i_am.public_id.clone(),
+                                                                endpoint.clone())
+                                    },
+                                };
+                            },
+                            Err(error) => {
                                 self.relay_map.remove_unknown_connection(endpoint);
+                                info!("Refused to relay for {:?} on {:?}: {}",
+                                    relay_address, endpoint, error);
+                                self.connection_manager.drop_node(endpoint.clone());
+                                return Err(error);
                             },
-                            _ => {}, // only accept identified as client in relay map.
-                        }
+                        };
 
                     }
                 };
-                if trigger_handle_churn {
-                    info!("Handle CHURN new node {:?}", i_am.public_id.name());
-                    let mut close_group : Vec<NameType> = self.routing_table
-                            .our_close_group().iter()
-                            .map(|node_info| node_info.fob.name())
-                            .collect::<Vec<NameType>>();
-                    close_group.insert(0, self.id.name().clone());
-                    let churn_actions = self.mut_interface().handle_churn(close_group);
-                    for action in churn_actions {
-                        match action {
-                            MethodCall::Put { destination: x, content: y, } => self.put(x, y),
-                            MethodCall::Get { name: x, data_request: y, } => self.get(x, y),
-                            MethodCall::Refresh { type_tag, from_group, payload } => self.refresh(type_tag, from_group, payload),
-                            MethodCall::Post { destination: x, content: y } => self.post(x, y),
-                            MethodCall::Delete { name: x, data : y } => self.delete(x, y),
-                            MethodCall::Forward { destination } =>
-                                info!("IGNORED: on handle_churn MethodCall:Forward {} is not a Valid action", destination),
-                            MethodCall::Reply { data: _data } =>
-                                info!("IGNORED: on handle_churn MethodCall:Reply is not a Valid action")
-                        };
-                    }
+                // only keep what was agreed on for connections we accepted
+                match self.lookup_endpoint(endpoint) {
+                    Some(ConnectionName::Routing(_)) | Some(ConnectionName::Relay(_)) => {
+                        let _ = self.connection_protocols.insert(endpoint.clone(), protocol);
+                    },
+                    _ => {},
                 }
+                self.check_churn();
                 Ok(())
             },
-            Err(_) => Err(RoutingError::UnknownMessageType)
+            // a peer which speaks none of our protocol versions tells us before dropping us
+            Err(_) => match wire_format::decode::<ProtocolRefusal>(&serialised_message) {
+                Ok(refusal) => {
+                    info!("Connection on {:?} refused, the peer speaks protocol versions {:?} \
+                        to {:?} only.", endpoint, refusal.min_version, refusal.max_version);
+                    self.relay_map.remove_unknown_connection(endpoint);
+                    self.connection_manager.drop_node(endpoint.clone());
+                    Err(refusal.to_error())
+                },
+                Err(_) => Err(RoutingError::UnknownMessageType),
+            }
+        }
+    }
+
+    /// Challenges the client which identified itself on the endpoint to prove it holds its key.
+    /// The connection stays unidentified until it answers, so it expires if it does not.
+    fn send_key_challenge(&mut self, endpoint: &Endpoint, public_id: PublicId,
+                          protocol: NegotiatedProtocol) -> RoutingResult {
+        let challenge = KeyChallenge::new();
+        let message = try!(wire_format::encode(&challenge));
+        let _ = self.key_challenges.insert(endpoint.clone(), (public_id, challenge, protocol));
+        ignore(self.connection_manager.send(endpoint.clone(), message));
+        Ok(())
+    }
+
+    /// Relays for the client once it answered our challenge, delivering what we held for it
+    /// while it was away.  A client failing the challenge is dropped.
+    fn handle_key_challenge_response(&mut self, endpoint: &Endpoint, serialised_message: Bytes)
+            -> RoutingResult {
+        let (public_id, challenge, protocol) = match self.key_challenges.remove(endpoint) {
+            Some(key_challenge) => key_challenge,
+            None => return Err(RoutingError::UnknownMessageType),
+        };
+        self.relay_map.remove_unknown_connection(endpoint);
+        let public_key = public_id.signing_public_key();
+        let proven = match wire_format::decode::<KeyChallengeResponse>(&serialised_message) {
+            Ok(response) => challenge.verify(&self.id.name(), &response, &public_key),
+            Err(_) => false,
+        };
+        if !proven {
+            info!("Dropping client {:?} on {:?}, it failed our key challenge.", public_key,
+                endpoint);
+            self.connection_manager.drop_node(endpoint.clone());
+            return Err(RoutingError::FailedSignature);
+        }
+        if self.relay_map.add_client(public_id, endpoint.clone()) {
+            let _ = self.connection_protocols.insert(endpoint.clone(), protocol);
+            for message in self.relay_map.take_pending_messages(&public_key) {
+                self.send_out_as_relay(&Address::Client(public_key), message);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns why the routing table refused to add the node.
+    fn refusal_reason(&self, node_info: &NodeInfo) -> RoutingError {
+        match self.routing_table.check_subnet_limits(node_info) {
+            Err(error) => error,
+            Ok(()) => RoutingError::RefusedFromRoutingTable,
         }
     }
 
     fn send_i_am_msg(&mut self, endpoint: Endpoint) -> RoutingResult {
-        let message = try!(encode(&IAm {
-            address: types::Address::Node(self.id.name()),
-            public_id : PublicId::new(&self.id)}));
+        let message = try!(wire_format::encode(&IAm::new(types::Address::Node(self.id.name()),
+                                                         PublicId::new(&self.id),
+                                                         self.capabilities.clone())));
         ignore(self.connection_manager.send(endpoint, message));
         Ok(())
     }
@@ -1154,6 +1772,102 @@ impl<F> RoutingMembrane<F> where F: Interface {
         Ok(())
     }
 
+    /// Splits a GetDataBatch or PutDataBatch into a persona call per item, as if each item had
+    /// been sent on its own to the same group, and answers with the results of all items in
+    /// one BatchResponse.  An item our group would not handle in the same persona as the batch
+    /// is refused with InvalidRequest.
+    fn handle_batch(&mut self, signed_message: SignedMessage, message: RoutingMessage)
+            -> RoutingResult {
+        match message.actual_source() {
+            Address::Client(public_key) => {
+                if !signed_message.verify_signature(&public_key) {
+                    return Err(RoutingError::FailedSignature);
+                }
+            },
+            Address::Node(_) => {}
+        };
+
+        // a GET item is addressed to the name of its data, a PUT item to the batch's destination
+        let items = match message.message_type {
+            MessageType::GetDataBatch(ref items) => items.iter()
+                .map(|&(ref name, ref data_request)|
+                     (DestinationAddress::Direct(name.clone()),
+                      MessageType::GetData(data_request.clone())))
+                .collect::<Vec<_>>(),
+            MessageType::PutDataBatch(ref data) => data.iter()
+                .map(|data| (message.destination_address(), MessageType::PutData(data.clone())))
+                .collect::<Vec<_>>(),
+            _ => return Err(RoutingError::UnknownMessageType),
+        };
+
+        let batch_authority = our_authority(&message, &self.routing_table);
+        let mut results = Vec::with_capacity(items.len());
+        for (destination, item) in items {
+            let mut item_message = message.clone();
+            item_message.destination = destination;
+            item_message.message_type = item;
+            let item_authority = our_authority(&item_message, &self.routing_table);
+            let result = if same_persona(&item_authority, &batch_authority) {
+                self.handle_batch_item(&signed_message, &item_message, item_authority)
+            } else {
+                BatchItemResult::Failed(ResponseError::InvalidRequest)
+            };
+            results.push(result);
+        }
+
+        let group_pub_keys = if batch_authority.is_group() {
+            self.group_pub_keys()
+        }
+        else {
+            BTreeMap::new()
+        };
+        let response = BatchResponse {
+            results        : results,
+            orig_request   : signed_message.without_route(),
+            group_pub_keys : group_pub_keys,
+        };
+        self.send_reply(&message, batch_authority, MessageType::BatchResponse(response))
+    }
+
+    fn handle_batch_item(&mut self, signed_message: &SignedMessage, message: &RoutingMessage,
+                         our_authority: Authority) -> BatchItemResult {
+        let from_authority = message.from_authority();
+        let from = message.source_address();
+        let to = message.destination_address();
+
+        let method_calls = match message.message_type {
+            MessageType::GetData(ref data_request) => self.mut_interface().handle_get(
+                data_request.clone(), our_authority, from_authority, from),
+            MessageType::PutData(Data::StructuredDataHistory(_)) =>
+                return BatchItemResult::Failed(ResponseError::InvalidRequest),
+            MessageType::PutData(ref data) => self.mut_interface().handle_put(
+                our_authority, from_authority, from, to, data.clone()),
+            _ => return BatchItemResult::Failed(ResponseError::InvalidRequest),
+        };
+        let method_calls = match method_calls {
+            Ok(method_calls) => method_calls,
+            Err(InterfaceError::Abort) => return BatchItemResult::Aborted,
+            Err(InterfaceError::Response(error)) => return BatchItemResult::Failed(error),
+        };
+
+        let mut result = BatchItemResult::Accepted;
+        for method_call in method_calls {
+            match method_call {
+                MethodCall::Put { destination: x, content: y, } => self.put(x, y),
+                MethodCall::Get { name: x, data_request: y, } => self.get(x, y),
+                MethodCall::Refresh { type_tag, from_group, payload } => self.refresh(type_tag, from_group, payload),
+                MethodCall::Post { destination: x, content: y, } => self.post(x, y),
+                MethodCall::Delete { name: x, data : y } => self.delete(x, y),
+                MethodCall::Forward { destination } => {
+                    ignore(self.forward(signed_message, message, destination));
+                    result = BatchItemResult::Forwarded;
+                },
+                MethodCall::Reply { data } => result = BatchItemResult::Data(data),
+            }
+        }
+        result
+    }
+
     fn handle_group_put_data_response(&mut self, signed_message: SignedMessage,
             message: RoutingMessage, response: ErrorReturn) -> RoutingResult {
         info!("Handle group PUT data response.");
@@ -1304,7 +2018,7 @@ impl<F> RoutingMembrane<F> where F: Interface {
                     let mut peer_endpoints = connect_request.local_endpoints.clone();
                     peer_endpoints.extend(connect_request.external_endpoints.clone().into_iter());
                     let peer_node_info =
-                        NodeInfo::new(connect_request.requester_fob.clone(), peer_endpoints, None);
+                        NodeInfo::new(connect_request.requester_fob.clone(), peer_endpoints, vec![]);
                     // Try to add to the routing table.  If unsuccessful, no need to continue.
                     let (added, _) = self.routing_table.add_node(peer_node_info.clone());
                     if !added {
@@ -1367,12 +2081,18 @@ impl<F> RoutingMembrane<F> where F: Interface {
 
     fn handle_connect_response(&mut self, connect_response: ConnectResponse) -> RoutingResult {
 
-        // Verify a connect request was initiated by us.
-        let connect_request = try!(decode::<ConnectRequest>(&connect_response.serialised_connect_request));
+        // Verify a connect request was initiated by us.  The request is returned as the body of
+        // the SignedMessage we sent it in, an enveloped RoutingMessage.
+        let request_message = try!(wire_format::decode::<RoutingMessage>(
+            &connect_response.serialised_connect_request));
+        let connect_request = match request_message.message_type {
+            MessageType::ConnectRequest(connect_request) => connect_request,
+            _ => return Err(RoutingError::Response(ResponseError::InvalidRequest)),
+        };
         if connect_request.requester_id != self.id.name() ||
            !verify_detached(&connect_response.connect_request_signature,
-                                  &connect_response.serialised_connect_request[..],
-                                  &self.id.signing_public_key()) {
+                            &signed_message_bytes(&connect_response.serialised_connect_request),
+                            &self.id.signing_public_key()) {
             return Err(RoutingError::Response(ResponseError::InvalidRequest));
         }
         // double check if fob is relocated;
@@ -1387,14 +2107,15 @@ impl<F> RoutingMembrane<F> where F: Interface {
   //     info!("")
   // }
         let peer_node_info =
-            NodeInfo::new(connect_response.receiver_fob.clone(), peer_endpoints, None);
+            NodeInfo::new(connect_response.receiver_fob.clone(), peer_endpoints, vec![]);
 
         // Try to add to the routing table.  If unsuccessful, no need to continue.
         let (added, _) = self.routing_table.add_node(peer_node_info.clone());
         if !added {
-           return Err(RoutingError::RefusedFromRoutingTable); }
+           return Err(self.refusal_reason(&peer_node_info)); }
         info!("RT (size : {:?}) added {:?} on connect response", self.routing_table.size(),
             peer_node_info.fob.name());
+        self.check_churn();
         // Try to connect to the peer.
         self.connection_manager.connect(connect_response.receiver_local_endpoints.clone());
         self.connection_manager.connect(connect_response.receiver_external_endpoints.clone());
@@ -1467,7 +2188,8 @@ impl<F> RoutingMembrane<F> where F: Interface {
             destination  : original_message.reply_destination(),
             source       : SourceAddress::Direct(self.id.name().clone()),
             orig_message : None,
-            message_type : MessageType::FindGroupResponse(group),
+            message_type : MessageType::FindGroupResponse(group,
+                               self.routing_table.estimate_network_size()),
             message_id   : original_message.message_id,
             authority    : Authority::Unknown,
         };
@@ -1493,6 +2215,42 @@ impl<F> RoutingMembrane<F> where F: Interface {
         Ok(())
     }
 
+    /// Records the network size estimate reported by a member of our close group and averages
+    /// it with our own estimate and those recently received from other members.  Estimates from
+    /// other peers are ignored.  The interface is notified when the averaged estimate changes.
+    fn update_network_size_estimate(&mut self, from_node: NameType, estimate: u64) {
+        // nodes of earlier releases send no estimate, read as 0
+        if estimate == 0 {
+            return;
+        }
+        if !self.routing_table.our_close_group().iter()
+                .any(|node_info| node_info.id() == from_node) {
+            return;
+        }
+        let time_now = SteadyTime::now();
+        let _ = self.network_size_estimates.insert(from_node, (estimate, time_now));
+
+        let mut expired_estimates : Vec<NameType> = Vec::new();
+        for (node, &(_, time)) in self.network_size_estimates.iter() {
+            if time_now - time > Duration::minutes(10) {
+                expired_estimates.push(node.clone());
+            }
+        }
+        for expired_name in expired_estimates {
+            self.network_size_estimates.remove(&expired_name);
+        }
+
+        let mut sum = self.routing_table.estimate_network_size() as f64;
+        for &(estimate, _) in self.network_size_estimates.values() {
+            sum += estimate as f64;
+        }
+        let average = (sum / (self.network_size_estimates.len() + 1) as f64) as u64;
+        if average != self.network_size_estimate {
+            self.network_size_estimate = average;
+            self.mut_interface().handle_network_size_estimate(average);
+        }
+    }
+
     fn handle_get_data(&mut self, orig_message: SignedMessage,
                                   message: RoutingMessage,
                                   data_request: DataRequest) -> RoutingResult {
@@ -1523,7 +2281,7 @@ impl<F> RoutingMembrane<F> where F: Interface {
 
                             let response = GetDataResponse {
                                 data           : data,
-                                orig_request   : orig_message.clone(),
+                                orig_request   : orig_message.without_route(),
                                 group_pub_keys : group_pub_keys
                             };
 
@@ -1545,7 +2303,7 @@ impl<F> RoutingMembrane<F> where F: Interface {
         let message = routing_message.create_forward(self.id.name().clone(),
                                                      our_authority,
                                                      destination,
-                                                     orig_message.clone());
+                                                     orig_message.without_route());
         ignore(self.send_swarm_or_parallel(&message));
         Ok(())
     }
@@ -1654,16 +2412,146 @@ impl<F> RoutingMembrane<F> where F: Interface {
         Ok(())
     }
 
-    fn group_pub_keys(&self) -> BTreeMap<NameType, sign::PublicKey> {
-        let name_and_key_from_info = |node_info : NodeInfo| {
-            (node_info.fob.name(), node_info.fob.signing_public_key())
+    /// Returns the key the node named as the direct source of the message signs with: ours, that
+    /// of a node in our routing table or whose relocated PublicId we cached, or one a quorum of
+    /// its group sent on our request.  The keys a group sends along with its responses are not
+    /// taken, as any node could send them claiming its own group.  The reporter of a RouteReport
+    /// need not be connected to us, so for a reporter we know no key for, the key in its
+    /// PublicId is taken.
+    fn sender_key(&mut self, message: &RoutingMessage) -> Option<sign::PublicKey> {
+        let sender = match message.source {
+            SourceAddress::Direct(ref sender) => sender.clone(),
+            _ => return None,
         };
-
-        let ourselves = (self.id.name(), self.id.signing_public_key());
-
-        self.routing_table.our_close_group()
-                          .into_iter()
-                          .map(name_and_key_from_info)
+        if sender == self.id.name() {
+            return Some(self.id.signing_public_key());
+        }
+        match self.routing_table.public_id(&sender) {
+            Some(public_id) => return Some(public_id.signing_public_key()),
+            None => {},
+        };
+        match self.public_id_cache.get(&sender) {
+            Some(public_id) => return Some(public_id.signing_public_key()),
+            None => {},
+        };
+        match self.group_keys.get(&sender) {
+            Some(key) => return Some(key.clone()),
+            None => {},
+        };
+        match message.message_type {
+            MessageType::RouteReport(ref report) if report.reporter.name() == sender =>
+                Some(report.reporter.signing_public_key()),
+            _ => None,
+        }
+    }
+
+    /// Asks the group for the keys of its members, unless we are waiting for them already.
+    fn request_group_keys(&mut self, group: NameType) -> RoutingResult {
+        self.prune_group_key_requests();
+        if self.group_key_requests.contains_key(&group) {
+            return Ok(());
+        }
+        let message = RoutingMessage {
+            destination  : DestinationAddress::Direct(group.clone()),
+            source       : SourceAddress::Direct(self.id.name()),
+            orig_message : None,
+            message_type : MessageType::GetGroupKey,
+            message_id   : self.get_next_message_id(),
+            authority    : Authority::ManagedNode,
+        };
+        let _ = self.group_key_requests.insert(group, GroupKeyRequest {
+            message_id : message.message_id,
+            sent       : SteadyTime::now(),
+            responses  : BTreeMap::new(),
+        });
+        self.send_swarm_or_parallel(&message)
+    }
+
+    fn prune_group_key_requests(&mut self) {
+        let time_now = SteadyTime::now();
+        let expired = self.group_key_requests.iter()
+                          .filter(|&(_, request)| time_now - request.sent > Duration::minutes(1))
+                          .map(|(group, _)| group.clone())
+                          .collect::<Vec<_>>();
+        for group in expired {
+            let _ = self.group_key_requests.remove(&group);
+        }
+    }
+
+    fn handle_get_group_key(&mut self, original_message: RoutingMessage) -> RoutingResult {
+        let our_authority = our_authority(&original_message, &self.routing_table);
+        if !our_authority.is_group() {
+            return Err(RoutingError::BadAuthority);
+        }
+        let message = RoutingMessage {
+            destination  : original_message.reply_destination(),
+            source       : SourceAddress::Direct(self.id.name()),
+            orig_message : None,
+            message_type : MessageType::GetGroupKeyResponse(self.group_pub_keys()),
+            message_id   : original_message.message_id,
+            authority    : our_authority,
+        };
+        self.send_swarm_or_parallel(&message)
+    }
+
+    /// Keeps the keys the members of a group sent in answer to our GetGroupKey, once a quorum of
+    /// them agree on a key.  A response we did not ask for is refused, and each member has to
+    /// sign its response with the key it sends for itself and plausibly be in the group.
+    fn handle_get_group_key_response(&mut self, message_wrap: SignedMessage,
+            message: RoutingMessage, group_keys: BTreeMap<NameType, sign::PublicKey>)
+            -> RoutingResult {
+        let group = match message.from_group() {
+            Some(group) => group,
+            None => return Err(RoutingError::BadAuthority),
+        };
+        let responder = message.non_relayed_source();
+        match group_keys.get(&responder) {
+            Some(key) if message_wrap.verify_signature(key) => {},
+            _ => return Err(RoutingError::FailedSignature),
+        }
+        if !self.routing_table.is_plausibly_in_close_group_of(&responder, &group) {
+            return Err(RoutingError::BadAuthority);
+        }
+
+        self.prune_group_key_requests();
+        let quorum = cmp::min(types::QUORUM_SIZE, self.routing_table.size());
+        let agreed_keys = {
+            let request = match self.group_key_requests.get_mut(&group) {
+                Some(request) => request,
+                None => return Err(RoutingError::Response(ResponseError::InvalidRequest)),
+            };
+            if request.message_id != message.message_id {
+                return Err(RoutingError::Response(ResponseError::InvalidRequest));
+            }
+            let _ = request.responses.insert(responder, group_keys.clone());
+            let responses = &request.responses;
+            group_keys.into_iter()
+                .filter(|&(ref name, ref key)| responses.values()
+                    .filter(|keys| keys.get(name) == Some(key)).count() >= quorum)
+                .collect::<Vec<_>>()
+        };
+        for (name, key) in agreed_keys {
+            if name == self.id.name() || self.routing_table.public_id(&name).is_some()
+                    || self.public_id_cache.contains_key(&name)
+                    || self.group_keys.contains_key(&name)
+                    || !self.routing_table.is_plausibly_in_close_group_of(&name, &group) {
+                continue;
+            }
+            self.group_keys.add(name, key);
+        }
+        Ok(())
+    }
+
+    fn group_pub_keys(&self) -> BTreeMap<NameType, sign::PublicKey> {
+        let name_and_key_from_info = |node_info : NodeInfo| {
+            (node_info.fob.name(), node_info.fob.signing_public_key())
+        };
+
+        let ourselves = (self.id.name(), self.id.signing_public_key());
+
+        self.routing_table.our_close_group()
+                          .into_iter()
+                          .map(name_and_key_from_info)
                           .chain(Some(ourselves).into_iter())
                           .collect()
     }
@@ -1674,19 +2562,42 @@ impl<F> RoutingMembrane<F> where F: Interface {
 
 fn ignore<R,E>(_result: Result<R,E>) {}
 
+// Returns true if the request would store a StructuredDataHistory, which is only ever returned
+// for a GetData.  The items of a batch are refused one by one, see handle_batch_item.
+fn stores_history(message_type: &MessageType) -> bool {
+    match *message_type {
+        MessageType::PutData(Data::StructuredDataHistory(_)) |
+        MessageType::Post(Data::StructuredDataHistory(_)) => true,
+        _ => false,
+    }
+}
+
+// Returns true if both are the same group persona, whichever name they act for.  The items of a
+// batch are each handled in the persona of the batch, see handle_batch.
+fn same_persona(authority: &Authority, other: &Authority) -> bool {
+    match (authority, other) {
+        (&Authority::ClientManager(_), &Authority::ClientManager(_)) |
+        (&Authority::NaeManager(_), &Authority::NaeManager(_)) |
+        (&Authority::NodeManager(_), &Authority::NodeManager(_)) => true,
+        _ => false,
+    }
+}
+
 #[cfg(test)]
 mod test {
 
 use super::*;
 use super::ConnectionName;
-use authority::Authority;
+use authority::{Authority, our_authority};
+use churn::ChurnEvent;
 use crust;
 use data::{Data, DataRequest};
-use error::{ResponseError, InterfaceError};
+use error::{RoutingError, ResponseError, InterfaceError};
 use id::Id;
 use immutable_data::{ImmutableData, ImmutableDataType};
-use structured_data::StructuredData;
-use messages::{ErrorReturn, RoutingMessage, MessageType, SignedMessage, GetDataResponse};
+use structured_data::{StructuredData, StructuredDataHistory};
+use messages::{ErrorReturn, RoutingMessage, MessageType, SignedMessage, GetDataResponse,
+               BatchItemResult, ConnectRequest, ConnectResponse};
 use name_type::{NameType, closer_to_target, NAME_TYPE_LEN};
 use node_interface::{Interface, MethodCall};
 use public_id::PublicId;
@@ -1698,8 +2609,12 @@ use sodiumoxide::crypto;
 use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
 use test_utils::Random;
-use types::{DestinationAddress, MessageId, SourceAddress, GROUP_SIZE, Address};
+use time::Duration;
+use types::{DestinationAddress, MessageId, SourceAddress, GROUP_SIZE, QUORUM_SIZE, Address,
+            RoutePath, RouteReport, Bytes, SubnetLimits};
 use utils;
+use who_are_you::{IAm, LegacyIAm, KeyChallengeResponse, ProtocolRefusal, LEGACY_PROTOCOL_VERSION,
+                  PROTOCOL_VERSION, KEY_CHALLENGE_VERSION, supported_capabilities};
 use crust::Endpoint;
 use rand::distributions::{IndependentSample, Range};
 use std::collections::BTreeMap;
@@ -1727,12 +2642,13 @@ pub fn random_endpoints() -> Vec<Endpoint> {
 #[derive(Clone)]
 struct Stats {
     call_count: usize,
-    data: Vec<u8>
+    data: Vec<u8>,
+    misbehaving_sources: Vec<NameType>,
 }
 
 impl Stats {
     pub fn new() -> Stats {
-        Stats {call_count: 0, data: vec![]}
+        Stats {call_count: 0, data: vec![], misbehaving_sources: vec![]}
     }
 }
 
@@ -1816,7 +2732,7 @@ impl Interface for TestInterface {
         Vec::<MethodCall>::new()
     }
 
-    fn handle_churn(&mut self, _close_group: Vec<NameType>)
+    fn handle_churn(&mut self, _churn_event: ChurnEvent)
         -> Vec<MethodCall> {
         unimplemented!();
     }
@@ -1832,6 +2748,16 @@ impl Interface for TestInterface {
                         _data: Data) -> Result<MethodCall, InterfaceError> {
         Err(InterfaceError::Abort)
     }
+
+    fn handle_route_report(&mut self, _report: RouteReport, _first_hops: Vec<NameType>) {
+        let stats = self.stats.clone();
+        let mut stats_value = stats.lock().unwrap();
+        stats_value.call_count += 1;
+    }
+
+    fn handle_misbehaving_source(&mut self, source: NameType) {
+        self.stats.lock().unwrap().misbehaving_sources.push(source);
+    }
 }
 
 fn create_membrane(stats: Arc<Mutex<Stats>>) -> RoutingMembrane<TestInterface> {
@@ -1898,7 +2824,7 @@ fn populate_routing_node() -> RoutingMembrane<TestInterface> {
         membrane.routing_table.add_node(routing_table::NodeInfo::new(
                                         PublicId::new(&Id::new()),
                                         random_endpoints(),
-                                        Some(random_endpoint())));
+                                        vec![random_endpoint()]));
         count += 1;
         if membrane.routing_table.size() >=
             routing_table::RoutingTable::get_optimal_size() { break; }
@@ -1943,6 +2869,589 @@ fn populate_routing_node() -> RoutingMembrane<TestInterface> {
         membrane.get(name, DataRequest::ImmutableData(ImmutableDataType::Normal));
     }
 
+    #[test]
+    fn call_get_along_disjoint_paths() {
+        let mut membrane = populate_routing_node();
+        membrane.set_disjoint_paths(3);
+        let name: NameType = Random::generate_random();
+        let message_id = membrane.next_message_id;
+        membrane.get(name.clone(), DataRequest::ImmutableData(ImmutableDataType::Normal));
+        let (destination, first_hops, _) =
+            membrane.disjoint_routes.get(&message_id).unwrap().clone();
+        assert_eq!(destination, name);
+        assert_eq!(first_hops.len(), 3);
+        assert!(first_hops[0] != first_hops[1] && first_hops[1] != first_hops[2]
+                && first_hops[0] != first_hops[2]);
+    }
+
+    #[test]
+    fn routed_copies_forwarded_once_per_path() {
+        let mut membrane = populate_routing_node();
+        let origin = Id::new();
+        let message = RoutingMessage {
+            destination : DestinationAddress::Direct(Random::generate_random()),
+            source      : SourceAddress::Direct(origin.name()),
+            orig_message: None,
+            message_type: MessageType::GetData(DataRequest::ImmutableData(
+                ImmutableDataType::Normal)),
+            message_id  : random::<u32>(),
+            authority   : Authority::Unknown,
+        };
+        let signed_message = SignedMessage::new(&message, origin.signing_private_key()).unwrap();
+        let first_hops = vec![membrane.id.name(), Random::generate_random()];
+        let copy = |path_index: u8| signed_message.with_route(RoutePath::new(
+            signed_message.signature(), path_index, first_hops.clone(),
+            origin.signing_private_key()));
+
+        assert!(membrane.message_received(copy(0)).is_ok());
+        // the copy along the other path is filtered as a message, but still passed on its path
+        match membrane.message_received(copy(1)) {
+            Err(RoutingError::FilterCheckFailed) => {},
+            _ => panic!("Expected the message to be filtered"),
+        }
+        assert!(membrane.route_filter.check(&(message.get_filter(), 0)));
+        assert!(membrane.route_filter.check(&(message.get_filter(), 1)));
+        // another copy on a path already passed on is not forwarded again
+        let repeated = copy(1);
+        match membrane.forward_routed_copy(&message, &repeated, repeated.route().unwrap()) {
+            Err(RoutingError::FilterCheckFailed) => {},
+            _ => panic!("Expected the copy to be filtered"),
+        }
+    }
+
+    #[test]
+    fn route_report_accepted_only_from_destination_group() {
+        let stats = Arc::new(Mutex::new(Stats::new()));
+        let mut membrane = create_membrane(stats.clone());
+        let reporter = Id::new();
+        assert!(membrane.routing_table.add_node(routing_table::NodeInfo::new(
+            PublicId::new(&reporter), vec![], vec![random_endpoint()])).0);
+        for _ in 0..3 {
+            assert!(membrane.routing_table.add_node(routing_table::NodeInfo::new(
+                PublicId::new(&Id::new()), vec![], vec![random_endpoint()])).0);
+        }
+        membrane.set_disjoint_paths(3);
+        // the reporter is in the close group of its own name
+        let destination = reporter.name();
+        let message_id = membrane.next_message_id;
+        membrane.get(destination.clone(), DataRequest::ImmutableData(ImmutableDataType::Normal));
+        let (_, first_hops, signature) =
+            membrane.disjoint_routes.get(&message_id).unwrap().clone();
+
+        let our_name = membrane.id.name();
+        let report = |signer: &Id, authority: Authority, route: RoutePath| {
+            let message = RoutingMessage {
+                destination : DestinationAddress::Direct(our_name.clone()),
+                source      : SourceAddress::Direct(reporter.name()),
+                orig_message: None,
+                message_type: MessageType::RouteReport(RouteReport {
+                    message_id  : message_id,
+                    destination : destination.clone(),
+                    route       : route,
+                    reporter    : PublicId::new(&reporter),
+                }),
+                message_id  : random::<u32>(),
+                authority   : authority,
+            };
+            SignedMessage::new(&message, signer.signing_private_key()).unwrap()
+        };
+        let route = RoutePath::new(&signature, 1, first_hops.clone(),
+                                   membrane.id.signing_private_key());
+        let group_authority = Authority::NaeManager(destination.clone());
+
+        // a report signed by another node in the reporter's name
+        assert!(membrane.message_received(
+            report(&Id::new(), group_authority.clone(), route.clone())).is_err());
+        // a report without the destination group's authority
+        assert!(membrane.message_received(
+            report(&reporter, Authority::Unknown, route.clone())).is_err());
+        // a report on a path we did not sign
+        let forged_route = RoutePath::new(&signature, 1, first_hops.clone(),
+                                          reporter.signing_private_key());
+        assert!(membrane.message_received(
+            report(&reporter, group_authority.clone(), forged_route)).is_err());
+        let mut moved_route = route.clone();
+        moved_route.path_index = 2;
+        assert!(membrane.message_received(
+            report(&reporter, group_authority.clone(), moved_route)).is_err());
+        assert_eq!(stats.lock().unwrap().call_count, 0);
+
+        assert!(membrane.message_received(report(&reporter, group_authority, route)).is_ok());
+        assert_eq!(stats.lock().unwrap().call_count, 1);
+    }
+
+    #[test]
+    fn group_claims_need_sender_key() {
+        let stats = Arc::new(Mutex::new(Stats::new()));
+        let mut membrane = create_membrane(stats.clone());
+        let sender = Id::new();
+        let member = Id::new();
+        assert!(membrane.routing_table.add_node(routing_table::NodeInfo::new(
+            PublicId::new(&member), vec![], vec![random_endpoint()])).0);
+        let group_message = |source: &Id, signer: &Id, message_type: MessageType| {
+            let message = RoutingMessage {
+                destination : DestinationAddress::Direct(Random::generate_random()),
+                source      : SourceAddress::Direct(source.name()),
+                orig_message: None,
+                message_type: message_type,
+                message_id  : random::<u32>(),
+                authority   : Authority::NaeManager(source.name()),
+            };
+            SignedMessage::new(&message, signer.signing_private_key()).unwrap()
+        };
+        let get_data = MessageType::GetData(DataRequest::PlainData);
+
+        // a node we know no key for can not speak for its group
+        match membrane.message_received(group_message(&sender, &sender, get_data.clone())) {
+            Err(RoutingError::BadAuthority) => {},
+            _ => panic!("Expected BadAuthority"),
+        }
+        // nor can a node naming a node we know as the source
+        match membrane.message_received(group_message(&member, &sender, get_data.clone())) {
+            Err(RoutingError::FailedSignature) => {},
+            _ => panic!("Expected FailedSignature"),
+        }
+        assert_eq!(stats.lock().unwrap().call_count, 0);
+
+        // a node we know can not plant a key for a name next to the group it wants to speak for,
+        // in a response we did not ask for
+        let attacker = Id::new();
+        let mut planted_keys = BTreeMap::new();
+        let _ = planted_keys.insert(member.name(), member.signing_public_key());
+        let _ = planted_keys.insert(sender.name(), attacker.signing_public_key());
+        match membrane.message_received(group_message(&member, &member,
+                MessageType::GetGroupKeyResponse(planted_keys))) {
+            Err(RoutingError::Response(ResponseError::InvalidRequest)) => {},
+            _ => panic!("Expected InvalidRequest"),
+        }
+        assert_eq!(membrane.sender_key(&group_message(&sender, &attacker, get_data.clone())
+                       .get_routing_message().unwrap()), None);
+        match membrane.message_received(group_message(&sender, &attacker, get_data.clone())) {
+            Err(RoutingError::BadAuthority) => {},
+            _ => panic!("Expected BadAuthority"),
+        }
+        // nor keep the node of that name from speaking, once we know it
+        assert!(membrane.routing_table.add_node(routing_table::NodeInfo::new(
+            PublicId::new(&sender), vec![], vec![random_endpoint()])).0);
+        assert!(membrane.message_received(group_message(&sender, &sender, get_data)).is_ok());
+        assert_eq!(stats.lock().unwrap().call_count, 1);
+
+        // the reporter of a RouteReport need not be connected to us, its own key is taken
+        let reporter = Id::new();
+        let signed_message = group_message(&reporter, &reporter, MessageType::FindGroup);
+        let report = RouteReport {
+            message_id  : random::<u32>(),
+            destination : reporter.name(),
+            route       : RoutePath::new(signed_message.signature(), 0, vec![],
+                                         reporter.signing_private_key()),
+            reporter    : PublicId::new(&reporter),
+        };
+        let mut message = signed_message.get_routing_message().unwrap();
+        message.message_type = MessageType::RouteReport(report);
+        assert_eq!(membrane.sender_key(&message), Some(reporter.signing_public_key()));
+        message.source = SourceAddress::Direct(Random::generate_random());
+        assert_eq!(membrane.sender_key(&message), None);
+    }
+
+    #[test]
+    fn group_keys_taken_from_quorum_of_requested_group() {
+        let stats = Arc::new(Mutex::new(Stats::new()));
+        let mut membrane = create_membrane(stats.clone());
+        // with fewer contacts than a quorum, as many members as we have contacts must agree
+        for _ in 0..QUORUM_SIZE - 1 {
+            assert!(membrane.routing_table.add_node(routing_table::NodeInfo::new(
+                PublicId::new(&Id::new()), vec![], vec![random_endpoint()])).0);
+        }
+        let quorum = membrane.routing_table.size();
+        let our_name = membrane.id.name();
+        let sender = Id::new();
+        let group = sender.name();
+        let claim = || {
+            let message = RoutingMessage {
+                destination : DestinationAddress::Direct(Random::generate_random()),
+                source      : SourceAddress::Direct(sender.name()),
+                orig_message: None,
+                message_type: MessageType::GetData(DataRequest::PlainData),
+                message_id  : random::<u32>(),
+                authority   : Authority::NaeManager(group.clone()),
+            };
+            SignedMessage::new(&message, sender.signing_private_key()).unwrap()
+        };
+        let response = |responder: &Id, signer: &Id, sender_key: &crypto::sign::PublicKey,
+                        message_id: MessageId| {
+            let mut group_keys = BTreeMap::new();
+            let _ = group_keys.insert(responder.name(), responder.signing_public_key());
+            let _ = group_keys.insert(sender.name(), sender_key.clone());
+            let message = RoutingMessage {
+                destination : DestinationAddress::Direct(our_name.clone()),
+                source      : SourceAddress::Direct(responder.name()),
+                orig_message: None,
+                message_type: MessageType::GetGroupKeyResponse(group_keys),
+                message_id  : message_id,
+                authority   : Authority::NaeManager(group.clone()),
+            };
+            SignedMessage::new(&message, signer.signing_private_key()).unwrap()
+        };
+
+        // the claim of a node we know no key for is not handled, but its group is asked
+        match membrane.message_received(claim()) {
+            Err(RoutingError::BadAuthority) => {},
+            _ => panic!("Expected BadAuthority"),
+        }
+        let message_id = match membrane.group_key_requests.get(&group) {
+            Some(request) => request.message_id,
+            None => panic!("Expected a GetGroupKey request"),
+        };
+        let sender_key = sender.signing_public_key();
+        let responders = (0..quorum).map(|_| Id::new()).collect::<Vec<_>>();
+
+        // responses to another request, or not signed by the member they name, are refused
+        let attacker = Id::new();
+        match membrane.message_received(response(&responders[0], &responders[0],
+                &attacker.signing_public_key(), message_id.wrapping_add(1))) {
+            Err(RoutingError::Response(ResponseError::InvalidRequest)) => {},
+            _ => panic!("Expected InvalidRequest"),
+        }
+        match membrane.message_received(response(&Id::new(), &attacker,
+                &attacker.signing_public_key(), message_id)) {
+            Err(RoutingError::FailedSignature) => {},
+            _ => panic!("Expected FailedSignature"),
+        }
+
+        // a key is only taken once a quorum of the members agrees on it
+        let claimed = claim().get_routing_message().unwrap();
+        assert!(membrane.message_received(response(&attacker, &attacker,
+            &attacker.signing_public_key(), message_id)).is_ok());
+        for responder in &responders[..quorum - 1] {
+            assert!(membrane.message_received(response(responder, responder, &sender_key,
+                                                       message_id)).is_ok());
+            assert_eq!(membrane.sender_key(&claimed), None);
+        }
+        assert!(membrane.message_received(response(&responders[quorum - 1],
+            &responders[quorum - 1], &sender_key, message_id)).is_ok());
+        assert_eq!(membrane.sender_key(&claimed), Some(sender_key));
+        assert_eq!(stats.lock().unwrap().call_count, 0);
+        assert!(membrane.message_received(claim()).is_ok());
+        assert_eq!(stats.lock().unwrap().call_count, 1);
+    }
+
+    #[test]
+    fn unknown_group_sender_forwarded_to_other_group() {
+        let stats = Arc::new(Mutex::new(Stats::new()));
+        let mut membrane = populate_routing_node();
+        membrane.interface = Box::new(TestInterface { stats: stats.clone() });
+        let mut destination : NameType = Random::generate_random();
+        while membrane.address_in_close_group_range(&destination) {
+            destination = Random::generate_random();
+        }
+        let sender = Id::new();
+        let message = RoutingMessage {
+            destination : DestinationAddress::Direct(destination.clone()),
+            source      : SourceAddress::Direct(sender.name()),
+            orig_message: None,
+            message_type: MessageType::PutData(Data::ImmutableData(ImmutableData::new(
+                ImmutableDataType::Normal, vec![1, 2, 3]))),
+            message_id  : random::<u32>(),
+            authority   : Authority::NaeManager(sender.name()),
+        };
+        assert_eq!(our_authority(&message, &membrane.routing_table), Authority::Unknown);
+        assert_eq!(membrane.sender_key(&message), None);
+
+        // we can not check the claim, but the group it is bound for does, so it is passed on
+        let signed_message = SignedMessage::new(&message, sender.signing_private_key()).unwrap();
+        assert!(membrane.message_received(signed_message).is_ok());
+        assert!(membrane.filter.check(&message.get_filter()));
+        assert!(membrane.group_key_requests.is_empty());
+        assert_eq!(stats.lock().unwrap().call_count, 0);
+    }
+
+    // A client we relayed for until its connection dropped, so messages for it are held.
+    fn departed_client(membrane: &mut RoutingMembrane<TestInterface>)
+            -> (crypto::sign::PublicKey, crypto::sign::SecretKey) {
+        let sign_keys = crypto::sign::gen_keypair();
+        let client = Id::with_keys(sign_keys.clone(), crypto::box_::gen_keypair());
+        let endpoint = random_endpoint();
+        assert!(membrane.relay_map.add_client(PublicId::new(&client), endpoint.clone()));
+        assert!(membrane.relay_map.drop_endpoint(&endpoint).is_some());
+        sign_keys
+    }
+
+    #[test]
+    fn rate_limited_get_data_refused() {
+        let mut membrane = create_membrane(Arc::new(Mutex::new(Stats::new())));
+        let (client_key, client_secret_key) = departed_client(&mut membrane);
+        let message = RoutingMessage {
+            destination : DestinationAddress::Direct(Random::generate_random()),
+            source      : SourceAddress::RelayedForClient(membrane.id.name(), client_key),
+            orig_message: None,
+            message_type: MessageType::GetData(DataRequest::PlainData),
+            message_id  : random::<u32>(),
+            authority   : Authority::Client(client_key),
+        };
+        let signed_message = SignedMessage::new(&message, &client_secret_key).unwrap();
+        match membrane.refuse_rate_limited(signed_message, message) {
+            Err(RoutingError::Response(ResponseError::RateLimitExceeded)) => {},
+            _ => panic!("Expected RateLimitExceeded"),
+        }
+
+        // the refusal is held for the client, as its connection dropped
+        let pending_messages = membrane.relay_map.take_pending_messages(&client_key);
+        assert_eq!(pending_messages.len(), 1);
+        let response = ::wire_format::decode::<SignedMessage>(&pending_messages[0]).unwrap()
+                           .get_routing_message().unwrap();
+        match response.message_type {
+            MessageType::GetDataErrorResponse(error_return) => {
+                assert_eq!(error_return.error, ResponseError::RateLimitExceeded);
+                assert!(error_return.verify_request_came_from(&client_key));
+            },
+            _ => panic!("Expected a GetDataErrorResponse"),
+        }
+    }
+
+    #[test]
+    fn structured_data_history_not_stored() {
+        let stats = Arc::new(Mutex::new(Stats::new()));
+        let mut membrane = create_membrane(stats.clone());
+        let (client_key, client_secret_key) = departed_client(&mut membrane);
+        let history = Data::StructuredDataHistory(StructuredDataHistory::new(
+            StructuredData::new(0, Random::generate_random(), vec![], vec![client_key], 0,
+                                vec![client_key], vec![]), 2));
+        let message = RoutingMessage {
+            destination : DestinationAddress::Direct(history.name()),
+            source      : SourceAddress::RelayedForClient(membrane.id.name(), client_key),
+            orig_message: None,
+            message_type: MessageType::PutData(history.clone()),
+            message_id  : random::<u32>(),
+            authority   : Authority::Client(client_key),
+        };
+        let signed_message = SignedMessage::new(&message, &client_secret_key).unwrap();
+        match membrane.relayed_message_received(Address::Client(client_key),
+                                                signed_message.clone(), 0) {
+            Err(RoutingError::Response(ResponseError::InvalidRequest)) => {},
+            _ => panic!("Expected InvalidRequest"),
+        }
+
+        // the refusal is held for the client, as its connection dropped
+        let pending_messages = membrane.relay_map.take_pending_messages(&client_key);
+        assert_eq!(pending_messages.len(), 1);
+        let response = ::wire_format::decode::<SignedMessage>(&pending_messages[0]).unwrap()
+                           .get_routing_message().unwrap();
+        match response.message_type {
+            MessageType::PutDataResponse(error_return, _) =>
+                assert_eq!(error_return.error, ResponseError::InvalidRequest),
+            _ => panic!("Expected a PutDataResponse"),
+        }
+
+        // in a batch only the history is refused, without asking the persona
+        let our_authority = Authority::ClientManager(Random::generate_random());
+        assert_eq!(membrane.handle_batch_item(&signed_message, &message, our_authority),
+                   BatchItemResult::Failed(ResponseError::InvalidRequest));
+        assert_eq!(stats.lock().unwrap().call_count, 0);
+    }
+
+    fn connect_response(requester_id: NameType, peer: &Id, request: &SignedMessage)
+            -> ConnectResponse {
+        ConnectResponse {
+            requester_local_endpoints: vec![],
+            requester_external_endpoints: vec![],
+            receiver_local_endpoints: vec![random_endpoint()],
+            receiver_external_endpoints: vec![],
+            requester_id: requester_id,
+            receiver_id: peer.name(),
+            receiver_fob: PublicId::new(peer),
+            serialised_connect_request: request.encoded_body().clone(),
+            connect_request_signature: request.signature().clone(),
+        }
+    }
+
+    #[test]
+    fn connect_response_returns_our_request() {
+        let mut membrane = create_membrane(Arc::new(Mutex::new(Stats::new())));
+        let our_name = membrane.id.name();
+        let peer = Id::new();
+        let mut message = RoutingMessage {
+            destination : DestinationAddress::Direct(peer.name()),
+            source      : SourceAddress::Direct(our_name.clone()),
+            orig_message: None,
+            message_type: MessageType::ConnectRequest(ConnectRequest {
+                local_endpoints: vec![random_endpoint()],
+                external_endpoints: vec![],
+                requester_id: our_name.clone(),
+                receiver_id: peer.name(),
+                requester_fob: PublicId::new(&membrane.id),
+            }),
+            message_id  : random::<u32>(),
+            authority   : Authority::ManagedNode,
+        };
+
+        // our request is read from the signed body and verified; the peer is then refused, as
+        // it is not relocated
+        let request = SignedMessage::new(&message, membrane.id.signing_private_key()).unwrap();
+        match membrane.handle_connect_response(connect_response(our_name.clone(), &peer,
+                                                                &request)) {
+            Err(RoutingError::RejectedPublicId) => {},
+            _ => panic!("Expected RejectedPublicId"),
+        }
+
+        // a request we did not sign, or a body which is not a ConnectRequest, is refused
+        let forged = SignedMessage::new(&message, peer.signing_private_key()).unwrap();
+        match membrane.handle_connect_response(connect_response(our_name.clone(), &peer,
+                                                                &forged)) {
+            Err(RoutingError::Response(ResponseError::InvalidRequest)) => {},
+            _ => panic!("Expected InvalidRequest"),
+        }
+        message.message_type = MessageType::FindGroup;
+        let other = SignedMessage::new(&message, membrane.id.signing_private_key()).unwrap();
+        match membrane.handle_connect_response(connect_response(our_name, &peer, &other)) {
+            Err(RoutingError::Response(ResponseError::InvalidRequest)) => {},
+            _ => panic!("Expected InvalidRequest"),
+        }
+    }
+
+    #[test]
+    fn unknown_connection_limits() {
+        let mut membrane = create_membrane(Arc::new(Mutex::new(Stats::new())));
+        membrane.set_unknown_connection_limits(Duration::seconds(60), 1);
+        let first_endpoint = random_endpoint();
+        membrane.handle_new_connection(first_endpoint.clone());
+        membrane.handle_new_connection(random_endpoint());
+        assert!(membrane.relay_map.lookup_unknown_connection(&first_endpoint));
+        assert_eq!(membrane.relay_map.refused_unknown_connections(), 1);
+    }
+
+    #[test]
+    fn subnet_limits() {
+        use std::net::{Ipv4Addr, SocketAddrV4, SocketAddr};
+        let endpoint = |host: u8| Endpoint::Tcp(SocketAddr::V4(SocketAddrV4::new(
+            Ipv4Addr::new(1, 2, 3, host), 5483)));
+        let mut membrane = create_membrane(Arc::new(Mutex::new(Stats::new())));
+        membrane.set_subnet_limits(SubnetLimits::new(1, 1), SubnetLimits::new(2, 2));
+
+        assert!(membrane.routing_table.add_node(routing_table::NodeInfo::new(
+            PublicId::new(&Id::new()), vec![endpoint(1)], vec![endpoint(1)])).0);
+        assert!(!membrane.routing_table.add_node(routing_table::NodeInfo::new(
+            PublicId::new(&Id::new()), vec![endpoint(2)], vec![endpoint(2)])).0);
+
+        assert!(membrane.relay_map.add_client(PublicId::new(&Id::new()), endpoint(3)));
+        assert!(membrane.relay_map.add_client(PublicId::new(&Id::new()), endpoint(4)));
+        assert!(!membrane.relay_map.add_client(PublicId::new(&Id::new()), endpoint(5)));
+    }
+
+    fn client_i_am(address: Address, client: &Id) -> Bytes {
+        ::wire_format::encode(&IAm::new(address, PublicId::new(client), supported_capabilities()))
+            .unwrap()
+    }
+
+    #[test]
+    fn incompatible_protocol() {
+        let mut membrane = create_membrane(Arc::new(Mutex::new(Stats::new())));
+
+        // a peer of an earlier release is refused
+        let peer = Id::new();
+        let endpoint = random_endpoint();
+        membrane.handle_new_connection(endpoint.clone());
+        let legacy_i_am = utils::encode(&LegacyIAm { address: Address::Node(peer.name()),
+                                                     public_id: PublicId::new(&peer) }).unwrap();
+        match membrane.handle_i_am(&endpoint, legacy_i_am) {
+            Err(RoutingError::IncompatibleProtocol(min_version, max_version)) => {
+                assert_eq!(min_version, LEGACY_PROTOCOL_VERSION);
+                assert_eq!(max_version, LEGACY_PROTOCOL_VERSION);
+            },
+            _ => panic!("Expected IncompatibleProtocol"),
+        }
+        assert!(!membrane.relay_map.lookup_unknown_connection(&endpoint));
+        assert!(membrane.lookup_endpoint(&endpoint).is_none());
+
+        // a peer refusing us is dropped
+        let endpoint = random_endpoint();
+        membrane.handle_new_connection(endpoint.clone());
+        let refusal = ProtocolRefusal { min_version: PROTOCOL_VERSION + 1,
+                                        max_version: PROTOCOL_VERSION + 2 };
+        match membrane.handle_i_am(&endpoint, ::wire_format::encode(&refusal).unwrap()) {
+            Err(RoutingError::IncompatibleProtocol(min_version, max_version)) => {
+                assert_eq!(min_version, PROTOCOL_VERSION + 1);
+                assert_eq!(max_version, PROTOCOL_VERSION + 2);
+            },
+            _ => panic!("Expected IncompatibleProtocol"),
+        }
+        assert!(!membrane.relay_map.lookup_unknown_connection(&endpoint));
+    }
+
+    #[test]
+    fn client_proves_key() {
+        let mut membrane = create_membrane(Arc::new(Mutex::new(Stats::new())));
+        let client = Id::new();
+        let client_key = client.signing_public_key();
+        // the client was relayed for before, until its connection dropped
+        let departed_endpoint = random_endpoint();
+        assert!(membrane.relay_map.add_client(PublicId::new(&client), departed_endpoint.clone()));
+        assert!(membrane.relay_map.drop_endpoint(&departed_endpoint).is_some());
+        assert!(membrane.relay_map.queue_pending_message(client_key, vec![1, 2, 3]));
+
+        // a client can not identify with someone else's key
+        let forged_endpoint = random_endpoint();
+        membrane.handle_new_connection(forged_endpoint.clone());
+        let forged_i_am = client_i_am(Address::Client(Id::new().signing_public_key()), &client);
+        match membrane.handle_i_am(&forged_endpoint, forged_i_am) {
+            Err(RoutingError::BadAuthority) => {},
+            _ => panic!("Expected BadAuthority"),
+        }
+        assert!(!membrane.relay_map.lookup_unknown_connection(&forged_endpoint));
+        assert!(membrane.key_challenges.is_empty());
+
+        // nor be relayed for without answering our challenge
+        let endpoint = random_endpoint();
+        membrane.handle_new_connection(endpoint.clone());
+        assert!(membrane.handle_i_am(&endpoint, client_i_am(Address::Client(client_key), &client))
+                        .is_ok());
+        assert!(membrane.key_challenges.contains_key(&endpoint));
+        assert!(membrane.relay_map.lookup_unknown_connection(&endpoint));
+        assert!(!membrane.relay_map.contains_endpoint(&endpoint));
+
+        // a wrong answer drops the connection, and the held messages are kept
+        let wrong_answer = membrane.key_challenges[&endpoint].1
+                               .answer(&membrane.id.name(), Id::new().signing_private_key());
+        match membrane.handle_key_challenge_response(&endpoint,
+                ::wire_format::encode(&wrong_answer).unwrap()) {
+            Err(RoutingError::FailedSignature) => {},
+            _ => panic!("Expected FailedSignature"),
+        }
+        assert!(membrane.key_challenges.is_empty());
+        assert!(!membrane.relay_map.lookup_unknown_connection(&endpoint));
+        assert!(!membrane.relay_map.contains_endpoint(&endpoint));
+        assert_eq!(membrane.relay_map.take_pending_messages(&client_key).len(), 1);
+
+        // the right answer gets the client relayed for
+        let endpoint = random_endpoint();
+        membrane.handle_new_connection(endpoint.clone());
+        assert!(membrane.handle_i_am(&endpoint, client_i_am(Address::Client(client_key), &client))
+                        .is_ok());
+        let answer: KeyChallengeResponse = membrane.key_challenges[&endpoint].1
+                                               .answer(&membrane.id.name(),
+                                                       client.signing_private_key());
+        assert!(membrane.handle_key_challenge_response(&endpoint,
+                    ::wire_format::encode(&answer).unwrap()).is_ok());
+        assert!(membrane.key_challenges.is_empty());
+        assert!(!membrane.relay_map.lookup_unknown_connection(&endpoint));
+        assert!(membrane.relay_map.contains_endpoint(&endpoint));
+        assert!(membrane.connection_protocol(&endpoint).is_some());
+
+        // a client speaking the protocol from before key challenges is refused
+        let endpoint = random_endpoint();
+        membrane.handle_new_connection(endpoint.clone());
+        let mut old_i_am = IAm::new(Address::Client(client_key), PublicId::new(&client),
+                                    supported_capabilities());
+        old_i_am.max_version = KEY_CHALLENGE_VERSION - 1;
+        match membrane.handle_i_am(&endpoint, ::wire_format::encode(&old_i_am).unwrap()) {
+            Err(RoutingError::IncompatibleProtocol(_, max_version)) =>
+                assert_eq!(max_version, KEY_CHALLENGE_VERSION - 1),
+            _ => panic!("Expected IncompatibleProtocol"),
+        }
+        assert!(membrane.key_challenges.is_empty());
+        assert!(!membrane.relay_map.lookup_unknown_connection(&endpoint));
+        assert!(!membrane.relay_map.contains_endpoint(&endpoint));
+    }
+
     #[test]
     fn call_refresh() {
         let mut array = [0u8; 64];
@@ -2049,12 +3558,75 @@ fn populate_routing_node() -> RoutingMembrane<TestInterface> {
     #[test]
     fn call_handle_get_data() {
         let get_data = MessageType::GetData(DataRequest::ImmutableData(ImmutableDataType::Normal));
-        assert_eq!(Tester::new().call_operation(get_data,
-            SourceAddress::Direct(Random::generate_random()),
+        // the membrane signs the message, so it is the one sender it knows the key of
+        let mut tester = Tester::new();
+        let our_name = tester.membrane.id.name();
+        assert_eq!(tester.call_operation(get_data,
+            SourceAddress::Direct(our_name),
             DestinationAddress::Direct(Random::generate_random()),
             Authority::NaeManager(Random::generate_random())).call_count, 1usize);
     }
 
+    #[test]
+    fn call_handle_get_data_batch() {
+        let get_data_batch = MessageType::GetDataBatch(vec![
+            (Random::generate_random(), DataRequest::ImmutableData(ImmutableDataType::Normal)),
+            (Random::generate_random(), DataRequest::ImmutableData(ImmutableDataType::Backup)),
+            (Random::generate_random(),
+             DataRequest::ImmutableData(ImmutableDataType::Sacrificial))]);
+        let mut tester = Tester::new();
+        let our_name = tester.membrane.id.name();
+        assert_eq!(tester.call_operation(get_data_batch,
+            SourceAddress::Direct(our_name),
+            DestinationAddress::Direct(Random::generate_random()),
+            Authority::NaeManager(Random::generate_random())).call_count, 3usize);
+    }
+
+    #[test]
+    fn batch_items_outside_group_refused() {
+        let mut membrane = populate_routing_node();
+        let (client_key, client_secret_key) = departed_client(&mut membrane);
+        let close_name = membrane.routing_table.our_close_group()[0].id();
+        // the furthest name from ours, which is all zeros
+        let far_name = NameType([255u8; NAME_TYPE_LEN]);
+        let message = RoutingMessage {
+            destination : DestinationAddress::Direct(close_name.clone()),
+            source      : SourceAddress::RelayedForClient(membrane.id.name(), client_key),
+            orig_message: None,
+            message_type: MessageType::GetDataBatch(vec![
+                (close_name.clone(), DataRequest::ImmutableData(ImmutableDataType::Normal)),
+                (far_name, DataRequest::PlainData),
+                (close_name, DataRequest::PlainData)]),
+            message_id  : random::<u32>(),
+            authority   : Authority::Client(client_key),
+        };
+        let signed_message = SignedMessage::new(&message, &client_secret_key).unwrap();
+        assert!(membrane.handle_batch(signed_message, message).is_ok());
+
+        // the results are held for the client in the order of the items
+        let pending_messages = membrane.relay_map.take_pending_messages(&client_key);
+        assert_eq!(pending_messages.len(), 1);
+        let response = ::wire_format::decode::<SignedMessage>(&pending_messages[0]).unwrap()
+                           .get_routing_message().unwrap();
+        let results = match response.message_type {
+            MessageType::BatchResponse(batch_response) => {
+                assert!(batch_response.verify_request_came_from(&client_key));
+                batch_response.results
+            },
+            _ => panic!("Expected a BatchResponse"),
+        };
+        assert_eq!(results.len(), 3);
+        match results[0] {
+            BatchItemResult::Data(_) => {},
+            _ => panic!("Expected Data"),
+        }
+        assert_eq!(results[1], BatchItemResult::Failed(ResponseError::InvalidRequest));
+        match results[2] {
+            BatchItemResult::Data(_) => {},
+            _ => panic!("Expected Data"),
+        }
+    }
+
     #[test]
     fn call_handle_get_data_response() {
         let mut tester = Tester::new();
@@ -2066,7 +3638,8 @@ fn populate_routing_node() -> RoutingMembrane<TestInterface> {
                            DataRequest::ImmutableData(ImmutableDataType::Normal));
 
         let message = RoutingMessage {
-            destination : DestinationAddress::Direct(Random::generate_random()),
+            destination : DestinationAddress::Direct(ImmutableData::new(ImmutableDataType::Normal,
+                              array.iter().map(|&x|x).collect::<Vec<_>>()).name()),
             source      : SourceAddress::Direct(Random::generate_random()),
             orig_message: None,
             message_type: get_data,
@@ -2091,6 +3664,98 @@ fn populate_routing_node() -> RoutingMembrane<TestInterface> {
             Authority::NaeManager(Random::generate_random())).call_count, 1usize);
     }
 
+    #[test]
+    fn drop_mismatched_get_data_response() {
+        let mut tester = Tester::new();
+
+        let mut array = [0u8; 64];
+        thread_rng().fill_bytes(&mut array);
+        let immutable_data = ImmutableData::new(ImmutableDataType::Normal,
+                                                array.iter().map(|&x|x).collect::<Vec<_>>());
+
+        // the name of the backup copy does not match the content as Normal data
+        let get_data = MessageType::GetData(
+                           DataRequest::ImmutableData(ImmutableDataType::Normal));
+        let message = RoutingMessage {
+            destination : DestinationAddress::Direct(ImmutableData::new(ImmutableDataType::Backup,
+                              immutable_data.value().clone()).name()),
+            source      : SourceAddress::Direct(Random::generate_random()),
+            orig_message: None,
+            message_type: get_data,
+            message_id  : random::<u32>(),
+            authority   : Authority::NaeManager(Random::generate_random())
+        };
+
+        let signed_message = SignedMessage::new(&message, &tester.membrane.id.signing_private_key()).unwrap();
+
+        let get_data_response = MessageType::GetDataResponse(
+            GetDataResponse {
+                data: Data::ImmutableData(immutable_data),
+                orig_request   : signed_message,
+                group_pub_keys : BTreeMap::new(),
+            });
+
+        assert_eq!(tester.call_operation(get_data_response,
+            SourceAddress::Direct(Random::generate_random()),
+            DestinationAddress::Direct(Random::generate_random()),
+            Authority::NaeManager(Random::generate_random())).call_count, 0usize);
+        assert_eq!(tester.membrane.mismatched_data_responses, 1usize);
+    }
+
+    #[test]
+    fn report_only_signed_mismatched_sources() {
+        let mut tester = Tester::new();
+        let member = Id::new();
+        assert!(tester.membrane.routing_table.add_node(routing_table::NodeInfo::new(
+            PublicId::new(&member), vec![], vec![random_endpoint()])).0);
+
+        // a response with data whose name does not match the requested name
+        let our_id = tester.membrane.id.clone();
+        let immutable_data = ImmutableData::new(ImmutableDataType::Normal, vec![1, 2, 3]);
+        let get_data = RoutingMessage {
+            destination : DestinationAddress::Direct(ImmutableData::new(ImmutableDataType::Backup,
+                              immutable_data.value().clone()).name()),
+            source      : SourceAddress::Direct(our_id.name()),
+            orig_message: None,
+            message_type: MessageType::GetData(
+                              DataRequest::ImmutableData(ImmutableDataType::Normal)),
+            message_id  : random::<u32>(),
+            authority   : Authority::ManagedNode,
+        };
+        let mismatched_response = |signer: &Id| {
+            let message = RoutingMessage {
+                destination : DestinationAddress::Direct(our_id.name()),
+                source      : SourceAddress::Direct(member.name()),
+                orig_message: None,
+                message_type: MessageType::GetDataResponse(GetDataResponse {
+                    data           : Data::ImmutableData(immutable_data.clone()),
+                    orig_request   : SignedMessage::new(&get_data,
+                                         our_id.signing_private_key()).unwrap(),
+                    group_pub_keys : BTreeMap::new(),
+                }),
+                message_id  : random::<u32>(),
+                authority   : Authority::ManagedNode,
+            };
+            SignedMessage::new(&message, signer.signing_private_key()).unwrap()
+        };
+        let forged = mismatched_response(&Id::new());
+        let signed = mismatched_response(&member);
+
+        // a node naming another node as the source does not get that node reported
+        match tester.membrane.message_received(forged) {
+            Err(RoutingError::DataNameMismatch) => {},
+            _ => panic!("Expected DataNameMismatch"),
+        }
+        assert!(tester.stats.lock().unwrap().misbehaving_sources.is_empty());
+
+        match tester.membrane.message_received(signed) {
+            Err(RoutingError::DataNameMismatch) => {},
+            _ => panic!("Expected DataNameMismatch"),
+        }
+        assert_eq!(tester.stats.lock().unwrap().misbehaving_sources, vec![member.name()]);
+        assert_eq!(tester.membrane.mismatched_data_responses, 2usize);
+    }
+
     #[test]
     fn call_handle_post() {
         let mut array = [0u8; 64];
@@ -2105,8 +3770,10 @@ fn populate_routing_node() -> RoutingMembrane<TestInterface> {
                                     0,
                                     vec![],
                                     vec![])));
-        assert_eq!(Tester::new().call_operation(post_data,
-                   SourceAddress::Direct(Random::generate_random()),
+        let mut tester = Tester::new();
+        let our_name = tester.membrane.id.name();
+        assert_eq!(tester.call_operation(post_data,
+                   SourceAddress::Direct(our_name),
                    DestinationAddress::Direct(Random::generate_random()),
                    Authority::NaeManager(Random::generate_random())).call_count, 1usize);
     }
@@ -2152,12 +3819,26 @@ fn populate_routing_node() -> RoutingMembrane<TestInterface> {
         let mut array = [0u8; 64];
         thread_rng().fill_bytes(&mut array);
         let refresh = MessageType::Refresh(random::<u64>(), array.iter().map(|&x|x).collect::<Vec<_>>());
-        assert_eq!(Tester::new().call_operation(refresh,
-                   SourceAddress::Direct(Random::generate_random()),
+        let mut tester = Tester::new();
+        let our_name = tester.membrane.id.name();
+        assert_eq!(tester.call_operation(refresh,
+                   SourceAddress::Direct(our_name),
                    DestinationAddress::Direct(Random::generate_random()),
                    Authority::NaeManager(Random::generate_random())).call_count, 1usize);
     }
 
+    #[test]
+    fn network_size_estimates_only_from_close_group() {
+        let mut membrane = populate_routing_node();
+        membrane.update_network_size_estimate(Random::generate_random(), 1_000_000);
+        assert!(membrane.network_size_estimates.is_empty());
+
+        let close_node = membrane.routing_table.our_close_group()[0].id();
+        membrane.update_network_size_estimate(close_node, 1_000_000);
+        assert_eq!(membrane.network_size_estimates.len(), 1);
+        assert!(membrane.network_size_estimate > 1_000_000 / 2 - 1);
+    }
+
     #[test]
     fn relocate_original_public_id() {
         let mut routing_node = populate_routing_node();
@@ -23,6 +23,8 @@ use std::sync::mpsc;
 use std::boxed::Box;
 use std::thread;
 use std::marker::PhantomData;
+use std::collections::BTreeSet;
+use time::Duration;
 
 use crust;
 use NameType;
@@ -30,9 +32,13 @@ use node_interface::{Interface, CreatePersonas};
 use routing_membrane::RoutingMembrane;
 use id::Id;
 use public_id::PublicId;
-use who_are_you::IAm;
-use types::{MessageId, SourceAddress, DestinationAddress, Address};
-use utils::{encode, decode};
+use who_are_you::{IAm, Capability, NegotiatedProtocol, ProtocolRefusal,
+                  supported_capabilities, only_supported};
+use compression;
+use compression::COMPRESSION_THRESHOLD;
+use types::{MessageId, SourceAddress, DestinationAddress, Address, Bytes, RateLimits,
+            SubnetLimits};
+use wire_format::{encode, decode};
 use authority::{Authority};
 use messages::{RoutingMessage, SignedMessage, MessageType, ConnectRequest};
 use error::{RoutingError};
@@ -56,6 +62,12 @@ pub struct RoutingNode<F, G> where F : Interface + 'static,
     own_name: NameType,
     next_message_id: MessageId,
     bootstrap: Option<(Endpoint, Option<NameType>)>,
+    disjoint_paths: usize,
+    relay_rate_limits: RateLimits,
+    unknown_connection_limits: Option<(Duration, usize)>,
+    subnet_limits: Option<(SubnetLimits, SubnetLimits)>,
+    capabilities: BTreeSet<Capability>,
+    compression_threshold: usize,
 }
 
 impl<F, G> RoutingNode<F, G> where F : Interface + 'static,
@@ -70,9 +82,54 @@ impl<F, G> RoutingNode<F, G> where F : Interface + 'static,
                       own_name : own_name.clone(),
                       next_message_id: rand::random::<MessageId>(),
                       bootstrap: None,
+                      disjoint_paths: 1,
+                      relay_rate_limits: RateLimits::default(),
+                      unknown_connection_limits: None,
+                      subnet_limits: None,
+                      capabilities: supported_capabilities(),
+                      compression_threshold: COMPRESSION_THRESHOLD,
                     }
     }
 
+    /// Send requests originating at this node along up to `path_count` paths with distinct first
+    /// hops once it joined the network, so the interface is told which copies reached the
+    /// destination.
+    pub fn set_disjoint_paths(&mut self, path_count: usize) {
+        self.disjoint_paths = path_count;
+    }
+
+    /// Limit the rate at which each client we relay for can send requests into the network,
+    /// once this node joined the network.
+    pub fn set_relay_rate_limits(&mut self, rate_limits: RateLimits) {
+        self.relay_rate_limits = rate_limits;
+    }
+
+    /// Limit how long a new connection may stay unidentified before it is dropped, and how many
+    /// unidentified connections are accepted at the same time, once this node joined the
+    /// network.
+    pub fn set_unknown_connection_limits(&mut self, expiry: Duration,
+                                         max_unknown_connections: usize) {
+        self.unknown_connection_limits = Some((expiry, max_unknown_connections));
+    }
+
+    /// Limit the number of contacts accepted per IP subnet into the routing table, and the number
+    /// of contacts relayed for per IP subnet, once this node joined the network.
+    pub fn set_subnet_limits(&mut self, routing_table_limits: SubnetLimits,
+                             relay_limits: SubnetLimits) {
+        self.subnet_limits = Some((routing_table_limits, relay_limits));
+    }
+
+    /// Sets the optional protocol features this node announces to its peers, leaving out those it
+    /// does not support.
+    pub fn set_capabilities(&mut self, capabilities: BTreeSet<Capability>) {
+        self.capabilities = only_supported(&capabilities);
+    }
+
+    /// Sets the size from which messages are compressed, on connections that agreed to it.
+    pub fn set_compression_threshold(&mut self, threshold: usize) {
+        self.compression_threshold = threshold;
+    }
+
     /// Run the Routing Node.
     /// This is a blocking call which will start a CRUST connection
     /// manager and the CRUST bootstrapping procedures.
@@ -87,6 +144,10 @@ impl<F, G> RoutingNode<F, G> where F : Interface + 'static,
         let mut possible_first = true;
         let mut relocated_name : Option<NameType> = None;
         let mut sent_name_request = false;
+        // messages for the membrane that arrive through our relay before we have joined
+        let mut pending_messages : Vec<(Endpoint, Bytes)> = Vec::new();
+        // what we agreed on with our bootstrap node, handed over to the membrane
+        let mut bootstrap_protocol : Option<NegotiatedProtocol> = None;
 
         let (event_output, event_input) = mpsc::channel();
         let mut cm = crust::ConnectionManager::new(event_output.clone());
@@ -96,6 +157,11 @@ impl<F, G> RoutingNode<F, G> where F : Interface + 'static,
             match event_input.recv() {
                 Err(_) => return Err(RoutingError::FailedToBootstrap),
                 Ok(crust::Event::NewMessage(endpoint, bytes)) => {
+                    // our bootstrap node compresses once we agreed on it
+                    let bytes = match compression::decompress(bytes) {
+                        Ok(bytes) => bytes,
+                        Err(_) => continue,
+                    };
                     let mut new_bootstrap_name :
                         Option<(Endpoint, Option<NameType>)> = None;
                     match self.bootstrap {
@@ -115,6 +181,13 @@ impl<F, G> RoutingNode<F, G> where F : Interface + 'static,
                                                           self.id.name());
                                                       break;
                                                 },
+                                                MessageType::FindGroupResponse(_, _) |
+                                                MessageType::ConnectRequest(_) |
+                                                MessageType::ConnectResponse(_) => {
+                                                    pending_messages.push(
+                                                        (endpoint.clone(), bytes.clone()));
+                                                    continue;
+                                                },
                                                 _ => continue,
                                             }
                                         }
@@ -124,6 +197,20 @@ impl<F, G> RoutingNode<F, G> where F : Interface + 'static,
                                     // Try to decode it as an IAm message
                                     match decode::<IAm>(&bytes) {
                                         Ok(he_is_msg) => {
+                                            match he_is_msg.negotiate(&self.capabilities) {
+                                                Ok(protocol) =>
+                                                    bootstrap_protocol = Some(protocol),
+                                                Err(error) => {
+                                                    // tell the relay why, before dropping it
+                                                    match encode(&ProtocolRefusal::new()) {
+                                                        Ok(refusal) => ignore(cm.send(
+                                                            bootstrap_endpoint.clone(), refusal)),
+                                                        Err(_) => {},
+                                                    }
+                                                    cm.drop_node(bootstrap_endpoint.clone());
+                                                    return Err(error);
+                                                },
+                                            }
                                             match he_is_msg.address {
                                                 Address::Node(node_name) => {
                                                     match *bootstrap_name {
@@ -136,7 +223,14 @@ impl<F, G> RoutingNode<F, G> where F : Interface + 'static,
                                                 _ => continue, // only care about a Node
                                             }
                                         },
-                                        Err(_) => continue,
+                                        // or the relay refused us, speaking none of our versions
+                                        Err(_) => match decode::<ProtocolRefusal>(&bytes) {
+                                            Ok(refusal) => {
+                                                cm.drop_node(bootstrap_endpoint.clone());
+                                                return Err(refusal.to_error());
+                                            },
+                                            Err(_) => continue,
+                                        },
                                     };
                                 }
                             };
@@ -199,11 +293,12 @@ impl<F, G> RoutingNode<F, G> where F : Interface + 'static,
                             // register the bootstrap endpoint
                             self.bootstrap = Some((endpoint.clone(), None));
                             // and send an IAm message to our bootstrap endpoint
-                            let i_am_message = try!(encode(&IAm {
-                                // before we retrieve a name for ourselves from the network
-                                // we identify ourselves with the sign::PublicKey
-                                address: Address::Client(self.id.signing_public_key()),
-                                public_id: PublicId::new(&self.id)}));
+                            // before we retrieve a name for ourselves from the network
+                            // we identify ourselves with our provisional name, so the
+                            // relay can verify it against our sign::PublicKey
+                            let i_am_message = try!(encode(&IAm::new(
+                                Address::Node(self.id.name()), PublicId::new(&self.id),
+                                self.capabilities.clone())));
                             ignore(cm.send(endpoint, i_am_message));
                         },
                         Some(_) => {
@@ -231,9 +326,13 @@ impl<F, G> RoutingNode<F, G> where F : Interface + 'static,
                     None => return Err(RoutingError::FailedToBootstrap)
                 };
 
-                // send FindGroup request before moving to Membrane
-                let find_group_msg =
-                    try!(self.construct_find_group_msg_as_client(&our_bootstrap.1));
+                // send FindGroup request for our relocated name before moving to Membrane
+                let relocated_target = match relocated_name {
+                    Some(ref new_name) => new_name.clone(),
+                    None => return Err(RoutingError::FailedToBootstrap),
+                };
+                let find_group_msg = try!(self.construct_find_group_msg_as_client(
+                    &our_bootstrap.1, &relocated_target));
                 ignore(cm.send(our_bootstrap.0.clone(), try!(encode(&find_group_msg))));
 
                 Some(our_bootstrap)
@@ -245,10 +344,35 @@ impl<F, G> RoutingNode<F, G> where F : Interface + 'static,
         match relocated_name {
             Some(new_name) => {
                 self.id.assign_relocated_name(new_name);
+                // hand the messages we received while joining over to the membrane
+                for (endpoint, bytes) in pending_messages {
+                    ignore(event_output.send(crust::Event::NewMessage(endpoint, bytes)));
+                }
+                let bootstrap_endpoint = our_bootstrap.as_ref()
+                    .map(|&(ref endpoint, _)| endpoint.clone());
                 let mut membrane = RoutingMembrane::<F>::new(
                     cm, event_output, event_input, our_bootstrap,
                     self.id.clone(),
                     self.genesis.create_personas());
+                membrane.set_capabilities(self.capabilities.clone());
+                membrane.set_compression_threshold(self.compression_threshold);
+                match (bootstrap_endpoint, bootstrap_protocol) {
+                    (Some(endpoint), Some(protocol)) =>
+                        membrane.add_connection_protocol(endpoint, protocol),
+                    _ => {},
+                }
+                membrane.set_disjoint_paths(self.disjoint_paths);
+                membrane.set_relay_rate_limits(self.relay_rate_limits.clone());
+                match self.unknown_connection_limits {
+                    Some((expiry, max_unknown_connections)) =>
+                        membrane.set_unknown_connection_limits(expiry, max_unknown_connections),
+                    None => {},
+                }
+                match self.subnet_limits.clone() {
+                    Some((routing_table_limits, relay_limits)) =>
+                        membrane.set_subnet_limits(routing_table_limits, relay_limits),
+                    None => {},
+                }
                 // TODO: currently terminated by main, should be signalable to terminate
                 // and join the routing_node thread.
                 spawn(move || membrane.run());
@@ -266,8 +390,8 @@ impl<F, G> RoutingNode<F, G> where F : Interface + 'static,
 
         let message =  RoutingMessage {
             destination  : DestinationAddress::Direct(our_unrelocated_id.name()),
-            source       : SourceAddress::RelayedForClient(relay_name.clone(),
-                self.id.signing_public_key()),
+            source       : SourceAddress::RelayedForNode(relay_name.clone(),
+                our_unrelocated_id.name()),
             orig_message : None,
             message_type : MessageType::PutPublicId(our_unrelocated_id.clone()),
             message_id   : message_id.clone(),
@@ -278,15 +402,14 @@ impl<F, G> RoutingNode<F, G> where F : Interface + 'static,
     }
 
 
-    fn construct_find_group_msg_as_client(&mut self, bootstrap_name: &NameType)
-        -> Result<SignedMessage, CborError> {
-        let name   = self.id.name().clone();
+    fn construct_find_group_msg_as_client(&mut self, bootstrap_name: &NameType,
+        relocated_name: &NameType) -> Result<SignedMessage, CborError> {
         let message_id = self.get_next_message_id();
 
         let message = RoutingMessage {
-            destination  : DestinationAddress::Direct(name.clone()),
-            source       : SourceAddress::RelayedForClient(bootstrap_name.clone(),
-                self.id.signing_public_key()),
+            destination  : DestinationAddress::Direct(relocated_name.clone()),
+            source       : SourceAddress::RelayedForNode(bootstrap_name.clone(),
+                self.id.name()),
             orig_message : None,
             message_type : MessageType::FindGroup,
             message_id   : message_id,
@@ -17,14 +17,17 @@
 
 use std::cmp;
 use std::usize;
-use std::collections::{HashMap};
+use std::u64;
+use std::collections::{BTreeSet, HashMap};
 
 use crust::Endpoint;
 
 use common_bits::*;
+use error::RoutingError;
 use public_id::PublicId;
 use name_type::{closer_to_target, closer_to_target_or_equal, NameType};
 use types;
+use types::{Subnet, SubnetLimits};
 
 static BUCKET_SIZE: usize = 1;
 pub static PARALLELISM: usize = 4;
@@ -34,7 +37,7 @@ static OPTIMAL_SIZE: usize = 64;
 pub struct NodeInfo {
     pub fob: PublicId,
     pub endpoints: Vec<Endpoint>,
-    pub connected_endpoint: Option<Endpoint>,
+    pub connected_endpoints: Vec<Endpoint>,
     #[cfg(test)]
     pub id: NameType,
 }
@@ -42,11 +45,11 @@ pub struct NodeInfo {
 impl NodeInfo {
     #[cfg(not(test))]
     pub fn new(fob: PublicId, endpoints: Vec<Endpoint>,
-               connected_endpoint: Option<Endpoint>) -> NodeInfo {
+               connected_endpoints: Vec<Endpoint>) -> NodeInfo {
         NodeInfo {
             fob: fob,
             endpoints: endpoints,
-            connected_endpoint: connected_endpoint,
+            connected_endpoints: connected_endpoints,
         }
     }
     #[cfg(not(test))]
@@ -56,12 +59,12 @@ impl NodeInfo {
 
     #[cfg(test)]
     pub fn new(fob: PublicId, endpoints: Vec<Endpoint>,
-               connected_endpoint: Option<Endpoint>) -> NodeInfo {
+               connected_endpoints: Vec<Endpoint>) -> NodeInfo {
         let id = fob.name();
         NodeInfo {
             fob: fob,
             endpoints: endpoints,
-            connected_endpoint: connected_endpoint,
+            connected_endpoints: connected_endpoints,
             id: id,
         }
     }
@@ -69,6 +72,19 @@ impl NodeInfo {
     pub fn id(&self) -> NameType {
         self.id.clone()
     }
+
+    /// Returns true if we hold at least one live connection to this node.
+    pub fn is_connected(&self) -> bool {
+        !self.connected_endpoints.is_empty()
+    }
+
+    /// Returns the distinct IP subnets of the live connections to this node.  The endpoints the
+    /// node reports for itself are not included, as it could claim any address.
+    pub fn subnets(&self) -> BTreeSet<Subnet> {
+        self.connected_endpoints.iter()
+                                .filter_map(|endpoint| Subnet::of_endpoint(endpoint))
+                                .collect::<BTreeSet<Subnet>>()
+    }
 }
 
 /// The RoutingTable class is used to maintain a list of contacts to which the node is connected.
@@ -76,17 +92,32 @@ pub struct RoutingTable {
     routing_table: Vec<NodeInfo>,
     lookup_map: HashMap<Endpoint, NameType>,
     our_id: NameType,
+    subnet_limits: SubnetLimits,
 }
 
 impl RoutingTable {
     pub fn new(our_id: &NameType) -> RoutingTable {
+        RoutingTable::with_subnet_limits(our_id,
+            SubnetLimits::new(types::MAX_ROUTING_NODES_PER_SUBNET,
+                              types::MAX_ROUTING_NODES_PER_SUBNET))
+    }
+
+    /// Creates a routing table accepting at most the given number of contacts per IP subnet.
+    pub fn with_subnet_limits(our_id: &NameType, subnet_limits: SubnetLimits) -> RoutingTable {
         RoutingTable {
             routing_table: Vec::<NodeInfo>::new(),
             lookup_map: HashMap::new(),
-            our_id: our_id.clone()
+            our_id: our_id.clone(),
+            subnet_limits: subnet_limits,
         }
     }
 
+    /// Sets the number of contacts accepted per IP subnet from now on.  Contacts already in the
+    /// table are kept.
+    pub fn set_subnet_limits(&mut self, subnet_limits: SubnetLimits) {
+        self.subnet_limits = subnet_limits;
+    }
+
     pub fn get_bucket_size() -> usize { BUCKET_SIZE }
 
     pub fn get_parallelism() -> usize { PARALLELISM }
@@ -101,7 +132,8 @@ impl RoutingTable {
     /// steps are used to determine whether to add the new contact or not:
     ///
     /// 1 - if the contact is ourself, or doesn't have a valid public key, or is already in the
-    ///     table, it will not be added
+    ///     table, or one of its IP subnets already reached its limit of contacts, it will not be
+    ///     added
     /// 2 - if the routing table is not full (size < OptimalSize()), the contact will be added
     /// 3 - if the contact is within our close group, it will be added
     /// 4 - if we can find a candidate for removal (a contact in a bucket with more than BUCKET_SIZE
@@ -116,6 +148,10 @@ impl RoutingTable {
             return (false, None);
         }
 
+        if self.check_subnet_limits(&their_info).is_err() {
+            return (false, None);
+        }
+
         if self.routing_table.len() < RoutingTable::get_optimal_size() {
             self.push_back_then_sort(their_info);
             return (true, None);
@@ -148,9 +184,42 @@ impl RoutingTable {
         (false, None)
     }
 
-    /// This changes the connected status of the peer from false to true.  Only one connection is
-    /// allowed per node, so this returns None if the endpoint doesn't exist anywhere in the table
-    /// or if the peer already has a connected endpoint.  Otherwise it returns the peer's ID.
+    /// Checks whether adding the contact would exceed the limit of contacts from any of the IP
+    /// subnets it is connected on.  Returns SubnetLimitReached if so.
+    pub fn check_subnet_limits(&self, their_info: &NodeInfo) -> Result<(), RoutingError> {
+        for subnet in their_info.subnets() {
+            try!(self.check_subnet_limit(&their_info.id(), &subnet));
+        }
+        Ok(())
+    }
+
+    /// Checks whether a live connection to the contact on the given endpoint would exceed the
+    /// limit of contacts from the endpoint's IP subnet.  Returns SubnetLimitReached if so.
+    pub fn check_connection_subnet_limits(&self, their_id: &NameType, endpoint: &Endpoint)
+            -> Result<(), RoutingError> {
+        match Subnet::of_endpoint(endpoint) {
+            Some(subnet) => self.check_subnet_limit(their_id, &subnet),
+            None => Ok(()),
+        }
+    }
+
+    fn check_subnet_limit(&self, their_id: &NameType, subnet: &Subnet)
+            -> Result<(), RoutingError> {
+        let contacts_in_subnet = self.routing_table.iter()
+            .filter(|node_info| node_info.id() != *their_id)
+            .filter(|node_info| node_info.subnets().contains(subnet))
+            .count();
+        if contacts_in_subnet >= self.subnet_limits.limit(subnet) {
+            return Err(RoutingError::SubnetLimitReached);
+        }
+        Ok(())
+    }
+
+    /// This marks the endpoint as a live connection to the peer which lists it among its
+    /// endpoints.  A peer can hold several live connections at once (e.g. on a local and an
+    /// external endpoint); an endpoint already marked as connected is not added twice.  This
+    /// returns None if the endpoint doesn't exist anywhere in the table, or if the connection
+    /// would exceed the limit of contacts from its IP subnet, otherwise it returns the peer's ID.
     pub fn mark_as_connected(&mut self, endpoint: &Endpoint) -> Option<NameType> {
         let has_endpoint = |ref node_info: &NodeInfo| {
             for ref candidate_endpoint in &node_info.endpoints {
@@ -163,7 +232,13 @@ impl RoutingTable {
         match self.routing_table.iter().position(has_endpoint) {
             None => None,
             Some(index) => {
-                self.routing_table[index].connected_endpoint = Some(endpoint.clone());
+                let their_id = self.routing_table[index].id();
+                if self.check_connection_subnet_limits(&their_id, endpoint).is_err() {
+                    return None;
+                }
+                if !self.routing_table[index].connected_endpoints.contains(endpoint) {
+                    self.routing_table[index].connected_endpoints.push(endpoint.clone());
+                }
                 // always force update lookup_map
                 self.lookup_map.remove(&endpoint);
                 self.lookup_map.entry(endpoint.clone())
@@ -173,6 +248,58 @@ impl RoutingTable {
         }
     }
 
+    /// This registers an additional live connection on the given endpoint for a peer already in
+    /// our routing table.  The endpoint is added to the peer's endpoints if it was not yet known.
+    /// Returns false if the peer is not in the table, its public id doesn't match the one we hold,
+    /// the endpoint is already assigned to a different peer, or the connection would exceed the
+    /// limit of contacts from the endpoint's IP subnet.
+    pub fn add_connection(&mut self, their_public_id: &PublicId, endpoint: &Endpoint) -> bool {
+        let their_id = their_public_id.name();
+        match self.lookup_map.get(endpoint) {
+            Some(name) => if *name != their_id { return false; },
+            None => {}
+        };
+        match self.routing_table.iter().position(|node_info| node_info.id() == their_id) {
+            None => false,
+            Some(index) => {
+                if self.routing_table[index].fob != *their_public_id {
+                    return false;
+                }
+                if self.check_connection_subnet_limits(&their_id, endpoint).is_err() {
+                    return false;
+                }
+                if !self.routing_table[index].endpoints.contains(endpoint) {
+                    self.routing_table[index].endpoints.push(endpoint.clone());
+                }
+                if !self.routing_table[index].connected_endpoints.contains(endpoint) {
+                    self.routing_table[index].connected_endpoints.push(endpoint.clone());
+                }
+                self.lookup_map.entry(endpoint.clone())
+                               .or_insert(their_id);
+                true
+            },
+        }
+    }
+
+    /// This removes a lost connection.  The peer is only removed from the routing table once its
+    /// last live connection is gone, in which case the dropped peer is returned; otherwise None.
+    pub fn drop_connection(&mut self, lost_endpoint: &Endpoint) -> Option<NodeInfo> {
+        let name = match self.lookup_map.remove(lost_endpoint) {
+            Some(name) => name,
+            None => return None,
+        };
+        let index = match self.routing_table.iter().position(|node_info| node_info.id() == name) {
+            Some(index) => index,
+            None => return None,
+        };
+        self.routing_table[index].connected_endpoints.retain(|endpoint| endpoint != lost_endpoint);
+        if self.routing_table[index].is_connected() {
+            return None;
+        }
+        self.remove_dangling_endpoints(&name);
+        Some(self.routing_table.remove(index))
+    }
+
     /// This is used to check whether it is worth while retrieving a contact's public key from the
     /// PKI with a view to adding the contact to our routing table.  The checking procedure is the
     /// same as for 'AddNode' above, except for the lack of a public key to check in step 1.
@@ -272,14 +399,39 @@ impl RoutingTable {
         result
     }
 
-    // /// This returns the public key for the given node if the node is in our table.
-    // pub fn public_id(&self, their_id: &NameType)->Option<PublicId> {
-    //     debug_assert!(self.is_nodes_sorted(), "RT::public_id: Nodes are not sorted");
-    //     match self.routing_table.iter().find(|&node_info| node_info.id() == *their_id) {
-    //         Some(node) => Some(node.fob.clone()),
-    //         None => None,
-    //     }
-    // }
+    /// This returns an estimate of the number of nodes in the network, derived from the density
+    /// of our close group.  In a network of N uniformly distributed names, the i-th closest node
+    /// to us is expected at a distance of i / N of the address space, and a node in bucket b is at
+    /// a distance of roughly 2^-(b + 1).  The estimate is the ratio of the summed ranks to the
+    /// summed distances.  While our close group is not yet full, we know all nodes there are.
+    pub fn estimate_network_size(&self) -> u64 {
+        let group_size = RoutingTable::get_group_size();
+        if self.routing_table.len() < group_size {
+            return self.routing_table.len() as u64 + 1;
+        }
+        let mut sum_of_ranks = 0f64;
+        let mut sum_of_distances = 0f64;
+        for (i, node_info) in self.routing_table.iter().take(group_size).enumerate() {
+            sum_of_ranks += (i + 1) as f64;
+            sum_of_distances += 2f64.powi(-(self.bucket_index(&node_info.id()) as i32 + 1));
+        }
+        // ourselves are part of the network as well
+        let estimate = sum_of_ranks / sum_of_distances + 1f64;
+        if estimate >= u64::MAX as f64 {
+            u64::MAX
+        } else {
+            cmp::max(estimate as u64, group_size as u64 + 1)
+        }
+    }
+
+    /// This returns the public id for the given node if the node is in our table.
+    pub fn public_id(&self, their_id: &NameType)->Option<PublicId> {
+        debug_assert!(self.is_nodes_sorted(), "RT::public_id: Nodes are not sorted");
+        match self.routing_table.iter().find(|&node_info| node_info.id() == *their_id) {
+            Some(node) => Some(node.fob.clone()),
+            None => None,
+        }
+    }
 
     pub fn lookup_endpoint(&self, their_endpoint: &Endpoint) -> Option<NameType> {
         debug_assert!(self.is_nodes_sorted(), "RT::Lookup: Nodes are not sorted");
@@ -295,6 +447,11 @@ impl RoutingTable {
         self.routing_table.len()
     }
 
+    /// Returns the names of all contacts, sorted by distance to our name.
+    pub fn contact_names(&self) -> Vec<NameType> {
+        self.routing_table.iter().map(|node_info| node_info.id()).collect()
+    }
+
     pub fn our_name(&self) -> NameType {
         self.our_id.clone()
     }
@@ -310,6 +467,16 @@ impl RoutingTable {
         closer_to_target_or_equal(&id, &furthest_close_node.id(), &self.our_id)
     }
 
+    /// Returns true if, as far as we know, the node is in the close group of the target, i.e.
+    /// fewer than 'GroupSize' of our contacts and ourselves are closer to the target than it.
+    /// This can be wrong in favour of the node for targets we know few contacts around.
+    pub fn is_plausibly_in_close_group_of(&self, node: &NameType, target: &NameType) -> bool {
+        is_plausibly_in_close_group_of(self.routing_table.iter()
+                                           .map(|node_info| node_info.id())
+                                           .chain(Some(self.our_id.clone()).into_iter()),
+                                       node, target)
+    }
+
     fn find_candidate_for_removal(&self) -> usize {
         assert!(self.routing_table.len() >= RoutingTable::get_optimal_size());
 
@@ -379,14 +546,11 @@ impl RoutingTable {
     }
 
     fn push_back_then_sort(&mut self, node_info: NodeInfo) {
-        match node_info.connected_endpoint.clone() {
-            Some(endpoint) => {
-                self.lookup_map.remove(&endpoint);
-                self.lookup_map.entry(endpoint.clone())
-                               .or_insert(node_info.id());
-            },
-            None => ()
-        };
+        for endpoint in &node_info.connected_endpoints {
+            self.lookup_map.remove(endpoint);
+            self.lookup_map.entry(endpoint.clone())
+                           .or_insert(node_info.id());
+        }
         self.routing_table.push(node_info);
         let our_id = &self.our_id;
         self.routing_table.sort_by(
@@ -436,6 +600,15 @@ impl RoutingTable {
     }
 }
 
+/// Returns true if fewer than 'GroupSize' of the known names, other than the node itself, are
+/// closer to the target than the node.  See RoutingTable::is_plausibly_in_close_group_of.
+pub fn is_plausibly_in_close_group_of<I>(known_names: I, node: &NameType, target: &NameType)
+        -> bool where I: Iterator<Item=NameType> {
+    let closer = known_names.filter(|name| name != node && closer_to_target(name, node, target))
+                            .count();
+    closer < types::GROUP_SIZE
+}
+
 
 
 #[cfg(test)]
@@ -455,7 +628,9 @@ mod test {
     use test_utils::{Random};
     use rand::{random, thread_rng};
     use crust::Endpoint;
+    use error::RoutingError;
     use rand::distributions::{IndependentSample, Range};
+    use types::{Subnet, SubnetLimits};
 
     enum ContactType {
         Far,
@@ -620,7 +795,7 @@ mod test {
             id: public_id.name(),
             fob: public_id,
             endpoints: random_endpoints(),
-            connected_endpoint: None,
+            connected_endpoints: vec![],
         }
     }
 
@@ -1146,7 +1321,7 @@ mod test {
         let mut count: usize = 0;
         loop {
             routing_table.add_node(
-                NodeInfo::new(PublicId::new(&Id::new()), random_endpoints(), None));
+                NodeInfo::new(PublicId::new(&Id::new()), random_endpoints(), vec![]));
             count += 1;
             if routing_table.size() >=
                 RoutingTable::get_optimal_size() { break; }
@@ -1304,6 +1479,156 @@ mod test {
         }
     }
 
+    #[test]
+    fn multiple_connections_test() {
+        let mut routing_table = RoutingTable::new(&Id::new().name());
+        let first_endpoint = random_endpoint();
+        let second_endpoint = random_endpoint();
+        let mut node_info = create_random_node_info();
+        node_info.endpoints = vec![first_endpoint.clone()];
+        node_info.connected_endpoints = vec![first_endpoint.clone()];
+        let node_id = node_info.id();
+        assert!(routing_table.add_node(node_info.clone()).0);
+
+        // a second connection from the same node is registered, not refused
+        assert!(routing_table.add_connection(&node_info.fob, &second_endpoint));
+        assert_eq!(routing_table.lookup_endpoint(&second_endpoint), Some(node_id.clone()));
+        assert_eq!(routing_table.our_close_group()[0].connected_endpoints,
+                   vec![first_endpoint.clone(), second_endpoint.clone()]);
+
+        // an unknown or mismatching public id is refused
+        assert!(!routing_table.add_connection(&PublicId::new(&Id::new()), &random_endpoint()));
+
+        // losing one connection keeps the node
+        assert!(routing_table.drop_connection(&first_endpoint).is_none());
+        assert_eq!(routing_table.size(), 1);
+        assert!(routing_table.lookup_endpoint(&first_endpoint).is_none());
+        assert_eq!(routing_table.our_close_group()[0].connected_endpoints,
+                   vec![second_endpoint.clone()]);
+
+        // marking a known endpoint as connected again restores the connection once
+        assert_eq!(routing_table.mark_as_connected(&first_endpoint), Some(node_id.clone()));
+        assert_eq!(routing_table.mark_as_connected(&first_endpoint), Some(node_id.clone()));
+        assert_eq!(routing_table.our_close_group()[0].connected_endpoints.len(), 2);
+
+        // losing the last connection drops the node
+        assert!(routing_table.drop_connection(&first_endpoint).is_none());
+        match routing_table.drop_connection(&second_endpoint) {
+            Some(dropped) => assert_eq!(dropped.id(), node_id),
+            None => panic!("Node should be dropped with its last connection"),
+        }
+        assert_eq!(routing_table.size(), 0);
+        assert!(routing_table.lookup_endpoint(&second_endpoint).is_none());
+    }
+
+    #[test]
+    fn estimate_network_size_test() {
+        let mut routing_table = RoutingTable::new(&Id::new().name());
+        assert_eq!(routing_table.estimate_network_size(), 1);
+
+        // while the close group is not full, the estimate is exact
+        for i in 0..RoutingTable::get_group_size() - 1 {
+            assert!(routing_table.add_node(create_random_node_info()).0);
+            assert_eq!(routing_table.estimate_network_size(), i as u64 + 2);
+        }
+
+        // in a network of 512 evenly spread nodes, the i-th closest is at i / 512 of the address
+        // space; its name has i in the top 9 bits when ours is all zeros
+        let mut routing_table = RoutingTable::new(&NameType([0u8; 64]));
+        for i in 1..9u16 {
+            let mut name = [0u8; 64];
+            name[0] = (i >> 1) as u8;
+            name[1] = (i << 7) as u8;
+            let mut node_info = create_random_node_info();
+            node_info.fob.set_name(NameType(name));
+            node_info.id = NameType(name);
+            assert!(routing_table.add_node(node_info).0);
+        }
+        // buckets 8, 7, 7, 6, 6, 6, 6 and 5 sum to 29 / 512, the ranks to 36, and we count too
+        assert_eq!(routing_table.estimate_network_size(), 36 * 512 / 29 + 1);
+    }
+
+    fn endpoint_in_subnet(a: u8, b: u8, c: u8) -> Endpoint {
+        use std::net::{Ipv4Addr, SocketAddrV4, SocketAddr};
+        Endpoint::Tcp(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(a, b, c, random::<u8>()),
+            random::<u16>())))
+    }
+
+    fn node_info_with_endpoint(endpoint: Endpoint) -> NodeInfo {
+        let mut node_info = create_random_node_info();
+        node_info.endpoints = vec![endpoint.clone()];
+        node_info.connected_endpoints = vec![endpoint];
+        node_info
+    }
+
+    #[test]
+    fn subnet_limits_test() {
+        let mut routing_table = RoutingTable::with_subnet_limits(&Id::new().name(),
+                                                                 SubnetLimits::new(2, 2));
+        assert!(routing_table.add_node(node_info_with_endpoint(endpoint_in_subnet(1, 2, 3))).0);
+        assert!(routing_table.add_node(node_info_with_endpoint(endpoint_in_subnet(1, 2, 3))).0);
+
+        // a third contact from the same /24 is refused
+        let refused = node_info_with_endpoint(endpoint_in_subnet(1, 2, 3));
+        match routing_table.check_subnet_limits(&refused) {
+            Err(RoutingError::SubnetLimitReached) => {},
+            _ => panic!("Expected SubnetLimitReached"),
+        }
+        assert!(!routing_table.add_node(refused).0);
+        assert_eq!(routing_table.size(), 2);
+
+        // also when only one of its connections is in that subnet
+        let mut refused = node_info_with_endpoint(endpoint_in_subnet(4, 5, 6));
+        refused.connected_endpoints.push(endpoint_in_subnet(1, 2, 3));
+        assert!(!routing_table.add_node(refused).0);
+
+        // the endpoints a contact reports for itself are not counted
+        let mut accepted = node_info_with_endpoint(endpoint_in_subnet(4, 5, 6));
+        accepted.endpoints.push(endpoint_in_subnet(1, 2, 3));
+        assert!(routing_table.add_node(accepted.clone()).0);
+
+        // nor can it connect on the full subnet afterwards
+        let full_subnet_endpoint = endpoint_in_subnet(1, 2, 3);
+        assert!(!routing_table.add_connection(&accepted.fob, &full_subnet_endpoint));
+        assert_eq!(routing_table.mark_as_connected(&accepted.endpoints[1]), None);
+        assert!(routing_table.lookup_endpoint(&full_subnet_endpoint).is_none());
+        assert!(routing_table.add_connection(&accepted.fob, &endpoint_in_subnet(4, 5, 6)));
+
+        // neighbouring subnets and private addresses are unaffected
+        assert!(routing_table.add_node(node_info_with_endpoint(endpoint_in_subnet(1, 2, 4))).0);
+        for _ in 0..5 {
+            assert!(routing_table.add_node(
+                node_info_with_endpoint(endpoint_in_subnet(127, 0, 0))).0);
+            assert!(routing_table.add_node(
+                node_info_with_endpoint(endpoint_in_subnet(192, 168, 1))).0);
+        }
+
+        // dropping a contact frees its slot
+        let name = routing_table.routing_table.iter()
+                                .find(|node_info| node_info.subnets()
+                                    .contains(&Subnet::V4([1, 2, 3])))
+                                .unwrap().id();
+        routing_table.drop_node(&name);
+        assert!(routing_table.add_node(node_info_with_endpoint(endpoint_in_subnet(1, 2, 3))).0);
+    }
+
+    #[test]
+    fn ipv6_subnet_limits_test() {
+        use std::net::{Ipv6Addr, SocketAddrV6, SocketAddr};
+        let endpoint = |last_segment: u16, third_segment: u16| {
+            Endpoint::Tcp(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::new(0x2001, 0xdb8, third_segment, 0, 0, 0, 0, last_segment),
+                random::<u16>(), 0, 0)))
+        };
+        let mut routing_table = RoutingTable::with_subnet_limits(&Id::new().name(),
+                                                                 SubnetLimits::new(2, 1));
+        assert!(routing_table.add_node(node_info_with_endpoint(endpoint(1, 1))).0);
+        // same /48
+        assert!(!routing_table.add_node(node_info_with_endpoint(endpoint(2, 1))).0);
+        // different /48
+        assert!(routing_table.add_node(node_info_with_endpoint(endpoint(3, 2))).0);
+    }
+
     #[test]
     fn trivial_functions_test() {
         let mut table_unit_test = RoutingTableUnitTest::new();
@@ -16,11 +16,84 @@
 // relating to use of the SAFE Network Software.
 
 use rustc_serialize::{Encodable, Encoder, Decoder};
-use cbor;
-use error::RoutingError;
+use std::cmp;
+use canonical::{CanonicalEncoder, STRUCTURED_DATA_DOMAIN};
+use error::{RoutingError, StructuredDataError};
 use NameType;
+use data::{Data, DataRequest};
 use sodiumoxide::crypto;
 
+/// The rule by which owners of a StructuredData have to sign its next version.
+#[derive(Debug, Eq, PartialEq, PartialOrd, Ord, Clone, RustcDecodable, RustcEncodable)]
+pub enum OwnershipPolicy {
+    /// At least half of the owners (rounded up) have to sign.
+    Majority,
+    /// The weights of the owners who signed have to add up to at least the threshold.  The
+    /// weights are given in the order of the owner keys; an owner without a weight counts as 1.
+    Weighted {
+        threshold: u64,
+        weights: Vec<u64>,
+    },
+}
+
+impl OwnershipPolicy {
+    fn encode_canonical(&self, encoder: &mut CanonicalEncoder) {
+        match *self {
+            OwnershipPolicy::Majority => { encoder.u64(0); },
+            OwnershipPolicy::Weighted { threshold, ref weights } => {
+                encoder.u64(1).u64(threshold).u64s(weights);
+            },
+        }
+    }
+
+    /// Any m of the owners have to sign.
+    pub fn m_of_n(m: u64) -> OwnershipPolicy {
+        OwnershipPolicy::Weighted { threshold: m, weights: vec![] }
+    }
+
+    /// All of the given owner keys have to sign.
+    pub fn unanimous(owner_keys: &[crypto::sign::PublicKey]) -> OwnershipPolicy {
+        OwnershipPolicy::m_of_n(owner_keys.len() as u64)
+    }
+
+    /// Returns the total weight of signatures required from the given number of owners.
+    pub fn threshold(&self, owner_count: usize) -> u64 {
+        match *self {
+            OwnershipPolicy::Majority => ((owner_count + 1) / 2) as u64,
+            OwnershipPolicy::Weighted { threshold, .. } => threshold,
+        }
+    }
+
+    /// Checks a weighted policy can be met by the given number of owners: the threshold must be
+    /// above zero and at most the sum of the weights, and there must be either no weights or one
+    /// weight per owner.
+    pub fn validate(&self, owner_count: usize) -> Result<(), StructuredDataError> {
+        match *self {
+            OwnershipPolicy::Majority => Ok(()),
+            OwnershipPolicy::Weighted { threshold, ref weights } => {
+                if !weights.is_empty() && weights.len() != owner_count {
+                    return Err(StructuredDataError::InvalidOwnershipPolicy);
+                }
+                let total_weight = (0..owner_count)
+                    .fold(0u64, |total, index| total.saturating_add(self.weight(index)));
+                if threshold == 0 || threshold > total_weight {
+                    return Err(StructuredDataError::InvalidOwnershipPolicy);
+                }
+                Ok(())
+            },
+        }
+    }
+
+    /// Returns the weight of the owner at the given position in the owner keys.
+    pub fn weight(&self, owner_index: usize) -> u64 {
+        match *self {
+            OwnershipPolicy::Majority => 1,
+            OwnershipPolicy::Weighted { ref weights, .. } =>
+                weights.get(owner_index).map(|weight| *weight).unwrap_or(1),
+        }
+    }
+}
+
 /// StructuredData
 /// These types may be stored unsigned with previous and current owner keys
 /// set to the same keys. Updates though require a signature to validate
@@ -32,13 +105,15 @@ pub struct StructuredData {
     previous_owner_keys: Vec<crypto::sign::PublicKey>,
     version: u64,
     current_owner_keys: Vec<crypto::sign::PublicKey>,
-    previous_owner_signatures: Vec<crypto::sign::Signature>
+    previous_owner_signatures: Vec<crypto::sign::Signature>,
+    previous_owner_policy: OwnershipPolicy,
+    current_owner_policy: OwnershipPolicy,
 }
 
 
 impl StructuredData {
 
-    /// Constructor
+    /// Constructor, with both the previous and the current owners requiring a majority to sign
     pub fn new(type_tag: u64,
                identifier: NameType,
                data: Vec<u8>,
@@ -54,10 +129,22 @@ impl StructuredData {
                    previous_owner_keys: previous_owner_keys,
                    version: version,
                    current_owner_keys : current_owner_keys,
-                   previous_owner_signatures: previous_owner_signatures
+                   previous_owner_signatures: previous_owner_signatures,
+                   previous_owner_policy: OwnershipPolicy::Majority,
+                   current_owner_policy: OwnershipPolicy::Majority,
                  }
     }
 
+    /// Sets the policies by which the previous owners signed this version, and by which the
+    /// current owners have to sign the next.  The policies are covered by the signatures, so
+    /// this has to be done before signing.
+    pub fn with_owner_policies(mut self, previous_owner_policy: OwnershipPolicy,
+                               current_owner_policy: OwnershipPolicy) -> StructuredData {
+        self.previous_owner_policy = previous_owner_policy;
+        self.current_owner_policy = current_owner_policy;
+        self
+    }
+
     /// This is a static function required for computing a name give the tag-type and identifier to
     /// be use by GETs
     pub fn compute_name(type_tag: u64, identifier: &NameType) -> NameType {
@@ -75,14 +162,7 @@ impl StructuredData {
     /// To transfer ownership the current owner signs over the data, the previous owners field
     /// must have the previous owners of version - 1 as the current owners of that last version.
     pub fn replace_with_other(&mut self, other: StructuredData) -> Result<(), RoutingError> {
-        // TODO(dirvine) Increase error types to be more descriptive  :07/07/2015
-        if      other.type_tag != self.type_tag     ||
-                other.identifier != self.identifier ||
-                other.version != self.version + 1   ||
-                other.previous_owner_keys != self.current_owner_keys  {
-            return Err(RoutingError::UnknownMessageType)
-        }
-        try!(other.verify_previous_owner_signatures());
+        try!(self.is_valid_successor(&other));
 
                    self.type_tag = other.type_tag;
                    self.identifier = other.identifier;
@@ -94,60 +174,139 @@ impl StructuredData {
                    Ok(())
     }
 
+    /// Checks, without modifying either, whether other can replace this data item: it must be
+    /// the next version of the same data, signed by enough of our current owners.
+    pub fn is_valid_successor(&self, other: &StructuredData) -> Result<(), StructuredDataError> {
+        if other.type_tag != self.type_tag {
+            return Err(StructuredDataError::WrongTypeTag);
+        }
+        if other.identifier != self.identifier {
+            return Err(StructuredDataError::WrongIdentifier);
+        }
+        if self.version.checked_add(1) != Some(other.version) {
+            return Err(StructuredDataError::VersionNotIncremented);
+        }
+        if other.previous_owner_keys != self.current_owner_keys {
+            return Err(StructuredDataError::OwnerKeysMismatch);
+        }
+        if other.previous_owner_policy != self.current_owner_policy {
+            return Err(StructuredDataError::OwnershipPolicyMismatch);
+        }
+        try!(other.previous_owner_policy.validate(other.previous_owner_keys.len()));
+        try!(other.current_owner_policy.validate(other.current_owner_keys.len()));
+        other.verify_previous_owner_signatures()
+    }
+
     /// Returns name and validates invariants
     pub fn name(&self) -> NameType {
         StructuredData::compute_name(self.type_tag, &self.identifier)
     }
 
-    /// Confirms *unique and valid* previous_owner_signatures are at least 50% of total owners
-    fn verify_previous_owner_signatures(&self) -> Result<(), RoutingError> {
-         // Refuse any duplicate previous_owner_signatures (people can have many owner keys)
-         // Any duplicates invalidates this type
-         if self.previous_owner_signatures.iter().filter(|&sig| self.previous_owner_signatures.iter()
-                                  .any(|ref sig_check| NameType(sig.0) == NameType(sig_check.0)))
-                                  .count() > (self.previous_owner_keys.len() + 1) /2 {
-
-            return Err(RoutingError::DuplicateSignatures);
+    /// Confirms the previous_owner_signatures are unique and valid, and that the previous owners
+    /// who signed carry enough weight under the previous owner policy
+    fn verify_previous_owner_signatures(&self) -> Result<(), StructuredDataError> {
+         // Refuse any duplicate previous_owner_signatures; as signatures are deterministic this
+         // also refuses an owner signing twice.  Any duplicates invalidates this type
+         for (index, sig) in self.previous_owner_signatures.iter().enumerate() {
+             if self.previous_owner_signatures[index + 1..].iter()
+                     .any(|sig_check| &sig.0[..] == &sig_check.0[..]) {
+                 return Err(StructuredDataError::DuplicateSignatures);
+             }
          }
 
-
-         // Refuse when not enough previous_owner_signatures found
-         if self.previous_owner_signatures.len() < (self.previous_owner_keys.len()  + 1 ) / 2 {
-             return Err(RoutingError::NotEnoughSignatures);
+         // data we cannot serialise cannot carry a valid signature either
+         let data = match self.data_to_sign() {
+             Ok(data) => data,
+             Err(_) => return Err(StructuredDataError::InvalidSignature),
+         };
+         // Refuse any signature not made by one of the previous owners
+         if !self.previous_owner_signatures.iter()
+                        .all(|&sig| self.previous_owner_keys
+                          .iter()
+                          .any(|ref pub_key| crypto::sign::verify_detached(&sig, &data, &pub_key))) {
+            return Err(StructuredDataError::InvalidSignature);
          }
 
-         let data = try!(self.data_to_sign());
-         // Count valid previous_owner_signatures and refuse if quantity is not enough
-         if self.previous_owner_signatures.iter()
-                        .filter(|&sig| self.previous_owner_keys
-                          .iter()
-                          .any(|ref pub_key| crypto::sign::verify_detached(&sig, &data, &pub_key)))
-                            .count() < self.previous_owner_keys.len() / 2 {
-            return Err(RoutingError::NotEnoughSignatures);
+         // Refuse when the owners who signed do not carry enough weight
+         if self.signed_weight(&data) < self.required_weight() {
+             return Err(StructuredDataError::NotEnoughSignatures);
          }
          Ok(())
     }
 
-    fn data_to_sign(&self) -> Result<Vec<u8>, RoutingError> {
-        // seems overkill to use serialisation here, but done
-        // to ensure cross platform signature handling is OK
-        let mut enc = cbor::Encoder::from_memory();
-        try!(enc.encode(self.type_tag.to_string().as_bytes()));
-        try!(enc.encode(&[self.identifier]));
-        try!(enc.encode(&self.data));
-        try!(enc.encode(&self.previous_owner_keys));
-        try!(enc.encode(&self.current_owner_keys));
-        try!(enc.encode(self.version.to_string().as_bytes()));
-        Ok(enc.into_bytes())
+    fn required_weight(&self) -> u64 {
+        self.previous_owner_policy.threshold(self.previous_owner_keys.len())
+    }
+
+    // Each previous owner counts once, with its weight, if any of the signatures is theirs
+    fn signed_weight(&self, data: &[u8]) -> u64 {
+        self.previous_owner_keys.iter().enumerate()
+            .filter(|&(_, pub_key)| self.previous_owner_signatures.iter()
+                .any(|sig| crypto::sign::verify_detached(sig, data, pub_key)))
+            .map(|(index, _)| self.previous_owner_policy.weight(index))
+            .fold(0u64, |total, weight| total.saturating_add(weight))
+    }
+
+    /// Returns the canonical encoding the previous owners sign, covering the owner policies.
+    /// See the canonical module for the format.
+    pub fn data_to_sign(&self) -> Result<Vec<u8>, RoutingError> {
+        let mut encoder = CanonicalEncoder::new(STRUCTURED_DATA_DOMAIN);
+        encoder.u64(self.type_tag)
+               .name(&self.identifier)
+               .bytes(&self.data)
+               .public_keys(&self.previous_owner_keys)
+               .public_keys(&self.current_owner_keys)
+               .u64(self.version);
+        self.previous_owner_policy.encode_canonical(&mut encoder);
+        self.current_owner_policy.encode_canonical(&mut encoder);
+        Ok(encoder.into_bytes())
+    }
+
+    /// Returns the weight of previous owner signatures still required (0 means this is complete)
+    pub fn remaining_signature_weight(&self) -> Result<u64, RoutingError> {
+        let data = try!(self.data_to_sign());
+        let signed_weight = self.signed_weight(&data);
+        let required_weight = self.required_weight();
+        if signed_weight >= required_weight {
+            Ok(0)
+        } else {
+            Ok(required_weight - signed_weight)
+        }
+    }
+
+    /// Signs this version with the secret key of one of the previous owners, without adding
+    /// the signature.  This allows owners to sign offline and hand their signature to whoever
+    /// collects them with add_signatures.
+    pub fn sign(&self, secret_key: &crypto::sign::SecretKey)
+            -> Result<crypto::sign::Signature, RoutingError> {
+        let data = try!(self.data_to_sign());
+        Ok(crypto::sign::sign_detached(&data, secret_key))
+    }
+
+    /// Returns the weight of previous owner signatures still required (if any, 0 means this is
+    /// complete).  Signing twice with the same key does not add a second signature.
+    pub fn add_signature(&mut self, secret_key: &crypto::sign::SecretKey) -> Result<u64, RoutingError> {
+        let sig = try!(self.sign(secret_key));
+        self.add_signatures(vec![sig])
     }
 
-    /// Returns number of previous_owner_signatures still required (if any, 0 means this is complete)
-    pub fn add_signature(&mut self, secret_key: &crypto::sign::SecretKey) -> Result<isize, RoutingError> {
+    /// Adds signatures collected from the previous owners, skipping those already present, and
+    /// returns the weight still required.  Refuses all of them if any is not a valid signature
+    /// of a previous owner.
+    pub fn add_signatures(&mut self, signatures: Vec<crypto::sign::Signature>)
+            -> Result<u64, RoutingError> {
         let data = try!(self.data_to_sign());
-        let sig = crypto::sign::sign_detached(&data, secret_key);
-        self.previous_owner_signatures.push(sig);
-        Ok(((self.previous_owner_keys.len() + 1) as isize / 2) -
-             self.previous_owner_signatures.len() as isize)
+        if !signatures.iter().all(|sig| self.previous_owner_keys.iter()
+                .any(|pub_key| crypto::sign::verify_detached(sig, &data, pub_key))) {
+            return Err(RoutingError::from(StructuredDataError::InvalidSignature));
+        }
+        for sig in signatures {
+            if !self.previous_owner_signatures.iter()
+                    .any(|sig_check| &sig.0[..] == &sig_check.0[..]) {
+                self.previous_owner_signatures.push(sig);
+            }
+        }
+        self.remaining_signature_weight()
     }
 
     /// Overwrite any existing signatures with the new signatures provided
@@ -185,6 +344,16 @@ impl StructuredData {
         &self.current_owner_keys
     }
 
+    /// Get the policy by which the previous owners signed this version
+    pub fn get_previous_owner_policy(&self) -> &OwnershipPolicy {
+        &self.previous_owner_policy
+    }
+
+    /// Get the policy by which the current owners have to sign the next version
+    pub fn get_owner_policy(&self) -> &OwnershipPolicy {
+        &self.current_owner_policy
+    }
+
     /// Get previous owner signatures
     pub fn get_previous_owner_signatures(&self) -> &Vec<crypto::sign::Signature> {
         &self.previous_owner_signatures
@@ -196,10 +365,100 @@ impl StructuredData {
 }
 
 
+/// A bounded history of consecutive StructuredData versions, including the signatures of the
+/// owners who made each change, so they can be audited and rolled back to.  Personas keep one
+/// per StructuredData, applying updates to it rather than to the StructuredData itself.
+#[derive(Debug, Eq, PartialEq, PartialOrd, Ord, Clone, RustcDecodable, RustcEncodable)]
+pub struct StructuredDataHistory {
+    // oldest first, never empty
+    versions: Vec<StructuredData>,
+    max_versions: u64,
+}
+
+impl StructuredDataHistory {
+    /// Starts a history with the given version, keeping at most max_versions (at least 1).
+    pub fn new(structured_data: StructuredData, max_versions: u64) -> StructuredDataHistory {
+        StructuredDataHistory {
+            versions: vec![structured_data],
+            max_versions: cmp::max(max_versions, 1),
+        }
+    }
+
+    /// Appends other if it is a valid successor of the current version, dropping the oldest
+    /// version once more than max_versions are kept.
+    pub fn apply(&mut self, other: StructuredData) -> Result<(), StructuredDataError> {
+        try!(self.current().is_valid_successor(&other));
+        self.versions.push(other);
+        while self.versions.len() as u64 > self.max_versions {
+            let _ = self.versions.remove(0);
+        }
+        Ok(())
+    }
+
+    /// Returns the current version.
+    pub fn current(&self) -> &StructuredData {
+        &self.versions[self.versions.len() - 1]
+    }
+
+    /// Returns the given version, if it is still kept.
+    pub fn version(&self, version: u64) -> Option<&StructuredData> {
+        self.versions.iter().find(|structured_data| structured_data.get_version() == version)
+    }
+
+    /// Returns a history of up to count of the most recent versions.
+    pub fn latest(&self, count: u64) -> StructuredDataHistory {
+        let count = cmp::max(cmp::min(count, self.versions.len() as u64), 1);
+        StructuredDataHistory {
+            versions: self.versions[self.versions.len() - count as usize..].to_vec(),
+            max_versions: count,
+        }
+    }
+
+    /// Returns the kept versions, oldest first.
+    pub fn versions(&self) -> &Vec<StructuredData> {
+        &self.versions
+    }
+
+    /// Returns the data answering a request for this StructuredData, if we hold what it asks for.
+    pub fn get(&self, data_request: &DataRequest) -> Option<Data> {
+        match *data_request {
+            DataRequest::StructuredData(_) =>
+                Some(Data::StructuredData(self.current().clone())),
+            DataRequest::StructuredDataVersion(_, version) =>
+                self.version(version).map(|structured_data|
+                    Data::StructuredData(structured_data.clone())),
+            DataRequest::StructuredDataHistory(_, count) =>
+                Some(Data::StructuredDataHistory(self.latest(count))),
+            _ => None,
+        }
+    }
+
+    /// Confirms every version is a valid successor of the one before, so a client can trust
+    /// the history as far back as it trusts the oldest version.
+    pub fn verify(&self) -> Result<(), StructuredDataError> {
+        for pair in self.versions.windows(2) {
+            try!(pair[0].is_valid_successor(&pair[1]));
+        }
+        Ok(())
+    }
+
+    /// Returns the name of the StructuredData.
+    pub fn name(&self) -> NameType {
+        self.current().name()
+    }
+
+    pub fn payload_size(&self) -> usize {
+        self.versions.iter().fold(0, |total, structured_data|
+            total + structured_data.payload_size())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use sodiumoxide::crypto;
-    use super::StructuredData;
+    use super::{StructuredData, StructuredDataHistory, OwnershipPolicy};
+    use data::{Data, DataRequest};
+    use error::StructuredDataError;
     use test_utils::Random;
     use NameType;
 
@@ -293,4 +552,309 @@ mod test {
 
     }
 
+    #[test]
+    fn invalid_successors() {
+        let keys1       = crypto::sign::gen_keypair();
+        let keys2       = crypto::sign::gen_keypair();
+        let identifier : NameType = Random::generate_random();
+
+        let orig_structured_data =   StructuredData::new(0,
+                                identifier.clone(),
+                                vec![],
+                                vec![keys1.0],
+                                0,
+                                vec![keys1.0],
+                                vec![]);
+        let successor = |type_tag: u64, identifier: NameType, previous_owner: crypto::sign::PublicKey,
+                         version: u64, signing_key: &crypto::sign::SecretKey| {
+            let mut structured_data = StructuredData::new(type_tag,
+                                identifier,
+                                vec![],
+                                vec![previous_owner],
+                                version,
+                                vec![keys1.0],
+                                vec![]);
+            let signature = structured_data.sign(signing_key).unwrap();
+            structured_data.replace_signatures(vec![signature]);
+            structured_data
+        };
+
+        assert_eq!(orig_structured_data.is_valid_successor(
+            &successor(0, identifier.clone(), keys1.0, 1, &keys1.1)), Ok(()));
+        assert_eq!(orig_structured_data.is_valid_successor(
+            &successor(1, identifier.clone(), keys1.0, 1, &keys1.1)),
+            Err(StructuredDataError::WrongTypeTag));
+        assert_eq!(orig_structured_data.is_valid_successor(
+            &successor(0, Random::generate_random(), keys1.0, 1, &keys1.1)),
+            Err(StructuredDataError::WrongIdentifier));
+        assert_eq!(orig_structured_data.is_valid_successor(
+            &successor(0, identifier.clone(), keys1.0, 2, &keys1.1)),
+            Err(StructuredDataError::VersionNotIncremented));
+        assert_eq!(orig_structured_data.is_valid_successor(
+            &successor(0, identifier.clone(), keys2.0, 1, &keys2.1)),
+            Err(StructuredDataError::OwnerKeysMismatch));
+        assert_eq!(orig_structured_data.is_valid_successor(
+            &successor(0, identifier.clone(), keys1.0, 1, &keys2.1)),
+            Err(StructuredDataError::InvalidSignature));
+
+        // the stored version has no successor once it reaches the last one
+        let last_structured_data = StructuredData::new(0,
+                                identifier.clone(),
+                                vec![],
+                                vec![keys1.0],
+                                ::std::u64::MAX,
+                                vec![keys1.0],
+                                vec![]);
+        assert_eq!(last_structured_data.is_valid_successor(
+            &successor(0, identifier.clone(), keys1.0, 0, &keys1.1)),
+            Err(StructuredDataError::VersionNotIncremented));
+
+        let mut unsigned = successor(0, identifier.clone(), keys1.0, 1, &keys1.1);
+        unsigned.replace_signatures(vec![]);
+        assert_eq!(orig_structured_data.is_valid_successor(&unsigned),
+            Err(StructuredDataError::NotEnoughSignatures));
+    }
+
+    #[test]
+    fn m_of_n_owners() {
+        let keys = (0..5).map(|_| crypto::sign::gen_keypair()).collect::<Vec<_>>();
+        let owner_keys = keys.iter().map(|keys| keys.0).collect::<Vec<_>>();
+
+        let mut structured_data =   StructuredData::new(0,
+                                Random::generate_random(),
+                                vec![],
+                                owner_keys.clone(),
+                                1,
+                                owner_keys.clone(),
+                                vec![])
+            .with_owner_policies(OwnershipPolicy::m_of_n(2), OwnershipPolicy::unanimous(&owner_keys));
+        assert_eq!(structured_data.add_signature(&keys[0].1).ok(), Some(1));
+        // signing twice with the same key does not count twice
+        assert_eq!(structured_data.add_signature(&keys[0].1).ok(), Some(1));
+        assert_eq!(structured_data.get_previous_owner_signatures().len(), 1);
+        assert_eq!(structured_data.verify_previous_owner_signatures().ok(), None);
+        assert_eq!(structured_data.add_signature(&keys[3].1).ok(), Some(0));
+        assert_eq!(structured_data.verify_previous_owner_signatures().ok(), Some(()));
+
+        // the same signatures do not satisfy a unanimous policy
+        let unanimous = structured_data.clone()
+            .with_owner_policies(OwnershipPolicy::unanimous(&owner_keys),
+                                 OwnershipPolicy::unanimous(&owner_keys));
+        assert_eq!(unanimous.remaining_signature_weight().ok(), Some(5));
+    }
+
+    #[test]
+    fn invalid_weighted_policies() {
+        let keys1 = crypto::sign::gen_keypair();
+        let keys2 = crypto::sign::gen_keypair();
+        let owner_keys = vec![keys1.0, keys2.0];
+        let identifier : NameType = Random::generate_random();
+        let structured_data = |version: u64, previous_owner_policy: OwnershipPolicy,
+                               current_owner_policy: OwnershipPolicy| {
+            let mut structured_data = StructuredData::new(0,
+                                identifier.clone(),
+                                vec![],
+                                owner_keys.clone(),
+                                version,
+                                owner_keys.clone(),
+                                vec![])
+                .with_owner_policies(previous_owner_policy, current_owner_policy);
+            let _ = structured_data.add_signature(&keys1.1);
+            let _ = structured_data.add_signature(&keys2.1);
+            structured_data
+        };
+        let zero_threshold = OwnershipPolicy::Weighted { threshold: 0, weights: vec![] };
+        let threshold_above_weights = OwnershipPolicy::Weighted { threshold: 5,
+                                                                  weights: vec![2, 2] };
+        let weight_per_owner_missing = OwnershipPolicy::Weighted { threshold: 1,
+                                                                   weights: vec![1] };
+        assert_eq!(zero_threshold.validate(2), Err(StructuredDataError::InvalidOwnershipPolicy));
+        assert_eq!(threshold_above_weights.validate(2),
+                   Err(StructuredDataError::InvalidOwnershipPolicy));
+        assert_eq!(weight_per_owner_missing.validate(2),
+                   Err(StructuredDataError::InvalidOwnershipPolicy));
+        assert_eq!(OwnershipPolicy::Weighted { threshold: 4, weights: vec![2, 2] }.validate(2),
+                   Ok(()));
+        assert_eq!(OwnershipPolicy::m_of_n(2).validate(2), Ok(()));
+        assert_eq!(OwnershipPolicy::m_of_n(3).validate(2),
+                   Err(StructuredDataError::InvalidOwnershipPolicy));
+
+        // a successor can not set a policy its owners could never, or always, meet
+        let stored = structured_data(0, OwnershipPolicy::Majority, OwnershipPolicy::Majority);
+        assert_eq!(stored.is_valid_successor(
+            &structured_data(1, OwnershipPolicy::Majority, OwnershipPolicy::m_of_n(2))), Ok(()));
+        for policy in vec![zero_threshold.clone(), threshold_above_weights.clone(),
+                           weight_per_owner_missing.clone()] {
+            assert_eq!(stored.is_valid_successor(
+                &structured_data(1, OwnershipPolicy::Majority, policy)),
+                Err(StructuredDataError::InvalidOwnershipPolicy));
+        }
+
+        // nor can a stored policy like that be used to replace the data without signatures
+        let stored = structured_data(0, OwnershipPolicy::Majority, zero_threshold.clone());
+        let mut unsigned = structured_data(1, zero_threshold, OwnershipPolicy::Majority);
+        unsigned.replace_signatures(vec![]);
+        assert_eq!(stored.is_valid_successor(&unsigned),
+                   Err(StructuredDataError::InvalidOwnershipPolicy));
+    }
+
+    #[test]
+    fn weighted_owners_signing_offline() {
+        let keys1 = crypto::sign::gen_keypair();
+        let keys2 = crypto::sign::gen_keypair();
+        let keys3 = crypto::sign::gen_keypair();
+        let policy = OwnershipPolicy::Weighted { threshold: 3, weights: vec![3, 1, 1] };
+
+        let mut structured_data =   StructuredData::new(0,
+                                Random::generate_random(),
+                                vec![],
+                                vec![keys1.0, keys2.0, keys3.0],
+                                1,
+                                vec![keys1.0, keys2.0, keys3.0],
+                                vec![])
+            .with_owner_policies(policy.clone(), policy);
+        // owners sign a copy each, the signatures are collected afterwards
+        let signature2 = structured_data.clone().sign(&keys2.1).unwrap();
+        let signature3 = structured_data.clone().sign(&keys3.1).unwrap();
+        assert_eq!(structured_data.add_signatures(vec![signature2, signature3]).ok(), Some(1));
+        assert_eq!(structured_data.verify_previous_owner_signatures(),
+                   Err(StructuredDataError::NotEnoughSignatures));
+        let signature1 = structured_data.sign(&keys1.1).unwrap();
+        assert_eq!(structured_data.add_signatures(vec![signature1]).ok(), Some(0));
+        assert_eq!(structured_data.verify_previous_owner_signatures().ok(), Some(()));
+
+        // a signature of someone else is refused
+        let stranger = crypto::sign::gen_keypair();
+        let stranger_signature = structured_data.sign(&stranger.1).unwrap();
+        assert!(structured_data.add_signatures(vec![stranger_signature]).is_err());
+    }
+
+    #[test]
+    fn weights_saturate() {
+        let keys1 = crypto::sign::gen_keypair();
+        let keys2 = crypto::sign::gen_keypair();
+        let policy = OwnershipPolicy::Weighted { threshold: 1,
+                                                 weights: vec![::std::u64::MAX, ::std::u64::MAX] };
+        assert_eq!(policy.validate(2), Ok(()));
+
+        let identifier : NameType = Random::generate_random();
+        let stored = StructuredData::new(0,
+                                identifier.clone(),
+                                vec![],
+                                vec![keys1.0, keys2.0],
+                                0,
+                                vec![keys1.0, keys2.0],
+                                vec![])
+            .with_owner_policies(policy.clone(), policy.clone());
+        let mut successor = StructuredData::new(0,
+                                identifier,
+                                vec![],
+                                vec![keys1.0, keys2.0],
+                                1,
+                                vec![keys1.0, keys2.0],
+                                vec![])
+            .with_owner_policies(policy.clone(), policy);
+        // the weights of both signers add up without overflowing
+        assert_eq!(successor.add_signature(&keys1.1).ok(), Some(0));
+        assert_eq!(successor.add_signature(&keys2.1).ok(), Some(0));
+        assert_eq!(successor.remaining_signature_weight().ok(), Some(0));
+        assert_eq!(stored.is_valid_successor(&successor), Ok(()));
+    }
+
+    #[test]
+    fn duplicate_signatures() {
+        let keys1 = crypto::sign::gen_keypair();
+        let keys2 = crypto::sign::gen_keypair();
+        let keys3 = crypto::sign::gen_keypair();
+
+        let mut structured_data =   StructuredData::new(0,
+                                Random::generate_random(),
+                                vec![],
+                                vec![keys1.0, keys2.0, keys3.0],
+                                1,
+                                vec![keys1.0],
+                                vec![]);
+        let signature1 = structured_data.sign(&keys1.1).unwrap();
+        let signature2 = structured_data.sign(&keys2.1).unwrap();
+        structured_data.replace_signatures(vec![signature1, signature2]);
+        assert_eq!(structured_data.verify_previous_owner_signatures().ok(), Some(()));
+        structured_data.replace_signatures(vec![signature1, signature1]);
+        assert_eq!(structured_data.verify_previous_owner_signatures(),
+                   Err(StructuredDataError::DuplicateSignatures));
+    }
+
+    #[test]
+    fn history() {
+        let keys = crypto::sign::gen_keypair();
+        let identifier : NameType = Random::generate_random();
+        let version = |version: u64, data: Vec<u8>| {
+            let mut structured_data = StructuredData::new(0,
+                                identifier.clone(),
+                                data,
+                                vec![keys.0],
+                                version,
+                                vec![keys.0],
+                                vec![]);
+            let _ = structured_data.add_signature(&keys.1);
+            structured_data
+        };
+
+        let mut history = StructuredDataHistory::new(version(0, vec![0u8]), 2);
+        assert_eq!(history.apply(version(2, vec![2u8])),
+                   Err(StructuredDataError::VersionNotIncremented));
+        assert_eq!(history.apply(version(1, vec![1u8])), Ok(()));
+        assert_eq!(history.apply(version(2, vec![2u8])), Ok(()));
+        assert_eq!(history.verify(), Ok(()));
+
+        // only the two most recent versions are kept
+        assert_eq!(history.versions().len(), 2);
+        assert!(history.version(0).is_none());
+        assert_eq!(history.current().get_version(), 2);
+        assert_eq!(history.get(&DataRequest::StructuredData(0)),
+                   Some(Data::StructuredData(version(2, vec![2u8]))));
+        assert_eq!(history.get(&DataRequest::StructuredDataVersion(0, 1)),
+                   Some(Data::StructuredData(version(1, vec![1u8]))));
+        assert_eq!(history.get(&DataRequest::StructuredDataVersion(0, 0)), None);
+        match history.get(&DataRequest::StructuredDataHistory(0, 5)) {
+            Some(Data::StructuredDataHistory(latest)) => {
+                assert_eq!(latest.versions().len(), 2);
+                assert_eq!(latest.name(), history.name());
+                assert_eq!(latest.verify(), Ok(()));
+            },
+            _ => panic!("Expected a StructuredDataHistory"),
+        }
+        assert_eq!(history.latest(1).versions(), &vec![version(2, vec![2u8])]);
+    }
+
+    #[test]
+    fn canonical_encoding() {
+        let structured_data = StructuredData::new(5,
+                                NameType([1u8; 64]),
+                                vec![7, 8],
+                                vec![crypto::sign::PublicKey([3u8; 32])],
+                                2,
+                                vec![],
+                                vec![])
+            .with_owner_policies(OwnershipPolicy::Majority, OwnershipPolicy::Weighted {
+                threshold: 2,
+                weights: vec![1, 3],
+            });
+
+        let mut expected = vec![0, 0, 0, 0, 0, 0, 0, 24];
+        expected.extend(b"routing/StructuredData/1".iter().cloned());
+        expected.extend(vec![0, 0, 0, 0, 0, 0, 0, 5].into_iter());
+        expected.extend(vec![1u8; 64].into_iter());
+        expected.extend(vec![0, 0, 0, 0, 0, 0, 0, 2, 7, 8].into_iter());
+        expected.extend(vec![0, 0, 0, 0, 0, 0, 0, 1].into_iter());
+        expected.extend(vec![3u8; 32].into_iter());
+        expected.extend(vec![0, 0, 0, 0, 0, 0, 0, 0].into_iter());
+        expected.extend(vec![0, 0, 0, 0, 0, 0, 0, 2].into_iter());
+        expected.extend(vec![0, 0, 0, 0, 0, 0, 0, 0].into_iter());
+        expected.extend(vec![0, 0, 0, 0, 0, 0, 0, 1,
+                             0, 0, 0, 0, 0, 0, 0, 2,
+                             0, 0, 0, 0, 0, 0, 0, 2,
+                             0, 0, 0, 0, 0, 0, 0, 1,
+                             0, 0, 0, 0, 0, 0, 0, 3].into_iter());
+        assert_eq!(structured_data.data_to_sign().ok(), Some(expected));
+    }
 }
@@ -18,10 +18,14 @@
 mod random_trait;
 mod types_util;
 mod messages_util;
+#[cfg(test)]
+mod routing_table_util;
 
 pub use self::random_trait::*;
 pub use self::types_util::*;
 pub use self::messages_util::*;
+#[cfg(test)]
+pub use self::routing_table_util::*;
 
 use NameType;
 
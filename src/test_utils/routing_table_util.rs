@@ -0,0 +1,98 @@
+// Copyright 2015 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use sodiumoxide::crypto::sign;
+use id::Id;
+use name_type::{closer_to_target, NameType};
+use public_id::PublicId;
+use routing_table::{RoutingTable, NodeInfo};
+use utils::public_key_to_client_name;
+use super::xor;
+use super::test::{random_endpoint, random_endpoints};
+
+/// A node with a routing table full enough to have a stable close group, and names at each
+/// position relative to that close group.
+pub struct CloseGroupFixture {
+    pub id: Id,
+    pub routing_table: RoutingTable,
+    /// a name in our close group range which is neither ours nor one of a node
+    pub name_in_range: NameType,
+    /// a name outside our close group range
+    pub name_outside_range: NameType,
+    /// a node in our close group
+    pub close_node: NameType,
+    /// the id of close_node, to sign as it
+    pub close_node_id: Id,
+    /// the key of a client whose name is in our close group range
+    pub client_in_range: sign::PublicKey,
+}
+
+impl CloseGroupFixture {
+    pub fn new() -> CloseGroupFixture {
+        let id = Id::new();
+        let mut routing_table = RoutingTable::new(&id.name());
+        let mut node_ids = Vec::new();
+        for _ in 0..100 {
+            let node_id = Id::new();
+            routing_table.add_node(NodeInfo::new(PublicId::new(&node_id),
+                                                 random_endpoints(),
+                                                 vec![random_endpoint()]));
+            node_ids.push(node_id);
+        }
+        let our_name = id.name();
+        let close_group = routing_table.our_close_group();
+        let closest_node = close_group[0].id.clone();
+        let close_node = close_group[1].id.clone();
+        let furthest_node = close_group.last().unwrap().id.clone();
+
+        let name_in_range = xor(&xor(&closest_node, &our_name), &close_node);
+        assert!(closer_to_target(&name_in_range, &furthest_node, &our_name));
+        assert!(close_group.iter().all(|node| node.id != name_in_range));
+        // with 100 nodes the close group is far from spanning the address space, so inverting
+        // the furthest close node takes us out of range
+        let name_outside_range = xor(&furthest_node, &NameType::new([255u8; 64]));
+        assert!(closer_to_target(&furthest_node, &name_outside_range, &our_name));
+
+        // tends to take 0 - 50 attempts to find a client name in our range
+        let mut client_in_range = sign::gen_keypair().0;
+        let mut attempts = 0;
+        while !routing_table.address_in_our_close_group_range(
+                &public_key_to_client_name(&client_in_range)) {
+            client_in_range = sign::gen_keypair().0;
+            attempts += 1;
+            assert!(attempts < 1000, "Failed to find a client name in our range.");
+        }
+
+        let close_node_id = node_ids.into_iter()
+                                    .find(|node_id| node_id.name() == close_node)
+                                    .unwrap();
+
+        CloseGroupFixture {
+            id: id,
+            routing_table: routing_table,
+            name_in_range: name_in_range,
+            name_outside_range: name_outside_range,
+            close_node: close_node,
+            close_node_id: close_node_id,
+            client_in_range: client_in_range,
+        }
+    }
+
+    pub fn our_name(&self) -> NameType {
+        self.id.name()
+    }
+}
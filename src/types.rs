@@ -19,6 +19,10 @@ use sodiumoxide::crypto;
 use rustc_serialize::{Decoder, Encodable, Encoder};
 use rand::random;
 use sodiumoxide::crypto::sign;
+use std::net::SocketAddr;
+use crust::Endpoint;
+use canonical::route_path_bytes;
+use public_id::PublicId;
 use NameType;
 
 pub fn array_as_vector(arr: &[u8]) -> Vec<u8> {
@@ -55,6 +59,119 @@ pub fn generate_random_vec_u8(size: usize) -> Vec<u8> {
 
 pub static GROUP_SIZE: usize = 8;
 pub static QUORUM_SIZE: usize = 6;
+pub static MAX_ROUTING_NODES_PER_SUBNET: usize = 4;
+pub static MAX_RELAYED_CLIENTS_PER_SUBNET: usize = 10;
+pub static MAX_GET_MESSAGES_PER_SECOND: u64 = 50;
+pub static MAX_GET_BYTES_PER_SECOND: u64 = 64 * 1024;
+pub static MAX_MUTATION_MESSAGES_PER_SECOND: u64 = 10;
+pub static MAX_MUTATION_BYTES_PER_SECOND: u64 = 2 * 1024 * 1024;
+
+/// An IPv4 /24 or IPv6 /48 prefix, which is assumed to be controlled by a single party.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Debug)]
+pub enum Subnet {
+    V4([u8; 3]),
+    V6([u16; 3]),
+}
+
+impl Subnet {
+    /// Returns the subnet of the endpoint's IP address.  Loopback and private addresses return
+    /// None, as they are not subject to subnet limits (e.g. for local test networks).
+    pub fn of_endpoint(endpoint: &Endpoint) -> Option<Subnet> {
+        match *endpoint {
+            Endpoint::Tcp(SocketAddr::V4(ref addr)) => {
+                let octets = addr.ip().octets();
+                if octets[0] == 127 || octets[0] == 10 ||
+                   (octets[0] == 172 && octets[1] & 0xf0 == 16) ||
+                   (octets[0] == 192 && octets[1] == 168) {
+                    return None;
+                }
+                Some(Subnet::V4([octets[0], octets[1], octets[2]]))
+            },
+            Endpoint::Tcp(SocketAddr::V6(ref addr)) => {
+                let segments = addr.ip().segments();
+                if segments == [0, 0, 0, 0, 0, 0, 0, 1] || segments[0] & 0xfe00 == 0xfc00 {
+                    return None;
+                }
+                Some(Subnet::V6([segments[0], segments[1], segments[2]]))
+            },
+            _ => None,
+        }
+    }
+}
+
+/// The maximum number of contacts accepted from a single IPv4 /24 and IPv6 /48 subnet, limiting
+/// how much of our routing table or relay slots a single machine or operator can occupy.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct SubnetLimits {
+    pub max_per_ipv4_subnet: usize,
+    pub max_per_ipv6_subnet: usize,
+}
+
+impl SubnetLimits {
+    pub fn new(max_per_ipv4_subnet: usize, max_per_ipv6_subnet: usize) -> SubnetLimits {
+        SubnetLimits {
+            max_per_ipv4_subnet: max_per_ipv4_subnet,
+            max_per_ipv6_subnet: max_per_ipv6_subnet,
+        }
+    }
+
+    /// Returns the limit for the given subnet.
+    pub fn limit(&self, subnet: &Subnet) -> usize {
+        match *subnet {
+            Subnet::V4(_) => self.max_per_ipv4_subnet,
+            Subnet::V6(_) => self.max_per_ipv6_subnet,
+        }
+    }
+}
+
+/// The kind of request a relayed client sends into the network, which is rate limited separately.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
+pub enum RequestKind {
+    /// GetData
+    Get,
+    /// PutData, Post and DeleteData
+    Mutation,
+}
+
+/// The sustained rate of messages and bytes a single relayed client may send into the network,
+/// per RequestKind.  A client can burst up to one second's worth of its rate.  A rate of 0
+/// refuses that kind of request outright.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct RateLimits {
+    pub get_messages_per_second: u64,
+    pub get_bytes_per_second: u64,
+    pub mutation_messages_per_second: u64,
+    pub mutation_bytes_per_second: u64,
+}
+
+impl RateLimits {
+    pub fn new(get_messages_per_second: u64, get_bytes_per_second: u64,
+               mutation_messages_per_second: u64, mutation_bytes_per_second: u64) -> RateLimits {
+        RateLimits {
+            get_messages_per_second: get_messages_per_second,
+            get_bytes_per_second: get_bytes_per_second,
+            mutation_messages_per_second: mutation_messages_per_second,
+            mutation_bytes_per_second: mutation_bytes_per_second,
+        }
+    }
+
+    /// Returns the (messages, bytes) per second allowed for the given kind of request.
+    pub fn limit(&self, request_kind: RequestKind) -> (u64, u64) {
+        match request_kind {
+            RequestKind::Get =>
+                (self.get_messages_per_second, self.get_bytes_per_second),
+            RequestKind::Mutation =>
+                (self.mutation_messages_per_second, self.mutation_bytes_per_second),
+        }
+    }
+}
+
+impl Default for RateLimits {
+    fn default() -> RateLimits {
+        RateLimits::new(MAX_GET_MESSAGES_PER_SECOND, MAX_GET_BYTES_PER_SECOND,
+                        MAX_MUTATION_MESSAGES_PER_SECOND, MAX_MUTATION_BYTES_PER_SECOND)
+    }
+}
 
 pub trait Mergeable {
     fn merge<'a, I>(xs: I) -> Option<Self> where I: Iterator<Item=&'a Self>;
@@ -138,6 +255,71 @@ impl DestinationAddress {
     }
 }
 
+/// Marks a copy of a message sent along one of several paths with distinct first hops.  It
+/// travels outside of the signed message body, so every hop can append itself.  The sender of
+/// the message picks the first hop of every path and signs them, together with the path index
+/// and the message's signature, so no hop can move a copy to another path.  Only the first hops
+/// are guaranteed to differ; later hops of different paths may coincide.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Debug, RustcEncodable, RustcDecodable)]
+pub struct RoutePath {
+    /// index of the path this copy travels along
+    pub path_index: u8,
+    /// the first hop of every path the message was sent along, by path index
+    pub first_hops: Vec<NameType>,
+    /// the sender's signature of the above and the message's signature
+    pub signature: sign::Signature,
+    /// the nodes this copy was passed to, starting with the first hop; not signed
+    pub hops: Vec<NameType>,
+}
+
+impl RoutePath {
+    /// Creates the path of index `path_index` for the message with the given signature.
+    pub fn new(message_signature: &sign::Signature, path_index: u8, first_hops: Vec<NameType>,
+               private_sign_key: &sign::SecretKey) -> RoutePath {
+        let signature = sign::sign_detached(
+            &route_path_bytes(message_signature, path_index, &first_hops), private_sign_key);
+        let hops = first_hops.get(path_index as usize).into_iter().cloned()
+                             .collect::<Vec<NameType>>();
+        RoutePath {
+            path_index: path_index,
+            first_hops: first_hops,
+            signature: signature,
+            hops: hops,
+        }
+    }
+
+    /// Returns true if the message's sender signed this path for the message with the given
+    /// signature, and the copy started out on the first hop signed for its path.
+    pub fn verify(&self, message_signature: &sign::Signature,
+                  public_sign_key: &sign::PublicKey) -> bool {
+        self.hops.first().is_some()
+            && self.hops.first() == self.first_hops.get(self.path_index as usize)
+            && sign::verify_detached(&self.signature,
+                                     &route_path_bytes(message_signature, self.path_index,
+                                                       &self.first_hops),
+                                     public_sign_key)
+    }
+
+    /// The number of paths the message was sent along.
+    pub fn path_count(&self) -> usize {
+        self.first_hops.len()
+    }
+}
+
+/// Reports to the sender that a copy sent along a disjoint path arrived at the destination group.
+/// The report is signed by the reporting member of the destination group.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Debug, RustcEncodable, RustcDecodable)]
+pub struct RouteReport {
+    /// id of the message that was sent along disjoint paths
+    pub message_id: MessageId,
+    /// the destination the message was sent to
+    pub destination: NameType,
+    /// the path the reported copy travelled along
+    pub route: RoutePath,
+    /// the member of the destination group sending the report
+    pub reporter: PublicId,
+}
+
 #[cfg(test)]
 #[allow(deprecated)]
 mod test {
@@ -15,18 +15,276 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
+use std::cmp;
+use std::collections::BTreeSet;
 use cbor::CborTagEncode;
+use rand::random;
 use rustc_serialize::{Decodable, Decoder, Encodable, Encoder};
+use sodiumoxide::crypto::sign;
+use canonical::key_challenge_bytes;
 use public_id::PublicId;
 use types::Address;
+use error::RoutingError;
+use NameType;
 
+/// The newest protocol version we speak.
+pub static PROTOCOL_VERSION: u64 = 2;
+/// The oldest protocol version we still speak.
+pub static MIN_PROTOCOL_VERSION: u64 = 1;
+/// The protocol version of earlier releases, whose IAm announced no versions; we no longer
+/// speak it, as their signatures are not domain separated.
+pub static LEGACY_PROTOCOL_VERSION: u64 = 0;
+/// From this protocol version on, a relay challenges a client to prove it holds the key it
+/// identified with, before relaying for it.  Clients and relays speaking older versions are
+/// refused by each other.
+pub static KEY_CHALLENGE_VERSION: u64 = 2;
+
+/// An optional feature of the protocol, used on a connection only if both sides announce it.
+/// Only the supported_capabilities are ever announced or agreed on; the others are reserved for
+/// features not built yet.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug, RustcEncodable, RustcDecodable)]
+pub enum Capability {
+    Encryption,
+    Compression,
+    Fragmentation,
+}
+
+/// Returns the optional features this implementation supports.
+pub fn supported_capabilities() -> BTreeSet<Capability> {
+    vec![Capability::Compression].into_iter().collect()
+}
+
+/// Returns those of the given capabilities this implementation supports.
+pub fn only_supported(capabilities: &BTreeSet<Capability>) -> BTreeSet<Capability> {
+    capabilities.intersection(&supported_capabilities()).cloned().collect()
+}
+
+/// The first message on every connection, identifying the peer and announcing the protocol
+/// versions and capabilities it speaks.
 #[derive(Debug, Eq, PartialEq, RustcEncodable, RustcDecodable)]
 pub struct IAm {
     pub address: Address,
     pub public_id: PublicId,
+    pub min_version: u64,
+    pub max_version: u64,
+    pub capabilities: BTreeSet<Capability>,
+}
+
+/// The IAm of earlier releases, sent without a wire format envelope; see wire_format.
+#[derive(Debug, Eq, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct LegacyIAm {
+    pub address: Address,
+    pub public_id: PublicId,
+}
+
+impl LegacyIAm {
+    /// Reads the legacy IAm as one announcing the legacy protocol version only, so negotiating
+    /// with it fails and the peer can be refused.
+    pub fn into_i_am(self) -> IAm {
+        IAm {
+            address: self.address,
+            public_id: self.public_id,
+            min_version: LEGACY_PROTOCOL_VERSION,
+            max_version: LEGACY_PROTOCOL_VERSION,
+            capabilities: BTreeSet::new(),
+        }
+    }
+}
+
+impl IAm {
+    /// Announces our own protocol version range, with the given capabilities.
+    pub fn new(address: Address, public_id: PublicId, capabilities: BTreeSet<Capability>)
+            -> IAm {
+        IAm {
+            address: address,
+            public_id: public_id,
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            capabilities: capabilities,
+        }
+    }
+
+    /// Agrees with the peer's IAm on the highest protocol version both speak, and on the
+    /// capabilities both announced which we support.  Fails with IncompatibleProtocol if the
+    /// version ranges do not overlap; the connection should then be dropped.
+    pub fn negotiate(&self, capabilities: &BTreeSet<Capability>)
+            -> Result<NegotiatedProtocol, RoutingError> {
+        let version = cmp::min(self.max_version, PROTOCOL_VERSION);
+        if version < cmp::max(self.min_version, MIN_PROTOCOL_VERSION) {
+            return Err(RoutingError::IncompatibleProtocol(self.min_version, self.max_version));
+        }
+        let announced_by_both = self.capabilities.intersection(capabilities).cloned()
+            .collect::<BTreeSet<Capability>>();
+        Ok(NegotiatedProtocol {
+            version: version,
+            capabilities: only_supported(&announced_by_both),
+        })
+    }
+}
+
+/// The protocol version and capabilities agreed on for a connection.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct NegotiatedProtocol {
+    pub version: u64,
+    pub capabilities: BTreeSet<Capability>,
+}
+
+impl NegotiatedProtocol {
+    /// Returns true if both sides announced the capability.
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+}
+
+/// Sent before dropping a peer whose IAm announced no protocol version we speak, telling it the
+/// versions we do speak.
+#[derive(Debug, Eq, PartialEq, Clone, RustcEncodable, RustcDecodable)]
+pub struct ProtocolRefusal {
+    pub min_version: u64,
+    pub max_version: u64,
+}
+
+impl ProtocolRefusal {
+    /// Announces our own protocol version range.
+    pub fn new() -> ProtocolRefusal {
+        ProtocolRefusal { min_version: MIN_PROTOCOL_VERSION, max_version: PROTOCOL_VERSION }
+    }
+
+    /// Announces the protocol versions a client and its relay have to speak, those in which the
+    /// relay challenges the client to prove its key.
+    pub fn for_relaying() -> ProtocolRefusal {
+        ProtocolRefusal { min_version: KEY_CHALLENGE_VERSION, max_version: PROTOCOL_VERSION }
+    }
+
+    /// Returns the error of the refused peer, naming the versions the refusing peer speaks.
+    pub fn to_error(&self) -> RoutingError {
+        RoutingError::IncompatibleProtocol(self.min_version, self.max_version)
+    }
+}
+
+/// Sent by a relay to a client which identified itself.  The client signs the nonce, together
+/// with the relay's name, to prove it holds the key it claims.
+#[derive(Debug, Eq, PartialEq, Clone, RustcEncodable, RustcDecodable)]
+pub struct KeyChallenge {
+    pub nonce: Vec<u8>,
+}
+
+/// A client's answer to a KeyChallenge.
+#[derive(Debug, Eq, PartialEq, Clone, RustcEncodable, RustcDecodable)]
+pub struct KeyChallengeResponse {
+    pub signature: sign::Signature,
+}
+
+impl KeyChallenge {
+    /// Creates a challenge with a fresh random nonce.
+    pub fn new() -> KeyChallenge {
+        KeyChallenge { nonce: (0..32).map(|_| random::<u8>()).collect() }
+    }
+
+    /// Answers the challenge of the relay with the given name.
+    pub fn answer(&self, relay_name: &NameType, private_sign_key: &sign::SecretKey)
+            -> KeyChallengeResponse {
+        KeyChallengeResponse {
+            signature: sign::sign_detached(&key_challenge_bytes(relay_name, &self.nonce),
+                                           private_sign_key),
+        }
+    }
+
+    /// Returns true if the response to the challenge we sent as the relay with the given name
+    /// was signed with the key.
+    pub fn verify(&self, relay_name: &NameType, response: &KeyChallengeResponse,
+                  public_sign_key: &sign::PublicKey) -> bool {
+        sign::verify_detached(&response.signature,
+                              &key_challenge_bytes(relay_name, &self.nonce),
+                              public_sign_key)
+    }
 }
 
 #[cfg(test)]
 mod test {
-    // TODO: add validation test
+    use super::*;
+    use std::collections::BTreeSet;
+    use error::RoutingError;
+    use id::Id;
+    use public_id::PublicId;
+    use types::Address;
+
+    fn i_am(min_version: u64, max_version: u64, capabilities: Vec<Capability>) -> IAm {
+        let id = Id::new();
+        IAm {
+            address: Address::Node(id.name()),
+            public_id: PublicId::new(&id),
+            min_version: min_version,
+            max_version: max_version,
+            capabilities: capabilities.into_iter().collect(),
+        }
+    }
+
+    #[test]
+    fn negotiate() {
+        let ours = vec![Capability::Encryption, Capability::Compression]
+            .into_iter().collect::<BTreeSet<Capability>>();
+
+        // a newer peer falls back to our newest version
+        let protocol = i_am(MIN_PROTOCOL_VERSION, PROTOCOL_VERSION + 2,
+                            vec![Capability::Compression, Capability::Fragmentation])
+            .negotiate(&ours).unwrap();
+        assert_eq!(protocol.version, PROTOCOL_VERSION);
+        assert!(protocol.supports(Capability::Compression));
+        assert!(!protocol.supports(Capability::Encryption));
+        assert!(!protocol.supports(Capability::Fragmentation));
+
+        // a capability both announce is not agreed on while it is not implemented
+        let protocol = i_am(MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+                            vec![Capability::Encryption, Capability::Compression])
+            .negotiate(&ours).unwrap();
+        assert!(protocol.supports(Capability::Compression));
+        assert!(!protocol.supports(Capability::Encryption));
+        assert_eq!(only_supported(&ours), supported_capabilities());
+
+        // a peer speaking only versions we do not is refused
+        match i_am(PROTOCOL_VERSION + 1, PROTOCOL_VERSION + 2, vec![]).negotiate(&ours) {
+            Err(RoutingError::IncompatibleProtocol(min_version, max_version)) => {
+                assert_eq!(min_version, PROTOCOL_VERSION + 1);
+                assert_eq!(max_version, PROTOCOL_VERSION + 2);
+            },
+            _ => panic!("Expected IncompatibleProtocol"),
+        }
+
+        // so is a peer of an earlier release, which announced no versions
+        let id = Id::new();
+        let legacy_i_am = LegacyIAm { address: Address::Node(id.name()),
+                                      public_id: PublicId::new(&id) }.into_i_am();
+        match legacy_i_am.negotiate(&ours) {
+            Err(RoutingError::IncompatibleProtocol(min_version, max_version)) => {
+                assert_eq!(min_version, LEGACY_PROTOCOL_VERSION);
+                assert_eq!(max_version, LEGACY_PROTOCOL_VERSION);
+            },
+            _ => panic!("Expected IncompatibleProtocol"),
+        }
+
+        // and the refused peer is told which versions we speak
+        match ProtocolRefusal::new().to_error() {
+            RoutingError::IncompatibleProtocol(min_version, max_version) => {
+                assert_eq!(min_version, MIN_PROTOCOL_VERSION);
+                assert_eq!(max_version, PROTOCOL_VERSION);
+            },
+            _ => panic!("Expected IncompatibleProtocol"),
+        }
+    }
+
+    #[test]
+    fn key_challenge() {
+        let relay_name = Id::new().name();
+        let client = Id::new();
+        let challenge = KeyChallenge::new();
+        let response = challenge.answer(&relay_name, client.signing_private_key());
+        assert!(challenge.verify(&relay_name, &response, &client.signing_public_key()));
+
+        // the answer holds for this challenge, from this relay, with this key only
+        assert!(!KeyChallenge::new().verify(&relay_name, &response,
+                                            &client.signing_public_key()));
+        assert!(!challenge.verify(&Id::new().name(), &response, &client.signing_public_key()));
+        assert!(!challenge.verify(&relay_name, &response, &Id::new().signing_public_key()));
+    }
 }
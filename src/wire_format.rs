@@ -0,0 +1,418 @@
+// Copyright 2015 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Every top-level type sent between nodes and clients is wrapped in a CBOR tag naming its
+//! schema, around the wire format version it was encoded with and the encoded value.  This lets
+//! a receiver refuse, rather than misread, a version it does not know.
+//!
+//! Version 0 is the untagged encoding of earlier releases.  It is decoded by the type's
+//! decode_previous, which reads the earlier schema into the current type where that is still
+//! meaningful.  An IAm of an earlier release is read so the peer can be refused explicitly.  A
+//! SignedMessage or RoutingMessage of an earlier release is read into the current type: it has no
+//! disjoint route, a FindGroupResponse in it carries a network size estimate of 0, and the
+//! SignedMessage verifies only over its body as it is, without a domain, since that is how
+//! earlier releases signed it.  A message whose Data no longer decodes, such as StructuredData
+//! without its policies, is refused with the decoding error.  Other types are refused with
+//! UnsupportedWireFormat(0).  Values nested in a wire type, such as the RoutingMessage signed
+//! inside a SignedMessage, are enveloped on their own as well.
+//!
+//! Only the envelope makes later changes detectable.  MessageType and Data are nested in a
+//! RoutingMessage and are not enveloped on their own, so adding a variant or a field to them
+//! still needs a new WIRE_FORMAT_VERSION.  ResponseError keeps its own tags, 5483_100 for the
+//! variants of earlier releases, 5483_101 for InvalidSuccessor and 5483_102 for InvalidAppend,
+//! which carry a reason.
+
+use cbor::{CborBytes, CborError, CborTagEncode};
+use rustc_serialize::{Decodable, Decoder, Encodable, Encoder};
+use error::RoutingError;
+use compression::CompressedMessage;
+use messages::{LegacyRoutingMessage, LegacySignedMessage, RoutingMessage, SignedMessage};
+use utils;
+use who_are_you::{IAm, LegacyIAm, KeyChallenge, KeyChallengeResponse, ProtocolRefusal};
+
+/// The wire format version we encode with.
+pub static WIRE_FORMAT_VERSION: u64 = 1;
+
+/// The schema tag of a SignedMessage.
+pub static SIGNED_MESSAGE_TAG: u64 = 5483_300;
+/// The schema tag of a RoutingMessage, as signed inside a SignedMessage.
+pub static ROUTING_MESSAGE_TAG: u64 = 5483_301;
+/// The schema tag of an IAm.
+pub static I_AM_TAG: u64 = 5483_302;
+/// The schema tag of a CompressedMessage.
+pub static COMPRESSED_MESSAGE_TAG: u64 = 5483_303;
+/// The schema tag of a KeyChallenge.
+pub static KEY_CHALLENGE_TAG: u64 = 5483_304;
+/// The schema tag of a KeyChallengeResponse.
+pub static KEY_CHALLENGE_RESPONSE_TAG: u64 = 5483_305;
+/// The schema tag of a ProtocolRefusal.
+pub static PROTOCOL_REFUSAL_TAG: u64 = 5483_306;
+
+// the CBOR major type of a tag, in the top three bits of the first byte
+static CBOR_TAG_MAJOR_TYPE: u8 = 6;
+
+/// A type sent on the wire on its own, under its schema tag.
+pub trait WireType: Encodable + Decodable {
+    /// Returns the CBOR tag identifying the schema of this type.
+    fn schema_tag() -> u64;
+
+    /// Decodes a value encoded with a wire format version before the current one, version 0
+    /// being untagged.  By default the earlier versions are refused.
+    fn decode_previous(version: u64, _bytes: &Vec<u8>) -> Result<Self, RoutingError> {
+        Err(RoutingError::UnsupportedWireFormat(version))
+    }
+}
+
+impl WireType for SignedMessage {
+    fn schema_tag() -> u64 { SIGNED_MESSAGE_TAG }
+
+    fn decode_previous(version: u64, bytes: &Vec<u8>) -> Result<SignedMessage, RoutingError> {
+        match version {
+            0 => Ok(try!(utils::decode::<LegacySignedMessage>(bytes)).into_signed_message()),
+            _ => Err(RoutingError::UnsupportedWireFormat(version)),
+        }
+    }
+}
+
+impl WireType for RoutingMessage {
+    fn schema_tag() -> u64 { ROUTING_MESSAGE_TAG }
+
+    fn decode_previous(version: u64, bytes: &Vec<u8>) -> Result<RoutingMessage, RoutingError> {
+        match version {
+            0 => Ok(try!(utils::decode::<LegacyRoutingMessage>(bytes)).into_routing_message()),
+            _ => Err(RoutingError::UnsupportedWireFormat(version)),
+        }
+    }
+}
+
+impl WireType for IAm {
+    fn schema_tag() -> u64 { I_AM_TAG }
+
+    fn decode_previous(version: u64, bytes: &Vec<u8>) -> Result<IAm, RoutingError> {
+        match version {
+            0 => Ok(try!(utils::decode::<LegacyIAm>(bytes)).into_i_am()),
+            _ => Err(RoutingError::UnsupportedWireFormat(version)),
+        }
+    }
+}
+
+impl WireType for CompressedMessage {
+    fn schema_tag() -> u64 { COMPRESSED_MESSAGE_TAG }
+}
+
+impl WireType for KeyChallenge {
+    fn schema_tag() -> u64 { KEY_CHALLENGE_TAG }
+}
+
+impl WireType for KeyChallengeResponse {
+    fn schema_tag() -> u64 { KEY_CHALLENGE_RESPONSE_TAG }
+}
+
+impl WireType for ProtocolRefusal {
+    fn schema_tag() -> u64 { PROTOCOL_REFUSAL_TAG }
+}
+
+struct Envelope {
+    schema_tag: u64,
+    version: u64,
+    body: CborBytes,
+}
+
+impl Encodable for Envelope {
+    fn encode<E: Encoder>(&self, e: &mut E) -> Result<(), E::Error> {
+        CborTagEncode::new(self.schema_tag, &(self.version, &self.body)).encode(e)
+    }
+}
+
+impl Decodable for Envelope {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Envelope, D::Error> {
+        let schema_tag = try!(d.read_u64());
+        let (version, body) : (u64, CborBytes) = try!(Decodable::decode(d));
+        Ok(Envelope { schema_tag: schema_tag, version: version, body: body })
+    }
+}
+
+/// Encodes the value under its schema tag, with the current wire format version.
+pub fn encode<T: WireType>(value: &T) -> Result<Vec<u8>, CborError> {
+    utils::encode(&Envelope {
+        schema_tag: T::schema_tag(),
+        version: WIRE_FORMAT_VERSION,
+        body: CborBytes(try!(utils::encode(value))),
+    })
+}
+
+/// Decodes a value of the current wire format version, or of an earlier one the type still
+/// reads, see WireType::decode_previous.  Fails with UnexpectedSchemaTag if the bytes hold a
+/// different type, and with UnsupportedWireFormat if they were encoded with a version we do not
+/// support.
+pub fn decode<T: WireType>(bytes: &Vec<u8>) -> Result<T, RoutingError> {
+    if !is_enveloped(bytes) {
+        return T::decode_previous(0, bytes);
+    }
+    let envelope = try!(utils::decode::<Envelope>(bytes));
+    if envelope.schema_tag != T::schema_tag() {
+        return Err(RoutingError::UnexpectedSchemaTag(envelope.schema_tag));
+    }
+    if envelope.version > WIRE_FORMAT_VERSION {
+        return Err(RoutingError::UnsupportedWireFormat(envelope.version));
+    }
+    if envelope.version < WIRE_FORMAT_VERSION {
+        return T::decode_previous(envelope.version, &envelope.body.0);
+    }
+    Ok(try!(utils::decode::<T>(&envelope.body.0)))
+}
+
+/// Returns true if the bytes start with a tag, as every version but 0 does.  The tag is not
+/// checked to be a schema tag.
+pub fn is_enveloped(bytes: &Vec<u8>) -> bool {
+    bytes.first().map(|byte| *byte >> 5 == CBOR_TAG_MAJOR_TYPE).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::Envelope;
+    use cbor::CborBytes;
+    use std::collections::BTreeSet;
+    use sodiumoxide::crypto::sign;
+    use data::Data;
+    use error::{ResponseError, RoutingError, StructuredDataError};
+    use id::Id;
+    use immutable_data::{ImmutableData, ImmutableDataType};
+    use messages::{LegacyMessageType, LegacyRoutingMessage, LegacySignedMessage, MessageType,
+                   RoutingMessage, SignedMessage};
+    use name_type::NameType;
+    use public_id::PublicId;
+    use types::{Address, DestinationAddress, SourceAddress};
+    use authority::Authority;
+    use utils;
+    use who_are_you::{IAm, LegacyIAm, KeyChallenge, LEGACY_PROTOCOL_VERSION};
+
+    fn signed_message(id: &Id) -> SignedMessage {
+        let message = RoutingMessage {
+            destination : DestinationAddress::Direct(NameType([2u8; 64])),
+            source      : SourceAddress::Direct(id.name()),
+            orig_message: None,
+            message_type: MessageType::FindGroup,
+            message_id  : 7,
+            authority   : Authority::ManagedNode,
+        };
+        SignedMessage::new(&message, id.signing_private_key()).unwrap()
+    }
+
+    #[test]
+    fn golden_bytes() {
+        // a NameType, embedded in most routed types, as encoded by earlier releases
+        assert_eq!(utils::encode(&NameType([1u8; 64])).unwrap(), name_type(1));
+
+        // the envelope: tag, then the version and the encoded value as a byte string
+        let envelope = Envelope { schema_tag: I_AM_TAG, version: 1,
+                                  body: CborBytes(vec![1, 2, 3]) };
+        assert_eq!(utils::encode(&envelope).unwrap(),
+                   vec![0xda, 0x00, 0x53, 0xab, 0x26, 0x82, 0x01, 0x43, 0x01, 0x02, 0x03]);
+
+        // a whole value: the envelope around a struct, encoded as a map keyed by field name
+        let key_challenge = KeyChallenge { nonce: vec![1, 2, 3] };
+        let bytes = vec![0xda, 0x00, 0x53, 0xab, 0x28, 0x82, 0x01, 0x4b,
+                         0xa1, 0x65, 0x6e, 0x6f, 0x6e, 0x63, 0x65, 0x83, 0x01, 0x02, 0x03];
+        assert_eq!(encode(&key_challenge).unwrap(), bytes);
+        assert_eq!(decode::<KeyChallenge>(&bytes).ok(), Some(key_challenge));
+
+        // a SignedMessage starts with its tag and the current version
+        let message = signed_message(&Id::new());
+        assert_eq!(&encode(&message).unwrap()[..7],
+                   &[0xda, 0x00, 0x53, 0xab, 0x24, 0x82, 0x01][..]);
+    }
+
+    // a CBOR text string shorter than 24 bytes
+    fn text(value: &str) -> Vec<u8> {
+        let mut bytes = vec![0x60 + value.len() as u8];
+        bytes.extend(value.bytes());
+        bytes
+    }
+
+    // an enum variant with fields, encoded as a map of its name and its fields
+    fn variant(name: &str, fields: Vec<Vec<u8>>) -> Vec<u8> {
+        let mut bytes = vec![0xa2];
+        bytes.extend(text("variant").into_iter());
+        bytes.extend(text(name).into_iter());
+        bytes.extend(text("fields").into_iter());
+        bytes.push(0x80 + fields.len() as u8);
+        for field in fields {
+            bytes.extend(field.into_iter());
+        }
+        bytes
+    }
+
+    fn name_type(byte: u8) -> Vec<u8> {
+        let mut bytes = vec![0xda, 0x00, 0x53, 0xa9, 0xf8, 0x98, 0x40];
+        bytes.extend(vec![byte; 64].into_iter());
+        bytes
+    }
+
+    #[test]
+    fn golden_bytes_of_routed_types() {
+        // a variant without fields is encoded as its name alone
+        assert_eq!(utils::encode(&MessageType::FindGroup).unwrap(),
+                   vec![0x69, 0x46, 0x69, 0x6e, 0x64, 0x47, 0x72, 0x6f, 0x75, 0x70]);
+        assert_eq!(utils::encode(&MessageType::FindGroupResponse(Vec::new(), 7)).unwrap(),
+                   variant("FindGroupResponse", vec![vec![0x80], vec![0x07]]));
+
+        let data = Data::ImmutableData(ImmutableData::new(ImmutableDataType::Normal,
+                                                          vec![1, 2, 3]));
+        let mut immutable_data = vec![0xa2];
+        immutable_data.extend(text("type_tag").into_iter());
+        immutable_data.extend(text("Normal").into_iter());
+        immutable_data.extend(text("value").into_iter());
+        immutable_data.extend(vec![0x83, 0x01, 0x02, 0x03].into_iter());
+        let data_bytes = variant("ImmutableData", vec![immutable_data]);
+        assert_eq!(utils::encode(&data).unwrap(), data_bytes);
+        assert_eq!(utils::decode::<Data>(&data_bytes).ok(), Some(data));
+
+        // the ResponseErrors of earlier releases keep their tag and (type_tag, data) body
+        let no_data = vec![0xda, 0x00, 0x53, 0xaa, 0x5c, 0x82,
+                           0x66, 0x4e, 0x6f, 0x44, 0x61, 0x74, 0x61, 0xf6];
+        assert_eq!(utils::encode(&ResponseError::NoData).unwrap(), no_data);
+        assert_eq!(utils::decode::<ResponseError>(&no_data).ok(), Some(ResponseError::NoData));
+        let invalid_successor = ResponseError::InvalidSuccessor(
+            StructuredDataError::OwnerKeysMismatch);
+        let mut invalid_successor_bytes = vec![0xda, 0x00, 0x53, 0xaa, 0x5d, 0x82];
+        invalid_successor_bytes.extend(text("InvalidSuccessor").into_iter());
+        invalid_successor_bytes.extend(text("OwnerKeysMismatch").into_iter());
+        assert_eq!(utils::encode(&invalid_successor).unwrap(), invalid_successor_bytes);
+        assert_eq!(utils::decode::<ResponseError>(&invalid_successor_bytes).ok(),
+                   Some(invalid_successor));
+
+        // a struct is encoded as a map keyed by field name, in declaration order
+        let message = RoutingMessage {
+            destination : DestinationAddress::Direct(NameType([2u8; 64])),
+            source      : SourceAddress::Direct(NameType([1u8; 64])),
+            orig_message: None,
+            message_type: MessageType::FindGroup,
+            message_id  : 7,
+            authority   : Authority::ManagedNode,
+        };
+        let mut message_bytes = vec![0xa6];
+        message_bytes.extend(text("destination").into_iter());
+        message_bytes.extend(variant("Direct", vec![name_type(2)]).into_iter());
+        message_bytes.extend(text("source").into_iter());
+        message_bytes.extend(variant("Direct", vec![name_type(1)]).into_iter());
+        message_bytes.extend(text("orig_message").into_iter());
+        message_bytes.push(0xf6);
+        message_bytes.extend(text("message_type").into_iter());
+        message_bytes.extend(text("FindGroup").into_iter());
+        message_bytes.extend(text("message_id").into_iter());
+        message_bytes.push(0x07);
+        message_bytes.extend(text("authority").into_iter());
+        message_bytes.extend(text("ManagedNode").into_iter());
+        assert_eq!(utils::encode(&message).unwrap(), message_bytes);
+
+        // enveloped under its tag and the current version, the body as a byte string
+        let mut enveloped = vec![0xda, 0x00, 0x53, 0xab, 0x25, 0x82, 0x01,
+                                 0x59, (message_bytes.len() >> 8) as u8,
+                                 message_bytes.len() as u8];
+        enveloped.extend(message_bytes.into_iter());
+        assert_eq!(encode(&message).unwrap(), enveloped);
+        assert_eq!(decode::<RoutingMessage>(&enveloped).ok(), Some(message));
+    }
+
+    #[test]
+    fn accepts_current_version() {
+        let id = Id::new();
+        let message = signed_message(&id);
+        assert_eq!(decode::<SignedMessage>(&encode(&message).unwrap()).ok(),
+                   Some(message.clone()));
+        assert_eq!(message.get_routing_message().ok().map(|routing_message|
+                       routing_message.message_id), Some(7));
+
+        let i_am = IAm::new(Address::Node(id.name()), PublicId::new(&id), BTreeSet::new());
+        assert_eq!(decode::<IAm>(&encode(&i_am).unwrap()).ok(), Some(i_am));
+    }
+
+    #[test]
+    fn previous_version() {
+        let id = Id::new();
+
+        // an IAm of an earlier release is read, announcing only the legacy protocol version
+        let legacy_i_am = LegacyIAm { address: Address::Node(id.name()),
+                                      public_id: PublicId::new(&id) };
+        let i_am = decode::<IAm>(&utils::encode(&legacy_i_am).unwrap()).unwrap();
+        assert_eq!(i_am.public_id, PublicId::new(&id));
+        assert_eq!((i_am.min_version, i_am.max_version),
+                   (LEGACY_PROTOCOL_VERSION, LEGACY_PROTOCOL_VERSION));
+        assert!(i_am.capabilities.is_empty());
+
+        // a SignedMessage of an earlier release is read, verifying over its untagged body
+        let message = signed_message(&id);
+        let routing_message = message.get_routing_message().unwrap();
+        let encoded_body = utils::encode(&routing_message).unwrap();
+        let legacy_message = LegacySignedMessage {
+            signature: sign::sign_detached(&encoded_body, id.signing_private_key()),
+            encoded_body: encoded_body,
+        };
+        let read = decode::<SignedMessage>(&utils::encode(&legacy_message).unwrap()).unwrap();
+        assert!(read.verify_signature(&id.signing_public_key()));
+        assert!(!read.verify_signature(&Id::new().signing_public_key()));
+        assert!(read.route().is_none());
+        assert_eq!(read.get_routing_message().ok(), Some(routing_message.clone()));
+
+        // a signature without the domain is accepted only over an untagged body
+        let undomained = LegacySignedMessage {
+            encoded_body: encode(&routing_message).unwrap(),
+            signature: legacy_message.signature,
+        };
+        let read = decode::<SignedMessage>(&utils::encode(&undomained).unwrap()).unwrap();
+        assert!(!read.verify_signature(&id.signing_public_key()));
+
+        // a FindGroupResponse of an earlier release carries no network size estimate
+        let legacy_routing_message = LegacyRoutingMessage {
+            destination : DestinationAddress::Direct(NameType([2u8; 64])),
+            source      : SourceAddress::Direct(id.name()),
+            orig_message: None,
+            message_type: LegacyMessageType::FindGroupResponse(vec![PublicId::new(&id)]),
+            message_id  : 7,
+            authority   : Authority::NaeManager(NameType([2u8; 64])),
+        };
+        let read = decode::<RoutingMessage>(&utils::encode(&legacy_routing_message).unwrap())
+                       .unwrap();
+        assert_eq!(read.message_type,
+                   MessageType::FindGroupResponse(vec![PublicId::new(&id)], 0));
+        assert_eq!(read.authority, Authority::NaeManager(NameType([2u8; 64])));
+    }
+
+    #[test]
+    fn refuses_unsupported_version_and_other_schemas() {
+        let id = Id::new();
+        let message = signed_message(&id);
+        let envelope = Envelope {
+            schema_tag: SIGNED_MESSAGE_TAG,
+            version: WIRE_FORMAT_VERSION + 1,
+            body: CborBytes(utils::encode(&message).unwrap()),
+        };
+        match decode::<SignedMessage>(&utils::encode(&envelope).unwrap()) {
+            Err(RoutingError::UnsupportedWireFormat(version)) =>
+                assert_eq!(version, WIRE_FORMAT_VERSION + 1),
+            _ => panic!("Expected UnsupportedWireFormat"),
+        }
+
+        let i_am = IAm::new(Address::Node(id.name()), PublicId::new(&id), BTreeSet::new());
+        match decode::<SignedMessage>(&encode(&i_am).unwrap()) {
+            Err(RoutingError::UnexpectedSchemaTag(tag)) => assert_eq!(tag, I_AM_TAG),
+            _ => panic!("Expected UnexpectedSchemaTag"),
+        }
+    }
+}